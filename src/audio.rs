@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::process::Child;
+#[cfg(target_os = "linux")]
+use std::process::{Command, Stdio};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// Microseconds of sound aplay may hold, which is as far behind the
+// emulation as the sound can get
+#[cfg(target_os = "linux")]
+const PLAYER_BUFFER_TIME: u32 = 100_000;
+
+const WAV_HEADER_LENGTH: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+// Where the mixed samples of each frame go. Samples are between -1 and 1.
+pub trait AudioSink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+}
+
+// 16 bit little endian, as WAV and AVI files and aplay take them
+pub fn to_pcm(samples: &[f32]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        bytes.push((value & 0xFF) as u8);
+        bytes.push((value >> 8) as u8);
+    }
+    bytes
+}
+
+fn write_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&[(value & 0xFF) as u8, (value >> 8) as u8]);
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    write_u16(bytes, (value & 0xFFFF) as u16);
+    write_u16(bytes, (value >> 16) as u16);
+}
+
+// 16 bit mono WAV file. The header is rewritten with the final lengths
+// when the writer is finished or dropped.
+pub struct WavWriter {
+    file: File,
+    sample_rate: u32,
+    data_length: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut writer = WavWriter {
+            file: File::create(path)?,
+            sample_rate,
+            data_length: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = BITS_PER_SAMPLE / 8;
+        let mut header = Vec::with_capacity(WAV_HEADER_LENGTH as usize);
+        header.extend_from_slice(b"RIFF");
+        write_u32(&mut header, WAV_HEADER_LENGTH - 8 + self.data_length);
+        header.extend_from_slice(b"WAVEfmt ");
+        write_u32(&mut header, 16);
+        // PCM, one channel
+        write_u16(&mut header, 1);
+        write_u16(&mut header, 1);
+        write_u32(&mut header, self.sample_rate);
+        write_u32(&mut header, self.sample_rate * block_align as u32);
+        write_u16(&mut header, block_align);
+        write_u16(&mut header, BITS_PER_SAMPLE);
+        header.extend_from_slice(b"data");
+        write_u32(&mut header, self.data_length);
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+impl AudioSink for WavWriter {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let bytes = to_pcm(samples);
+        self.file.write_all(&bytes)?;
+        self.data_length += bytes.len() as u32;
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("No he podido cerrar el WAV: {}", e);
+        }
+    }
+}
+
+// Plays the samples through the sound device by feeding raw PCM to aplay,
+// the ALSA player, for want of a sound library. Only Linux has it, on
+// other systems there is no sound but the files. Writes block while the
+// player's buffer is full, which also paces the emulation to the speed of
+// the sound card.
+pub struct PlayerSink {
+    child: Child,
+}
+
+impl PlayerSink {
+    #[cfg(target_os = "linux")]
+    pub fn open(sample_rate: u32) -> io::Result<PlayerSink> {
+        let child = Command::new("aplay")
+            .args(["-q", "-t", "raw", "-f", "S16_LE", "-c", "1"])
+            .arg("-r")
+            .arg(sample_rate.to_string())
+            .arg(format!("--buffer-time={}", PLAYER_BUFFER_TIME))
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no encuentro aplay, de alsa-utils, que es la única salida de \
+                     sonido; --wav y --video lo graban",
+                ),
+                _ => e,
+            })?;
+        Ok(PlayerSink { child })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_sample_rate: u32) -> io::Result<PlayerSink> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "el sonido solo sale por aplay, en Linux; --wav y --video lo graban",
+        ))
+    }
+}
+
+impl AudioSink for PlayerSink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        match self.child.stdin {
+            Some(ref mut stdin) => stdin.write_all(&to_pcm(samples)),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "el reproductor no acepta datos",
+            )),
+        }
+    }
+}
+
+impl Drop for PlayerSink {
+    fn drop(&mut self) {
+        // Closing stdin lets the player drain its buffer and exit
+        self.child.stdin.take();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_header() {
+        let path = std::env::temp_dir().join(format!("z80-{}.wav", std::process::id()));
+        {
+            let mut writer = WavWriter::create(&path, 22_050).unwrap();
+            writer.write(&[0.0, 1.0]).unwrap();
+            writer.write(&[-1.0, 2.0]).unwrap();
+        }
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 16);
+        // PCM, mono, the rate, bytes per second and per sample, and bits
+        assert_eq!((u16_at(20), u16_at(22)), (1, 1));
+        assert_eq!((u32_at(24), u32_at(28)), (22_050, 44_100));
+        assert_eq!((u16_at(32), u16_at(34)), (2, 16));
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(40), 8);
+        // Clamped to the 16 bit range
+        let samples: Vec<i16> = (0..4).map(|i| u16_at(44 + i * 2) as i16).collect();
+        assert_eq!(samples, [0, 32767, -32767, 32767]);
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use audio;
+
+// Uncompressed AVI files: each frame is a 24 bit bitmap followed by the
+// 16 bit mono samples produced during it, so both streams stay in step
+// and any player can open the file without a codec.
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+const BITS_PER_PIXEL: u16 = 24;
+const BLOCK_ALIGN: u16 = 2;
+const WAVE_FORMAT_PCM: u16 = 1;
+
+const VIDEO_CHUNK: &[u8; 4] = b"00db";
+const AUDIO_CHUNK: &[u8; 4] = b"01wb";
+
+// RIFF, hdrl with the main header and two stream lists, and the head of
+// the movi list
+const HEADER_LENGTH: u64 = 12 + 12 + 64 + 2 * (12 + 64) + 48 + 24 + 12;
+const INDEX_ENTRY_LENGTH: u64 = 16;
+// Sizes in a RIFF file are 32 bits long
+const MAX_FILE_LENGTH: u64 = u32::MAX as u64;
+
+fn write_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(id);
+    write_u32(bytes, data.len() as u32);
+    bytes.extend_from_slice(data);
+}
+
+fn write_list(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(b"LIST");
+    write_u32(bytes, data.len() as u32 + 4);
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(data);
+}
+
+// The header is rewritten with the final counts, and the index appended,
+// when the writer is finished or dropped
+pub struct AviWriter {
+    file: File,
+    width: usize,
+    height: usize,
+    // Frames last `scale` / `rate` seconds
+    rate: u32,
+    scale: u32,
+    sample_rate: u32,
+    frames: u32,
+    samples: u32,
+    // Bytes written after the movi list type
+    movi_length: u64,
+    index: Vec<u8>,
+}
+
+impl AviWriter {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+        rate: u32,
+        scale: u32,
+        sample_rate: u32,
+    ) -> io::Result<AviWriter> {
+        let mut writer = AviWriter {
+            file: File::create(path)?,
+            width,
+            height,
+            rate,
+            scale,
+            sample_rate,
+            frames: 0,
+            samples: 0,
+            movi_length: 0,
+            index: Vec::new(),
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn frame_length(&self) -> u32 {
+        // Rows of 24 bit pixels are padded to 4 bytes
+        let row = (self.width * 3 + 3) & !3;
+        (row * self.height) as u32
+    }
+
+    fn stream_header(&self, kind: &[u8; 4], scale: u32, rate: u32, length: u32) -> Vec<u8> {
+        let mut strh = Vec::with_capacity(56);
+        strh.extend_from_slice(kind);
+        // Handler, flags, priority and language, and initial frames
+        write_u32(&mut strh, 0);
+        write_u32(&mut strh, 0);
+        write_u32(&mut strh, 0);
+        write_u32(&mut strh, 0);
+        write_u32(&mut strh, scale);
+        write_u32(&mut strh, rate);
+        write_u32(&mut strh, 0);
+        write_u32(&mut strh, length);
+        let (buffer_size, sample_size, width, height) = if kind == b"vids" {
+            (self.frame_length(), 0, self.width, self.height)
+        } else {
+            (
+                self.sample_rate * BLOCK_ALIGN as u32,
+                BLOCK_ALIGN as u32,
+                0,
+                0,
+            )
+        };
+        write_u32(&mut strh, buffer_size);
+        // Default quality
+        write_u32(&mut strh, u32::MAX);
+        write_u32(&mut strh, sample_size);
+        write_u16(&mut strh, 0);
+        write_u16(&mut strh, 0);
+        write_u16(&mut strh, width as u16);
+        write_u16(&mut strh, height as u16);
+        strh
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let frame_length = self.frame_length();
+        let micros = self.scale as u64 * 1_000_000 / self.rate as u64;
+        let audio_rate = self.sample_rate * BLOCK_ALIGN as u32;
+
+        let mut avih = Vec::with_capacity(56);
+        write_u32(&mut avih, micros as u32);
+        let bytes_per_second = frame_length as u64 * self.rate as u64 / self.scale as u64;
+        write_u32(&mut avih, (bytes_per_second + audio_rate as u64) as u32);
+        write_u32(&mut avih, 0);
+        write_u32(&mut avih, AVIF_HASINDEX);
+        write_u32(&mut avih, self.frames);
+        write_u32(&mut avih, 0);
+        // Video and audio streams
+        write_u32(&mut avih, 2);
+        write_u32(&mut avih, frame_length);
+        write_u32(&mut avih, self.width as u32);
+        write_u32(&mut avih, self.height as u32);
+        avih.extend_from_slice(&[0; 16]);
+
+        // Bitmap info: the rows go from the bottom up
+        let mut bitmap = Vec::with_capacity(40);
+        write_u32(&mut bitmap, 40);
+        write_u32(&mut bitmap, self.width as u32);
+        write_u32(&mut bitmap, self.height as u32);
+        write_u16(&mut bitmap, 1);
+        write_u16(&mut bitmap, BITS_PER_PIXEL);
+        write_u32(&mut bitmap, 0);
+        write_u32(&mut bitmap, frame_length);
+        bitmap.extend_from_slice(&[0; 16]);
+
+        let mut wave = Vec::with_capacity(16);
+        write_u16(&mut wave, WAVE_FORMAT_PCM);
+        write_u16(&mut wave, 1);
+        write_u32(&mut wave, self.sample_rate);
+        write_u32(&mut wave, audio_rate);
+        write_u16(&mut wave, BLOCK_ALIGN);
+        write_u16(&mut wave, BLOCK_ALIGN * 8);
+
+        let mut video = Vec::new();
+        let strh = self.stream_header(b"vids", self.scale, self.rate, self.frames);
+        write_chunk(&mut video, b"strh", &strh);
+        write_chunk(&mut video, b"strf", &bitmap);
+        let mut sound = Vec::new();
+        let strh = self.stream_header(b"auds", BLOCK_ALIGN as u32, audio_rate, self.samples);
+        write_chunk(&mut sound, b"strh", &strh);
+        write_chunk(&mut sound, b"strf", &wave);
+
+        let mut hdrl = Vec::new();
+        write_chunk(&mut hdrl, b"avih", &avih);
+        write_list(&mut hdrl, b"strl", &video);
+        write_list(&mut hdrl, b"strl", &sound);
+
+        let riff_length = HEADER_LENGTH - 8 + self.movi_length + self.index_length();
+        let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+        header.extend_from_slice(b"RIFF");
+        write_u32(&mut header, riff_length as u32);
+        header.extend_from_slice(b"AVI ");
+        write_list(&mut header, b"hdrl", &hdrl);
+        header.extend_from_slice(b"LIST");
+        write_u32(&mut header, self.movi_length as u32 + 4);
+        header.extend_from_slice(b"movi");
+        debug_assert_eq!(header.len() as u64, HEADER_LENGTH);
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file
+            .seek(SeekFrom::Start(HEADER_LENGTH + self.movi_length))?;
+        Ok(())
+    }
+
+    // The idx1 chunk, once written
+    fn index_length(&self) -> u64 {
+        8 + self.index.len() as u64
+    }
+
+    fn write_data(&mut self, id: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        let mut chunk = Vec::with_capacity(8 + data.len());
+        write_chunk(&mut chunk, id, data);
+        self.file.write_all(&chunk)?;
+
+        // Offsets count from the movi list type
+        self.index.extend_from_slice(id);
+        write_u32(&mut self.index, AVIIF_KEYFRAME);
+        write_u32(&mut self.index, self.movi_length as u32 + 4);
+        write_u32(&mut self.index, data.len() as u32);
+        self.movi_length += chunk.len() as u64;
+        Ok(())
+    }
+
+    // `pixels` holds the rows of the frame as 0xRRGGBB values and `samples`
+    // the sound that goes with it, between -1 and 1
+    pub fn frame(&mut self, pixels: &[u32], samples: &[f32]) -> io::Result<()> {
+        let pcm = audio::to_pcm(samples);
+        let added = 16 + self.frame_length() as u64 + pcm.len() as u64 + 2 * INDEX_ENTRY_LENGTH;
+        let length = HEADER_LENGTH + self.movi_length + self.index_length() + added;
+        if length > MAX_FILE_LENGTH {
+            return Err(io::Error::other("el AVI ha llegado a 4 GB"));
+        }
+
+        let mut bitmap = Vec::with_capacity(self.frame_length() as usize);
+        let padding = (4 - self.width * 3 % 4) % 4;
+        for row in pixels.chunks(self.width).take(self.height).rev() {
+            for &pixel in row {
+                bitmap.extend_from_slice(&[pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8]);
+            }
+            bitmap.extend(std::iter::repeat_n(0, padding));
+        }
+        self.write_data(VIDEO_CHUNK, &bitmap)?;
+        self.frames += 1;
+
+        if !pcm.is_empty() {
+            self.write_data(AUDIO_CHUNK, &pcm)?;
+            self.samples += samples.len() as u32;
+        }
+        Ok(())
+    }
+
+    // More frames can still come, they go over the index, which is
+    // written again with them
+    pub fn finish(&mut self) -> io::Result<()> {
+        let mut index = Vec::with_capacity(self.index_length() as usize);
+        write_chunk(&mut index, b"idx1", &self.index);
+        self.file.write_all(&index)?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for AviWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("No he podido cerrar el AVI: {}", e);
+        }
+    }
+}
@@ -0,0 +1,414 @@
+// General Instrument AY-3-8912 sound generator.
+//
+// The chip runs at half the CPU clock and its generators advance once
+// every 8 of its own cycles, so the emulation ticks every 16 T-states.
+// Samples are the average of the ticks that fall in each sample period.
+
+use std::io;
+
+use state::{SaveState, StateReader, StateWriter};
+
+// T-states between two ticks of the generators
+const T_STATES_PER_TICK: u64 = 16;
+
+pub const REGISTER_COUNT: usize = 16;
+
+// Bits that exist in each register
+const REGISTER_MASKS: [u8; REGISTER_COUNT] = [
+    0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0x1F, 0xFF, 0x1F, 0x1F, 0x1F, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF,
+];
+
+const NOISE_PERIOD: usize = 6;
+const MIXER: usize = 7;
+const AMPLITUDE_A: usize = 8;
+const ENVELOPE_PERIOD_FINE: usize = 11;
+const ENVELOPE_PERIOD_COARSE: usize = 12;
+const ENVELOPE_SHAPE: usize = 13;
+
+// Amplitude register bit that hands the level over to the envelope
+const ENVELOPE_MODE: u8 = 0x10;
+
+// Envelope shape bits
+const HOLD: u8 = 0x01;
+const ALTERNATE: u8 = 0x02;
+const ATTACK: u8 = 0x04;
+const CONTINUE: u8 = 0x08;
+
+// The DAC is logarithmic, roughly 3 dB per level
+const VOLUMES: [f32; 16] = [
+    0.0, 0.0137, 0.0205, 0.0291, 0.0423, 0.0618, 0.0847, 0.1369, 0.1691, 0.2647, 0.3527, 0.4499,
+    0.5704, 0.6873, 0.8482, 1.0,
+];
+
+pub struct Ay {
+    regs: [u8; REGISTER_COUNT],
+    selected: u8,
+
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+
+    // Noise and envelope step at half the rate of the tone generators
+    noise_counter: u16,
+    noise_lfsr: u32,
+    envelope_counter: u32,
+    envelope_step: u8,
+    envelope_mask: u8,
+    envelope_holding: bool,
+
+    // T-state the generators have been run up to
+    t_states: u64,
+
+    ticks_per_sample: f64,
+    sample_position: f64,
+    accumulator: f32,
+    accumulated: u32,
+    samples: Vec<f32>,
+}
+
+impl Ay {
+    pub fn new(cpu_clock: u64, sample_rate: u32) -> Ay {
+        let mut ay = Ay {
+            regs: [0; REGISTER_COUNT],
+            selected: 0,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_lfsr: 1,
+            envelope_counter: 0,
+            envelope_step: 15,
+            envelope_mask: 0,
+            envelope_holding: true,
+            t_states: 0,
+            ticks_per_sample: 1.0,
+            sample_position: 0.0,
+            accumulator: 0.0,
+            accumulated: 0,
+            samples: Vec::new(),
+        };
+        ay.set_sample_rate(cpu_clock, sample_rate);
+        ay
+    }
+
+    pub fn set_sample_rate(&mut self, cpu_clock: u64, sample_rate: u32) {
+        self.ticks_per_sample = cpu_clock as f64 / T_STATES_PER_TICK as f64 / sample_rate as f64;
+    }
+
+    pub fn select_register(&mut self, value: u8) {
+        self.selected = value;
+    }
+
+    pub fn selected_register(&self) -> u8 {
+        self.selected
+    }
+
+    // Writes the selected register. Registers above 15 do not exist.
+    pub fn write_register(&mut self, value: u8) {
+        let reg = self.selected as usize;
+        if reg < REGISTER_COUNT {
+            self.set_register(reg, value);
+        }
+    }
+
+    pub fn read_register(&self) -> u8 {
+        let reg = self.selected as usize;
+        if reg < REGISTER_COUNT {
+            self.regs[reg]
+        } else {
+            0xFF
+        }
+    }
+
+    pub fn register(&self, reg: usize) -> u8 {
+        self.regs[reg]
+    }
+
+    pub fn set_register(&mut self, reg: usize, value: u8) {
+        self.regs[reg] = value & REGISTER_MASKS[reg];
+        if reg == ENVELOPE_SHAPE {
+            // Writing the shape restarts the envelope
+            self.envelope_counter = 0;
+            self.envelope_step = 15;
+            self.envelope_mask = if value & ATTACK != 0 { 0x0F } else { 0 };
+            self.envelope_holding = false;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = (self.regs[channel * 2 + 1] as u16) << 8 | self.regs[channel * 2] as u16;
+        period.max(1)
+    }
+
+    fn noise_period(&self) -> u16 {
+        (self.regs[NOISE_PERIOD] as u16).max(1)
+    }
+
+    fn envelope_period(&self) -> u32 {
+        let period = (self.regs[ENVELOPE_PERIOD_COARSE] as u32) << 8
+            | self.regs[ENVELOPE_PERIOD_FINE] as u32;
+        period.max(1)
+    }
+
+    fn step_envelope(&mut self) {
+        if self.envelope_step > 0 {
+            self.envelope_step -= 1;
+            return;
+        }
+
+        // End of a ramp
+        let shape = self.regs[ENVELOPE_SHAPE];
+        if shape & CONTINUE == 0 {
+            self.envelope_mask = 0;
+            self.envelope_holding = true;
+        } else {
+            if shape & ALTERNATE != 0 {
+                self.envelope_mask ^= 0x0F;
+            }
+            if shape & HOLD != 0 {
+                self.envelope_holding = true;
+            } else {
+                self.envelope_step = 15;
+            }
+        }
+    }
+
+    fn envelope_volume(&self) -> u8 {
+        self.envelope_step ^ self.envelope_mask
+    }
+
+    fn tick(&mut self) {
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period() * 2 {
+            self.noise_counter = 0;
+            // 17 bit shift register with taps at bits 0 and 3
+            let bit = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 1;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (bit << 16);
+        }
+
+        if !self.envelope_holding {
+            self.envelope_counter += 1;
+            if self.envelope_counter >= self.envelope_period() * 2 {
+                self.envelope_counter = 0;
+                self.step_envelope();
+            }
+        }
+    }
+
+    // Mixes the three channels into a level between 0 and 1
+    fn output(&self) -> f32 {
+        let mixer = self.regs[MIXER];
+        let noise = self.noise_lfsr & 1 != 0;
+        let mut level = 0.0;
+        for channel in 0..3 {
+            let tone_off = mixer & (0x01 << channel) != 0;
+            let noise_off = mixer & (0x08 << channel) != 0;
+            if (self.tone_outputs[channel] || tone_off) && (noise || noise_off) {
+                let amplitude = self.regs[AMPLITUDE_A + channel];
+                let volume = if amplitude & ENVELOPE_MODE != 0 {
+                    self.envelope_volume()
+                } else {
+                    amplitude & 0x0F
+                };
+                level += VOLUMES[volume as usize];
+            }
+        }
+        level / 3.0
+    }
+
+    // Moves the clock without running the generators, for when the CPU
+    // clock jumps as on a snapshot load
+    pub fn reset_clock(&mut self, t_states: u64) {
+        self.t_states = t_states;
+    }
+
+    // Advances the generators up to the given T-state
+    pub fn run_to(&mut self, t_states: u64) {
+        while self.t_states + T_STATES_PER_TICK <= t_states {
+            self.t_states += T_STATES_PER_TICK;
+            self.tick();
+
+            self.accumulator += self.output();
+            self.accumulated += 1;
+            self.sample_position += 1.0;
+            if self.sample_position >= self.ticks_per_sample {
+                self.sample_position -= self.ticks_per_sample;
+                self.samples
+                    .push(self.accumulator / self.accumulated as f32);
+                self.accumulator = 0.0;
+                self.accumulated = 0;
+            }
+        }
+    }
+
+    // Samples generated since the last call, between 0 and 1
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Takes at most `count` samples, leaving the rest for the next call
+    pub fn drain_samples(&mut self, count: usize) -> Vec<f32> {
+        let count = count.min(self.samples.len());
+        self.samples.drain(..count).collect()
+    }
+}
+
+impl SaveState for Ay {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.regs);
+        state.u8(self.selected);
+        for channel in 0..3 {
+            state.u16(self.tone_counters[channel]);
+            state.bool(self.tone_outputs[channel]);
+        }
+        state.u16(self.noise_counter);
+        state.u32(self.noise_lfsr);
+        state.u32(self.envelope_counter);
+        state.u8(self.envelope_step);
+        state.u8(self.envelope_mask);
+        state.bool(self.envelope_holding);
+        state.u64(self.t_states);
+        state.f64(self.sample_position);
+        state.f32(self.accumulator);
+        state.u32(self.accumulated);
+        state.u32(self.samples.len() as u32);
+        for &sample in self.samples.iter() {
+            state.f32(sample);
+        }
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.regs.copy_from_slice(state.bytes(REGISTER_COUNT)?);
+        self.selected = state.u8()?;
+        for channel in 0..3 {
+            self.tone_counters[channel] = state.u16()?;
+            self.tone_outputs[channel] = state.bool()?;
+        }
+        self.noise_counter = state.u16()?;
+        self.noise_lfsr = state.u32()?;
+        self.envelope_counter = state.u32()?;
+        self.envelope_step = state.u8()?;
+        self.envelope_mask = state.u8()?;
+        self.envelope_holding = state.bool()?;
+        self.t_states = state.u64()?;
+        self.sample_position = state.f64()?;
+        self.accumulator = state.f32()?;
+        self.accumulated = state.u32()?;
+        self.samples.clear();
+        for _ in 0..state.u32()? {
+            self.samples.push(state.f32()?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iobus::IoBus;
+    use machine::Model;
+    use memory::Memory;
+
+    fn new_ay() -> Ay {
+        Ay::new(3_546_900, 44_100)
+    }
+
+    // The ticks, of the first `ticks`, after which the value changed
+    fn changes<T: PartialEq, F: Fn(&Ay) -> T>(ay: &mut Ay, ticks: usize, value: F) -> Vec<usize> {
+        let mut flips = Vec::new();
+        for tick in 1..=ticks {
+            let before = value(ay);
+            ay.tick();
+            if value(ay) != before {
+                flips.push(tick);
+            }
+        }
+        flips
+    }
+
+    #[test]
+    fn tone_period() {
+        let mut ay = new_ay();
+        ay.set_register(0, 0x05);
+        assert_eq!(changes(&mut ay, 16, |ay| ay.tone_outputs[0]), [5, 10, 15]);
+
+        // Fine and coarse together, and 0 taken as 1
+        let mut ay = new_ay();
+        ay.set_register(2, 0x02);
+        ay.set_register(3, 0x01);
+        assert_eq!(changes(&mut ay, 600, |ay| ay.tone_outputs[1]), [258, 516]);
+        assert_eq!(changes(&mut ay, 3, |ay| ay.tone_outputs[2]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn noise_period() {
+        let mut ay = new_ay();
+        ay.set_register(NOISE_PERIOD, 3);
+        assert_eq!(changes(&mut ay, 20, |ay| ay.noise_lfsr), [6, 12, 18]);
+        // Only 5 bits of period
+        let mut ay = new_ay();
+        ay.set_register(NOISE_PERIOD, 0x21);
+        assert_eq!(changes(&mut ay, 5, |ay| ay.noise_lfsr), [2, 4]);
+    }
+
+    #[test]
+    fn envelope_shapes() {
+        let down: Vec<u8> = (0..16).rev().collect();
+        let up: Vec<u8> = (0..16).collect();
+        let low = vec![0; 16];
+        let high = vec![15; 16];
+        let shapes: [(u8, [&Vec<u8>; 3]); 10] = [
+            (0x00, [&down, &low, &low]),
+            (0x04, [&up, &low, &low]),
+            (0x08, [&down, &down, &down]),
+            (0x09, [&down, &low, &low]),
+            (0x0A, [&down, &up, &down]),
+            (0x0B, [&down, &high, &high]),
+            (0x0C, [&up, &up, &up]),
+            (0x0D, [&up, &high, &high]),
+            (0x0E, [&up, &down, &up]),
+            (0x0F, [&up, &low, &low]),
+        ];
+        for &(shape, ref ramps) in shapes.iter() {
+            let mut ay = new_ay();
+            ay.set_register(ENVELOPE_PERIOD_FINE, 1);
+            ay.set_register(ENVELOPE_SHAPE, shape);
+            // A step every 2 ticks with a period of 1
+            let mut volumes = vec![ay.envelope_volume()];
+            for _ in 1..48 {
+                ay.tick();
+                ay.tick();
+                volumes.push(ay.envelope_volume());
+            }
+            let expected: Vec<u8> = ramps.iter().flat_map(|ramp| ramp.iter().cloned()).collect();
+            assert_eq!(volumes, expected, "shape {:02X}", shape);
+        }
+    }
+
+    #[test]
+    fn port_writes_are_masked() {
+        let mut io = IoBus::new();
+        let mut mem = Memory::for_model(Model::Spectrum128K);
+        io.ay = Some(new_ay());
+        let masks = [
+            0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0x1F, 0xFF, 0x1F, 0x1F, 0x1F, 0xFF, 0xFF, 0x0F,
+            0xFF, 0xFF,
+        ];
+        for (reg, &mask) in masks.iter().enumerate() {
+            io.write_port(&mut mem, 0xFFFD, reg as u8, 0);
+            io.write_port(&mut mem, 0xBFFD, 0xFF, 0);
+            assert_eq!(io.read_port(&mem, 0xFFFD, 0), mask, "R{}", reg);
+        }
+        // There is no register 16
+        io.write_port(&mut mem, 0xFFFD, 16, 0);
+        io.write_port(&mut mem, 0xBFFD, 0x00, 0);
+        assert_eq!(io.read_port(&mem, 0xFFFD, 0), 0xFF);
+        assert_eq!(io.ay.as_ref().unwrap().register(15), 0xFF);
+    }
+}
@@ -0,0 +1,206 @@
+// The speaker driven by bit 4 of port 0xFE.
+//
+// Transitions are stored with their T-state and turned into samples when
+// the machine catches up. Each sample is the exact average of the level
+// over its period, which is a box filter, and a one pole low-pass takes
+// the remaining edges off before the samples leave.
+
+use std::io;
+
+use state::{SaveState, StateReader, StateWriter};
+
+// Cut-off of the low-pass, close to what the 48K speaker circuit lets through
+const CUT_OFF_HZ: f64 = 8000.0;
+
+pub struct Beeper {
+    // Transitions not yet turned into samples: (T-state, level)
+    edges: Vec<(u64, bool)>,
+    level: bool,
+
+    // T-state the samples have been generated up to
+    t_states: u64,
+
+    t_states_per_sample: f64,
+    // T-states already integrated into the current sample and the
+    // time spent high among them
+    position: f64,
+    high_time: f64,
+
+    filter_factor: f32,
+    filtered: f32,
+    samples: Vec<f32>,
+}
+
+impl Beeper {
+    pub fn new(cpu_clock: u64, sample_rate: u32) -> Beeper {
+        let mut beeper = Beeper {
+            edges: Vec::new(),
+            level: false,
+            t_states: 0,
+            t_states_per_sample: 1.0,
+            position: 0.0,
+            high_time: 0.0,
+            filter_factor: 1.0,
+            filtered: 0.0,
+            samples: Vec::new(),
+        };
+        beeper.set_sample_rate(cpu_clock, sample_rate);
+        beeper
+    }
+
+    pub fn set_sample_rate(&mut self, cpu_clock: u64, sample_rate: u32) {
+        self.t_states_per_sample = cpu_clock as f64 / sample_rate as f64;
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * CUT_OFF_HZ);
+        let dt = 1.0 / sample_rate as f64;
+        self.filter_factor = (dt / (rc + dt)) as f32;
+    }
+
+    // Records a change of the speaker bit
+    pub fn edge(&mut self, t_states: u64, level: bool) {
+        self.edges.push((t_states, level));
+    }
+
+    // Moves the clock without generating samples, for when the CPU
+    // clock jumps as on a snapshot load
+    pub fn reset_clock(&mut self, t_states: u64, level: bool) {
+        self.edges.clear();
+        self.level = level;
+        self.t_states = t_states;
+        self.position = 0.0;
+        self.high_time = 0.0;
+    }
+
+    // Integrates the current level up to the given T-state
+    fn advance(&mut self, t_states: u64) {
+        if t_states <= self.t_states {
+            return;
+        }
+        let mut remaining = (t_states - self.t_states) as f64;
+        self.t_states = t_states;
+
+        while self.position + remaining >= self.t_states_per_sample {
+            let step = self.t_states_per_sample - self.position;
+            if self.level {
+                self.high_time += step;
+            }
+            remaining -= step;
+
+            let sample = (self.high_time / self.t_states_per_sample) as f32;
+            self.filtered += self.filter_factor * (sample - self.filtered);
+            self.samples.push(self.filtered);
+            self.position = 0.0;
+            self.high_time = 0.0;
+        }
+
+        self.position += remaining;
+        if self.level {
+            self.high_time += remaining;
+        }
+    }
+
+    // Generates the samples up to the given T-state
+    pub fn run_to(&mut self, t_states: u64) {
+        let mut pending = 0;
+        while pending < self.edges.len() && self.edges[pending].0 <= t_states {
+            let (time, level) = self.edges[pending];
+            self.advance(time);
+            self.level = level;
+            pending += 1;
+        }
+        self.edges.drain(..pending);
+        self.advance(t_states);
+    }
+
+    // Samples generated since the last call, between 0 and 1
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+// The pending edges and samples go too, the rate and filter factor
+// come from the machine
+impl SaveState for Beeper {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u32(self.edges.len() as u32);
+        for &(t_states, level) in self.edges.iter() {
+            state.u64(t_states);
+            state.bool(level);
+        }
+        state.bool(self.level);
+        state.u64(self.t_states);
+        state.f64(self.position);
+        state.f64(self.high_time);
+        state.f32(self.filtered);
+        state.u32(self.samples.len() as u32);
+        for &sample in self.samples.iter() {
+            state.f32(sample);
+        }
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.edges.clear();
+        for _ in 0..state.u32()? {
+            let t_states = state.u64()?;
+            self.edges.push((t_states, state.bool()?));
+        }
+        self.level = state.bool()?;
+        self.t_states = state.u64()?;
+        self.position = state.f64()?;
+        self.high_time = state.f64()?;
+        self.filtered = state.f32()?;
+        self.samples.clear();
+        for _ in 0..state.u32()? {
+            self.samples.push(state.f32()?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_CLOCK: u64 = 3_500_000;
+    const SAMPLE_RATE: u32 = 44_100;
+
+    // A tenth of a second of a square wave with the given half period,
+    // leaving out the first samples, where the low-pass is still settling
+    fn square_wave(half_period: u64) -> Vec<f32> {
+        let mut beeper = Beeper::new(CPU_CLOCK, SAMPLE_RATE);
+        let end = CPU_CLOCK / 10;
+        for (i, t_states) in (0..end).step_by(half_period as usize).enumerate() {
+            beeper.edge(t_states, i % 2 == 0);
+        }
+        beeper.run_to(end);
+        let mut samples = beeper.take_samples();
+        // The last one may be a rounding away
+        let expected = (SAMPLE_RATE / 10) as usize;
+        assert!(samples.len() + 1 >= expected && samples.len() <= expected);
+        samples.drain(..100);
+        samples
+    }
+
+    fn stats(samples: &[f32]) -> (f32, f32, f32) {
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        let min = samples.iter().cloned().fold(1.0, f32::min);
+        let max = samples.iter().cloned().fold(0.0, f32::max);
+        (mean, min, max)
+    }
+
+    #[test]
+    fn square_wave_in_band() {
+        // 1 kHz goes through nearly whole
+        let (mean, min, max) = stats(&square_wave(1750));
+        assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+        assert!((0.0..0.05).contains(&min), "{}", min);
+        assert!(max <= 1.0 && max > 0.95, "{}", max);
+    }
+
+    #[test]
+    fn square_wave_above_cut_off() {
+        // 17.5 kHz keeps its level but loses most of its swing
+        let (mean, min, max) = stats(&square_wave(100));
+        assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+        assert!(max - min < 0.5, "{} {}", min, max);
+    }
+}
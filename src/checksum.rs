@@ -0,0 +1,40 @@
+// CRC-32 as used by zip, PNG and most ROM catalogues (polynomial
+// 0xEDB88320, reflected), and the Adler-32 of zlib streams
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+const ADLER_MODULUS: u32 = 65521;
+
+// Carries on a CRC from `crc`, which starts at 0
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    // 5552 bytes is the most that can be added before the sums overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MODULUS;
+        b %= ADLER_MODULUS;
+    }
+    (b << 16) | a
+}
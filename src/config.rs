@@ -0,0 +1,307 @@
+// Settings file of the emulator, in TOML. It is read before the command
+// line, whose arguments override it.
+//
+//     [machine]
+//     model = "128k"
+//     roms = ["128-0.rom", "128-1.rom", "+3:plus3.rom"]
+//
+//     [video]
+//     scale = 2                               # 1 to 4
+//     fullscreen = false
+//     border = "normal"                       # "none", "normal" or "full"
+//     aspect = "pal"                          # "square", "pal" or "4:3"
+//     filters = ["scanlines", "pal", "phosphor"]
+//     ulaplus = true
+//     palette = [0x000000, 0x0000D7, ...]    # the 16 colours, bright last
+//
+//     [audio]
+//     sample_rate = 48000
+//
+//     [input]
+//     joysticks = ["kempston"]
+//     joystick_keys = ["up", "down", "left", "right", "rightctrl"]
+//     mouse = false
+//
+//     [keys]                                  # host key = Spectrum keys
+//     backspace = ["CAPS", "0"]
+//     comma = ["SYMBOL", "N"]
+//     tab = []                                # unbound
+//
+//     [tape]
+//     autoload = true
+//     autoload_frame = 150
+//     save_tap = "saved.tap"                  # SAVE through the ROM
+//     mic_tap = "mic.tap"                     # anything on the MIC output
+//
+//     [trace]
+//     file = "trace.txt"
+//     enabled = true
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use display::{Aspect, BorderSize, MAX_SCALE};
+use keymap;
+use keymap::JoystickKeys;
+use options::{valid_sample_rate, Options};
+use toml;
+use toml::{Table, Value};
+use z80::filter::Filter;
+use z80::joystick::JoystickKind;
+use z80::keyboard::SpectrumKey;
+use z80::machine::Model;
+
+// Looked for in the current directory and then in the user's
+const LOCAL_FILE: &str = "z80.toml";
+const USER_FILE: &str = ".config/z80/config.toml";
+
+// Besides [keys], which takes host key names
+const SECTIONS: [&str; 6] = ["machine", "video", "audio", "input", "tape", "trace"];
+
+pub fn default_path() -> Option<String> {
+    let local = PathBuf::from(LOCAL_FILE);
+    let user = env::var_os("HOME").map(|home| Path::new(&home).join(USER_FILE));
+    Some(local)
+        .into_iter()
+        .chain(user)
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn wrong_type(section: &str, key: &str, expected: &str, value: &Value) -> io::Error {
+    invalid(format!(
+        "{}.{} tiene que ser {} y es {}",
+        section,
+        key,
+        expected,
+        value.type_name()
+    ))
+}
+
+fn string<'a>(section: &str, key: &str, value: &'a Value) -> io::Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| wrong_type(section, key, "texto", value))
+}
+
+fn integer(section: &str, key: &str, value: &Value) -> io::Result<i64> {
+    value
+        .as_integer()
+        .ok_or_else(|| wrong_type(section, key, "entero", value))
+}
+
+fn boolean(section: &str, key: &str, value: &Value) -> io::Result<bool> {
+    value
+        .as_bool()
+        .ok_or_else(|| wrong_type(section, key, "true o false", value))
+}
+
+fn strings<'a>(section: &str, key: &str, value: &'a Value) -> io::Result<Vec<&'a str>> {
+    let values = value
+        .as_array()
+        .ok_or_else(|| wrong_type(section, key, "una lista", value))?;
+    values.iter().map(|v| string(section, key, v)).collect()
+}
+
+fn bad_value(section: &str, key: &str, value: &Value) -> io::Error {
+    invalid(format!("{}.{} no admite {}", section, key, value))
+}
+
+// Files named in the settings are relative to where the settings are
+fn relative(dir: &Path, path: &str) -> String {
+    dir.join(path).to_string_lossy().into_owned()
+}
+
+fn apply_setting(
+    options: &mut Options,
+    dir: &Path,
+    section: &str,
+    key: &str,
+    value: &Value,
+) -> io::Result<()> {
+    match (section, key) {
+        ("machine", "model") => {
+            let name = string(section, key, value)?;
+            options.model = Model::from_name(name).ok_or_else(|| bad_value(section, key, value))?;
+        }
+        ("machine", "roms") => {
+            // The model before the colon stays as it is, only the file is
+            // relative
+            options.roms = strings(section, key, value)?
+                .iter()
+                .map(|rom| match rom.split_once(':') {
+                    Some((name, path)) if Model::from_name(name).is_some() => {
+                        format!("{}:{}", name, relative(dir, path))
+                    }
+                    _ => relative(dir, rom),
+                })
+                .collect();
+        }
+        ("video", "scale") => match integer(section, key, value)? {
+            scale if scale >= 1 && scale <= MAX_SCALE as i64 => options.scale = scale as usize,
+            _ => return Err(bad_value(section, key, value)),
+        },
+        ("video", "filters") => {
+            let mut filters = Vec::new();
+            for name in strings(section, key, value)? {
+                let filter = Filter::from_name(name);
+                filters.push(filter.ok_or_else(|| bad_value(section, key, value))?);
+            }
+            options.filters = filters;
+        }
+        ("video", "ulaplus") => options.ulaplus = boolean(section, key, value)?,
+        ("video", "fullscreen") => options.fullscreen = boolean(section, key, value)?,
+        ("video", "border") => {
+            let name = string(section, key, value)?;
+            options.border =
+                BorderSize::from_name(name).ok_or_else(|| bad_value(section, key, value))?;
+        }
+        ("video", "aspect") => {
+            let name = string(section, key, value)?;
+            options.aspect =
+                Aspect::from_name(name).ok_or_else(|| bad_value(section, key, value))?;
+        }
+        ("video", "palette") => {
+            let colours = value
+                .as_array()
+                .ok_or_else(|| wrong_type(section, key, "una lista", value))?;
+            if colours.len() != options.palette.len() {
+                return Err(invalid(format!(
+                    "video.palette tiene que tener {} colores",
+                    options.palette.len()
+                )));
+            }
+            for (entry, colour) in options.palette.iter_mut().zip(colours.iter()) {
+                match integer(section, key, colour)? {
+                    rgb @ 0..=0xFF_FFFF => *entry = rgb as u32,
+                    _ => return Err(bad_value(section, key, colour)),
+                }
+            }
+        }
+        ("audio", "sample_rate") => {
+            let rate = integer(section, key, value)?;
+            if rate < 0 || !valid_sample_rate(rate as u32) {
+                return Err(bad_value(section, key, value));
+            }
+            options.sample_rate = Some(rate as u32);
+        }
+        ("input", "joysticks") => {
+            let mut joysticks = Vec::new();
+            for name in strings(section, key, value)? {
+                let kind = JoystickKind::from_name(name);
+                joysticks.push(kind.ok_or_else(|| bad_value(section, key, value))?);
+            }
+            options.joysticks = joysticks;
+        }
+        ("input", "joystick_keys") => {
+            let names = strings(section, key, value)?.join(",");
+            options.joystick_keys =
+                JoystickKeys::parse(&names).ok_or_else(|| bad_value(section, key, value))?;
+        }
+        ("input", "mouse") => options.mouse = boolean(section, key, value)?,
+        ("tape", "autoload") => options.autoload = boolean(section, key, value)?,
+        ("tape", "autoload_frame") => match integer(section, key, value)? {
+            frame if frame >= 0 => options.autoload_frame = frame as u64,
+            _ => return Err(bad_value(section, key, value)),
+        },
+        ("tape", "save_tap") => {
+            options.save_tap = Some(relative(dir, string(section, key, value)?));
+        }
+        ("tape", "mic_tap") => {
+            options.mic_tap = Some(relative(dir, string(section, key, value)?));
+        }
+        ("trace", "file") => {
+            options.trace = Some(relative(dir, string(section, key, value)?));
+        }
+        ("trace", "enabled") => {}
+        _ => return Err(invalid(format!("{}.{} no es un ajuste", section, key))),
+    }
+    Ok(())
+}
+
+// A host key name and the Spectrum keys it presses, either one name or
+// a list of them
+fn apply_binding(options: &mut Options, host: &str, value: &Value) -> io::Result<()> {
+    let host_key = keymap::host_key(host)
+        .ok_or_else(|| invalid(format!("keys.{} no es una tecla conocida", host)))?;
+    let names = match *value {
+        Value::String(ref name) => vec![name.as_str()],
+        _ => strings("keys", host, value)?,
+    };
+    let mut keys = Vec::new();
+    for name in names {
+        let key = SpectrumKey::from_name(name);
+        keys.push(key.ok_or_else(|| bad_value("keys", host, value))?);
+    }
+    options.key_bindings.bind(host_key, keys);
+    Ok(())
+}
+
+fn apply(options: &mut Options, dir: &Path, root: &Table) -> io::Result<()> {
+    for (name, value) in root.iter() {
+        let table = value
+            .as_table()
+            .ok_or_else(|| invalid(format!("{} está fuera de una sección", name)))?;
+        if name == "keys" {
+            for (host, keys) in table.iter() {
+                apply_binding(options, host, keys)?;
+            }
+            continue;
+        }
+        if !SECTIONS.contains(&name.as_str()) {
+            return Err(invalid(format!("[{}] no es una sección", name)));
+        }
+        for (key, value) in table.iter() {
+            apply_setting(options, dir, name, key, value)?;
+        }
+    }
+
+    // A trace file can be kept in the settings but switched off
+    let trace_enabled = root
+        .get("trace")
+        .and_then(Value::as_table)
+        .and_then(|trace| trace.get("enabled"));
+    if let Some(enabled) = trace_enabled {
+        if !boolean("trace", "enabled", enabled)? {
+            options.trace = None;
+        }
+    }
+    Ok(())
+}
+
+pub fn load(path: &str, options: &mut Options) -> io::Result<()> {
+    let text = fs::read_to_string(path)?;
+    let root = toml::parse(&text)?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    apply(options, dir, &root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use options;
+
+    #[test]
+    fn roms_keep_their_model() {
+        let dir = env::temp_dir().join(format!("z80-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[machine]\nroms = [\"+3:plus3.rom\", \"48.rom\"]\n").unwrap();
+
+        let mut options = Options::default();
+        load(path.to_str().unwrap(), &mut options).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let plus3 = dir.join("plus3.rom").to_string_lossy().into_owned();
+        let (model, rom) = options::rom_model(&options.roms[0], Model::Spectrum48K);
+        assert_eq!((model, rom), (Model::SpectrumPlus3, plus3.as_str()));
+        let rom48 = dir.join("48.rom").to_string_lossy().into_owned();
+        assert_eq!(options.roms[1], rom48);
+    }
+}
@@ -0,0 +1,199 @@
+// What the window shows of the frames the machine draws: the border cut
+// down to the size chosen and every pixel repeated `scale` times each way,
+// stretched across to the pixel aspect chosen, with scanlines between the
+// rows if wanted
+
+use z80::filter;
+use z80::ula::{BORDER_LEFT, BORDER_TOP, FRAME_HEIGHT, FRAME_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const MAX_SCALE: usize = 4;
+
+// Width of a pixel against its height on a PAL television
+const PAL_PIXEL_ASPECT: f32 = 1.09;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BorderSize {
+    None,
+    // About what a television shows
+    Normal,
+    // All the machine draws
+    Full,
+}
+
+impl BorderSize {
+    pub fn from_name(name: &str) -> Option<BorderSize> {
+        match name {
+            "none" => Some(BorderSize::None),
+            "normal" => Some(BorderSize::Normal),
+            "full" => Some(BorderSize::Full),
+            _ => None,
+        }
+    }
+
+    // Left, top, width and height of the part of the frame shown
+    fn area(self) -> (usize, usize, usize, usize) {
+        match self {
+            BorderSize::None => (BORDER_LEFT, BORDER_TOP, SCREEN_WIDTH, SCREEN_HEIGHT),
+            BorderSize::Normal => (
+                BORDER_LEFT - 32,
+                BORDER_TOP - 24,
+                SCREEN_WIDTH + 64,
+                SCREEN_HEIGHT + 48,
+            ),
+            BorderSize::Full => (0, 0, FRAME_WIDTH, FRAME_HEIGHT),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Aspect {
+    Square,
+    Pal,
+    // The part of the frame shown, border and all, made 4:3
+    FourThree,
+}
+
+impl Aspect {
+    pub fn from_name(name: &str) -> Option<Aspect> {
+        match name {
+            "square" => Some(Aspect::Square),
+            "pal" => Some(Aspect::Pal),
+            "4:3" => Some(Aspect::FourThree),
+            _ => None,
+        }
+    }
+
+    // How much wider than tall a pixel is shown
+    fn stretch(self, columns: usize, rows: usize) -> f32 {
+        match self {
+            Aspect::Square => 1.0,
+            Aspect::Pal => PAL_PIXEL_ASPECT,
+            Aspect::FourThree => 4.0 * rows as f32 / (3.0 * columns as f32),
+        }
+    }
+}
+
+pub struct Display {
+    scale: usize,
+    top: usize,
+    // Spectrum pixels shown each way
+    columns: usize,
+    rows: usize,
+    width: usize,
+    height: usize,
+    // Column of the frame each column of the window takes
+    source_columns: Vec<usize>,
+    scanlines: bool,
+    buffer: Vec<u32>,
+}
+
+impl Display {
+    pub fn new(scale: usize, border: BorderSize, aspect: Aspect, scanlines: bool) -> Display {
+        let (left, top, columns, rows) = border.area();
+        let stretch = aspect.stretch(columns, rows);
+        let width = (columns as f32 * scale as f32 * stretch).round() as usize;
+        let height = rows * scale;
+        Display {
+            scale,
+            top,
+            columns,
+            rows,
+            width,
+            height,
+            source_columns: (0..width).map(|x| left + x * columns / width).collect(),
+            scanlines,
+            buffer: vec![0; width * height],
+        }
+    }
+
+    // Size of the window
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    // Where a point of the window falls on the picture, in Spectrum
+    // pixels. The window may be larger than asked for, full screen above
+    // all, and shows the picture as large as it fits, centred.
+    pub fn picture_position(&self, window: (usize, usize), (x, y): (f32, f32)) -> (f32, f32) {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let pixel_width = width / self.columns as f32;
+        let pixel_height = height / self.rows as f32;
+        let factor = (window.0 as f32 / width).min(window.1 as f32 / height);
+        if factor <= 0.0 {
+            return (x / pixel_width, y / pixel_height);
+        }
+        let left = (window.0 as f32 - width * factor) / 2.0;
+        let top = (window.1 as f32 - height * factor) / 2.0;
+        (
+            (x - left) / factor / pixel_width,
+            (y - top) / factor / pixel_height,
+        )
+    }
+
+    // Takes a FRAME_WIDTH x FRAME_HEIGHT frame and returns what the window
+    // shows of it
+    pub fn show(&mut self, frame: &[u32]) -> &[u32] {
+        let rows = frame[..FRAME_WIDTH * FRAME_HEIGHT]
+            .chunks(FRAME_WIDTH)
+            .skip(self.top)
+            .take(self.rows);
+        for (row, lines) in rows.zip(self.buffer.chunks_mut(self.width * self.scale)) {
+            let (first, rest) = lines.split_at_mut(self.width);
+            for (target, &column) in first.iter_mut().zip(self.source_columns.iter()) {
+                *target = row[column];
+            }
+            for line in rest.chunks_mut(self.width) {
+                line.copy_from_slice(first);
+            }
+        }
+        // Every other row when not scaled
+        if self.scanlines {
+            filter::scanlines(&mut self.buffer, self.width, self.scale.max(2));
+        }
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picture_position() {
+        let display = Display::new(2, BorderSize::None, Aspect::Square, false);
+        assert_eq!(
+            display.picture_position((512, 384), (100.0, 50.0)),
+            (50.0, 25.0)
+        );
+        // 256x192 shown 5.625 times as large in the middle of 1920x1080
+        let (x, y) = display.picture_position((1920, 1080), (240.0 + 56.25, 112.5));
+        assert!((x - 10.0).abs() < 0.001 && (y - 20.0).abs() < 0.001);
+        // Outside the picture there are positions too, for the movements
+        let (x, _) = display.picture_position((1920, 1080), (0.0, 0.0));
+        assert!(x < 0.0);
+    }
+
+    #[test]
+    fn aspect() {
+        let frame: Vec<u32> = (0..FRAME_WIDTH * FRAME_HEIGHT).map(|i| i as u32).collect();
+        let mut display = Display::new(1, BorderSize::None, Aspect::Pal, false);
+        assert_eq!(display.size(), (279, 192));
+        let row = display.show(&frame)[..279].to_vec();
+        // Every column is there, left to right, some of them twice
+        let first = (BORDER_TOP * FRAME_WIDTH + BORDER_LEFT) as u32;
+        let mut columns = row.clone();
+        columns.dedup();
+        assert_eq!(columns, (first..first + 256).collect::<Vec<_>>());
+        // And the mouse finds them where they are shown
+        let (x, y) = display.picture_position((279, 192), (278.5, 0.5));
+        assert!((x - 255.6).abs() < 0.1 && (y - 0.5).abs() < 0.001);
+
+        let display = Display::new(2, BorderSize::Full, Aspect::FourThree, false);
+        let (width, height) = display.size();
+        assert!((width as f32 / height as f32 - 4.0 / 3.0).abs() < 0.01);
+        assert_eq!(
+            Display::new(2, BorderSize::Normal, Aspect::Square, false).size(),
+            (640, 480)
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+// Disk images in the CPC DSK format, standard or extended. A disk info
+// block of 256 bytes is followed by the tracks, side 0 and side 1 of each
+// cylinder in turn. Each track starts with its own 256 byte info block
+// listing the sector IDs, then holds the data of the sectors in order.
+
+const STANDARD_MAGIC: &[u8] = b"MV - CPC";
+const EXTENDED_MAGIC: &[u8] = b"EXTENDED CPC DSK File\r\nDisk-Info\r\n";
+const TRACK_MAGIC: &[u8] = b"Track-Info\r\n";
+const INFO_LENGTH: usize = 256;
+const MAX_SECTORS: usize = 29;
+const CREATOR: &[u8; 14] = b"viceRazer     ";
+
+pub struct Sector {
+    // ID written in the sector header: cylinder, head, number and size
+    pub c: u8,
+    pub h: u8,
+    pub r: u8,
+    pub n: u8,
+    // Error flags the FDC reports for the sector
+    pub st1: u8,
+    pub st2: u8,
+    pub data: Vec<u8>,
+}
+
+pub struct Track {
+    pub gap3: u8,
+    pub filler: u8,
+    // Empty on unformatted tracks
+    pub sectors: Vec<Sector>,
+}
+
+pub struct Disk {
+    pub cylinders: usize,
+    pub sides: usize,
+    // Indexed by cylinder * sides + side
+    pub tracks: Vec<Track>,
+    pub write_protected: bool,
+    // Set when a sector has been written or a track formatted
+    pub modified: bool,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_word(bytes: &[u8], offset: usize) -> usize {
+    ((bytes[offset + 1] as usize) << 8) | bytes[offset] as usize
+}
+
+// Bytes in a sector of size code `n`
+pub fn sector_size(n: u8) -> usize {
+    128 << (n.min(6) as usize)
+}
+
+fn parse_track(bytes: &[u8], extended: bool) -> io::Result<Track> {
+    if bytes.len() < INFO_LENGTH || &bytes[..TRACK_MAGIC.len()] != TRACK_MAGIC {
+        return Err(invalid_data("pista de la imagen DSK no válida"));
+    }
+    let track_n = bytes[0x14];
+    let count = (bytes[0x15] as usize).min(MAX_SECTORS);
+    let mut track = Track {
+        gap3: bytes[0x16],
+        filler: bytes[0x17],
+        sectors: Vec::with_capacity(count),
+    };
+
+    let mut offset = INFO_LENGTH;
+    for index in 0..count {
+        let info = &bytes[0x18 + index * 8..0x20 + index * 8];
+        // The standard format gives every sector the size of the track
+        let length = if extended {
+            read_word(info, 6)
+        } else {
+            sector_size(track_n)
+        };
+        if offset + length > bytes.len() {
+            return Err(invalid_data("imagen DSK truncada"));
+        }
+        track.sectors.push(Sector {
+            c: info[0],
+            h: info[1],
+            r: info[2],
+            n: info[3],
+            st1: info[4],
+            st2: info[5],
+            data: bytes[offset..offset + length].to_vec(),
+        });
+        offset += length;
+    }
+    Ok(track)
+}
+
+impl Disk {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Disk> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Disk::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Disk> {
+        if bytes.len() < INFO_LENGTH {
+            return Err(invalid_data("no es una imagen DSK"));
+        }
+        let extended = bytes.starts_with(EXTENDED_MAGIC);
+        if !extended && !bytes.starts_with(STANDARD_MAGIC) {
+            return Err(invalid_data("no es una imagen DSK"));
+        }
+        let cylinders = bytes[0x30] as usize;
+        let sides = bytes[0x31] as usize;
+        if sides == 0 || sides > 2 {
+            return Err(invalid_data("número de caras de la imagen DSK no válido"));
+        }
+
+        let mut disk = Disk {
+            cylinders,
+            sides,
+            tracks: Vec::with_capacity(cylinders * sides),
+            write_protected: false,
+            modified: false,
+        };
+        let mut offset = INFO_LENGTH;
+        for index in 0..cylinders * sides {
+            // The extended format keeps the size of each track, zero
+            // meaning unformatted
+            let length = if extended {
+                match bytes.get(0x34 + index) {
+                    Some(&high) => high as usize * 256,
+                    None => 0,
+                }
+            } else {
+                read_word(bytes, 0x32)
+            };
+            if length == 0 {
+                disk.tracks.push(Track {
+                    gap3: 0,
+                    filler: 0,
+                    sectors: Vec::new(),
+                });
+                continue;
+            }
+            if offset + length > bytes.len() {
+                return Err(invalid_data("imagen DSK truncada"));
+            }
+            disk.tracks
+                .push(parse_track(&bytes[offset..offset + length], extended)?);
+            offset += length;
+        }
+        Ok(disk)
+    }
+
+    // A blank disk with no formatted tracks
+    pub fn unformatted(cylinders: usize, sides: usize) -> Disk {
+        Disk {
+            cylinders,
+            sides,
+            tracks: (0..cylinders * sides)
+                .map(|_| Track {
+                    gap3: 0,
+                    filler: 0,
+                    sectors: Vec::new(),
+                })
+                .collect(),
+            write_protected: false,
+            modified: false,
+        }
+    }
+
+    pub fn track(&self, cylinder: usize, side: usize) -> Option<&Track> {
+        if cylinder < self.cylinders && side < self.sides {
+            self.tracks.get(cylinder * self.sides + side)
+        } else {
+            None
+        }
+    }
+
+    pub fn track_mut(&mut self, cylinder: usize, side: usize) -> Option<&mut Track> {
+        if cylinder < self.cylinders && side < self.sides {
+            self.tracks.get_mut(cylinder * self.sides + side)
+        } else {
+            None
+        }
+    }
+
+    // Replaces a track, adding cylinders if it lies past the last one
+    pub fn format_track(&mut self, cylinder: usize, side: usize, track: Track) -> bool {
+        if side >= self.sides || cylinder > 0xFF {
+            return false;
+        }
+        while self.cylinders <= cylinder {
+            for _ in 0..self.sides {
+                self.tracks.push(Track {
+                    gap3: 0,
+                    filler: 0,
+                    sectors: Vec::new(),
+                });
+            }
+            self.cylinders += 1;
+        }
+        self.tracks[cylinder * self.sides + side] = track;
+        self.modified = true;
+        true
+    }
+
+    // Always written in the extended format, which keeps every sector as
+    // it was. Tracks formatted with more sectors than their info block
+    // lists lose the ones past it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut tracks = Vec::with_capacity(self.tracks.len());
+        for (index, track) in self.tracks.iter().enumerate() {
+            let sectors = &track.sectors[..track.sectors.len().min(MAX_SECTORS)];
+            if sectors.is_empty() {
+                tracks.push(Vec::new());
+                continue;
+            }
+            let mut bytes = vec![0; INFO_LENGTH];
+            bytes[..TRACK_MAGIC.len()].copy_from_slice(TRACK_MAGIC);
+            bytes[0x10] = (index / self.sides) as u8;
+            bytes[0x11] = (index % self.sides) as u8;
+            bytes[0x14] = sectors[0].n;
+            bytes[0x15] = sectors.len() as u8;
+            bytes[0x16] = track.gap3;
+            bytes[0x17] = track.filler;
+            for (index, sector) in sectors.iter().enumerate() {
+                let info = &mut bytes[0x18 + index * 8..0x20 + index * 8];
+                info.copy_from_slice(&[
+                    sector.c,
+                    sector.h,
+                    sector.r,
+                    sector.n,
+                    sector.st1,
+                    sector.st2,
+                    (sector.data.len() & 0xFF) as u8,
+                    (sector.data.len() >> 8) as u8,
+                ]);
+            }
+            for sector in sectors {
+                bytes.extend_from_slice(&sector.data);
+            }
+            // Tracks take whole blocks of 256 bytes
+            let length = (bytes.len() + 0xFF) & !0xFF;
+            bytes.resize(length, 0);
+            tracks.push(bytes);
+        }
+
+        let mut bytes = vec![0; INFO_LENGTH];
+        bytes[..EXTENDED_MAGIC.len()].copy_from_slice(EXTENDED_MAGIC);
+        bytes[0x22..0x30].copy_from_slice(CREATOR);
+        bytes[0x30] = self.cylinders as u8;
+        bytes[0x31] = self.sides as u8;
+        for (size, track) in bytes[0x34..].iter_mut().zip(tracks.iter()) {
+            *size = (track.len() / 256) as u8;
+        }
+        for track in tracks {
+            bytes.extend_from_slice(&track);
+        }
+        bytes
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        File::create(path)?.write_all(&self.to_bytes())
+    }
+}
@@ -0,0 +1,778 @@
+use std::collections::VecDeque;
+use std::io;
+
+use dsk::{sector_size, Disk, Sector, Track};
+use state::{SaveState, StateReader, StateWriter};
+
+// The µPD765 floppy disk controller of the +3, as wired there: no DMA, no
+// interrupt and no terminal count, so the CPU polls the main status
+// register at 0x2FFD and moves every byte through the data register at
+// 0x3FFD. Only the drive select line US0 reaches the drives. Commands run
+// at once, without the delays of the mechanics.
+
+pub const DRIVES: usize = 2;
+
+// Main status register
+const MSR_REQUEST: u8 = 0x80;
+const MSR_TO_CPU: u8 = 0x40;
+const MSR_EXECUTION: u8 = 0x20;
+const MSR_BUSY: u8 = 0x10;
+
+// Status registers of the result phase
+const ST0_INVALID: u8 = 0x80;
+const ST0_ABNORMAL: u8 = 0x40;
+const ST0_SEEK_END: u8 = 0x20;
+const ST0_NOT_READY: u8 = 0x08;
+const ST1_END_OF_CYLINDER: u8 = 0x80;
+const ST1_DATA_ERROR: u8 = 0x20;
+const ST1_NO_DATA: u8 = 0x04;
+const ST1_NOT_WRITABLE: u8 = 0x02;
+const ST1_MISSING_MARK: u8 = 0x01;
+const ST2_CONTROL_MARK: u8 = 0x40;
+const ST2_DATA_ERROR: u8 = 0x20;
+const ST3_WRITE_PROTECTED: u8 = 0x40;
+const ST3_READY: u8 = 0x20;
+const ST3_TRACK_0: u8 = 0x10;
+const ST3_TWO_SIDE: u8 = 0x08;
+
+// Commands, in the low 5 bits of the first byte
+const READ_TRACK: u8 = 0x02;
+const SPECIFY: u8 = 0x03;
+const SENSE_DRIVE_STATUS: u8 = 0x04;
+const WRITE_DATA: u8 = 0x05;
+const READ_DATA: u8 = 0x06;
+const RECALIBRATE: u8 = 0x07;
+const SENSE_INTERRUPT_STATUS: u8 = 0x08;
+const WRITE_DELETED_DATA: u8 = 0x09;
+const READ_ID: u8 = 0x0A;
+const READ_DELETED_DATA: u8 = 0x0C;
+const FORMAT_TRACK: u8 = 0x0D;
+const SEEK: u8 = 0x0F;
+const COMMAND_MASK: u8 = 0x1F;
+const MULTI_TRACK: u8 = 0x80;
+const SKIP: u8 = 0x20;
+
+// Bytes of each command, the command byte included
+fn command_length(command: u8) -> usize {
+    match command & COMMAND_MASK {
+        READ_TRACK | WRITE_DATA | READ_DATA | WRITE_DELETED_DATA | READ_DELETED_DATA => 9,
+        FORMAT_TRACK => 6,
+        SPECIFY | SEEK => 3,
+        SENSE_DRIVE_STATUS | RECALIBRATE | READ_ID => 2,
+        _ => 1,
+    }
+}
+
+#[derive(Default)]
+pub struct Drive {
+    pub disk: Option<Disk>,
+    cylinder: u8,
+    // Sector passing under the head, for READ ID
+    next_sector: usize,
+}
+
+#[derive(PartialEq)]
+enum Phase {
+    Command,
+    // Bytes going to the CPU or coming from it
+    Read,
+    Write,
+    Result,
+}
+
+// Progress of a read, write or format
+#[derive(Default)]
+struct Transfer {
+    unit: u8,
+    head: u8,
+    c: u8,
+    h: u8,
+    r: u8,
+    n: u8,
+    eot: u8,
+    dtl: u8,
+    st0: u8,
+    st1: u8,
+    st2: u8,
+    // Sector being moved and, on READ TRACK, sectors moved so far
+    sector: usize,
+    count: u8,
+    // The sector had an error or a wrong mark: stop after it
+    last: bool,
+}
+
+pub struct Upd765 {
+    pub drives: [Drive; DRIVES],
+    motor: bool,
+
+    phase: Phase,
+    command: Vec<u8>,
+    data: Vec<u8>,
+    data_index: usize,
+    result: Vec<u8>,
+    result_index: usize,
+    transfer: Transfer,
+
+    // ST0 and cylinder of the seeks not yet sensed
+    seeks: VecDeque<(u8, u8)>,
+}
+
+impl Default for Upd765 {
+    fn default() -> Upd765 {
+        Upd765::new()
+    }
+}
+
+impl Upd765 {
+    pub fn new() -> Upd765 {
+        Upd765 {
+            drives: Default::default(),
+            motor: false,
+            phase: Phase::Command,
+            command: Vec::new(),
+            data: Vec::new(),
+            data_index: 0,
+            result: Vec::new(),
+            result_index: 0,
+            transfer: Transfer::default(),
+            seeks: VecDeque::new(),
+        }
+    }
+
+    pub fn insert(&mut self, drive: usize, disk: Disk) {
+        self.drives[drive].disk = Some(disk);
+        self.drives[drive].next_sector = 0;
+    }
+
+    pub fn eject(&mut self, drive: usize) -> Option<Disk> {
+        self.drives[drive].disk.take()
+    }
+
+    // Both motors are switched by bit 3 of 0x1FFD
+    pub fn set_motor(&mut self, on: bool) {
+        self.motor = on;
+    }
+
+    pub fn read_status(&self) -> u8 {
+        match self.phase {
+            Phase::Command if self.command.is_empty() => MSR_REQUEST,
+            Phase::Command => MSR_REQUEST | MSR_BUSY,
+            Phase::Read => MSR_REQUEST | MSR_TO_CPU | MSR_EXECUTION | MSR_BUSY,
+            Phase::Write => MSR_REQUEST | MSR_EXECUTION | MSR_BUSY,
+            Phase::Result => MSR_REQUEST | MSR_TO_CPU | MSR_BUSY,
+        }
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        match self.phase {
+            Phase::Read => {
+                let value = self.data[self.data_index];
+                self.data_index += 1;
+                if self.data_index == self.data.len() {
+                    self.next_sector();
+                }
+                value
+            }
+            Phase::Result => {
+                let value = self.result[self.result_index];
+                self.result_index += 1;
+                if self.result_index == self.result.len() {
+                    self.phase = Phase::Command;
+                    self.command.clear();
+                }
+                value
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        match self.phase {
+            Phase::Command => {
+                self.command.push(value);
+                if self.command.len() == command_length(self.command[0]) {
+                    self.execute();
+                }
+            }
+            Phase::Write => {
+                self.data[self.data_index] = value;
+                self.data_index += 1;
+                if self.data_index == self.data.len() {
+                    if self.command[0] & COMMAND_MASK == FORMAT_TRACK {
+                        self.finish_format();
+                    } else {
+                        self.store_sector();
+                        self.next_sector();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn execute(&mut self) {
+        let command = self.command[0];
+        match command & COMMAND_MASK {
+            READ_DATA | READ_DELETED_DATA | READ_TRACK | WRITE_DATA | WRITE_DELETED_DATA => {
+                self.start_transfer()
+            }
+            FORMAT_TRACK => self.start_format(),
+            READ_ID => self.read_id(),
+            SPECIFY => self.phase = Phase::Command,
+            SENSE_DRIVE_STATUS => {
+                let st3 = self.drive_status(self.command[1]);
+                self.set_result(vec![st3]);
+            }
+            RECALIBRATE => self.seek(self.command[1], 0),
+            SEEK => self.seek(self.command[1], self.command[2]),
+            SENSE_INTERRUPT_STATUS => match self.seeks.pop_front() {
+                Some((st0, cylinder)) => self.set_result(vec![st0, cylinder]),
+                None => self.set_result(vec![ST0_INVALID]),
+            },
+            _ => self.set_result(vec![ST0_INVALID]),
+        }
+        // The parameters stay until the result has been read
+        if self.phase == Phase::Command {
+            self.command.clear();
+        }
+    }
+
+    fn set_result(&mut self, result: Vec<u8>) {
+        self.result = result;
+        self.result_index = 0;
+        self.phase = Phase::Result;
+    }
+
+    fn drive(&self, unit: u8) -> &Drive {
+        &self.drives[(unit & 0x01) as usize]
+    }
+
+    fn drive_mut(&mut self, unit: u8) -> &mut Drive {
+        &mut self.drives[(unit & 0x01) as usize]
+    }
+
+    fn ready(&self, unit: u8) -> bool {
+        self.motor && self.drive(unit).disk.is_some()
+    }
+
+    fn drive_status(&self, select: u8) -> u8 {
+        let drive = self.drive(select);
+        let mut st3 = select & 0x07;
+        if self.ready(select) {
+            st3 |= ST3_READY;
+        }
+        if drive.cylinder == 0 {
+            st3 |= ST3_TRACK_0;
+        }
+        if let Some(ref disk) = drive.disk {
+            if disk.write_protected {
+                st3 |= ST3_WRITE_PROTECTED;
+            }
+            if disk.sides > 1 {
+                st3 |= ST3_TWO_SIDE;
+            }
+        }
+        st3
+    }
+
+    fn seek(&mut self, select: u8, cylinder: u8) {
+        let unit = select & 0x03;
+        let mut st0 = ST0_SEEK_END | unit;
+        if self.drive(unit).disk.is_some() {
+            let drive = self.drive_mut(unit);
+            drive.cylinder = cylinder;
+            drive.next_sector = 0;
+        } else {
+            st0 |= ST0_ABNORMAL | ST0_NOT_READY;
+        }
+        let cylinder = self.drive(unit).cylinder;
+        self.seeks.push_back((st0, cylinder));
+        self.phase = Phase::Command;
+    }
+
+    fn current_track(&self) -> Option<&Track> {
+        let transfer = &self.transfer;
+        let drive = self.drive(transfer.unit);
+        drive
+            .disk
+            .as_ref()
+            .and_then(|disk| disk.track(drive.cylinder as usize, transfer.head as usize))
+    }
+
+    fn current_track_mut(&mut self) -> Option<&mut Track> {
+        let (unit, head) = (self.transfer.unit, self.transfer.head);
+        let drive = self.drive_mut(unit);
+        let cylinder = drive.cylinder as usize;
+        drive
+            .disk
+            .as_mut()
+            .and_then(|disk| disk.track_mut(cylinder, head as usize))
+    }
+
+    fn finish_transfer(&mut self) {
+        let t = &self.transfer;
+        let result = vec![t.st0, t.st1, t.st2, t.c, t.h, t.r, t.n];
+        self.set_result(result);
+    }
+
+    fn fail_transfer(&mut self, st0: u8, st1: u8) {
+        self.transfer.st0 |= ST0_ABNORMAL | st0;
+        self.transfer.st1 |= st1;
+        self.finish_transfer();
+    }
+
+    fn start_transfer(&mut self) {
+        let cmd = &self.command;
+        self.transfer = Transfer {
+            unit: cmd[1] & 0x03,
+            head: (cmd[1] >> 2) & 0x01,
+            c: cmd[2],
+            h: cmd[3],
+            r: cmd[4],
+            n: cmd[5],
+            eot: cmd[6],
+            dtl: cmd[8],
+            st0: cmd[1] & 0x07,
+            ..Transfer::default()
+        };
+        let unit = self.transfer.unit;
+        if !self.ready(unit) {
+            self.fail_transfer(ST0_NOT_READY, 0);
+            return;
+        }
+        let command = self.command[0] & COMMAND_MASK;
+        if command == WRITE_DATA || command == WRITE_DELETED_DATA {
+            let protected = match self.drive(unit).disk {
+                Some(ref disk) => disk.write_protected,
+                None => false,
+            };
+            if protected {
+                self.fail_transfer(0, ST1_NOT_WRITABLE);
+                return;
+            }
+        }
+        self.find_sector();
+    }
+
+    // Bytes the command moves for one sector. DTL only counts for the 128
+    // byte sectors of N 0, and 0 there moves them whole.
+    fn transfer_length(&self) -> usize {
+        if self.transfer.n == 0 && self.transfer.dtl != 0 {
+            self.transfer.dtl as usize
+        } else {
+            sector_size(self.transfer.n)
+        }
+    }
+
+    // Locates the sector the transfer is at and starts moving it
+    fn find_sector(&mut self) {
+        let command = self.command[0];
+        let reading_track = command & COMMAND_MASK == READ_TRACK;
+        let (c, h, r, n, count) = {
+            let t = &self.transfer;
+            (t.c, t.h, t.r, t.n, t.count)
+        };
+        let found = match self.current_track() {
+            None => Err(ST1_MISSING_MARK),
+            Some(track) if track.sectors.is_empty() => Err(ST1_MISSING_MARK),
+            // READ TRACK takes the sectors as they come
+            Some(track) if reading_track => {
+                if (count as usize) < track.sectors.len() {
+                    Ok(count as usize)
+                } else {
+                    Err(ST1_NO_DATA)
+                }
+            }
+            Some(track) => track
+                .sectors
+                .iter()
+                .position(|s| s.c == c && s.h == h && s.r == r && s.n == n)
+                .ok_or(ST1_NO_DATA),
+        };
+        let index = match found {
+            Ok(index) => index,
+            Err(st1) => {
+                self.fail_transfer(0, st1);
+                return;
+            }
+        };
+        self.transfer.sector = index;
+        let length = self.transfer_length();
+
+        let (deleted, st1, st2, data) = {
+            let sector: &Sector = &self.current_track().unwrap().sectors[index];
+            let mut data = sector.data.clone();
+            data.resize(length, 0);
+            (
+                sector.st2 & ST2_CONTROL_MARK != 0,
+                sector.st1 & ST1_DATA_ERROR,
+                sector.st2 & ST2_DATA_ERROR,
+                data,
+            )
+        };
+        match command & COMMAND_MASK {
+            WRITE_DATA | WRITE_DELETED_DATA => {
+                self.data = vec![0; length];
+                self.data_index = 0;
+                self.phase = Phase::Write;
+            }
+            op => {
+                // A mark other than the one asked for ends the command
+                // after the sector, or skips it with SK
+                let wanted_deleted = op == READ_DELETED_DATA;
+                if op != READ_TRACK && deleted != wanted_deleted {
+                    if command & SKIP != 0 {
+                        self.next_sector();
+                        return;
+                    }
+                    self.transfer.st2 |= ST2_CONTROL_MARK;
+                    self.transfer.last = true;
+                }
+                if st1 != 0 || st2 != 0 {
+                    self.transfer.st1 |= st1;
+                    self.transfer.st2 |= st2;
+                    self.transfer.last = true;
+                }
+                self.data = data;
+                self.data_index = 0;
+                self.phase = Phase::Read;
+            }
+        }
+    }
+
+    fn store_sector(&mut self) {
+        let index = self.transfer.sector;
+        let deleted = self.command[0] & COMMAND_MASK == WRITE_DELETED_DATA;
+        let data = std::mem::take(&mut self.data);
+        if let Some(track) = self.current_track_mut() {
+            let sector = &mut track.sectors[index];
+            sector.data = data;
+            sector.st1 = 0;
+            sector.st2 = if deleted { ST2_CONTROL_MARK } else { 0 };
+        }
+        let unit = self.transfer.unit;
+        if let Some(ref mut disk) = self.drive_mut(unit).disk {
+            disk.modified = true;
+        }
+    }
+
+    // Moves on once a sector is done, until the end of the track. With no
+    // terminal count on the +3 every transfer ends past EOT.
+    fn next_sector(&mut self) {
+        if self.transfer.last {
+            self.transfer.st0 |= ST0_ABNORMAL;
+            self.finish_transfer();
+            return;
+        }
+        let command = self.command[0];
+        self.transfer.count = self.transfer.count.wrapping_add(1);
+        if self.transfer.r == self.transfer.eot {
+            if command & MULTI_TRACK != 0 && self.transfer.head == 0 {
+                self.transfer.head = 1;
+                self.transfer.st0 |= 0x04;
+                self.transfer.h ^= 1;
+                self.transfer.r = 1;
+            } else {
+                self.transfer.c = self.transfer.c.wrapping_add(1);
+                self.transfer.r = 1;
+                self.fail_transfer(0, ST1_END_OF_CYLINDER);
+                return;
+            }
+        } else {
+            self.transfer.r = self.transfer.r.wrapping_add(1);
+        }
+        self.find_sector();
+    }
+
+    fn read_id(&mut self) {
+        let select = self.command[1];
+        self.transfer = Transfer {
+            unit: select & 0x03,
+            head: (select >> 2) & 0x01,
+            st0: select & 0x07,
+            ..Transfer::default()
+        };
+        let unit = self.transfer.unit;
+        if !self.ready(unit) {
+            self.fail_transfer(ST0_NOT_READY, 0);
+            return;
+        }
+        let next = self.drive(unit).next_sector;
+        let id = match self.current_track() {
+            Some(track) if !track.sectors.is_empty() => {
+                let sector = &track.sectors[next % track.sectors.len()];
+                Some((sector.c, sector.h, sector.r, sector.n))
+            }
+            _ => None,
+        };
+        match id {
+            Some((c, h, r, n)) => {
+                self.drive_mut(unit).next_sector = next + 1;
+                let t = &mut self.transfer;
+                t.c = c;
+                t.h = h;
+                t.r = r;
+                t.n = n;
+                self.finish_transfer();
+            }
+            None => self.fail_transfer(0, ST1_MISSING_MARK),
+        }
+    }
+
+    fn start_format(&mut self) {
+        let cmd = &self.command;
+        self.transfer = Transfer {
+            unit: cmd[1] & 0x03,
+            head: (cmd[1] >> 2) & 0x01,
+            n: cmd[2],
+            st0: cmd[1] & 0x07,
+            ..Transfer::default()
+        };
+        let unit = self.transfer.unit;
+        if !self.ready(unit) {
+            self.fail_transfer(ST0_NOT_READY, 0);
+            return;
+        }
+        let protected = match self.drive(unit).disk {
+            Some(ref disk) => disk.write_protected,
+            None => false,
+        };
+        if protected {
+            self.fail_transfer(0, ST1_NOT_WRITABLE);
+            return;
+        }
+        // Four ID bytes for each sector
+        let sectors = self.command[3] as usize;
+        self.data = vec![0; 4 * sectors];
+        self.data_index = 0;
+        if sectors == 0 {
+            self.finish_format();
+        } else {
+            self.phase = Phase::Write;
+        }
+    }
+
+    fn finish_format(&mut self) {
+        let (n, gap3, filler) = (self.command[2], self.command[4], self.command[5]);
+        let sectors: Vec<Sector> = self
+            .data
+            .chunks(4)
+            .map(|id| Sector {
+                c: id[0],
+                h: id[1],
+                r: id[2],
+                n: id[3],
+                st1: 0,
+                st2: 0,
+                data: vec![filler; sector_size(n)],
+            })
+            .collect();
+        if let Some(id) = self.data.rchunks(4).next() {
+            let t = &mut self.transfer;
+            t.c = id[0];
+            t.h = id[1];
+            t.r = id[2];
+            t.n = id[3];
+        }
+
+        let (unit, head) = (self.transfer.unit, self.transfer.head);
+        let drive = self.drive_mut(unit);
+        let cylinder = drive.cylinder as usize;
+        let formatted = match drive.disk {
+            Some(ref mut disk) => disk.format_track(
+                cylinder,
+                head as usize,
+                Track {
+                    gap3,
+                    filler,
+                    sectors,
+                },
+            ),
+            None => false,
+        };
+        if formatted {
+            self.finish_transfer();
+        } else {
+            self.fail_transfer(0, ST1_NOT_WRITABLE);
+        }
+    }
+}
+
+// The disks go in as extended DSK images, so the ones written to since
+// they went in come back as they were
+impl SaveState for Upd765 {
+    fn write_state(&self, state: &mut StateWriter) {
+        for drive in self.drives.iter() {
+            match drive.disk {
+                Some(ref disk) => {
+                    state.bool(true);
+                    state.block(&disk.to_bytes());
+                    state.bool(disk.write_protected);
+                    state.bool(disk.modified);
+                }
+                None => state.bool(false),
+            }
+            state.u8(drive.cylinder);
+            state.u32(drive.next_sector as u32);
+        }
+        state.bool(self.motor);
+
+        state.u8(match self.phase {
+            Phase::Command => 0,
+            Phase::Read => 1,
+            Phase::Write => 2,
+            Phase::Result => 3,
+        });
+        state.block(&self.command);
+        state.block(&self.data);
+        state.u32(self.data_index as u32);
+        state.block(&self.result);
+        state.u32(self.result_index as u32);
+
+        let t = &self.transfer;
+        state.bytes(&[t.unit, t.head, t.c, t.h, t.r, t.n, t.eot, t.dtl, t.st0, t.st1, t.st2]);
+        state.u32(t.sector as u32);
+        state.u8(t.count);
+        state.bool(t.last);
+
+        state.u32(self.seeks.len() as u32);
+        for &(st0, cylinder) in self.seeks.iter() {
+            state.u8(st0);
+            state.u8(cylinder);
+        }
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for drive in self.drives.iter_mut() {
+            drive.disk = if state.bool()? {
+                let mut disk = Disk::from_bytes(state.block()?)?;
+                disk.write_protected = state.bool()?;
+                disk.modified = state.bool()?;
+                Some(disk)
+            } else {
+                None
+            };
+            drive.cylinder = state.u8()?;
+            drive.next_sector = state.u32()? as usize;
+        }
+        self.motor = state.bool()?;
+
+        self.phase = match state.u8()? {
+            0 => Phase::Command,
+            1 => Phase::Read,
+            2 => Phase::Write,
+            3 => Phase::Result,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "fase desconocida del controlador de disco",
+                ))
+            }
+        };
+        self.command = state.block()?.to_vec();
+        self.data = state.block()?.to_vec();
+        self.data_index = state.u32()? as usize;
+        self.result = state.block()?.to_vec();
+        self.result_index = state.u32()? as usize;
+
+        let bytes = state.bytes(11)?;
+        self.transfer = Transfer {
+            unit: bytes[0],
+            head: bytes[1],
+            c: bytes[2],
+            h: bytes[3],
+            r: bytes[4],
+            n: bytes[5],
+            eot: bytes[6],
+            dtl: bytes[7],
+            st0: bytes[8],
+            st1: bytes[9],
+            st2: bytes[10],
+            sector: state.u32()? as usize,
+            count: state.u8()?,
+            last: state.bool()?,
+        };
+
+        self.seeks.clear();
+        for _ in 0..state.u32()? {
+            let st0 = state.u8()?;
+            self.seeks.push_back((st0, state.u8()?));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> Upd765 {
+        let mut fdc = Upd765::new();
+        fdc.insert(0, Disk::unformatted(40, 1));
+        fdc.set_motor(true);
+        fdc
+    }
+
+    fn command(fdc: &mut Upd765, bytes: &[u8]) {
+        for &byte in bytes {
+            fdc.write_data(byte);
+        }
+    }
+
+    fn result(fdc: &mut Upd765) -> Vec<u8> {
+        let mut result = Vec::new();
+        while fdc.read_status() & MSR_TO_CPU != 0 {
+            result.push(fdc.read_data());
+        }
+        result
+    }
+
+    // Formats track 0 with sectors 1 to `sectors` of size code `n`
+    fn format(fdc: &mut Upd765, n: u8, sectors: u8) -> Vec<u8> {
+        command(fdc, &[0x40 | FORMAT_TRACK, 0, n, sectors, 0x2A, 0xE5]);
+        for r in 1..=sectors {
+            command(fdc, &[0, 0, r, n]);
+        }
+        result(fdc)
+    }
+
+    #[test]
+    fn zero_dtl_moves_whole_sectors() {
+        let mut fdc = controller();
+        assert_eq!(format(&mut fdc, 0, 1)[..3], [0, 0, 0]);
+
+        command(&mut fdc, &[0x40 | WRITE_DATA, 0, 0, 0, 1, 0, 1, 0x2A, 0]);
+        for i in 0..128 {
+            assert_eq!(fdc.read_status() & MSR_EXECUTION, MSR_EXECUTION);
+            fdc.write_data(i as u8);
+        }
+        // Past EOT, the end the +3 always gets
+        assert_eq!(result(&mut fdc)[..2], [ST0_ABNORMAL, ST1_END_OF_CYLINDER]);
+
+        command(&mut fdc, &[0x40 | READ_DATA, 0, 0, 0, 1, 0, 1, 0x2A, 0]);
+        let mut data = Vec::new();
+        while fdc.read_status() & MSR_EXECUTION != 0 {
+            data.push(fdc.read_data());
+        }
+        assert_eq!(data, (0..128).collect::<Vec<u8>>());
+        assert_eq!(result(&mut fdc).len(), 7);
+
+        // A DTL below 128 cuts the sector short
+        command(&mut fdc, &[0x40 | READ_DATA, 0, 0, 0, 1, 0, 1, 0x2A, 16]);
+        let mut length = 0;
+        while fdc.read_status() & MSR_EXECUTION != 0 {
+            fdc.read_data();
+            length += 1;
+        }
+        assert_eq!(length, 16);
+    }
+
+    #[test]
+    fn format_past_the_sectors_a_dsk_holds() {
+        let mut fdc = controller();
+        assert_eq!(format(&mut fdc, 0, 40)[..3], [0, 0, 0]);
+        let disk = fdc.eject(0).unwrap();
+        let disk = Disk::from_bytes(&disk.to_bytes()).unwrap();
+        assert_eq!(disk.track(0, 0).unwrap().sectors.len(), 29);
+    }
+}
@@ -0,0 +1,159 @@
+// Filters that make the display look more like a television. They change
+// rows of 0xRRGGBB pixels in place and only affect what is shown, never
+// screenshots or recordings.
+
+// Scanlines keep 3/4 of their brightness
+const SCANLINE_LEVEL: (u32, u32) = (3, 4);
+// What the phosphor keeps of the frame before
+const PHOSPHOR_DECAY: (u32, u32) = (1, 2);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    Scanlines,
+    PalBlend,
+    Phosphor,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "scanlines" => Some(Filter::Scanlines),
+            "pal" => Some(Filter::PalBlend),
+            "phosphor" => Some(Filter::Phosphor),
+            _ => None,
+        }
+    }
+}
+
+fn channels(pixel: u32) -> [u32; 3] {
+    [(pixel >> 16) & 0xFF, (pixel >> 8) & 0xFF, pixel & 0xFF]
+}
+
+fn pixel(channels: [u32; 3]) -> u32 {
+    (channels[0] << 16) | (channels[1] << 8) | channels[2]
+}
+
+fn scale(pixel_value: u32, (num, den): (u32, u32)) -> u32 {
+    let mut c = channels(pixel_value);
+    for channel in c.iter_mut() {
+        *channel = *channel * num / den;
+    }
+    pixel(c)
+}
+
+// Darkens the last row of every `period`, the gap between the lines of
+// the picture when it has been scaled up by `period`
+pub fn scanlines(buffer: &mut [u32], width: usize, period: usize) {
+    for (y, row) in buffer.chunks_mut(width).enumerate() {
+        if y % period == period - 1 {
+            for pixel in row.iter_mut() {
+                *pixel = scale(*pixel, SCANLINE_LEVEL);
+            }
+        }
+    }
+}
+
+// Mixes each pixel with its neighbours on the row, weighing 1, 2 and 1,
+// as the narrow PAL colour signal smears sharp colour changes
+pub fn pal_blend(buffer: &mut [u32], width: usize) {
+    let mut source = vec![0; width];
+    for row in buffer.chunks_mut(width) {
+        source[..row.len()].copy_from_slice(row);
+        let last = row.len() - 1;
+        for (x, target) in row.iter_mut().enumerate() {
+            let left = channels(source[x.saturating_sub(1)]);
+            let centre = channels(source[x]);
+            let right = channels(source[(x + 1).min(last)]);
+            let mut mixed = [0; 3];
+            for (c, channel) in mixed.iter_mut().enumerate() {
+                *channel = (left[c] + 2 * centre[c] + right[c]) / 4;
+            }
+            *target = pixel(mixed);
+        }
+    }
+}
+
+// Lit phosphor fades over the following frames instead of going out at
+// once, which softens flicker
+#[derive(Default)]
+pub struct Phosphor {
+    previous: Vec<u32>,
+}
+
+impl Phosphor {
+    pub fn new() -> Phosphor {
+        Phosphor {
+            previous: Vec::new(),
+        }
+    }
+
+    // Each channel keeps the brighter of its value and what is left of
+    // the one before
+    pub fn apply(&mut self, buffer: &mut [u32]) {
+        if self.previous.len() != buffer.len() {
+            self.previous = buffer.to_vec();
+            return;
+        }
+        for (current, previous) in buffer.iter_mut().zip(self.previous.iter_mut()) {
+            let now = channels(*current);
+            let faded = channels(scale(*previous, PHOSPHOR_DECAY));
+            let mut lit = [0; 3];
+            for (c, channel) in lit.iter_mut().enumerate() {
+                *channel = now[c].max(faded[c]);
+            }
+            *current = pixel(lit);
+            *previous = *current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanlines_darken_the_last_row_of_each_period() {
+        let mut buffer = vec![0xFF_FFFF, 0x80_4020, 0xFF_FFFF, 0x80_4020];
+        scanlines(&mut buffer, 1, 2);
+        assert_eq!(buffer, [0xFF_FFFF, 0x60_3018, 0xFF_FFFF, 0x60_3018]);
+
+        let mut buffer = vec![0xFF_FFFF; 2 * 3];
+        scanlines(&mut buffer, 2, 3);
+        assert_eq!(buffer[..4], [0xFF_FFFF; 4]);
+        assert_eq!(buffer[4..], [0xBF_BFBF; 2]);
+    }
+
+    #[test]
+    fn pal_blend_mixes_each_row_alone() {
+        let mut buffer = vec![
+            0xFF_0000, 0x00_0000, 0x00_00FF, //
+            0x00_FF00, 0x00_FF00, 0x00_FF00,
+        ];
+        pal_blend(&mut buffer, 3);
+        // The edges count themselves for the missing neighbour
+        assert_eq!(buffer[..3], [0xBF_0000, 0x3F_003F, 0x00_00BF]);
+        assert_eq!(buffer[3..], [0x00_FF00; 3]);
+    }
+
+    #[test]
+    fn phosphor_fades_by_half() {
+        let mut phosphor = Phosphor::new();
+        let mut buffer = vec![0xFF_8040, 0x00_0000];
+        phosphor.apply(&mut buffer);
+        assert_eq!(buffer, [0xFF_8040, 0x00_0000]);
+
+        let mut buffer = vec![0x00_0000, 0x10_2030];
+        phosphor.apply(&mut buffer);
+        assert_eq!(buffer, [0x7F_4020, 0x10_2030]);
+
+        // Each channel on its own: what is lit now wins over what is left
+        let mut buffer = vec![0x00_FF00, 0x00_0000];
+        phosphor.apply(&mut buffer);
+        assert_eq!(buffer, [0x3F_FF10, 0x08_1018]);
+
+        // A buffer of another size starts over
+        let mut buffer = vec![0x00_0000; 3];
+        phosphor.apply(&mut buffer);
+        assert_eq!(buffer, [0; 3]);
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+// Animated GIF recordings. The colour table is the palette itself, so no
+// colour is approximated, and each frame only holds the rectangle that
+// changed since the one before, with the pixels that did not change left
+// transparent. Frames that change nothing lengthen the one before instead.
+
+// Hundredths of a second per frame, 50 frames per second
+const FRAME_DELAY: u16 = 2;
+
+const EXTENSION: u8 = 0x21;
+const GRAPHIC_CONTROL: u8 = 0xF9;
+const APPLICATION: u8 = 0xFF;
+const IMAGE: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+
+const GLOBAL_TABLE: u8 = 0x80;
+// Frames are drawn over the one before
+const KEEP_PREVIOUS: u8 = 1 << 2;
+const HAS_TRANSPARENT: u8 = 0x01;
+
+const MAX_CODE_SIZE: u32 = 12;
+const MAX_CODES: u16 = 1 << MAX_CODE_SIZE;
+const MAX_SUB_BLOCK: usize = 255;
+
+fn write_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+// Codes of variable width, least significant bit first, cut into sub-blocks
+struct CodeWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl CodeWriter {
+    fn code(&mut self, code: u16, size: u32) {
+        self.bits |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        let mut blocks =
+            Vec::with_capacity(self.bytes.len() + self.bytes.len() / MAX_SUB_BLOCK + 2);
+        for block in self.bytes.chunks(MAX_SUB_BLOCK) {
+            blocks.push(block.len() as u8);
+            blocks.extend_from_slice(block);
+        }
+        blocks.push(0);
+        blocks
+    }
+}
+
+// LZW with the table cleared when it fills up. The table maps a code and
+// the index that follows it to the code of both, 0 being no entry since
+// the first codes never follow anything.
+fn lzw(indices: &[u8], min_code_size: u32) -> Vec<u8> {
+    let clear: u16 = 1 << min_code_size;
+    let end = clear + 1;
+    let width = clear as usize;
+    let mut table = vec![0u16; MAX_CODES as usize * width];
+    let mut next = clear + 2;
+    let mut size = min_code_size + 1;
+    let mut out = CodeWriter {
+        bytes: Vec::new(),
+        bits: 0,
+        count: 0,
+    };
+    out.code(clear, size);
+
+    let mut prefix: Option<u16> = None;
+    for &index in indices {
+        let code = match prefix {
+            None => {
+                prefix = Some(index as u16);
+                continue;
+            }
+            Some(code) => code,
+        };
+        let entry = code as usize * width + index as usize;
+        if table[entry] != 0 {
+            prefix = Some(table[entry]);
+            continue;
+        }
+        out.code(code, size);
+        if next < MAX_CODES {
+            table[entry] = next;
+            // The reader gets to the same entry one code later
+            if next == 1 << size && size < MAX_CODE_SIZE {
+                size += 1;
+            }
+            next += 1;
+        } else {
+            out.code(clear, size);
+            for entry in table.iter_mut() {
+                *entry = 0;
+            }
+            next = clear + 2;
+            size = min_code_size + 1;
+        }
+        prefix = Some(index as u16);
+    }
+    if let Some(code) = prefix {
+        out.code(code, size);
+    }
+    out.code(end, size);
+
+    let mut data = vec![min_code_size as u8];
+    data.extend(out.finish());
+    data
+}
+
+pub struct GifWriter {
+    file: File,
+    width: usize,
+    height: usize,
+    colours: Vec<u32>,
+    // Colour table entry after the colours
+    transparent: u8,
+    // Colour table entry of every colour seen, the nearest one for those
+    // outside the palette
+    lookup: HashMap<u32, u8>,
+    previous: Vec<u8>,
+    // Image of the last frame, written when its length is known
+    pending: Option<(Vec<u8>, u16)>,
+    finished: bool,
+}
+
+impl GifWriter {
+    // Up to 255 colours, one entry is left for transparency
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+        colours: &[u32],
+    ) -> io::Result<GifWriter> {
+        if colours.is_empty() || colours.len() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "el GIF admite de 1 a 255 colores",
+            ));
+        }
+        let mut writer = GifWriter {
+            file: File::create(path)?,
+            width,
+            height,
+            colours: colours.to_vec(),
+            transparent: colours.len() as u8,
+            lookup: colours
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(index, &colour)| (colour & 0xFF_FFFF, index as u8))
+                .collect(),
+            previous: Vec::new(),
+            pending: None,
+            finished: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    // Bits of an entry of the colour table, which has a power of two entries
+    fn table_bits(&self) -> u32 {
+        let entries = self.colours.len() + 1;
+        (usize::BITS - (entries - 1).leading_zeros()).max(1)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let bits = self.table_bits();
+        let mut header = b"GIF89a".to_vec();
+        write_u16(&mut header, self.width as u16);
+        write_u16(&mut header, self.height as u16);
+        // 8 bits per primary, background colour and aspect ratio unknown
+        header.extend_from_slice(&[GLOBAL_TABLE | 0x70 | (bits - 1) as u8, 0, 0]);
+        for entry in 0..1 << bits {
+            let colour = self.colours.get(entry).cloned().unwrap_or(0);
+            header.extend_from_slice(&[(colour >> 16) as u8, (colour >> 8) as u8, colour as u8]);
+        }
+        // Played over and over
+        header.extend_from_slice(&[EXTENSION, APPLICATION, 11]);
+        header.extend_from_slice(b"NETSCAPE2.0");
+        header.extend_from_slice(&[3, 1, 0, 0, 0]);
+        self.file.write_all(&header)
+    }
+
+    fn index(&mut self, pixel: u32) -> u8 {
+        let pixel = pixel & 0xFF_FFFF;
+        if let Some(&index) = self.lookup.get(&pixel) {
+            return index;
+        }
+        let distance = |colour: u32| {
+            (0..3)
+                .map(|shift| {
+                    let a = (pixel >> (shift * 8)) as u8 as i32;
+                    let b = (colour >> (shift * 8)) as u8 as i32;
+                    (a - b) * (a - b)
+                })
+                .sum::<i32>()
+        };
+        let index = (0..self.colours.len())
+            .min_by_key(|&index| distance(self.colours[index]))
+            .unwrap() as u8;
+        self.lookup.insert(pixel, index);
+        index
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        let (image, delay) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let mut bytes = vec![
+            EXTENSION,
+            GRAPHIC_CONTROL,
+            4,
+            KEEP_PREVIOUS | HAS_TRANSPARENT,
+        ];
+        write_u16(&mut bytes, delay);
+        bytes.extend_from_slice(&[self.transparent, 0]);
+        bytes.extend_from_slice(&image);
+        self.file.write_all(&bytes)
+    }
+
+    // `pixels` holds the rows of the frame as 0xRRGGBB values
+    pub fn frame(&mut self, pixels: &[u32]) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("el GIF ya está cerrado"));
+        }
+        let indices: Vec<u8> = pixels[..self.width * self.height]
+            .iter()
+            .map(|&pixel| self.index(pixel))
+            .collect();
+
+        // Rectangle holding every pixel that changed
+        let (mut left, mut top, mut right, mut bottom) = (0, 0, self.width, self.height);
+        if !self.previous.is_empty() {
+            let changed = |y: usize, x: usize| {
+                let i = y * self.width + x;
+                indices[i] != self.previous[i]
+            };
+            let row_changed = |y: usize| (0..self.width).any(|x| changed(y, x));
+            top = match (0..self.height).find(|&y| row_changed(y)) {
+                Some(y) => y,
+                None => {
+                    // Nothing to draw, the last frame stays up longer
+                    if let Some((_, ref mut delay)) = self.pending {
+                        *delay = delay.saturating_add(FRAME_DELAY);
+                    }
+                    return Ok(());
+                }
+            };
+            bottom = (top..self.height).rev().find(|&y| row_changed(y)).unwrap() + 1;
+            let column_changed = |x: usize| (top..bottom).any(|y| changed(y, x));
+            left = (0..self.width).find(|&x| column_changed(x)).unwrap();
+            right = (left..self.width)
+                .rev()
+                .find(|&x| column_changed(x))
+                .unwrap()
+                + 1;
+        }
+
+        let mut area = Vec::with_capacity((right - left) * (bottom - top));
+        for y in top..bottom {
+            for x in left..right {
+                let i = y * self.width + x;
+                let unchanged = !self.previous.is_empty() && indices[i] == self.previous[i];
+                area.push(if unchanged {
+                    self.transparent
+                } else {
+                    indices[i]
+                });
+            }
+        }
+
+        let mut image = vec![IMAGE];
+        write_u16(&mut image, left as u16);
+        write_u16(&mut image, top as u16);
+        write_u16(&mut image, (right - left) as u16);
+        write_u16(&mut image, (bottom - top) as u16);
+        // No local colour table nor interlacing
+        image.push(0);
+        image.extend(lzw(&area, self.table_bits().max(2)));
+
+        self.write_pending()?;
+        self.pending = Some((image, FRAME_DELAY));
+        self.previous = indices;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write_pending()?;
+        self.file.write_all(&[TRAILER])?;
+        self.file.flush()
+    }
+}
+
+impl Drop for GifWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("No he podido cerrar el GIF: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads back what lzw wrote, sub-blocks and all, as a GIF decoder
+    // would. Returns the indices and how many clear codes there were.
+    fn unlzw(image_data: &[u8]) -> (Vec<u8>, usize) {
+        let min_code_size = image_data[0] as u32;
+        let mut data = Vec::new();
+        let mut blocks = &image_data[1..];
+        while blocks[0] != 0 {
+            let len = blocks[0] as usize;
+            data.extend_from_slice(&blocks[1..=len]);
+            blocks = &blocks[len + 1..];
+        }
+
+        let clear = 1usize << min_code_size;
+        let end = clear + 1;
+        let mut size = min_code_size + 1;
+        let (mut bits, mut count, mut pos) = (0u32, 0, 0);
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        let mut clears = 0;
+        loop {
+            while count < size {
+                bits |= (data[pos] as u32) << count;
+                pos += 1;
+                count += 8;
+            }
+            let code = (bits & ((1 << size) - 1)) as usize;
+            bits >>= size;
+            count -= size;
+
+            if code == clear {
+                table = (0..clear + 2).map(|i| vec![i as u8]).collect();
+                size = min_code_size + 1;
+                previous = None;
+                clears += 1;
+                continue;
+            }
+            if code == end {
+                return (out, clears);
+            }
+            let entry = match table.get(code) {
+                Some(entry) => entry.clone(),
+                // The code being defined: the previous string and its start
+                None => {
+                    let mut entry = previous.clone().unwrap();
+                    entry.push(entry[0]);
+                    entry
+                }
+            };
+            if let Some(mut string) = previous.take() {
+                if table.len() < MAX_CODES as usize {
+                    string.push(entry[0]);
+                    table.push(string);
+                }
+            }
+            if table.len() == 1 << size && size < MAX_CODE_SIZE {
+                size += 1;
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        // Indices that hardly repeat fill the table several times
+        let mut seed = 7u32;
+        let noise: Vec<u8> = (0..40_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                ((seed >> 16) % 16) as u8
+            })
+            .collect();
+        let (decoded, clears) = unlzw(&lzw(&noise, 4));
+        assert_eq!(decoded, noise);
+        assert!(clears > 2);
+
+        // Runs, where a code is used as it is being defined
+        let runs: Vec<u8> = (0..20_000).map(|i| (i / 1000 % 3) as u8).collect();
+        let (decoded, _) = unlzw(&lzw(&runs, 2));
+        assert_eq!(decoded, runs);
+
+        for indices in [vec![], vec![3], vec![1, 1, 1]].iter() {
+            assert_eq!(&unlzw(&lzw(indices, 2)).0, indices);
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::io;
+
+use audio::DEFAULT_SAMPLE_RATE;
+use ay::Ay;
+use beeper::Beeper;
+use fdc::Upd765;
+use joystick::Joystick;
+use keyboard::Keyboard;
+use memory::Memory;
+use mouse::KempstonMouse;
+use rzx::{RzxPlayer, RzxRecorder};
+use state::{SaveState, StateReader, StateWriter};
+use tape::MicRecorder;
+use ula;
+use ulaplus::UlaPlus;
+
+// Bits of the ULA port 0xFE on write
+const BORDER_MASK: u8 = 0x07;
+const MIC_BIT: u8 = 0x08;
+const EAR_BIT: u8 = 0x10;
+
+// Bit of 0x1FFD that drives the disk motors
+const MOTOR_BIT: u8 = 0x08;
+
+pub struct IoBus {
+    // State of the ULA output latch
+    pub border: u8,
+    pub mic: bool,
+    pub ear: bool,
+
+    // Receives the MIC edges when a program is saving to tape
+    pub mic_recorder: Option<MicRecorder>,
+
+    pub beeper: Beeper,
+
+    pub keyboard: Keyboard,
+    // Joystick interfaces plugged in, Sinclair ones go through the keyboard
+    pub joysticks: Vec<Joystick>,
+    pub mouse: Option<KempstonMouse>,
+
+    // Present on the 128K models or when a 48K has an AY interface
+    pub ay: Option<Ay>,
+    // Disk controller of the +3
+    pub fdc: Option<Upd765>,
+    pub ulaplus: Option<UlaPlus>,
+
+    // While replaying an input recording the ports read what it says,
+    // while recording one what they read goes into it
+    pub rzx_player: Option<RzxPlayer>,
+    pub rzx_recorder: Option<RzxRecorder>,
+}
+
+impl Default for IoBus {
+    fn default() -> IoBus {
+        IoBus::new()
+    }
+}
+
+impl IoBus {
+    pub fn new() -> IoBus {
+        IoBus {
+            border: 0,
+            mic: false,
+            ear: false,
+            mic_recorder: None,
+            beeper: Beeper::new(ula::TIMING_48K.cpu_clock, DEFAULT_SAMPLE_RATE),
+            keyboard: Keyboard::new(),
+            joysticks: Vec::new(),
+            mouse: None,
+            ay: None,
+            fdc: None,
+            ulaplus: None,
+            rzx_player: None,
+            rzx_recorder: None,
+        }
+    }
+
+    fn is_ula_port(port: u16) -> bool {
+        // The ULA answers to every even port
+        port & 0x01 == 0
+    }
+
+    // 0xFFFD selects and reads the AY registers, 0xBFFD writes them
+    fn is_ay_select_port(port: u16) -> bool {
+        port & 0xC002 == 0xC000
+    }
+
+    fn is_ay_data_port(port: u16) -> bool {
+        port & 0xC002 == 0x8000
+    }
+
+    // 0x2FFD reads the status of the disk controller, 0x3FFD moves the
+    // data
+    fn is_fdc_status_port(port: u16) -> bool {
+        port & 0xF002 == 0x2000
+    }
+
+    fn is_fdc_data_port(port: u16) -> bool {
+        port & 0xF002 == 0x3000
+    }
+
+    // Keys down in the selected half rows, joysticks on the keyboard included
+    fn pressed_keys(&self, high_byte: u8) -> u8 {
+        let mut keys = self.keyboard.pressed(high_byte);
+        for joystick in self.joysticks.iter() {
+            for key in joystick.pressed_keys() {
+                let (row, bit) = key.position();
+                if high_byte & (1 << row) == 0 {
+                    keys |= bit;
+                }
+            }
+        }
+        keys
+    }
+
+    // The devices are read even on a replay, so they change as they did
+    pub fn read_port(&mut self, mem: &Memory, port: u16, t_states: u64) -> u8 {
+        let value = self.read_devices(mem, port, t_states);
+        if let Some(ref mut player) = self.rzx_player {
+            return player.next_input().unwrap_or(value);
+        }
+        if let Some(ref mut recorder) = self.rzx_recorder {
+            recorder.input(value);
+        }
+        value
+    }
+
+    fn read_devices(&mut self, mem: &Memory, port: u16, t_states: u64) -> u8 {
+        if IoBus::is_ula_port(port) {
+            // Nothing on the EAR input
+            0xBF & !self.pressed_keys((port >> 8) as u8)
+        } else {
+            if let Some(ref ay) = self.ay {
+                if IoBus::is_ay_select_port(port) {
+                    return ay.read_register();
+                }
+            }
+            if let Some(ref mut fdc) = self.fdc {
+                if IoBus::is_fdc_status_port(port) {
+                    return fdc.read_status();
+                } else if IoBus::is_fdc_data_port(port) {
+                    return fdc.read_data();
+                }
+            }
+            if let Some(value) = self.mouse.as_ref().and_then(|mouse| mouse.read_port(port)) {
+                return value;
+            }
+            if let Some(value) = self.ulaplus.as_ref().and_then(|ulaplus| ulaplus.read_port(port)) {
+                return value;
+            }
+            for joystick in self.joysticks.iter() {
+                if let Some(value) = joystick.read_port(port) {
+                    return value;
+                }
+            }
+            mem.floating_bus(t_states)
+        }
+    }
+
+    fn is_paging_port(port: u16) -> bool {
+        // 0x7FFD is decoded from A15 and A1 only
+        port & 0x8002 == 0
+    }
+
+    // The +2A/+3 decode A14 too, to make room for 0x1FFD
+    fn is_plus3_paging_port(port: u16) -> bool {
+        port & 0xC002 == 0x4000
+    }
+
+    fn is_special_paging_port(port: u16) -> bool {
+        port & 0xF002 == 0x1000
+    }
+
+    pub fn write_port(&mut self, mem: &mut Memory, port: u16, value: u8, t_states: u64) {
+        if IoBus::is_ula_port(port) {
+            self.border = value & BORDER_MASK;
+            let ear = value & EAR_BIT != 0;
+            if ear != self.ear {
+                self.beeper.edge(t_states, ear);
+            }
+            self.ear = ear;
+
+            let mic = value & MIC_BIT != 0;
+            if mic != self.mic {
+                if let Some(ref mut recorder) = self.mic_recorder {
+                    recorder.edge(t_states);
+                }
+            }
+            self.mic = mic;
+        }
+        if mem.has_special_paging() {
+            if IoBus::is_plus3_paging_port(port) {
+                mem.write_7ffd(value);
+            } else if IoBus::is_special_paging_port(port) {
+                // The motors follow the port even when paging is locked
+                if let Some(ref mut fdc) = self.fdc {
+                    fdc.set_motor(value & MOTOR_BIT != 0);
+                }
+                mem.write_1ffd(value);
+            }
+        } else if IoBus::is_paging_port(port) {
+            mem.write_7ffd(value);
+        }
+        if let Some(ref mut fdc) = self.fdc {
+            if IoBus::is_fdc_data_port(port) {
+                fdc.write_data(value);
+            }
+        }
+        if let Some(ref mut ulaplus) = self.ulaplus {
+            ulaplus.write_port(port, value);
+        }
+        if let Some(ref mut ay) = self.ay {
+            if IoBus::is_ay_select_port(port) {
+                ay.select_register(value);
+            } else if IoBus::is_ay_data_port(port) {
+                // The registers change at this T-state, not at the next tick
+                ay.run_to(t_states);
+                ay.write_register(value);
+            }
+        }
+    }
+}
+
+// Writes a device that may not be plugged in
+fn write_optional<T: SaveState>(device: &Option<T>, state: &mut StateWriter) {
+    state.bool(device.is_some());
+    if let Some(ref device) = *device {
+        device.write_state(state);
+    }
+}
+
+// Reads a device written by write_optional. When the state has it and
+// this machine does not, it is read into `spare` and left out.
+fn read_optional<T: SaveState>(
+    device: &mut Option<T>,
+    mut spare: T,
+    state: &mut StateReader,
+) -> io::Result<()> {
+    if state.bool()? {
+        match *device {
+            Some(ref mut device) => device.read_state(state)?,
+            None => spare.read_state(state)?,
+        }
+    }
+    Ok(())
+}
+
+// The peripherals plugged in are left as they are: a device in the state
+// that is not here is skipped, one here that is not in the state stays
+// as it was. The AY is the exception, the machine plugs it in or takes
+// it out before reading.
+impl SaveState for IoBus {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u8(self.border);
+        state.bool(self.mic);
+        state.bool(self.ear);
+        self.beeper.write_state(state);
+        self.keyboard.write_state(state);
+        state.u32(self.joysticks.len() as u32);
+        for joystick in self.joysticks.iter() {
+            state.u8(joystick.state);
+        }
+        write_optional(&self.mouse, state);
+        write_optional(&self.ay, state);
+        write_optional(&self.fdc, state);
+        write_optional(&self.ulaplus, state);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.border = state.u8()?;
+        self.mic = state.bool()?;
+        self.ear = state.bool()?;
+        self.beeper.read_state(state)?;
+        self.keyboard.read_state(state)?;
+        for index in 0..state.u32()? as usize {
+            let joystick_state = state.u8()?;
+            if let Some(joystick) = self.joysticks.get_mut(index) {
+                joystick.state = joystick_state;
+            }
+        }
+        read_optional(&mut self.mouse, KempstonMouse::new(), state)?;
+        read_optional(&mut self.ay, Ay::new(ula::TIMING_48K.cpu_clock, DEFAULT_SAMPLE_RATE), state)?;
+        read_optional(&mut self.fdc, Upd765::new(), state)?;
+        read_optional(&mut self.ulaplus, UlaPlus::new(), state)?;
+        Ok(())
+    }
+}
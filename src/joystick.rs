@@ -0,0 +1,137 @@
+use keyboard::SpectrumKey;
+
+// Directions and fire, a set bit meaning pressed
+pub const UP: u8 = 0x01;
+pub const DOWN: u8 = 0x02;
+pub const LEFT: u8 = 0x04;
+pub const RIGHT: u8 = 0x08;
+pub const FIRE: u8 = 0x10;
+
+const DIRECTIONS: [u8; 5] = [UP, DOWN, LEFT, RIGHT, FIRE];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum JoystickKind {
+    // Port 0x1F, active high: 000FUDLR
+    Kempston,
+    // Interface 2: keys 6 to 0 and 1 to 5
+    Sinclair1,
+    Sinclair2,
+    // Cursor keys 5 to 8 and 0 for fire
+    Cursor,
+    // Port 0x7F, active low: F000RLDU
+    Fuller,
+}
+
+impl JoystickKind {
+    pub fn from_name(name: &str) -> Option<JoystickKind> {
+        match name.to_lowercase().as_str() {
+            "kempston" => Some(JoystickKind::Kempston),
+            "sinclair1" | "sinclair" => Some(JoystickKind::Sinclair1),
+            "sinclair2" => Some(JoystickKind::Sinclair2),
+            "cursor" | "protek" => Some(JoystickKind::Cursor),
+            "fuller" => Some(JoystickKind::Fuller),
+            _ => None,
+        }
+    }
+
+    // Keys pressed for up, down, left, right and fire on the interfaces
+    // that go through the keyboard
+    fn keys(self) -> Option<[SpectrumKey; 5]> {
+        match self {
+            JoystickKind::Sinclair1 => Some([
+                SpectrumKey::Num9,
+                SpectrumKey::Num8,
+                SpectrumKey::Num6,
+                SpectrumKey::Num7,
+                SpectrumKey::Num0,
+            ]),
+            JoystickKind::Sinclair2 => Some([
+                SpectrumKey::Num4,
+                SpectrumKey::Num3,
+                SpectrumKey::Num1,
+                SpectrumKey::Num2,
+                SpectrumKey::Num5,
+            ]),
+            JoystickKind::Cursor => Some([
+                SpectrumKey::Num7,
+                SpectrumKey::Num6,
+                SpectrumKey::Num5,
+                SpectrumKey::Num8,
+                SpectrumKey::Num0,
+            ]),
+            JoystickKind::Kempston | JoystickKind::Fuller => None,
+        }
+    }
+}
+
+pub fn direction_from_name(name: &str) -> Option<u8> {
+    match name.to_lowercase().as_str() {
+        "up" => Some(UP),
+        "down" => Some(DOWN),
+        "left" => Some(LEFT),
+        "right" => Some(RIGHT),
+        "fire" => Some(FIRE),
+        _ => None,
+    }
+}
+
+pub struct Joystick {
+    pub kind: JoystickKind,
+    pub state: u8,
+}
+
+impl Joystick {
+    pub fn new(kind: JoystickKind) -> Joystick {
+        Joystick { kind, state: 0 }
+    }
+
+    pub fn set(&mut self, direction: u8, pressed: bool) {
+        if pressed {
+            self.state |= direction;
+        } else {
+            self.state &= !direction;
+        }
+    }
+
+    // Value of the interface's own port, if it has one and `port` is it
+    pub fn read_port(&self, port: u16) -> Option<u8> {
+        match self.kind {
+            // A5 to A7 low, so it stays off the mouse ports
+            JoystickKind::Kempston if port & 0xE0 == 0 => {
+                let mut value = 0;
+                for (bit, &direction) in [RIGHT, LEFT, DOWN, UP, FIRE].iter().enumerate() {
+                    if self.state & direction != 0 {
+                        value |= 1 << bit;
+                    }
+                }
+                Some(value)
+            }
+            JoystickKind::Fuller if port & 0xFF == 0x7F => {
+                let mut value = 0xFF;
+                for (bit, &direction) in [UP, DOWN, LEFT, RIGHT].iter().enumerate() {
+                    if self.state & direction != 0 {
+                        value &= !(1 << bit);
+                    }
+                }
+                if self.state & FIRE != 0 {
+                    value &= 0x7F;
+                }
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    // Keys held down on the keyboard mapped interfaces
+    pub fn pressed_keys(&self) -> Vec<SpectrumKey> {
+        match self.kind.keys() {
+            Some(keys) => DIRECTIONS
+                .iter()
+                .zip(keys.iter())
+                .filter(|&(&direction, _)| self.state & direction != 0)
+                .map(|(_, &key)| key)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// The 40 key matrix read through port 0xFE. Each half row is selected by
+// one of the address lines A8 to A15 going low and answers with its five
+// keys in bits 0 to 4, a pressed key reading 0.
+
+use std::io;
+
+use state::{SaveState, StateReader, StateWriter};
+
+pub const ROWS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SpectrumKey {
+    CapsShift, Z, X, C, V,
+    A, S, D, F, G,
+    Q, W, E, R, T,
+    Num1, Num2, Num3, Num4, Num5,
+    Num0, Num9, Num8, Num7, Num6,
+    P, O, I, U, Y,
+    Enter, L, K, J, H,
+    Space, SymbolShift, M, N, B,
+}
+
+// Keys in matrix order: row by row, bit 0 first
+const KEYS: [SpectrumKey; ROWS * 5] = [
+    SpectrumKey::CapsShift, SpectrumKey::Z, SpectrumKey::X, SpectrumKey::C, SpectrumKey::V,
+    SpectrumKey::A, SpectrumKey::S, SpectrumKey::D, SpectrumKey::F, SpectrumKey::G,
+    SpectrumKey::Q, SpectrumKey::W, SpectrumKey::E, SpectrumKey::R, SpectrumKey::T,
+    SpectrumKey::Num1, SpectrumKey::Num2, SpectrumKey::Num3, SpectrumKey::Num4, SpectrumKey::Num5,
+    SpectrumKey::Num0, SpectrumKey::Num9, SpectrumKey::Num8, SpectrumKey::Num7, SpectrumKey::Num6,
+    SpectrumKey::P, SpectrumKey::O, SpectrumKey::I, SpectrumKey::U, SpectrumKey::Y,
+    SpectrumKey::Enter, SpectrumKey::L, SpectrumKey::K, SpectrumKey::J, SpectrumKey::H,
+    SpectrumKey::Space, SpectrumKey::SymbolShift, SpectrumKey::M, SpectrumKey::N, SpectrumKey::B,
+];
+
+const NAMES: [&str; ROWS * 5] = [
+    "CAPS", "Z", "X", "C", "V",
+    "A", "S", "D", "F", "G",
+    "Q", "W", "E", "R", "T",
+    "1", "2", "3", "4", "5",
+    "0", "9", "8", "7", "6",
+    "P", "O", "I", "U", "Y",
+    "ENTER", "L", "K", "J", "H",
+    "SPACE", "SYMBOL", "M", "N", "B",
+];
+
+impl SpectrumKey {
+    // Half row and bit of the key
+    pub fn position(self) -> (usize, u8) {
+        let index = KEYS.iter().position(|&key| key == self).unwrap();
+        (index / 5, 1 << (index % 5))
+    }
+
+    pub fn from_name(name: &str) -> Option<SpectrumKey> {
+        let name = name.to_uppercase();
+        NAMES.iter().position(|&n| n == name).map(|index| KEYS[index])
+    }
+}
+
+pub struct Keyboard {
+    // Pressed keys of each half row, a set bit meaning pressed
+    rows: [u8; ROWS],
+}
+
+impl Default for Keyboard {
+    fn default() -> Keyboard {
+        Keyboard::new()
+    }
+}
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard { rows: [0; ROWS] }
+    }
+
+    pub fn set_key(&mut self, key: SpectrumKey, pressed: bool) {
+        let (row, bit) = key.position();
+        if pressed {
+            self.rows[row] |= bit;
+        } else {
+            self.rows[row] &= !bit;
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.rows = [0; ROWS];
+    }
+
+    // Pressed keys, active high, of the half rows selected by the high
+    // byte of the port. Several rows can be read at once.
+    pub fn pressed(&self, high_byte: u8) -> u8 {
+        let mut keys = 0;
+        for (row, pressed) in self.rows.iter().enumerate() {
+            if high_byte & (1 << row) == 0 {
+                keys |= pressed;
+            }
+        }
+        keys
+    }
+}
+
+impl SaveState for Keyboard {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.rows);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.rows.copy_from_slice(state.bytes(ROWS)?);
+        Ok(())
+    }
+}
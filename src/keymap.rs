@@ -0,0 +1,231 @@
+// Host keys of the window as Spectrum keys and joystick directions, and
+// the host pointer as the Kempston mouse
+
+use display::Display;
+use minifb::{Key, MouseButton, MouseMode, Window};
+use z80::iobus::IoBus;
+use z80::joystick::{DOWN, FIRE, LEFT, RIGHT, UP};
+use z80::keyboard::SpectrumKey;
+use z80::mouse::{KempstonMouse, LEFT_BUTTON, MIDDLE_BUTTON, RIGHT_BUTTON};
+
+const HOST_KEYS: [(Key, SpectrumKey); 40] = [
+    (Key::LeftShift, SpectrumKey::CapsShift),
+    (Key::Z, SpectrumKey::Z),
+    (Key::X, SpectrumKey::X),
+    (Key::C, SpectrumKey::C),
+    (Key::V, SpectrumKey::V),
+    (Key::A, SpectrumKey::A),
+    (Key::S, SpectrumKey::S),
+    (Key::D, SpectrumKey::D),
+    (Key::F, SpectrumKey::F),
+    (Key::G, SpectrumKey::G),
+    (Key::Q, SpectrumKey::Q),
+    (Key::W, SpectrumKey::W),
+    (Key::E, SpectrumKey::E),
+    (Key::R, SpectrumKey::R),
+    (Key::T, SpectrumKey::T),
+    (Key::Key1, SpectrumKey::Num1),
+    (Key::Key2, SpectrumKey::Num2),
+    (Key::Key3, SpectrumKey::Num3),
+    (Key::Key4, SpectrumKey::Num4),
+    (Key::Key5, SpectrumKey::Num5),
+    (Key::Key0, SpectrumKey::Num0),
+    (Key::Key9, SpectrumKey::Num9),
+    (Key::Key8, SpectrumKey::Num8),
+    (Key::Key7, SpectrumKey::Num7),
+    (Key::Key6, SpectrumKey::Num6),
+    (Key::P, SpectrumKey::P),
+    (Key::O, SpectrumKey::O),
+    (Key::I, SpectrumKey::I),
+    (Key::U, SpectrumKey::U),
+    (Key::Y, SpectrumKey::Y),
+    (Key::Enter, SpectrumKey::Enter),
+    (Key::L, SpectrumKey::L),
+    (Key::K, SpectrumKey::K),
+    (Key::J, SpectrumKey::J),
+    (Key::H, SpectrumKey::H),
+    (Key::Space, SpectrumKey::Space),
+    (Key::RightShift, SpectrumKey::SymbolShift),
+    (Key::M, SpectrumKey::M),
+    (Key::N, SpectrumKey::N),
+    (Key::B, SpectrumKey::B),
+];
+
+const LETTER_KEYS: [Key; 26] = [
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K,
+    Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V,
+    Key::W, Key::X, Key::Y, Key::Z,
+];
+
+const DIGIT_KEYS: [Key; 10] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+];
+
+// Names of the host keys other than letters and digits
+const KEY_NAMES: [(&str, Key); 34] = [
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("space", Key::Space),
+    ("enter", Key::Enter),
+    ("tab", Key::Tab),
+    ("backspace", Key::Backspace),
+    ("leftshift", Key::LeftShift),
+    ("rightshift", Key::RightShift),
+    ("leftctrl", Key::LeftCtrl),
+    ("rightctrl", Key::RightCtrl),
+    ("leftalt", Key::LeftAlt),
+    ("rightalt", Key::RightAlt),
+    ("capslock", Key::CapsLock),
+    ("comma", Key::Comma),
+    ("period", Key::Period),
+    ("minus", Key::Minus),
+    ("equal", Key::Equal),
+    ("semicolon", Key::Semicolon),
+    ("apostrophe", Key::Apostrophe),
+    ("slash", Key::Slash),
+    ("backslash", Key::Backslash),
+    ("leftbracket", Key::LeftBracket),
+    ("rightbracket", Key::RightBracket),
+    ("insert", Key::Insert),
+    ("delete", Key::Delete),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("numpad8", Key::NumPad8),
+    ("numpad2", Key::NumPad2),
+    ("numpad4", Key::NumPad4),
+    ("numpad6", Key::NumPad6),
+    ("numpad0", Key::NumPad0),
+];
+
+// Host key by name: a letter, a digit or one of KEY_NAMES
+pub fn host_key(name: &str) -> Option<Key> {
+    let name = name.trim().to_lowercase();
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_lowercase() {
+            return Some(LETTER_KEYS[(c as u8 - b'a') as usize]);
+        }
+        if c.is_ascii_digit() {
+            return Some(DIGIT_KEYS[(c as u8 - b'0') as usize]);
+        }
+    }
+    KEY_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, key)| key)
+}
+
+// Spectrum keys each host key presses, several for shifted combinations
+pub struct KeyBindings {
+    keys: Vec<(Key, Vec<SpectrumKey>)>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            keys: HOST_KEYS.iter().map(|&(host, key)| (host, vec![key])).collect(),
+        }
+    }
+}
+
+impl KeyBindings {
+    // Replaces what `host` presses. No keys leaves it unbound.
+    pub fn bind(&mut self, host: Key, keys: Vec<SpectrumKey>) {
+        self.keys.retain(|&(k, _)| k != host);
+        if !keys.is_empty() {
+            self.keys.push((host, keys));
+        }
+    }
+}
+
+// Host keys for up, down, left, right and fire
+pub struct JoystickKeys {
+    keys: [Key; 5],
+}
+
+impl Default for JoystickKeys {
+    fn default() -> JoystickKeys {
+        JoystickKeys {
+            keys: [Key::Up, Key::Down, Key::Left, Key::Right, Key::RightCtrl],
+        }
+    }
+}
+
+impl JoystickKeys {
+    // Parses "up,down,left,right,fire" with the names host_key takes
+    pub fn parse(spec: &str) -> Option<JoystickKeys> {
+        let mut keys = Vec::new();
+        for name in spec.split(',') {
+            keys.push(host_key(name)?);
+        }
+        if keys.len() != 5 {
+            return None;
+        }
+        Some(JoystickKeys {
+            keys: [keys[0], keys[1], keys[2], keys[3], keys[4]],
+        })
+    }
+
+    fn state(&self, window: &Window) -> u8 {
+        let mut state = 0;
+        for (&key, &direction) in self.keys.iter().zip([UP, DOWN, LEFT, RIGHT, FIRE].iter()) {
+            if window.is_key_down(key) {
+                state |= direction;
+            }
+        }
+        state
+    }
+}
+
+// Copies the host keyboard into the Spectrum one and the first joystick.
+// Keys used by the joystick do not reach the keyboard.
+pub fn update(
+    window: &Window,
+    bindings: &KeyBindings,
+    joystick_keys: &JoystickKeys,
+    io: &mut IoBus,
+) {
+    io.keyboard.release_all();
+    for &(host, ref keys) in bindings.keys.iter() {
+        if window.is_key_down(host) && !joystick_keys.keys.contains(&host) {
+            for &key in keys.iter() {
+                io.keyboard.set_key(key, true);
+            }
+        }
+    }
+    let state = joystick_keys.state(window);
+    if let Some(joystick) = io.joysticks.first_mut() {
+        joystick.state = state;
+    }
+}
+
+// Follows the window pointer with the Kempston mouse. `last` keeps the
+// previous position, the interface only sees movements, which are counted
+// in Spectrum pixels whatever size the window shows the picture at.
+pub fn update_mouse(
+    window: &Window,
+    display: &Display,
+    last: &mut Option<(f32, f32)>,
+    mouse: &mut KempstonMouse,
+) {
+    if let Some(position) = window.get_unscaled_mouse_pos(MouseMode::Pass) {
+        let (x, y) = display.picture_position(window.get_size(), position);
+        if let Some((last_x, last_y)) = *last {
+            mouse.move_by((x - last_x) as i32, (last_y - y) as i32);
+        }
+        *last = Some((x, y));
+    }
+
+    mouse.buttons = 0;
+    for &(button, bit) in [
+        (MouseButton::Left, LEFT_BUTTON),
+        (MouseButton::Right, RIGHT_BUTTON),
+        (MouseButton::Middle, MIDDLE_BUTTON),
+    ]
+    .iter()
+    {
+        if window.get_mouse_down(button) {
+            mouse.buttons |= bit;
+        }
+    }
+}
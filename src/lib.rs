@@ -0,0 +1,29 @@
+pub mod audio;
+pub mod avi;
+pub mod ay;
+pub mod beeper;
+pub mod checksum;
+pub mod dsk;
+pub mod fdc;
+pub mod filter;
+pub mod gif;
+pub mod iobus;
+pub mod joystick;
+pub mod keyboard;
+pub mod machine;
+pub mod memory;
+pub mod mouse;
+pub mod png;
+pub mod rom;
+pub mod rzx;
+pub mod script;
+pub mod scr;
+pub mod sna;
+pub mod state;
+pub mod szx;
+pub mod tape;
+pub mod ula;
+pub mod ulaplus;
+pub mod z80_snapshot;
+pub mod zlib;
+pub mod z80;
@@ -0,0 +1,529 @@
+use std::io;
+
+use audio::DEFAULT_SAMPLE_RATE;
+use ay::Ay;
+use beeper::Beeper;
+use fdc::Upd765;
+use iobus::IoBus;
+use memory::{Memory, PAGE_SIZE};
+use state::{SaveState, StateReader, StateWriter};
+use tape;
+use tape::{TapReader, TapWriter};
+use ula;
+use ula::{Palette, UlaTiming, DEFAULT_PALETTE};
+use z80::Z80;
+
+// Share of the output each sound source gets when mixed
+const BEEPER_VOLUME: f32 = 0.5;
+const AY_VOLUME: f32 = 0.5;
+// Pole of the high-pass that removes the offset of the idle speaker
+const DC_BLOCKER_POLE: f32 = 0.995;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    // The 48K board with only the lower 16K of RAM fitted
+    Spectrum16K,
+    Spectrum48K,
+    Spectrum128K,
+    // 128K paging with its own timing
+    Pentagon128,
+    // Amstrad models with four ROMs and the 0x1FFD port, the +3 adding
+    // the disk drive
+    SpectrumPlus2A,
+    SpectrumPlus3,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "16" | "16k" => Some(Model::Spectrum16K),
+            "48" | "48k" => Some(Model::Spectrum48K),
+            "128" | "128k" => Some(Model::Spectrum128K),
+            "pentagon" | "pentagon128" => Some(Model::Pentagon128),
+            "+2a" | "plus2a" => Some(Model::SpectrumPlus2A),
+            "+3" | "plus3" => Some(Model::SpectrumPlus3),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Spectrum16K => "16K",
+            Model::Spectrum48K => "48K",
+            Model::Spectrum128K => "128K",
+            Model::Pentagon128 => "Pentagon 128",
+            Model::SpectrumPlus2A => "+2A",
+            Model::SpectrumPlus3 => "+3",
+        }
+    }
+
+    pub fn timing(self) -> &'static UlaTiming {
+        match self {
+            Model::Spectrum16K | Model::Spectrum48K => &ula::TIMING_48K,
+            Model::Spectrum128K => &ula::TIMING_128K,
+            Model::Pentagon128 => &ula::TIMING_PENTAGON,
+            Model::SpectrumPlus2A | Model::SpectrumPlus3 => &ula::TIMING_PLUS3,
+        }
+    }
+
+    pub fn rom_count(self) -> usize {
+        match self {
+            Model::Spectrum16K | Model::Spectrum48K => 1,
+            Model::Spectrum128K | Model::Pentagon128 => 2,
+            Model::SpectrumPlus2A | Model::SpectrumPlus3 => 4,
+        }
+    }
+
+    pub fn has_paging(self) -> bool {
+        self != Model::Spectrum16K && self != Model::Spectrum48K
+    }
+
+    // Whether there is RAM from 0x8000 up
+    pub fn has_upper_ram(self) -> bool {
+        self != Model::Spectrum16K
+    }
+
+    // Port 0x1FFD, with the all RAM configurations
+    pub fn has_special_paging(self) -> bool {
+        self == Model::SpectrumPlus2A || self == Model::SpectrumPlus3
+    }
+
+    pub fn has_fdc(self) -> bool {
+        self == Model::SpectrumPlus3
+    }
+
+    // The ULA shares the odd banks, bank 5 being the only one a 48K has,
+    // while the gate array of the +2A/+3 shares banks 4 to 7
+    pub fn is_contended_bank(self, bank: usize) -> bool {
+        if self.has_special_paging() {
+            bank >= 4
+        } else {
+            bank % 2 == 1
+        }
+    }
+
+    // ROM page holding 48 BASIC, the one the tape traps apply to
+    pub fn basic_rom(self) -> usize {
+        self.rom_count() - 1
+    }
+
+    pub fn has_ay(self) -> bool {
+        self.has_paging()
+    }
+
+    // RAM banks that exist on the model
+    pub fn ram_banks(self) -> &'static [usize] {
+        match self {
+            Model::Spectrum16K => &[5],
+            Model::Spectrum48K => &[5, 2, 0],
+            _ => &[0, 1, 2, 3, 4, 5, 6, 7],
+        }
+    }
+}
+
+pub struct Machine {
+    pub model: Model,
+    pub cpu: Z80,
+    pub mem: Memory,
+    pub io: IoBus,
+
+    // T-state at which the current frame started
+    pub frame_start: u64,
+    pub frames: u64,
+    pub palette: Palette,
+
+    sample_rate: u32,
+    // Last input and output of the DC blocker
+    dc_input: f32,
+    dc_output: f32,
+
+    // When set, the blocks saved through the ROM are written straight here
+    pub save_trap: Option<TapWriter>,
+    // And the blocks loaded through the ROM come from here
+    pub load_trap: Option<TapReader>,
+
+    // ROM images given for each model and the page they start at, put
+    // back whenever the machine becomes that model
+    roms: Vec<(Model, usize, Vec<u8>)>,
+}
+
+impl Default for Machine {
+    fn default() -> Machine {
+        Machine::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Machine {
+        Machine::with_model(Model::Spectrum48K)
+    }
+
+    pub fn with_model(model: Model) -> Machine {
+        let mut machine = Machine {
+            model,
+            cpu: Z80::new(),
+            mem: Memory::for_model(model),
+            io: IoBus::new(),
+            frame_start: 0,
+            frames: 0,
+            palette: DEFAULT_PALETTE,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            dc_input: 0.0,
+            dc_output: 0.0,
+            save_trap: None,
+            load_trap: None,
+            roms: Vec::new(),
+        };
+        machine.io.beeper = Beeper::new(model.timing().cpu_clock, DEFAULT_SAMPLE_RATE);
+        if model.has_ay() {
+            machine.attach_ay();
+        }
+        if model.has_fdc() {
+            machine.io.fdc = Some(Upd765::new());
+        }
+        machine
+    }
+
+    // Swaps the hardware keeping the CPU, for snapshots of another model
+    pub fn set_model(&mut self, model: Model) {
+        if model != self.model {
+            self.model = model;
+            self.mem = Memory::for_model(model);
+            for &(rom_model, first, ref rom) in self.roms.iter() {
+                if rom_model == model {
+                    self.mem.load_rom_pages(first, rom);
+                }
+            }
+            self.mem.start_frame(self.frame_start);
+            let cpu_clock = model.timing().cpu_clock;
+            self.io.beeper.set_sample_rate(cpu_clock, self.sample_rate);
+            self.io.ay = None;
+            if model.has_ay() {
+                self.attach_ay();
+            }
+            // Disks stay in the drives while the model has them
+            if !model.has_fdc() {
+                self.io.fdc = None;
+            } else if self.io.fdc.is_none() {
+                self.io.fdc = Some(Upd765::new());
+            }
+        }
+    }
+
+    // Gives `model` a ROM image read with rom::load for the pages from
+    // `first` on. It goes in now if the machine is that model and
+    // otherwise when it becomes it.
+    pub fn load_rom(&mut self, model: Model, first: usize, rom: &[u8]) -> io::Result<()> {
+        let pages = rom.len() / PAGE_SIZE;
+        if first + pages > model.rom_count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "el {} tiene {} páginas de ROM, no caben {} desde la {}",
+                    model.name(),
+                    model.rom_count(),
+                    pages,
+                    first
+                ),
+            ));
+        }
+        if model == self.model {
+            self.mem.load_rom_pages(first, rom);
+        }
+        self.roms.push((model, first, rom.to_vec()));
+        Ok(())
+    }
+
+    // Plugs an AY chip in, as the interfaces for the 48K did
+    pub fn attach_ay(&mut self) {
+        let mut ay = Ay::new(self.model.timing().cpu_clock, self.sample_rate);
+        ay.reset_clock(self.cpu.t_states);
+        self.io.ay = Some(ay);
+    }
+
+    // Brings the sound sources to the CPU clock after it has been set
+    // from outside, as snapshot loaders do
+    pub fn sync_sound(&mut self) {
+        let t_states = self.cpu.t_states;
+        self.io.beeper.reset_clock(t_states, self.io.ear);
+        if let Some(ref mut ay) = self.io.ay {
+            ay.reset_clock(t_states);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let cpu_clock = self.model.timing().cpu_clock;
+        self.sample_rate = sample_rate;
+        self.io.beeper.set_sample_rate(cpu_clock, sample_rate);
+        if let Some(ref mut ay) = self.io.ay {
+            ay.set_sample_rate(cpu_clock, sample_rate);
+        }
+    }
+
+    pub fn step(&mut self) {
+        let in_basic_rom = self.mem.rom_page() == Some(self.model.basic_rom());
+        if self.cpu.pc == tape::SA_BYTES && in_basic_rom {
+            if let Some(ref mut tap) = self.save_trap {
+                match tape::save_trap(&mut self.cpu, &self.mem, &mut self.io.border, tap) {
+                    Ok(()) => return,
+                    Err(e) => eprintln!("No he podido grabar el bloque: {}", e),
+                }
+            }
+        }
+        if self.cpu.pc == tape::LD_BYTES && in_basic_rom {
+            if let Some(ref mut tap) = self.load_trap {
+                if tape::load_trap(&mut self.cpu, &mut self.mem, &mut self.io.border, tap) {
+                    return;
+                }
+            }
+        }
+        self.cpu.exec(&mut self.mem, &mut self.io);
+        while self.cpu.prefix_pending() && !self.cpu.halt {
+            self.cpu.exec(&mut self.mem, &mut self.io);
+        }
+    }
+
+    // Runs until the end of the current frame and raises the interrupt
+    // the ULA generates at the start of the next one
+    pub fn run_frame(&mut self) {
+        self.run_frame_until(None);
+    }
+
+    // Same, but stops before executing the instruction at `stop`. Returns
+    // true if it got there, the rest of the frame being left to run.
+    pub fn run_frame_until(&mut self, stop: Option<u16>) -> bool {
+        if let Some(fetches) = self.io.rzx_player.as_ref().and_then(|p| p.frame_end()) {
+            return self.replay_frame_until(fetches, stop);
+        }
+        let frame_end = self.frame_start + self.model.timing().frame_t_states;
+        while self.cpu.t_states < frame_end && !self.cpu.halt {
+            if Some(self.cpu.pc) == stop {
+                return true;
+            }
+            self.step();
+        }
+        if let Some(ref mut recorder) = self.io.rzx_recorder {
+            recorder.end_frame(self.cpu.fetches);
+        }
+        self.end_frame(frame_end);
+        false
+    }
+
+    // A replayed frame lasts the fetches recorded instead of the T-states,
+    // the next one starting where it ended
+    fn replay_frame_until(&mut self, fetches: u64, stop: Option<u16>) -> bool {
+        while self.cpu.fetches < fetches && !self.cpu.halt {
+            if Some(self.cpu.pc) == stop {
+                return true;
+            }
+            self.step();
+        }
+        let frame_end = self.cpu.t_states;
+        self.end_frame(frame_end);
+        if let Some(ref mut player) = self.io.rzx_player {
+            player.end_frame(self.cpu.fetches);
+        }
+        false
+    }
+
+    fn end_frame(&mut self, frame_end: u64) {
+        self.io.beeper.run_to(self.cpu.t_states);
+        if let Some(ref mut ay) = self.io.ay {
+            ay.run_to(self.cpu.t_states);
+        }
+        self.frame_start = frame_end;
+        self.mem.start_frame(frame_end);
+        self.frames += 1;
+        self.cpu.interrupt(&mut self.mem);
+    }
+
+    // Once the recording is over the machine runs on by itself
+    pub fn replaying(&self) -> bool {
+        self.io.rzx_player.as_ref().is_some_and(|p| !p.finished())
+    }
+
+    // Mixes the sound produced since the last call, between -1 and 1.
+    // Both sources give levels between 0 and 1 and the offset is filtered out.
+    // The beeper sets the pace; AY samples left over wait for the next call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let mut samples = self.io.beeper.take_samples();
+        let ay_samples = match self.io.ay {
+            Some(ref mut ay) => ay.drain_samples(samples.len()),
+            None => Vec::new(),
+        };
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let ay = ay_samples.get(i).cloned().unwrap_or(0.0);
+            let mixed = *sample * BEEPER_VOLUME + ay * AY_VOLUME;
+            self.dc_output = mixed - self.dc_input + DC_BLOCKER_POLE * self.dc_output;
+            self.dc_input = mixed;
+            *sample = self.dc_output;
+        }
+        samples
+    }
+
+    // T-states elapsed since the start of the current frame
+    pub fn frame_t_states(&self) -> u64 {
+        self.cpu.t_states.saturating_sub(self.frame_start)
+    }
+
+    // Draws the display into a FRAME_WIDTH x FRAME_HEIGHT buffer.
+    // Flashing attributes swap colours every 16 frames, unless the ULAplus
+    // palette is on.
+    pub fn render(&self, buffer: &mut [u32]) {
+        let screen = self.mem.screen();
+        match self.io.ulaplus {
+            Some(ref ulaplus) if ulaplus.enabled => {
+                ula::render_ulaplus(screen, self.io.border, &ulaplus.colours(), buffer)
+            }
+            _ => {
+                let flash = (self.frames / 16) % 2 == 1;
+                ula::render(screen, self.io.border, flash, &self.palette, buffer);
+            }
+        }
+    }
+}
+
+// The tape being saved is left out and only the position of the one
+// being loaded goes in, the blocks come from its file
+impl SaveState for Machine {
+    fn write_state(&self, state: &mut StateWriter) {
+        self.cpu.write_state(state);
+        self.mem.write_state(state);
+        state.bool(self.io.ay.is_some());
+        self.io.write_state(state);
+        state.u64(self.frame_start);
+        state.u64(self.frames);
+        state.f32(self.dc_input);
+        state.f32(self.dc_output);
+        state.u32(self.load_trap.as_ref().map_or(0, |tap| tap.position()) as u32);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.cpu.read_state(state)?;
+        self.mem.read_state(state)?;
+        // A 48K may have had an AY plugged in
+        if state.bool()? {
+            if self.io.ay.is_none() {
+                self.attach_ay();
+            }
+        } else {
+            self.io.ay = None;
+        }
+        self.io.read_state(state)?;
+        self.frame_start = state.u64()?;
+        self.frames = state.u64()?;
+        self.dc_input = state.f32()?;
+        self.dc_output = state.f32()?;
+        let tape_position = state.u32()? as usize;
+        if let Some(ref mut tap) = self.load_trap {
+            tap.set_position(tape_position);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(pages: usize, fill: u8) -> Vec<u8> {
+        vec![fill; pages * PAGE_SIZE]
+    }
+
+    #[test]
+    fn roms_outlive_model_changes() {
+        let mut machine = Machine::with_model(Model::Spectrum128K);
+        machine
+            .load_rom(Model::Spectrum128K, 0, &rom(2, 0x12))
+            .unwrap();
+        machine
+            .load_rom(Model::Spectrum48K, 0, &rom(1, 0x48))
+            .unwrap();
+        assert!(machine.mem.missing_roms().is_empty());
+
+        machine.set_model(Model::Spectrum48K);
+        assert_eq!(machine.mem.peek(0x0000), 0x48);
+        machine.set_model(Model::Spectrum128K);
+        assert!(machine.mem.missing_roms().is_empty());
+        assert_eq!(machine.mem.peek(0x0000), 0x12);
+
+        // Nothing was given for the +3
+        machine.set_model(Model::SpectrumPlus3);
+        assert_eq!(machine.mem.missing_roms(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn rom_must_fit_its_model() {
+        let mut machine = Machine::new();
+        assert!(machine.load_rom(Model::Spectrum48K, 0, &rom(2, 0)).is_err());
+        assert!(machine
+            .load_rom(Model::SpectrumPlus3, 2, &rom(2, 0))
+            .is_ok());
+        assert!(machine
+            .load_rom(Model::SpectrumPlus3, 3, &rom(2, 0))
+            .is_err());
+    }
+
+    // The text at a screen position, read back with the ROM font
+    fn text_at(machine: &Machine, row: u16, column: u16, len: u16) -> String {
+        let font = 0x3D00;
+        (column..column + len)
+            .map(|col| {
+                let cell = 0x4000 + ((row & 0x18) << 8) + ((row & 0x07) << 5) + col;
+                let glyph: Vec<u8> = (0..8)
+                    .map(|line| machine.mem.peek(cell + (line << 8)))
+                    .collect();
+                (0x20u8..0x80)
+                    .find(|&ch| {
+                        (0..8).all(|line| {
+                            machine.mem.peek(font + (ch as u16 - 0x20) * 8 + line)
+                                == glyph[line as usize]
+                        })
+                    })
+                    .map_or('?', |ch| ch as char)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn boots_to_basic() {
+        for &model in &[Model::Spectrum48K, Model::Spectrum16K] {
+            let mut machine = Machine::with_model(model);
+            // The ROM clears and tests the memory for about a second and a half
+            for _ in 0..150 {
+                machine.run_frame();
+            }
+            assert!(!machine.cpu.halt, "{}", model.name());
+            assert_eq!(
+                text_at(&machine, 23, 2, 26),
+                "1982 Sinclair Research Ltd",
+                "{}",
+                model.name()
+            );
+        }
+    }
+
+    #[test]
+    fn mixer_takes_the_offset_off() {
+        let mut machine = Machine::new();
+        // Half a second of a 1 kHz square wave on the speaker
+        let end = machine.model.timing().cpu_clock / 2;
+        for (i, t_states) in (0..end).step_by(1750).enumerate() {
+            machine.io.beeper.edge(t_states, i % 2 == 0);
+        }
+        machine.io.beeper.run_to(end);
+        let samples = machine.take_samples();
+        let settled = &samples[2000..];
+        let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+        let min = settled.iter().cloned().fold(0.0, f32::min);
+        let max = settled.iter().cloned().fold(0.0, f32::max);
+        assert!(mean.abs() < 0.01, "{}", mean);
+        // Swinging around 0 by half the beeper volume
+        assert!(max > 0.2 && max < 0.3, "{}", max);
+        assert!(min < -0.2 && min > -0.3, "{}", min);
+    }
+}
@@ -1,96 +1,614 @@
-mod memory;
-mod z80;
 extern crate minifb;
+extern crate z80;
 
-use minifb::{Key, WindowOptions, Window};
-
-const WIDTH: usize = 256;
-const HEIGHT: usize = 192;
-
-// const C : u8 = 1;
-// const N : u8 = 2;
-// const P_V: u8 = 4;
-// const BIT_3: u8 = 8;
-// const H: u8 = 16;
-// const BIT_5: u8 = 32;
-// const Z: u8 = 64;
-// const S: u8  = 128;
-fn get_color(color: u8) -> u32 {
-    let x = match color {
-        0 => 0x000000,
-        1 => 0x0000D7,
-        2 => 0xD70000,
-        3 => 0xD700D7,
-        4 => 0x00D700,
-        5 => 0x00D7D7,
-        6 => 0xD7D700,
-        7 => 0xD7D7D7,
-        _ => panic!("Color no definido"),
+mod config;
+mod display;
+mod keymap;
+mod options;
+mod toml;
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use display::Display;
+use minifb::{Key, KeyRepeat, Scale, WindowOptions, Window};
+use options::Options;
+use z80::audio::{AudioSink, PlayerSink, WavWriter};
+use z80::avi::AviWriter;
+use z80::checksum;
+use z80::dsk::Disk;
+use z80::filter;
+use z80::filter::{Filter, Phosphor};
+use z80::gif::GifWriter;
+use z80::joystick::Joystick;
+use z80::keyboard::SpectrumKey;
+use z80::machine::{Machine, Model};
+use z80::memory::PAGE_SIZE;
+use z80::mouse::KempstonMouse;
+use z80::png;
+use z80::rom;
+use z80::rzx;
+use z80::rzx::{Rzx, RzxRecorder};
+use z80::script::InputScript;
+use z80::scr;
+use z80::sna;
+use z80::state;
+use z80::state::RewindBuffer;
+use z80::szx;
+use z80::tape::{MicRecorder, TapReader, TapWriter};
+use z80::ulaplus::UlaPlus;
+use z80::ula::{FRAME_WIDTH, FRAME_HEIGHT};
+use z80::z80_snapshot;
+
+// Exit status, for the scripts that drive the emulator
+const EXIT_OK: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_PC_NOT_REACHED: i32 = 2;
+const EXIT_CPU_STOPPED: i32 = 3;
+const EXIT_REPLAY_DIVERGED: i32 = 4;
+
+// The autoload keys are each held for a few frames
+const AUTOLOAD_HOLD: u64 = 4;
+const AUTOLOAD_GAP: u64 = 10;
+
+// A state every half second for the last minute, F8 going back 5 seconds
+const REWIND_INTERVAL: u64 = 25;
+const REWIND_CAPACITY: usize = 120;
+const REWIND_FRAMES: u64 = 250;
+
+// F5 saves to the quick slot, F9 loads it and F7 moves to the next one
+const QUICK_SLOTS: usize = 9;
+
+// F12 saves the display as PNG, without the border if shift is held, and
+// F10 the screen memory as SCR. F6 starts and stops a GIF recording.
+const SCREENSHOT_PREFIX: &str = "z80-";
+
+fn fail(msg: String) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(EXIT_ERROR);
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+// Loads a tape, snapshot, save state, input recording, screen or disk.
+// Returns whether the program still has
+// to be started from the ROM.
+fn load_media(machine: &mut Machine, path: &str) -> io::Result<bool> {
+    match extension(path).as_str() {
+        "tap" => {
+            machine.load_trap = Some(TapReader::open(path)?);
+            Ok(true)
+        }
+        "sna" => sna::load(machine, path).map(|_| false),
+        "z80" => z80_snapshot::load(machine, path).map(|_| false),
+        "szx" => szx::load(machine, path).map(|_| false),
+        "state" => state::load(machine, path).map(|_| false),
+        "rzx" => rzx::play(machine, Rzx::load(path)?).map(|_| false),
+        "scr" => scr::load(machine, path).map(|_| false),
+        "dsk" => {
+            let disk = Disk::load(path)?;
+            match machine.io.fdc {
+                Some(ref mut fdc) => fdc.insert(0, disk),
+                None => return Err(io::Error::other("el modelo elegido no tiene unidad de disco")),
+            }
+            Ok(true)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "tipo de fichero desconocido",
+        )),
+    }
+}
+
+// Types what starts the program from `start` on: LOAD "" on the 16K and
+// 48K, the first option of the menu, the loader, on the others
+fn autoload(model: Model, start: u64, script: &mut InputScript) {
+    let keys: Vec<&[SpectrumKey]> = match model {
+        Model::Spectrum16K | Model::Spectrum48K => vec![
+            &[SpectrumKey::J],
+            &[SpectrumKey::SymbolShift, SpectrumKey::P],
+            &[SpectrumKey::SymbolShift, SpectrumKey::P],
+            &[SpectrumKey::Enter],
+        ],
+        _ => vec![&[SpectrumKey::Enter]],
     };
+    for (index, keys) in keys.iter().enumerate() {
+        let frame = start + index as u64 * AUTOLOAD_GAP;
+        script.add_keys(frame, keys, AUTOLOAD_HOLD);
+    }
+}
 
-    return x;
+// Quick slots go next to the first file loaded, or in the current
+// directory: game.tap has game.1.state to game.9.state
+fn slot_path(media: &[String], slot: usize) -> String {
+    let base = media
+        .first()
+        .map(|path| Path::new(path).with_extension(""))
+        .unwrap_or_else(|| Path::new("z80").to_path_buf());
+    format!("{}.{}.state", base.to_string_lossy(), slot)
 }
-fn get_color_for_byte(mem: &memory::Memory, address: u16) -> (u32, u32) {
-    let relative_address = address - 0x4000;
-    let attribute_relative_address = (relative_address >> 3) & 0x300 | relative_address & 0xFF;
-    let contents = mem.peek(attribute_relative_address + 0x5800);
-    let foreground = get_color(contents & 0x07);
-    let background = get_color((contents >> 3) & 0x0F);
-    (foreground, background)
+
+// Answers the keys for the save states
+// Only the 16K and 48K can run on the ROM built in
+fn missing_roms(machine: &Machine) -> Option<String> {
+    let missing = machine.mem.missing_roms();
+    if missing.is_empty() {
+        return None;
+    }
+    let pages: Vec<String> = missing.iter().map(|page| page.to_string()).collect();
+    Some(format!(
+        "El {} necesita sus propias ROM y faltan las páginas {}, dalas con --rom [MODELO:]FICHERO",
+        machine.model.name(),
+        pages.join(", ")
+    ))
 }
 
-fn print_screen(mem: &memory::Memory) {
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+fn state_keys(
+    window: &Window,
+    machine: &mut Machine,
+    rewind: &mut RewindBuffer,
+    slot: &mut usize,
+    media: &[String],
+) {
+    let model = machine.model;
+    if window.is_key_pressed(Key::F7, KeyRepeat::No) {
+        *slot = *slot % QUICK_SLOTS + 1;
+        println!("Ranura {}", slot);
+    }
+    if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+        let path = slot_path(media, *slot);
+        match state::save(machine, &path) {
+            Ok(()) => println!("Estado guardado en {}", path),
+            Err(e) => eprintln!("No he podido guardar {}: {}", path, e),
+        }
+    }
+    if window.is_key_pressed(Key::F9, KeyRepeat::No) {
+        let path = slot_path(media, *slot);
+        match state::load(machine, &path) {
+            // The states recorded after it belong to another run
+            Ok(()) => rewind.clear(),
+            Err(e) => eprintln!("No he podido cargar {}: {}", path, e),
+        }
+    }
+    if window.is_key_pressed(Key::F8, KeyRepeat::Yes) {
+        if let Err(e) = rewind.rewind(machine, REWIND_FRAMES) {
+            eprintln!("No he podido volver atrás: {}", e);
+        }
+    }
+    // A state of another model runs on whatever ROMs it has
+    if machine.model != model {
+        if let Some(msg) = missing_roms(machine) {
+            eprintln!("AVISO: {}. Lo que hay en su lugar no va a funcionar bien.", msg);
+        }
+    }
+}
+
+// Files the frames go to as they are made
+struct Recording {
+    video: Option<AviWriter>,
+    gif: Option<GifWriter>,
+}
 
+impl Recording {
+    fn active(&self) -> bool {
+        self.video.is_some() || self.gif.is_some()
+    }
+
+    // A writer that fails is dropped
+    fn frame(&mut self, machine: &Machine, samples: &[f32]) {
+        if !self.active() {
+            return;
+        }
+        let mut buffer = vec![0; FRAME_WIDTH * FRAME_HEIGHT];
+        machine.render(&mut buffer);
+        let failed = match self.video {
+            Some(ref mut avi) => avi.frame(&buffer, samples).err(),
+            None => None,
+        };
+        if let Some(e) = failed {
+            eprintln!("Error al grabar el vídeo, se detiene: {}", e);
+            self.video = None;
+        }
+        let failed = match self.gif {
+            Some(ref mut gif) => gif.frame(&buffer).err(),
+            None => None,
+        };
+        if let Some(e) = failed {
+            eprintln!("Error al grabar el GIF, se detiene: {}", e);
+            self.gif = None;
+        }
+    }
+}
+
+// ULAplus colours go to the nearest of the palette
+fn create_gif(machine: &Machine, path: &str) -> io::Result<GifWriter> {
+    GifWriter::create(path, FRAME_WIDTH, FRAME_HEIGHT, &machine.palette)
+}
+
+// First of z80-001.png, z80-002.png... not taken yet
+fn free_path(extension: &str) -> String {
+    (1..)
+        .map(|n| format!("{}{:03}.{}", SCREENSHOT_PREFIX, n, extension))
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
+// PNG of the display or SCR of the screen memory, told by the extension
+fn save_screenshot(machine: &Machine, path: &str, border: bool) {
+    let saved = match extension(path).as_str() {
+        "scr" => scr::save(machine, path),
+        _ => png::save_screen(machine, path, border),
+    };
+    match saved {
+        Ok(()) => println!("Pantalla guardada en {}", path),
+        Err(e) => eprintln!("No he podido guardar {}: {}", path, e),
+    }
+}
+
+fn screenshot_keys(window: &Window, machine: &Machine, recording: &mut Recording) {
+    if window.is_key_pressed(Key::F12, KeyRepeat::No) {
+        let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+        save_screenshot(machine, &free_path("png"), !shift);
+    }
+    if window.is_key_pressed(Key::F10, KeyRepeat::No) {
+        save_screenshot(machine, &free_path("scr"), true);
+    }
+    if window.is_key_pressed(Key::F6, KeyRepeat::No) {
+        if recording.gif.take().is_some() {
+            println!("GIF terminado");
+        } else {
+            let path = free_path("gif");
+            match create_gif(machine, &path) {
+                Ok(gif) => {
+                    println!("Grabando {}", path);
+                    recording.gif = Some(gif);
+                }
+                Err(e) => eprintln!("No he podido crear {}: {}", path, e),
+            }
+        }
+    }
+}
+
+// Writes back the disk in drive A if the program changed it
+fn save_disk(machine: &Machine, path: &Option<String>) {
+    let path = match *path {
+        Some(ref path) => path,
+        None => return,
+    };
+    if let Some(ref fdc) = machine.io.fdc {
+        if let Some(ref disk) = fdc.drives[0].disk {
+            if disk.modified {
+                if let Err(e) = disk.save(path) {
+                    eprintln!("No he podido guardar {}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+// Sends the sound of the last frame, dropping the sink if it fails, and
+// adds the frame to the recordings
+fn play_frame(
+    machine: &mut Machine,
+    audio: &mut Option<Box<dyn AudioSink>>,
+    recording: &mut Recording,
+) {
+    let samples = machine.take_samples();
+    let failed = match *audio {
+        Some(ref mut sink) => sink.write(&samples).is_err(),
+        None => false,
+    };
+    if failed {
+        eprintln!("Error en la salida de audio, se desactiva");
+        *audio = None;
+    }
+    recording.frame(machine, &samples);
+}
+
+fn run_headless(
+    machine: &mut Machine,
+    audio: &mut Option<Box<dyn AudioSink>>,
+    recording: &mut Recording,
+    script: &mut Option<InputScript>,
+    frames: u64,
+    until_pc: Option<u16>,
+) -> i32 {
+    // A replay ends the run when it is over
+    let replay = machine.replaying();
+    for _ in 0..frames {
+        if machine.cpu.halt || (replay && !machine.replaying()) {
+            break;
+        }
+        if let Some(ref mut script) = *script {
+            script.apply(machine.frames, &mut machine.io);
+        }
+        if machine.run_frame_until(until_pc) {
+            return EXIT_OK;
+        }
+        play_frame(machine, audio, recording);
+    }
+    if machine.cpu.halt {
+        EXIT_CPU_STOPPED
+    } else if until_pc.is_some() {
+        EXIT_PC_NOT_REACHED
+    } else {
+        EXIT_OK
+    }
+}
+
+fn run_window(
+    machine: &mut Machine,
+    audio: &mut Option<Box<dyn AudioSink>>,
+    recording: &mut Recording,
+    script: &mut Option<InputScript>,
+    options: &Options,
+) -> i32 {
+    let mut buffer: Vec<u32> = vec![0; FRAME_WIDTH * FRAME_HEIGHT];
+
+    // Full screen is a window without decorations as large as the screen
+    // takes, which the window system scales to by itself
+    let (scale, window_options) = if options.fullscreen {
+        let window_options = WindowOptions {
+            borderless: true,
+            title: false,
+            scale: Scale::FitScreen,
+            ..WindowOptions::default()
+        };
+        (1, window_options)
+    } else {
+        (options.scale, WindowOptions::default())
+    };
+    let filters = &options.filters;
+    let scanlines = filters.contains(&Filter::Scanlines);
+    let mut display = Display::new(scale, options.border, options.aspect, scanlines);
+    let mut phosphor = if filters.contains(&Filter::Phosphor) {
+        Some(Phosphor::new())
+    } else {
+        None
+    };
+    let (width, height) = display.size();
     let mut window = Window::new("Test - ESC to exit",
-                                 WIDTH,
-                                 HEIGHT,
-                                 WindowOptions::default()).unwrap_or_else(|e| {
+                                 width,
+                                 height,
+                                 window_options).unwrap_or_else(|e| {
         panic!("{}", e);
     });
 
-    // Base bitmat video memory
-    let init_video_mem = 0x4000;
-    let end_video_mem = 0x57FF;
-    for addr in init_video_mem..end_video_mem {
-        let pixel = mem.peek(addr);
-        let colors = get_color_for_byte(mem, addr);
-        let foreground = colors.0;
-        let background = colors.1;
-        // The memory address to coordinates
-        // has the format
-        //       H
-        // 0 1 0 Y7 Y6 Y2 Y1 Y0 Y5
-        //       L
-        // Y4 Y3 X4 X3 X2 X1 X0
-        let x_in_byte = addr & 0x1F;
-        let y = (addr & 0x1800 | addr & 0x700 >> 3 | addr & 0x14 << 3) >> 5;
-
-        for i in 0..7 {
-            let x_in_bit = x_in_byte << 3 | i;
-            let mask = 0x80 >> i;
-            let index: usize = (y as usize * WIDTH) + x_in_bit as usize;
-            if pixel & mask != 0 {
-                //set_pixel(x_in_bit, y, foreground);
-                buffer[index] = foreground as u32;
-            } else {
-                //set_pixel(x_in_bit, y, background);
-                buffer[index] = background as u32;
+    let mut last_mouse = None;
+    let mut rewind = RewindBuffer::new(REWIND_INTERVAL, REWIND_CAPACITY);
+    let mut slot = 1;
+    // 20 ms on the Sinclair models, a bit longer on the Pentagon
+    let timing = machine.model.timing();
+    let frame_duration =
+        Duration::from_nanos(timing.frame_t_states * 1_000_000_000 / timing.cpu_clock);
+    while window.is_open() && !window.is_key_down(Key::Escape) && !machine.cpu.halt {
+        let frame_start = Instant::now();
+        state_keys(&window, machine, &mut rewind, &mut slot, &options.media);
+        screenshot_keys(&window, machine, recording);
+
+        // The host keyboard waits until the scripted keys are done
+        match *script {
+            Some(ref mut script) if !script.finished() => {
+                script.apply(machine.frames, &mut machine.io)
             }
+            _ => keymap::update(
+                &window,
+                &options.key_bindings,
+                &options.joystick_keys,
+                &mut machine.io,
+            ),
+        }
+        if let Some(ref mut mouse) = machine.io.mouse {
+            keymap::update_mouse(&window, &display, &mut last_mouse, mouse);
+        }
+        if machine.run_frame_until(options.until_pc) {
+            return EXIT_OK;
+        }
+        play_frame(machine, audio, recording);
+        rewind.record(machine);
+        machine.render(&mut buffer);
+        if filters.contains(&Filter::PalBlend) {
+            filter::pal_blend(&mut buffer, FRAME_WIDTH);
         }
-        while window.is_open() && !window.is_key_down(Key::Escape) {
-          window.update_with_buffer(&buffer).unwrap();
+        if let Some(ref mut phosphor) = phosphor {
+            phosphor.apply(&mut buffer);
+        }
+        window.update_with_buffer(display.show(&buffer)).unwrap();
+
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_duration {
+            thread::sleep(frame_duration - elapsed);
         }
     }
+    if machine.cpu.halt {
+        EXIT_CPU_STOPPED
+    } else {
+        EXIT_OK
+    }
 }
 
 fn main() {
-    let mut micro = z80::Z80::new();
-    let mut mem = memory::Memory::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut options = Options::default();
+    if let Some(path) = options::config_arg(&args).or_else(config::default_path) {
+        config::load(&path, &mut options)
+            .unwrap_or_else(|e| fail(format!("Error en los ajustes de {}: {}", path, e)));
+    }
+    options.parse_args(args.into_iter());
+
+    let mut machine = Machine::with_model(options.model);
+    machine.palette = options.palette;
+    if let Some(rate) = options.sample_rate {
+        machine.set_sample_rate(rate);
+    }
+    // Each image follows the pages of the previous one for the same model
+    let mut next_pages: Vec<(Model, usize)> = Vec::new();
+    for rom in options.roms.iter() {
+        let (model, path) = options::rom_model(rom, options.model);
+        let index = match next_pages.iter().position(|&(m, _)| m == model) {
+            Some(index) => index,
+            None => {
+                next_pages.push((model, 0));
+                next_pages.len() - 1
+            }
+        };
+        let next_page = &mut next_pages[index].1;
+        let loaded = rom::load(path).and_then(|image| {
+            machine.load_rom(model, *next_page, &image)?;
+            Ok(image)
+        });
+        let image = loaded
+            .unwrap_or_else(|e| fail(format!("No he podido cargar la ROM {}: {}", path, e)));
+        for page in image.chunks(PAGE_SIZE) {
+            match rom::identify(page) {
+                Some(name) => println!("ROM {} del {}: {}", next_page, model.name(), name),
+                None => println!(
+                    "ROM {} del {}: desconocida, CRC-32 {:08X}",
+                    next_page,
+                    model.name(),
+                    checksum::crc32(page)
+                ),
+            }
+            *next_page += 1;
+        }
+    }
+    for &kind in options.joysticks.iter() {
+        machine.io.joysticks.push(Joystick::new(kind));
+    }
+    if options.mouse {
+        machine.io.mouse = Some(KempstonMouse::new());
+    }
+    if options.ulaplus {
+        machine.io.ulaplus = Some(UlaPlus::new());
+    }
+    if let Some(ref path) = options.trace {
+        let file = File::create(path)
+            .unwrap_or_else(|e| fail(format!("No he podido crear {}: {}", path, e)));
+        machine.cpu.set_trace(file);
+    }
 
-    while !micro.halt {
-        micro.exec(&mut mem);
+    if options.save_tap.is_some() && options.save_tap == options.mic_tap {
+        fail("--save-tap y --mic-tap no pueden ser el mismo fichero".to_string());
+    }
+    if let Some(ref path) = options.save_tap {
+        let tap = TapWriter::create(path)
+            .unwrap_or_else(|e| fail(format!("No he podido crear {}: {}", path, e)));
+        machine.save_trap = Some(tap);
+    }
+    if let Some(ref path) = options.mic_tap {
+        let tap = TapWriter::create(path)
+            .unwrap_or_else(|e| fail(format!("No he podido crear {}: {}", path, e)));
+        machine.io.mic_recorder = Some(MicRecorder::new(tap));
     }
 
-    print_screen(&mem);
+    let mut script = options.script.as_ref().map(|path| {
+        InputScript::load(path)
+            .unwrap_or_else(|e| fail(format!("No he podido leer {}: {}", path, e)))
+    });
+    let mut start_program = false;
+    let mut restored = false;
+    let mut disk_path = None;
+    for path in options.media.iter() {
+        match load_media(&mut machine, path) {
+            Ok(start) => {
+                start_program |= start;
+                restored |= !start;
+            }
+            Err(e) => fail(format!("No he podido cargar {}: {}", path, e)),
+        }
+        if extension(path) == "dsk" {
+            disk_path = Some(path.clone());
+        }
+    }
+    if let Some(msg) = missing_roms(&machine) {
+        fail(msg);
+    }
+    // A snapshot already has the program running
+    if options.autoload && start_program && !restored {
+        let script = script.get_or_insert_with(InputScript::new);
+        autoload(machine.model, options.autoload_frame, script);
+    }
+    // The recording starts from a snapshot of the machine with everything
+    // loaded
+    if options.record.is_some() {
+        machine.io.rzx_recorder = Some(RzxRecorder::new(&machine));
+    }
+
+    let mut audio: Option<Box<dyn AudioSink>> = match options.wav {
+        Some(ref path) => match WavWriter::create(path, machine.sample_rate()) {
+            Ok(writer) => Some(Box::new(writer)),
+            Err(e) => fail(format!("No he podido crear {}: {}", path, e)),
+        },
+        None if options.headless.is_none() => match PlayerSink::open(machine.sample_rate()) {
+            Ok(player) => Some(Box::new(player)),
+            Err(e) => {
+                eprintln!("Sin sonido: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    // Frames last as long as the model makes them
+    let video = options.video.as_ref().map(|path| {
+        let timing = machine.model.timing();
+        AviWriter::create(
+            path,
+            FRAME_WIDTH,
+            FRAME_HEIGHT,
+            timing.cpu_clock as u32,
+            timing.frame_t_states as u32,
+            machine.sample_rate(),
+        )
+        .unwrap_or_else(|e| fail(format!("No he podido crear {}: {}", path, e)))
+    });
+    let gif = options.gif.as_ref().map(|path| {
+        create_gif(&machine, path)
+            .unwrap_or_else(|e| fail(format!("No he podido crear {}: {}", path, e)))
+    });
+    let mut recording = Recording { video, gif };
+
+    let status = match options.headless {
+        Some(frames) => run_headless(
+            &mut machine,
+            &mut audio,
+            &mut recording,
+            &mut script,
+            frames,
+            options.until_pc,
+        ),
+        None => run_window(&mut machine, &mut audio, &mut recording, &mut script, &options),
+    };
+    if let Some(ref path) = options.screenshot {
+        save_screenshot(&machine, path, true);
+    }
+    save_disk(&machine, &disk_path);
+    let recorder = machine.io.rzx_recorder.take();
+    if let (Some(recorder), Some(path)) = (recorder, options.record.as_ref()) {
+        if let Err(e) = recorder.finish().save(path) {
+            fail(format!("No he podido guardar {}: {}", path, e));
+        }
+    }
+    let overruns = machine.io.rzx_player.as_ref().map_or(0, |player| player.overruns);
+    if overruns > 0 {
+        eprintln!("La reproducción se ha desviado: {} lecturas sin valor grabado", overruns);
+    }
+    let status = if status == EXIT_OK && overruns > 0 {
+        EXIT_REPLAY_DIVERGED
+    } else {
+        status
+    };
+    // The WAV and AVI headers are completed, the GIF closed and a block
+    // still on the MIC written, when the writers go
+    drop(audio);
+    drop(recording);
+    machine.io.mic_recorder = None;
+    std::process::exit(status);
 }
@@ -1,29 +1,341 @@
+use std::io;
+
+use machine::Model;
+use state::{SaveState, StateReader, StateWriter};
+use ula;
+use ula::UlaTiming;
+
 pub const ROM_48K: &'static [u8; 16 * 1024] = include_bytes!("48.rom");
+pub const PAGE_SIZE: usize = 16 * 1024;
+const RAM_BANKS: usize = 8;
+
+// Bits of the 128K paging port 0x7FFD
+const RAM_BANK_MASK: u8 = 0x07;
+const SHADOW_SCREEN: u8 = 0x08;
+const ROM_SELECT: u8 = 0x10;
+const PAGING_LOCK: u8 = 0x20;
+
+// Bits of the +2A/+3 port 0x1FFD
+const SPECIAL_PAGING: u8 = 0x01;
+const SPECIAL_CONFIG_MASK: u8 = 0x06;
+const ROM_SELECT_HIGH: u8 = 0x04;
+
+// Banks seen at 0x0000, 0x4000, 0x8000 and 0xC000 on each all RAM
+// configuration
+const SPECIAL_CONFIGS: [[usize; 4]; 4] = [
+    [0, 1, 2, 3],
+    [4, 5, 6, 7],
+    [4, 5, 6, 3],
+    [4, 7, 6, 3],
+];
+
+#[derive(Clone, Copy)]
+enum Page {
+    Rom(usize),
+    Ram(usize),
+    // Nothing fitted, as the upper 32K of a 16K
+    Unpopulated,
+}
+
 pub struct Memory {
-    // Simular una memoria de 64 K
-    contents: [u8; 64 * 1024]
+    // Simular una memoria de 64 K repartida en páginas de 16 K
+    roms: Vec<[u8; PAGE_SIZE]>,
+    // Pages holding the ROM the model needs rather than a stand-in
+    rom_loaded: Vec<bool>,
+    ram: Vec<[u8; PAGE_SIZE]>,
+    slots: [Page; 4],
+
+    // Only the 128K models answer to 0x7FFD, and the +2A/+3 to 0x1FFD
+    paging: bool,
+    special_paging: bool,
+    last_7ffd: u8,
+    last_1ffd: u8,
+
+    // ULA contention for each T-state of the frame that starts at frame_start
+    timing: &'static UlaTiming,
+    contended_banks: [bool; RAM_BANKS],
+    contention: Vec<u8>,
+    frame_start: u64,
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
 }
 
 impl Memory {
     pub fn new () -> Memory {
+        Memory::for_model(Model::Spectrum48K)
+    }
+
+    // RAM is always laid out in 128K banks: the 48K sees banks 5, 2 and 0
+    // and the 16K only bank 5
+    pub fn for_model(model: Model) -> Memory {
         let mut out = Memory {
-         contents: [0; 64 * 1024]
+            roms: vec![[0xFF; PAGE_SIZE]; model.rom_count()],
+            rom_loaded: vec![false; model.rom_count()],
+            ram: vec![[0; PAGE_SIZE]; RAM_BANKS],
+            slots: [Page::Rom(0), Page::Ram(5), Page::Ram(2), Page::Ram(0)],
+            paging: model.has_paging(),
+            special_paging: model.has_special_paging(),
+            last_7ffd: 0,
+            last_1ffd: 0,
+            timing: model.timing(),
+            contended_banks: [false; RAM_BANKS],
+            contention: ula::contention_table(model.timing()),
+            frame_start: 0,
         };
 
-        out.load_rom(ROM_48K);
+        if !model.has_upper_ram() {
+            out.slots[2] = Page::Unpopulated;
+            out.slots[3] = Page::Unpopulated;
+        }
+        for (bank, contended) in out.contended_banks.iter_mut().enumerate() {
+            *contended = model.is_contended_bank(bank);
+        }
+
+        // The built in 48 BASIC is the ROM of the 16K and 48K. On the
+        // other models it only stands in for their own BASIC page until
+        // their ROMs are loaded, and the rest read as an empty bus.
+        let basic = model.basic_rom();
+        out.roms[basic].copy_from_slice(ROM_48K);
+        out.rom_loaded[basic] = !model.has_paging();
 
         out
     }
+
     pub fn peek (&self, addr: u16) -> u8 {
-        self.contents[addr as usize]
+        let offset = addr as usize & (PAGE_SIZE - 1);
+        match self.slots[addr as usize / PAGE_SIZE] {
+            Page::Rom(n) => self.roms[n][offset],
+            Page::Ram(n) => self.ram[n][offset],
+            // Nothing drives the data bus
+            Page::Unpopulated => 0xFF,
+        }
     }
 
     pub fn poke (&mut self, addr: u16, value: u8) {
-        self.contents[addr as usize] = value;
+        let offset = addr as usize & (PAGE_SIZE - 1);
+        // Writes to ROM are lost
+        if let Page::Ram(n) = self.slots[addr as usize / PAGE_SIZE] {
+            self.ram[n][offset] = value;
+        }
     }
 
+    // Loads an image of one or more consecutive 16K ROM pages
     pub fn load_rom(&mut self, rom: &[u8])
     {
-        self.contents[..16 * 1024].copy_from_slice(&rom);
+        self.load_rom_pages(0, rom);
+    }
+
+    // Same, from ROM page `first` on
+    pub fn load_rom_pages(&mut self, first: usize, rom: &[u8]) {
+        let pages = self
+            .roms
+            .iter_mut()
+            .zip(self.rom_loaded.iter_mut())
+            .skip(first);
+        for ((page, loaded), data) in pages.zip(rom.chunks(PAGE_SIZE)) {
+            page[..data.len()].copy_from_slice(data);
+            *loaded = true;
+        }
+    }
+
+    // ROM pages of the model that have not been loaded
+    pub fn missing_roms(&self) -> Vec<usize> {
+        (0..self.roms.len())
+            .filter(|&page| !self.rom_loaded[page])
+            .collect()
+    }
+
+    pub fn rom_count(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn rom(&self, page: usize) -> &[u8] {
+        &self.roms[page]
+    }
+
+    // Maps the slots from the last values of both paging ports
+    fn update_slots(&mut self) {
+        if self.last_1ffd & SPECIAL_PAGING != 0 {
+            let config = ((self.last_1ffd & SPECIAL_CONFIG_MASK) >> 1) as usize;
+            for (slot, &bank) in self.slots.iter_mut().zip(SPECIAL_CONFIGS[config].iter()) {
+                *slot = Page::Ram(bank);
+            }
+        } else {
+            // The high bit of the ROM comes from 0x1FFD on the +2A/+3
+            let mut rom = if self.last_7ffd & ROM_SELECT != 0 { 1 } else { 0 };
+            if self.last_1ffd & ROM_SELECT_HIGH != 0 {
+                rom += 2;
+            }
+            self.slots = [
+                Page::Rom(rom),
+                Page::Ram(5),
+                Page::Ram(2),
+                Page::Ram((self.last_7ffd & RAM_BANK_MASK) as usize),
+            ];
+        }
+    }
+
+    pub fn write_7ffd(&mut self, value: u8) {
+        if !self.paging || self.last_7ffd & PAGING_LOCK != 0 {
+            return;
+        }
+        self.last_7ffd = value;
+        self.update_slots();
+    }
+
+    // The lock bit of 0x7FFD locks this port too
+    pub fn write_1ffd(&mut self, value: u8) {
+        if !self.special_paging || self.last_7ffd & PAGING_LOCK != 0 {
+            return;
+        }
+        self.last_1ffd = value;
+        self.update_slots();
+    }
+
+    // Set the paging state even if it was locked, for snapshots
+    pub fn restore_7ffd(&mut self, value: u8) {
+        if self.paging {
+            self.last_7ffd = value;
+            self.update_slots();
+        }
+    }
+
+    pub fn restore_1ffd(&mut self, value: u8) {
+        if self.special_paging {
+            self.last_1ffd = value;
+            self.update_slots();
+        }
+    }
+
+    pub fn last_7ffd(&self) -> u8 {
+        self.last_7ffd
+    }
+
+    pub fn last_1ffd(&self) -> u8 {
+        self.last_1ffd
+    }
+
+    pub fn has_special_paging(&self) -> bool {
+        self.special_paging
+    }
+
+    // ROM page currently mapped at 0x0000, if any
+    pub fn rom_page(&self) -> Option<usize> {
+        match self.slots[0] {
+            Page::Rom(n) => Some(n),
+            Page::Ram(_) | Page::Unpopulated => None,
+        }
+    }
+
+    pub fn ram_bank(&self, bank: usize) -> &[u8] {
+        &self.ram[bank]
+    }
+
+    pub fn ram_bank_mut(&mut self, bank: usize) -> &mut [u8] {
+        &mut self.ram[bank]
+    }
+
+    pub fn start_frame(&mut self, t_states: u64) {
+        self.frame_start = t_states;
+    }
+
+    pub fn is_contended(&self, addr: u16) -> bool {
+        match self.slots[addr as usize / PAGE_SIZE] {
+            Page::Ram(n) => self.contended_banks[n],
+            Page::Rom(_) | Page::Unpopulated => false,
+        }
+    }
+
+    pub fn io_contended(&self) -> bool {
+        self.timing.io_contended
+    }
+
+    // T-states a contended access starting at `t_states` is delayed
+    pub fn contention(&self, t_states: u64) -> u64 {
+        match t_states.checked_sub(self.frame_start) {
+            Some(offset) if (offset as usize) < self.contention.len() => {
+                self.contention[offset as usize] as u64
+            }
+            _ => 0,
+        }
+    }
+
+    // Value read from a port nothing answers to
+    pub fn floating_bus(&self, t_states: u64) -> u8 {
+        let frame_t_states = t_states.saturating_sub(self.frame_start);
+        ula::floating_bus(self.timing, self.screen(), frame_t_states).unwrap_or(0xFF)
+    }
+
+    // Bank the ULA fetches the display from
+    pub fn screen(&self) -> &[u8] {
+        if self.last_7ffd & SHADOW_SCREEN != 0 {
+            &self.ram[7]
+        } else {
+            &self.ram[5]
+        }
+    }
+}
+
+// RAM and paging, the ROMs stay as loaded
+impl SaveState for Memory {
+    fn write_state(&self, state: &mut StateWriter) {
+        for bank in self.ram.iter() {
+            state.bytes(bank);
+        }
+        state.u8(self.last_7ffd);
+        state.u8(self.last_1ffd);
+        state.u64(self.frame_start);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for bank in self.ram.iter_mut() {
+            bank.copy_from_slice(state.bytes(PAGE_SIZE)?);
+        }
+        self.last_7ffd = state.u8()?;
+        self.last_1ffd = state.u8()?;
+        self.frame_start = state.u64()?;
+        if self.paging {
+            self.update_slots();
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_rom_only_serves_the_48k() {
+        let mem = Memory::for_model(Model::Spectrum48K);
+        assert!(mem.missing_roms().is_empty());
+        assert_eq!(mem.rom(0), &ROM_48K[..]);
+        assert!(Memory::for_model(Model::Spectrum16K)
+            .missing_roms()
+            .is_empty());
+
+        let mem = Memory::for_model(Model::Spectrum128K);
+        assert_eq!(mem.missing_roms(), [0, 1]);
+        // 48 BASIC stands in for ROM 1 and ROM 0 reads as an empty bus
+        assert_eq!(mem.rom(1), &ROM_48K[..]);
+        assert!(mem.rom(0).iter().all(|&byte| byte == 0xFF));
+        assert_eq!(
+            Memory::for_model(Model::SpectrumPlus3).missing_roms(),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn loaded_roms_are_no_longer_missing() {
+        let mut mem = Memory::for_model(Model::SpectrumPlus2A);
+        mem.load_rom_pages(1, &[0x11; 2 * PAGE_SIZE]);
+        assert_eq!(mem.missing_roms(), [0, 3]);
+        assert_eq!(mem.rom(2)[0], 0x11);
+        mem.load_rom(&[0x22; 4 * PAGE_SIZE]);
+        assert!(mem.missing_roms().is_empty());
+    }
+}
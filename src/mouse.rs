@@ -0,0 +1,79 @@
+// Kempston mouse interface. Each axis is an 8 bit counter that wraps
+// around, the program works out the movement from the difference between
+// two reads.
+
+use std::io;
+
+use state::{SaveState, StateReader, StateWriter};
+
+// Buttons, a set bit meaning pressed
+pub const LEFT_BUTTON: u8 = 0x01;
+pub const RIGHT_BUTTON: u8 = 0x02;
+pub const MIDDLE_BUTTON: u8 = 0x04;
+
+pub struct KempstonMouse {
+    pub x: u8,
+    // Grows upwards
+    pub y: u8,
+    pub buttons: u8,
+}
+
+impl Default for KempstonMouse {
+    fn default() -> KempstonMouse {
+        KempstonMouse::new()
+    }
+}
+
+impl KempstonMouse {
+    pub fn new() -> KempstonMouse {
+        KempstonMouse {
+            x: 0,
+            y: 0,
+            buttons: 0,
+        }
+    }
+
+    // Moves the pointer, `dy` positive meaning up
+    pub fn move_by(&mut self, dx: i32, dy: i32) {
+        self.x = self.x.wrapping_add(dx as u8);
+        self.y = self.y.wrapping_add(dy as u8);
+    }
+
+    // 0xFADF buttons, 0xFBDF X and 0xFFDF Y, partially decoded
+    pub fn read_port(&self, port: u16) -> Option<u8> {
+        if port & 0x0121 == 0x0001 {
+            // Active low: bit 0 right, bit 1 left, bit 2 middle
+            let mut value = 0xFF;
+            if self.buttons & RIGHT_BUTTON != 0 {
+                value &= !0x01;
+            }
+            if self.buttons & LEFT_BUTTON != 0 {
+                value &= !0x02;
+            }
+            if self.buttons & MIDDLE_BUTTON != 0 {
+                value &= !0x04;
+            }
+            Some(value)
+        } else if port & 0x0521 == 0x0101 {
+            Some(self.x)
+        } else if port & 0x0521 == 0x0501 {
+            Some(self.y)
+        } else {
+            None
+        }
+    }
+}
+
+impl SaveState for KempstonMouse {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bytes(&[self.x, self.y, self.buttons]);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        let bytes = state.bytes(3)?;
+        self.x = bytes[0];
+        self.y = bytes[1];
+        self.buttons = bytes[2];
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+// Command line of the emulator
+
+use display::{Aspect, BorderSize, MAX_SCALE};
+use keymap::{JoystickKeys, KeyBindings};
+use z80::filter::Filter;
+use z80::joystick::JoystickKind;
+use z80::machine::Model;
+use z80::ula::{Palette, DEFAULT_PALETTE};
+
+// Frame at which the keys that start a tape or disk are typed, once the
+// ROM is waiting for them
+pub const AUTOLOAD_FRAME: u64 = 150;
+
+pub struct Options {
+    pub model: Model,
+    // ROM images, loaded one after another from page 0 of the model named
+    // before a colon, or of the one chosen
+    pub roms: Vec<String>,
+    // Tapes, snapshots and disks to load on start, told apart by extension
+    pub media: Vec<String>,
+    // Times each pixel is repeated across and down
+    pub scale: usize,
+    pub fullscreen: bool,
+    pub border: BorderSize,
+    pub aspect: Aspect,
+    pub filters: Vec<Filter>,
+    pub palette: Palette,
+    // 64 colour palette programs can set
+    pub ulaplus: bool,
+    pub sample_rate: Option<u32>,
+    pub trace: Option<String>,
+    // Frames to run without a window
+    pub headless: Option<u64>,
+    // Address that ends the run when the PC gets there
+    pub until_pc: Option<u16>,
+    pub wav: Option<String>,
+    // TAP the blocks saved through the ROM go to
+    pub save_tap: Option<String>,
+    // TAP rebuilt from the MIC output, for programs that save on their own
+    pub mic_tap: Option<String>,
+    // Input recording to write, in RZX
+    pub record: Option<String>,
+    // Display saved when the run ends, PNG or SCR
+    pub screenshot: Option<String>,
+    // AVI the frames and the sound go to
+    pub video: Option<String>,
+    // Animated GIF the frames go to
+    pub gif: Option<String>,
+    pub joysticks: Vec<JoystickKind>,
+    pub joystick_keys: JoystickKeys,
+    pub key_bindings: KeyBindings,
+    pub script: Option<String>,
+    pub mouse: bool,
+    // Whether tapes and disks are started by typing the keys for it
+    pub autoload: bool,
+    pub autoload_frame: u64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            model: Model::Spectrum48K,
+            roms: Vec::new(),
+            media: Vec::new(),
+            scale: 1,
+            fullscreen: false,
+            border: BorderSize::Full,
+            aspect: Aspect::Square,
+            filters: Vec::new(),
+            ulaplus: false,
+            palette: DEFAULT_PALETTE,
+            sample_rate: None,
+            trace: None,
+            headless: None,
+            until_pc: None,
+            wav: None,
+            save_tap: None,
+            mic_tap: None,
+            record: None,
+            screenshot: None,
+            video: None,
+            gif: None,
+            joysticks: Vec::new(),
+            joystick_keys: JoystickKeys::default(),
+            key_bindings: KeyBindings::default(),
+            script: None,
+            mouse: false,
+            autoload: true,
+            autoload_frame: AUTOLOAD_FRAME,
+        }
+    }
+}
+
+// Help asked for on purpose ends well, a wrong argument does not
+pub fn usage() -> ! {
+    print_usage();
+    std::process::exit(1);
+}
+
+fn print_usage() {
+    eprintln!("Uso: z80 [MODELO] [FICHERO]... [OPCIONES]");
+    eprintln!();
+    eprintln!("MODELO es 16k, 48k, 128k, +2a, +3 o pentagon. Los FICHEROS .tap se");
+    eprintln!("cargan con LOAD \"\", los .sna, .z80, .szx y .state se restauran, los");
+    eprintln!(".rzx se reproducen, los .scr van a la pantalla y los .dsk a la unidad A.");
+    eprintln!("Salvo el 16K y el 48K, los modelos necesitan sus ROM, dadas con --rom.");
+    eprintln!();
+    eprintln!("  --config FICHERO      ajustes a usar en lugar de z80.toml o");
+    eprintln!("                        ~/.config/z80/config.toml");
+    eprintln!("  --model MODELO        igual que MODELO");
+    eprintln!("  --rom [MODELO:]FICHERO");
+    eprintln!("                        ROM a usar, repetible para los juegos de varias;");
+    eprintln!("                        con MODELO, la de ese modelo si se pasa a él");
+    eprintln!("  --disk FICHERO.DSK    disco para la unidad A");
+    eprintln!("  --scale 1|2|3|4       aumento de la ventana");
+    eprintln!("  --fullscreen          pantalla completa");
+    eprintln!("  --border none|normal|full");
+    eprintln!("                        cuánto borde se ve, todo si no se dice");
+    eprintln!("  --aspect square|pal|4:3");
+    eprintln!("                        píxeles cuadrados, si no se dice, tan anchos");
+    eprintln!("                        como en un televisor PAL, o lo que se ve a 4:3");
+    eprintln!("  --filter FILTRO       scanlines, pal, phosphor o none para ninguno");
+    eprintln!("  --ulaplus             paleta de 64 colores ULAplus");
+    eprintln!("  --sample-rate HZ      frecuencia de muestreo del sonido");
+    eprintln!("  --trace FICHERO       lista cada instrucción y los registros");
+    eprintln!("  --headless FRAMES     ejecuta sin ventana ese número de frames");
+    eprintln!("  --until-pc DIRECCIÓN  termina al llegar a esa dirección (0x para hex)");
+    eprintln!("  --wav FICHERO         graba el sonido en lugar de reproducirlo con aplay");
+    eprintln!("  --record FICHERO.RZX  graba las entradas de la sesión");
+    eprintln!("  --save-tap FICHERO.TAP");
+    eprintln!("                        añade ahí los bloques que se graban con SAVE");
+    eprintln!("  --mic-tap FICHERO.TAP reconstruye los bloques de la salida MIC, para");
+    eprintln!("                        los programas que graban sin la ROM");
+    eprintln!("  --screenshot FICHERO  guarda la pantalla al terminar, en PNG o SCR");
+    eprintln!("  --video FICHERO.AVI   graba imagen y sonido sin comprimir");
+    eprintln!("  --gif FICHERO.GIF     graba la imagen en un GIF animado");
+    eprintln!("  --joystick TIPO       kempston, sinclair1, sinclair2, cursor o fuller");
+    eprintln!("  --joy-keys ARRIBA,ABAJO,IZQUIERDA,DERECHA,FUEGO");
+    eprintln!("  --script FICHERO      pulsaciones a dar en cada frame");
+    eprintln!("  --mouse               ratón Kempston");
+    eprintln!("  --no-autoload         no arranca solo las cintas y los discos");
+    eprintln!();
+    eprintln!("En la ventana F5 guarda el estado en la ranura elegida, F9 lo recupera,");
+    eprintln!("F7 pasa a la ranura siguiente y F8 vuelve 5 segundos atrás. F12 guarda");
+    eprintln!("la pantalla en PNG, sin borde con mayúsculas, y F10 en SCR. F6 empieza y");
+    eprintln!("termina un GIF animado.");
+    eprintln!();
+    eprintln!("El sonido se oye a través de aplay, de alsa-utils, y por tanto solo en");
+    eprintln!("Linux. En otros sistemas, o sin aplay, --wav y --video lo graban.");
+    eprintln!();
+    eprintln!("Los argumentos mandan sobre el fichero de ajustes. Un --rom, un");
+    eprintln!("--joystick o un --filter sustituye la lista que traiga.");
+    eprintln!();
+    eprintln!("Termina con 0 si todo va bien, 1 si hay un error en los argumentos o");
+    eprintln!("los ficheros, 2 si no se llega a --until-pc, 3 si la CPU se detiene y");
+    eprintln!("4 si un .rzx lee más entradas de las grabadas. Con --headless, un .rzx");
+    eprintln!("termina la ejecución al acabarse.");
+}
+
+// Takes "0x" or "$" for hexadecimal
+pub fn parse_address(text: &str) -> Option<u16> {
+    let hex = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'));
+    match hex {
+        Some(digits) => u16::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// Splits "MODELO:FICHERO", the file alone going to `model`
+pub fn rom_model(rom: &str, model: Model) -> (Model, &str) {
+    if let Some((name, path)) = rom.split_once(':') {
+        if let Some(model) = Model::from_name(name) {
+            return (model, path);
+        }
+    }
+    (model, rom)
+}
+
+// Sample rates the sound output accepts
+pub fn valid_sample_rate(rate: u32) -> bool {
+    (8000..=192_000).contains(&rate)
+}
+
+// Only the settings file named in the arguments, which have to be read
+// after it
+pub fn config_arg(args: &[String]) -> Option<String> {
+    let position = args.iter().position(|arg| arg == "--config")?;
+    Some(args.get(position + 1).cloned().unwrap_or_else(|| usage()))
+}
+
+impl Options {
+    // Applies the arguments over the options already set. The lists of
+    // ROMs, joysticks and filters start again with the first one given.
+    pub fn parse_args<I: Iterator<Item = String>>(&mut self, args: I) {
+        let mut args = args;
+        let mut roms_given = false;
+        let mut joysticks_given = false;
+        let mut filters_given = false;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| usage());
+            match arg.as_str() {
+                // Already read, see config_arg
+                "--config" => {
+                    value();
+                }
+                "--model" => self.model = Model::from_name(&value()).unwrap_or_else(|| usage()),
+                "--rom" => {
+                    if !roms_given {
+                        self.roms.clear();
+                        roms_given = true;
+                    }
+                    self.roms.push(value());
+                }
+                "--disk" => self.media.push(value()),
+                "--scale" => {
+                    let scale = value().parse::<usize>().ok();
+                    self.scale = scale
+                        .filter(|scale| (1..=MAX_SCALE).contains(scale))
+                        .unwrap_or_else(|| usage());
+                }
+                "--fullscreen" => self.fullscreen = true,
+                "--ulaplus" => self.ulaplus = true,
+                "--filter" => {
+                    if !filters_given {
+                        self.filters.clear();
+                        filters_given = true;
+                    }
+                    let name = value();
+                    if name != "none" {
+                        let filter = Filter::from_name(&name);
+                        self.filters.push(filter.unwrap_or_else(|| usage()));
+                    }
+                }
+                "--border" => {
+                    self.border = BorderSize::from_name(&value()).unwrap_or_else(|| usage())
+                }
+                "--aspect" => {
+                    self.aspect = Aspect::from_name(&value()).unwrap_or_else(|| usage())
+                }
+                "--sample-rate" => {
+                    let rate = value().parse::<u32>().ok().filter(|&rate| valid_sample_rate(rate));
+                    self.sample_rate = Some(rate.unwrap_or_else(|| usage()));
+                }
+                "--trace" => self.trace = Some(value()),
+                "--headless" => {
+                    let frames = value().parse::<u64>().ok();
+                    self.headless = Some(frames.unwrap_or_else(|| usage()));
+                }
+                "--until-pc" => {
+                    self.until_pc = Some(parse_address(&value()).unwrap_or_else(|| usage()))
+                }
+                "--wav" => self.wav = Some(value()),
+                "--save-tap" => self.save_tap = Some(value()),
+                "--mic-tap" => self.mic_tap = Some(value()),
+                "--record" => self.record = Some(value()),
+                "--screenshot" => self.screenshot = Some(value()),
+                "--video" => self.video = Some(value()),
+                "--gif" => self.gif = Some(value()),
+                "--mouse" => self.mouse = true,
+                "--no-autoload" => self.autoload = false,
+                "--joystick" => {
+                    if !joysticks_given {
+                        self.joysticks.clear();
+                        joysticks_given = true;
+                    }
+                    let kind = JoystickKind::from_name(&value());
+                    self.joysticks.push(kind.unwrap_or_else(|| usage()));
+                }
+                "--joy-keys" => {
+                    self.joystick_keys = JoystickKeys::parse(&value()).unwrap_or_else(|| usage())
+                }
+                "--script" => self.script = Some(value()),
+                "--help" | "-h" => {
+                    print_usage();
+                    std::process::exit(0);
+                }
+                option if option.starts_with("--") => usage(),
+                name => match Model::from_name(name) {
+                    Some(model) => self.model = model,
+                    None => self.media.push(name.to_string()),
+                },
+            }
+        }
+    }
+}
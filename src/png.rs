@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use checksum;
+use machine::Machine;
+use ula::{BORDER_LEFT, BORDER_TOP, FRAME_HEIGHT, FRAME_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH};
+use zlib;
+
+// PNG writer for screenshots: 8 bit RGB rows without filtering, which
+// the compressor turns into matches with the row above
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+const BIT_DEPTH: u8 = 8;
+const COLOUR_RGB: u8 = 2;
+const NO_FILTER: u8 = 0;
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = checksum::crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// `pixels` holds `height` rows of `width` 0xRRGGBB values
+pub fn encode(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Compression, filter and interlace methods are all 0
+    header.extend_from_slice(&[BIT_DEPTH, COLOUR_RGB, 0, 0, 0]);
+
+    let mut raw = Vec::with_capacity(height * (1 + width * 3));
+    for row in pixels.chunks(width).take(height) {
+        raw.push(NO_FILTER);
+        for &pixel in row {
+            raw.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+    }
+
+    let mut out = SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"IDAT", &zlib::compress(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    pixels: &[u32],
+) -> io::Result<()> {
+    File::create(path)?.write_all(&encode(width, height, pixels))
+}
+
+// The display as it is now, with the border or only the bitmap
+pub fn save_screen<P: AsRef<Path>>(machine: &Machine, path: P, border: bool) -> io::Result<()> {
+    let mut buffer = vec![0; FRAME_WIDTH * FRAME_HEIGHT];
+    machine.render(&mut buffer);
+    if border {
+        return save(path, FRAME_WIDTH, FRAME_HEIGHT, &buffer);
+    }
+    let mut bitmap = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+    for row in buffer
+        .chunks(FRAME_WIDTH)
+        .skip(BORDER_TOP)
+        .take(SCREEN_HEIGHT)
+    {
+        bitmap.extend_from_slice(&row[BORDER_LEFT..BORDER_LEFT + SCREEN_WIDTH]);
+    }
+    save(path, SCREEN_WIDTH, SCREEN_HEIGHT, &bitmap)
+}
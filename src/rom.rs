@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use checksum;
+use memory::PAGE_SIZE;
+
+// ROM images read at runtime instead of the built in 48 BASIC. Each 16K
+// page is identified by its CRC-32 so the known dumps can be named.
+
+const KNOWN_ROMS: [(u32, &str); 7] = [
+    (0xDDEE_531F, "48K"),
+    (0xE767_99D2, "128K ROM 0 (editor)"),
+    (0xB96A_36BE, "128K ROM 1 (48 BASIC)"),
+    (0x1737_3DA2, "+3 v4.0 ROM 0"),
+    (0xF1D1_D99E, "+3 v4.0 ROM 1"),
+    (0x3DBF_351D, "+3 v4.0 ROM 2"),
+    (0x0444_8EAA, "+3 v4.0 ROM 3"),
+];
+
+// Name of a known 16K ROM page
+pub fn identify(page: &[u8]) -> Option<&'static str> {
+    let crc = checksum::crc32(page);
+    KNOWN_ROMS
+        .iter()
+        .find(|&&(known, _)| known == crc)
+        .map(|&(_, name)| name)
+}
+
+// Reads an image of one or more whole 16K pages
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.is_empty() || bytes.len() % PAGE_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("la ROM ocupa {} bytes, no un múltiplo de 16K", bytes.len()),
+        ));
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,479 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use machine::Machine;
+use sna;
+use szx;
+use z80_snapshot;
+use zlib;
+
+// Input recordings in the RZX format. For each frame, that is for each
+// interrupt, they keep the number of opcode fetches the CPU made, counted
+// as R counts them, and the value every IN read. Fed back to the same
+// machine from the same snapshot they reproduce the session exactly.
+
+const MAGIC: &[u8; 4] = b"RZX!";
+const MAJOR_VERSION: u8 = 0;
+const MINOR_VERSION: u8 = 13;
+const HEADER_LENGTH: usize = 10;
+const BLOCK_HEADER_LENGTH: usize = 5;
+
+const CREATOR_BLOCK: u8 = 0x10;
+const SNAPSHOT_BLOCK: u8 = 0x30;
+const INPUT_BLOCK: u8 = 0x80;
+
+const CREATOR: &[u8; 20] = b"z80\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+const CREATOR_MAJOR: u16 = 0;
+const CREATOR_MINOR: u16 = 1;
+
+// Flags of the snapshot and input blocks
+const EXTERNAL_SNAPSHOT: u32 = 0x01;
+const COMPRESSED: u32 = 0x02;
+
+// IN count of a frame that reads the same values as the one before
+const REPEAT_INPUTS: u16 = 0xFFFF;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_word(bytes: &[u8], offset: usize) -> u16 {
+    ((bytes[offset + 1] as u16) << 8) | bytes[offset] as u16
+}
+
+fn read_dword(bytes: &[u8], offset: usize) -> u32 {
+    (read_word(bytes, offset + 2) as u32) << 16 | read_word(bytes, offset) as u32
+}
+
+fn write_word(bytes: &mut Vec<u8>, word: u16) {
+    bytes.extend_from_slice(&word.to_le_bytes());
+}
+
+fn write_dword(bytes: &mut Vec<u8>, dword: u32) {
+    bytes.extend_from_slice(&dword.to_le_bytes());
+}
+
+fn write_block(bytes: &mut Vec<u8>, id: u8, data: &[u8]) {
+    bytes.push(id);
+    write_dword(bytes, (BLOCK_HEADER_LENGTH + data.len()) as u32);
+    bytes.extend_from_slice(data);
+}
+
+pub struct RzxFrame {
+    // Opcode fetches until the interrupt that ends the frame
+    pub fetches: u16,
+    pub inputs: Vec<u8>,
+}
+
+pub struct Rzx {
+    // Snapshot the recording starts from and the extension that tells
+    // its format: "szx", "z80" or "sna"
+    pub snapshot: Option<(String, Vec<u8>)>,
+    // T-state of the frame at which the first frame starts
+    pub t_states: u32,
+    pub frames: Vec<RzxFrame>,
+}
+
+impl Rzx {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Rzx> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Rzx::from_bytes(&bytes)
+    }
+
+    // Takes the first snapshot and the frames of every input block, in
+    // order. Security blocks are skipped without checking them.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Rzx> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..4] != MAGIC {
+            return Err(invalid_data("no es un fichero RZX"));
+        }
+        let mut rzx = Rzx {
+            snapshot: None,
+            t_states: 0,
+            frames: Vec::new(),
+        };
+        let mut first_input = true;
+
+        let mut data = &bytes[HEADER_LENGTH..];
+        while data.len() >= BLOCK_HEADER_LENGTH {
+            let id = data[0];
+            let len = read_dword(data, 1) as usize;
+            if len < BLOCK_HEADER_LENGTH || data.len() < len {
+                return Err(invalid_data("bloque del RZX incompleto"));
+            }
+            let block = &data[BLOCK_HEADER_LENGTH..len];
+            match id {
+                SNAPSHOT_BLOCK if rzx.snapshot.is_none() => {
+                    rzx.snapshot = Some(read_snapshot(block)?);
+                }
+                INPUT_BLOCK => {
+                    let t_states = read_input(block, &mut rzx.frames)?;
+                    if first_input {
+                        rzx.t_states = t_states;
+                        first_input = false;
+                    }
+                }
+                _ => {}
+            }
+            data = &data[len..];
+        }
+        Ok(rzx)
+    }
+
+    // The blocks are left uncompressed, which every player takes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(MAJOR_VERSION);
+        bytes.push(MINOR_VERSION);
+        write_dword(&mut bytes, 0);
+
+        let mut creator = CREATOR.to_vec();
+        write_word(&mut creator, CREATOR_MAJOR);
+        write_word(&mut creator, CREATOR_MINOR);
+        write_block(&mut bytes, CREATOR_BLOCK, &creator);
+
+        if let Some((ref extension, ref data)) = self.snapshot {
+            let mut snapshot = Vec::with_capacity(12 + data.len());
+            write_dword(&mut snapshot, 0);
+            let mut name = [0; 4];
+            for (c, &b) in name.iter_mut().zip(extension.as_bytes()) {
+                *c = b;
+            }
+            snapshot.extend_from_slice(&name);
+            write_dword(&mut snapshot, data.len() as u32);
+            snapshot.extend_from_slice(data);
+            write_block(&mut bytes, SNAPSHOT_BLOCK, &snapshot);
+        }
+
+        let mut input = Vec::new();
+        write_dword(&mut input, self.frames.len() as u32);
+        input.push(0);
+        write_dword(&mut input, self.t_states);
+        write_dword(&mut input, 0);
+        let mut previous: Option<&[u8]> = None;
+        for frame in self.frames.iter() {
+            write_word(&mut input, frame.fetches);
+            if previous == Some(&frame.inputs) {
+                write_word(&mut input, REPEAT_INPUTS);
+            } else {
+                write_word(&mut input, frame.inputs.len() as u16);
+                input.extend_from_slice(&frame.inputs);
+            }
+            previous = Some(&frame.inputs);
+        }
+        write_block(&mut bytes, INPUT_BLOCK, &input);
+        bytes
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        File::create(path)?.write_all(&self.to_bytes())
+    }
+}
+
+fn read_snapshot(block: &[u8]) -> io::Result<(String, Vec<u8>)> {
+    if block.len() < 12 {
+        return Err(invalid_data("bloque de snapshot del RZX incompleto"));
+    }
+    let flags = read_dword(block, 0);
+    if flags & EXTERNAL_SNAPSHOT != 0 {
+        return Err(invalid_data("el RZX usa un snapshot externo"));
+    }
+    let extension: String = block[4..8]
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| (b as char).to_ascii_lowercase())
+        .collect();
+    let length = read_dword(block, 8) as usize;
+    let data = if flags & COMPRESSED != 0 {
+        zlib::decompress(&block[12..])?
+    } else {
+        block[12..].to_vec()
+    };
+    if data.len() != length {
+        return Err(invalid_data("longitud del snapshot del RZX incorrecta"));
+    }
+    Ok((extension, data))
+}
+
+// Appends the frames of an input block and returns its starting T-state
+fn read_input(block: &[u8], frames: &mut Vec<RzxFrame>) -> io::Result<u32> {
+    if block.len() < 13 {
+        return Err(invalid_data("bloque de entradas del RZX incompleto"));
+    }
+    let count = read_dword(block, 0) as usize;
+    let t_states = read_dword(block, 5);
+    let flags = read_dword(block, 9);
+    let inflated;
+    let mut data = if flags & COMPRESSED != 0 {
+        inflated = zlib::decompress(&block[13..])?;
+        &inflated[..]
+    } else {
+        &block[13..]
+    };
+
+    for _ in 0..count {
+        if data.len() < 4 {
+            return Err(invalid_data("frame del RZX incompleto"));
+        }
+        let fetches = read_word(data, 0);
+        let in_count = read_word(data, 2);
+        data = &data[4..];
+        let inputs = if in_count == REPEAT_INPUTS {
+            frames
+                .last()
+                .map(|frame| frame.inputs.clone())
+                .unwrap_or_default()
+        } else {
+            let len = in_count as usize;
+            if data.len() < len {
+                return Err(invalid_data("frame del RZX incompleto"));
+            }
+            let inputs = data[..len].to_vec();
+            data = &data[len..];
+            inputs
+        };
+        frames.push(RzxFrame { fetches, inputs });
+    }
+    Ok(t_states)
+}
+
+// Replays the frames of a recording: the frame ends after its fetches and
+// the ports read what was recorded
+pub struct RzxPlayer {
+    frames: Vec<RzxFrame>,
+    frame: usize,
+    input: usize,
+    frame_start: u64,
+    // Reads past the values the frame has, a sign the replay went astray
+    pub overruns: u64,
+}
+
+impl RzxPlayer {
+    pub fn new(frames: Vec<RzxFrame>, fetches: u64) -> RzxPlayer {
+        RzxPlayer {
+            frames,
+            frame: 0,
+            input: 0,
+            frame_start: fetches,
+            overruns: 0,
+        }
+    }
+
+    // Value of the fetch counter at which the current frame ends
+    pub fn frame_end(&self) -> Option<u64> {
+        let frame = self.frames.get(self.frame)?;
+        Some(self.frame_start + frame.fetches as u64)
+    }
+
+    // None once the recording is over, or when the frame has no more
+    pub fn next_input(&mut self) -> Option<u8> {
+        let value = self.frames.get(self.frame)?.inputs.get(self.input).cloned();
+        match value {
+            Some(_) => self.input += 1,
+            None => self.overruns += 1,
+        }
+        value
+    }
+
+    pub fn end_frame(&mut self, fetches: u64) {
+        self.frame += 1;
+        self.input = 0;
+        self.frame_start = fetches;
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.frames.len()
+    }
+}
+
+// Collects what the machine reads, frame by frame
+pub struct RzxRecorder {
+    rzx: Rzx,
+    inputs: Vec<u8>,
+    frame_start: u64,
+}
+
+impl RzxRecorder {
+    // Starts from a snapshot of the machine as it is now
+    pub fn new(machine: &Machine) -> RzxRecorder {
+        RzxRecorder {
+            rzx: Rzx {
+                snapshot: Some(("szx".to_string(), szx::save_bytes(machine))),
+                t_states: machine.frame_t_states() as u32,
+                frames: Vec::new(),
+            },
+            inputs: Vec::new(),
+            frame_start: machine.cpu.fetches,
+        }
+    }
+
+    pub fn input(&mut self, value: u8) {
+        self.inputs.push(value);
+    }
+
+    pub fn end_frame(&mut self, fetches: u64) {
+        self.rzx.frames.push(RzxFrame {
+            fetches: (fetches - self.frame_start) as u16,
+            inputs: std::mem::take(&mut self.inputs),
+        });
+        self.frame_start = fetches;
+    }
+
+    pub fn finish(self) -> Rzx {
+        self.rzx
+    }
+}
+
+// Puts the machine where the recording starts and hands it the frames
+pub fn play(machine: &mut Machine, rzx: Rzx) -> io::Result<()> {
+    if let Some((ref extension, ref data)) = rzx.snapshot {
+        match extension.as_str() {
+            "szx" => szx::load_bytes(machine, data)?,
+            "z80" => z80_snapshot::load_bytes(machine, data)?,
+            "sna" => sna::load_bytes(machine, data)?,
+            _ => return Err(invalid_data("formato del snapshot del RZX no soportado")),
+        }
+    }
+    // Where in the frame the first one starts, which an SNA does not keep
+    if !rzx.frames.is_empty() {
+        machine.cpu.t_states = machine.frame_start + rzx.t_states as u64;
+    }
+    machine.io.rzx_player = Some(RzxPlayer::new(rzx.frames, machine.cpu.fetches));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyboard::SpectrumKey;
+
+    fn frame(fetches: u16, inputs: &[u8]) -> RzxFrame {
+        RzxFrame {
+            fetches,
+            inputs: inputs.to_vec(),
+        }
+    }
+
+    fn frames(rzx: &Rzx) -> Vec<(u16, Vec<u8>)> {
+        rzx.frames
+            .iter()
+            .map(|frame| (frame.fetches, frame.inputs.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let rzx = Rzx {
+            snapshot: Some(("sna".to_string(), vec![1, 2, 3, 4])),
+            t_states: 1234,
+            frames: vec![
+                frame(100, &[0xBF, 0xFE]),
+                frame(200, &[0xBF, 0xFE]),
+                frame(300, &[]),
+                frame(400, &[0x1F]),
+            ],
+        };
+        let bytes = rzx.to_bytes();
+        // The second frame reads what the first did
+        let repeated = [200, 0, 0xFF, 0xFF];
+        assert!(bytes.windows(4).any(|window| window == repeated));
+
+        let loaded = Rzx::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.snapshot, rzx.snapshot);
+        assert_eq!(loaded.t_states, 1234);
+        assert_eq!(frames(&loaded), frames(&rzx));
+    }
+
+    #[test]
+    fn compressed_inputs() {
+        let mut frames_data = Vec::new();
+        for &(fetches, ref inputs) in [(10u16, vec![1u8, 2]), (20, vec![])].iter() {
+            write_word(&mut frames_data, fetches);
+            write_word(&mut frames_data, inputs.len() as u16);
+            frames_data.extend_from_slice(inputs);
+        }
+        write_word(&mut frames_data, 30);
+        write_word(&mut frames_data, REPEAT_INPUTS);
+
+        let mut input = Vec::new();
+        write_dword(&mut input, 3);
+        input.push(0);
+        write_dword(&mut input, 500);
+        write_dword(&mut input, COMPRESSED);
+        input.extend_from_slice(&zlib::compress(&frames_data));
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(MAJOR_VERSION);
+        bytes.push(MINOR_VERSION);
+        write_dword(&mut bytes, 0);
+        write_block(&mut bytes, INPUT_BLOCK, &input);
+
+        let rzx = Rzx::from_bytes(&bytes).unwrap();
+        assert_eq!(rzx.t_states, 500);
+        assert_eq!(
+            frames(&rzx),
+            vec![(10, vec![1, 2]), (20, vec![]), (30, vec![])]
+        );
+    }
+
+    #[test]
+    fn replay_matches_the_recording() {
+        let mut machine = Machine::new();
+        for _ in 0..150 {
+            machine.run_frame();
+        }
+        machine.io.rzx_recorder = Some(RzxRecorder::new(&machine));
+        // Types a couple of keys into the BASIC editor
+        for i in 0..60 {
+            match i {
+                10 => machine.io.keyboard.set_key(SpectrumKey::P, true),
+                15 => machine.io.keyboard.set_key(SpectrumKey::P, false),
+                30 => machine.io.keyboard.set_key(SpectrumKey::Num1, true),
+                35 => machine.io.keyboard.set_key(SpectrumKey::Num1, false),
+                _ => {}
+            }
+            machine.run_frame();
+        }
+        let recorded = machine.io.rzx_recorder.take().unwrap().finish();
+        assert!(recorded.frames.iter().any(|frame| !frame.inputs.is_empty()));
+        let rzx = Rzx::from_bytes(&recorded.to_bytes()).unwrap();
+
+        let mut replay = Machine::new();
+        play(&mut replay, rzx).unwrap();
+        while replay.replaying() {
+            replay.run_frame();
+        }
+        let player = replay.io.rzx_player.as_ref().unwrap();
+        assert_eq!(player.overruns, 0);
+        // A replayed frame starts where the fetches ran out, so only the
+        // state is compared, not the T-states
+        let registers = |m: &Machine| (m.cpu.pc, m.cpu.sp, m.cpu.a, m.cpu.f, m.cpu.h, m.cpu.l);
+        assert_eq!(registers(&replay), registers(&machine));
+        for addr in 0x4000..=0xFFFF {
+            assert_eq!(
+                replay.mem.peek(addr),
+                machine.mem.peek(addr),
+                "{:04X}",
+                addr
+            );
+        }
+    }
+
+    #[test]
+    fn play_starts_at_the_recorded_t_state() {
+        let mut machine = Machine::new();
+        machine.run_frame();
+        let rzx = Rzx {
+            snapshot: Some(("sna".to_string(), sna::save_bytes(&machine))),
+            t_states: 1000,
+            frames: vec![frame(10, &[])],
+        };
+        let mut replay = Machine::new();
+        play(&mut replay, rzx).unwrap();
+        assert_eq!(replay.frame_t_states(), 1000);
+    }
+}
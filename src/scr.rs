@@ -0,0 +1,61 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use machine::Machine;
+use ulaplus::{UlaPlus, PALETTE_SIZE};
+
+// Screen dumps: the 6144 bytes of bitmap and 768 of attributes exactly as
+// they sit in memory from 0x4000, followed by the 64 ULAplus palette
+// entries when the screen uses them
+
+pub const SCR_LENGTH: usize = 6912;
+const ULAPLUS_SCR_LENGTH: usize = SCR_LENGTH + PALETTE_SIZE;
+const SCREEN_START: u16 = 0x4000;
+
+pub fn load<P: AsRef<Path>>(machine: &mut Machine, path: P) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    load_bytes(machine, &bytes)
+}
+
+// Goes through 0x4000 like a program writing it, so on the 128K it is
+// the normal screen in bank 5. A palette plugs in the ULAplus if the
+// machine does not have it and turns it on.
+pub fn load_bytes(machine: &mut Machine, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() != SCR_LENGTH && bytes.len() != ULAPLUS_SCR_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "una pantalla SCR ocupa {} bytes, o {} con paleta",
+                SCR_LENGTH, ULAPLUS_SCR_LENGTH
+            ),
+        ));
+    }
+    for (offset, &byte) in bytes[..SCR_LENGTH].iter().enumerate() {
+        machine.mem.poke(SCREEN_START + offset as u16, byte);
+    }
+    if bytes.len() == ULAPLUS_SCR_LENGTH {
+        let ulaplus = machine.io.ulaplus.get_or_insert_with(UlaPlus::new);
+        ulaplus.palette.copy_from_slice(&bytes[SCR_LENGTH..]);
+        ulaplus.enabled = true;
+    }
+    Ok(())
+}
+
+pub fn save<P: AsRef<Path>>(machine: &Machine, path: P) -> io::Result<()> {
+    File::create(path)?.write_all(&save_bytes(machine))
+}
+
+// The screen being displayed, the shadow one if it is selected, and the
+// palette if the ULAplus has it on
+pub fn save_bytes(machine: &Machine) -> Vec<u8> {
+    let mut bytes = machine.mem.screen()[..SCR_LENGTH].to_vec();
+    if let Some(ref ulaplus) = machine.io.ulaplus {
+        if ulaplus.enabled {
+            bytes.extend_from_slice(&ulaplus.palette);
+        }
+    }
+    bytes
+}
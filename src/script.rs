@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use iobus::IoBus;
+use joystick;
+use keyboard::SpectrumKey;
+
+// Input played back on given frames, so games can be driven without a
+// window. One event per line:
+//
+//     <frame> press|release <key>
+//
+// where <key> is a key name (A, 1, ENTER, SPACE, CAPS, SYMBOL...) or a
+// joystick direction: joy-up, joy-fire... for the first joystick and
+// joy2-up... for the second. Blank lines and lines starting with # are
+// ignored.
+
+enum Target {
+    Key(SpectrumKey),
+    Joystick(usize, u8),
+}
+
+struct Event {
+    frame: u64,
+    pressed: bool,
+    target: Target,
+}
+
+pub struct InputScript {
+    events: Vec<Event>,
+    next: usize,
+}
+
+fn invalid_line(number: usize, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("línea {} del guion: {}", number, msg),
+    )
+}
+
+fn parse_target(name: &str) -> Option<Target> {
+    let lower = name.to_lowercase();
+    if lower.starts_with("joy") {
+        let (index, direction) = match lower.find('-') {
+            Some(dash) => (&lower[3..dash], &lower[dash + 1..]),
+            None => return None,
+        };
+        let index = match index {
+            "" | "1" => 0,
+            "2" => 1,
+            _ => return None,
+        };
+        return joystick::direction_from_name(direction)
+            .map(|direction| Target::Joystick(index, direction));
+    }
+    SpectrumKey::from_name(name).map(Target::Key)
+}
+
+impl Default for InputScript {
+    fn default() -> InputScript {
+        InputScript::new()
+    }
+}
+
+impl InputScript {
+    pub fn new() -> InputScript {
+        InputScript {
+            events: Vec::new(),
+            next: 0,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<InputScript> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        InputScript::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> io::Result<InputScript> {
+        let mut events = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(invalid_line(number, "se esperaba <frame> <acción> <tecla>"));
+            }
+            let frame = fields[0]
+                .parse::<u64>()
+                .map_err(|_| invalid_line(number, "frame no válido"))?;
+            let pressed = match fields[1] {
+                "press" => true,
+                "release" => false,
+                _ => return Err(invalid_line(number, "la acción es press o release")),
+            };
+            let target =
+                parse_target(fields[2]).ok_or_else(|| invalid_line(number, "tecla desconocida"))?;
+            events.push(Event {
+                frame,
+                pressed,
+                target,
+            });
+        }
+
+        // Events of the same frame keep their order
+        events.sort_by_key(|event| event.frame);
+        Ok(InputScript { events, next: 0 })
+    }
+
+    // Presses `keys` together at `frame` and releases them `hold` frames
+    // later, as when typing a keyword
+    pub fn add_keys(&mut self, frame: u64, keys: &[SpectrumKey], hold: u64) {
+        for &key in keys {
+            self.events.push(Event {
+                frame,
+                pressed: true,
+                target: Target::Key(key),
+            });
+            self.events.push(Event {
+                frame: frame + hold,
+                pressed: false,
+                target: Target::Key(key),
+            });
+        }
+        self.events[self.next..].sort_by_key(|event| event.frame);
+    }
+
+    // Applies the events due at the start of `frame`
+    pub fn apply(&mut self, frame: u64, io: &mut IoBus) {
+        while self.next < self.events.len() && self.events[self.next].frame <= frame {
+            let event = &self.events[self.next];
+            match event.target {
+                Target::Key(key) => io.keyboard.set_key(key, event.pressed),
+                Target::Joystick(index, direction) => {
+                    if let Some(joystick) = io.joysticks.get_mut(index) {
+                        joystick.set(direction, event.pressed);
+                    }
+                }
+            }
+            self.next += 1;
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.events.len()
+    }
+}
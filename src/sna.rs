@@ -0,0 +1,333 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use machine::{Machine, Model};
+use memory::PAGE_SIZE;
+
+const HEADER_LENGTH: usize = 27;
+const RAM_START: usize = 0x4000;
+const RAM_LENGTH: usize = 48 * 1024;
+pub const SNA_48K_LENGTH: usize = HEADER_LENGTH + RAM_LENGTH;
+// The 128K variant appends PC, 0x7FFD, the TR-DOS flag and the banks not
+// already saved. There are six of them when the paged bank is 2 or 5.
+const EXTENSION_LENGTH: usize = 4;
+pub const SNA_128K_LENGTH: usize = SNA_48K_LENGTH + EXTENSION_LENGTH + 5 * PAGE_SIZE;
+pub const SNA_128K_LONG_LENGTH: usize = SNA_128K_LENGTH + PAGE_SIZE;
+
+// Bit of the interrupt byte that holds IFF2
+const IFF2_BIT: u8 = 0x04;
+
+fn read_word(bytes: &[u8], offset: usize) -> (u8, u8) {
+    // Words are little endian: (high, low)
+    (bytes[offset + 1], bytes[offset])
+}
+
+fn write_word(bytes: &mut Vec<u8>, hi: u8, lo: u8) {
+    bytes.push(lo);
+    bytes.push(hi);
+}
+
+// Banks in the 48K part of the file: the two fixed ones and the paged one
+fn low_banks(last_7ffd: u8) -> [usize; 3] {
+    [5, 2, (last_7ffd & 0x07) as usize]
+}
+
+// Banks stored after the 128K extension, in ascending order
+fn high_banks(last_7ffd: u8) -> Vec<usize> {
+    let low = low_banks(last_7ffd);
+    (0..8).filter(|bank| !low.contains(bank)).collect()
+}
+
+pub fn load<P: AsRef<Path>>(machine: &mut Machine, path: P) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    load_bytes(machine, &bytes)
+}
+
+pub fn load_bytes(machine: &mut Machine, bytes: &[u8]) -> io::Result<()> {
+    let model = match bytes.len() {
+        SNA_48K_LENGTH => Model::Spectrum48K,
+        // Nothing tells a Pentagon apart, so it stays one
+        SNA_128K_LENGTH | SNA_128K_LONG_LENGTH if machine.model == Model::Pentagon128 => {
+            Model::Pentagon128
+        }
+        SNA_128K_LENGTH | SNA_128K_LONG_LENGTH => Model::Spectrum128K,
+        len => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("SNA con longitud {} no válida", len),
+            ))
+        }
+    };
+    machine.set_model(model);
+
+    {
+        let cpu = &mut machine.cpu;
+        cpu.i = bytes[0];
+        let (h, l) = read_word(bytes, 1);
+        cpu.h_alt = h;
+        cpu.l_alt = l;
+        let (d, e) = read_word(bytes, 3);
+        cpu.d_alt = d;
+        cpu.e_alt = e;
+        let (b, c) = read_word(bytes, 5);
+        cpu.b_alt = b;
+        cpu.c_alt = c;
+        let (a, f) = read_word(bytes, 7);
+        cpu.a_alt = a;
+        cpu.f_alt = f;
+        let (h, l) = read_word(bytes, 9);
+        cpu.h = h;
+        cpu.l = l;
+        let (d, e) = read_word(bytes, 11);
+        cpu.d = d;
+        cpu.e = e;
+        let (b, c) = read_word(bytes, 13);
+        cpu.b = b;
+        cpu.c = c;
+        let (iy_h, iy_l) = read_word(bytes, 15);
+        cpu.iy_h = iy_h;
+        cpu.iy_l = iy_l;
+        let (ix_h, ix_l) = read_word(bytes, 17);
+        cpu.ix_h = ix_h;
+        cpu.ix_l = ix_l;
+        cpu.iff2 = bytes[19] & IFF2_BIT != 0;
+        cpu.r = bytes[20];
+        let (a, f) = read_word(bytes, 21);
+        cpu.a = a;
+        cpu.f = f;
+        let (sp_h, sp_l) = read_word(bytes, 23);
+        cpu.sp = ((sp_h as u16) << 8) | sp_l as u16;
+        cpu.im = bytes[25] & 0x03;
+        cpu.halted = false;
+    }
+    machine.io.border = bytes[26] & 0x07;
+
+    if model.has_paging() {
+        let extension = &bytes[SNA_48K_LENGTH..SNA_48K_LENGTH + EXTENSION_LENGTH];
+        let last_7ffd = extension[2];
+        // When the paged bank is 2 or 5 it is stored twice
+        let high = high_banks(last_7ffd);
+        if bytes.len() != SNA_128K_LENGTH + (high.len() - 5) * PAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("SNA de 128K con longitud {} no válida", bytes.len()),
+            ));
+        }
+
+        machine.mem.restore_7ffd(last_7ffd);
+        let ram = bytes[HEADER_LENGTH..SNA_48K_LENGTH].chunks(PAGE_SIZE);
+        for (&bank, data) in low_banks(last_7ffd).iter().zip(ram) {
+            machine.mem.ram_bank_mut(bank).copy_from_slice(data);
+        }
+        let ram = bytes[SNA_48K_LENGTH + EXTENSION_LENGTH..].chunks(PAGE_SIZE);
+        for (&bank, data) in high.iter().zip(ram) {
+            machine.mem.ram_bank_mut(bank).copy_from_slice(data);
+        }
+
+        // PC is stored in the file, nothing was pushed
+        let cpu = &mut machine.cpu;
+        cpu.pc = ((extension[1] as u16) << 8) | extension[0] as u16;
+        cpu.iff1 = cpu.iff2;
+        machine.sync_sound();
+        return Ok(());
+    }
+
+    for (offset, value) in bytes[HEADER_LENGTH..].iter().enumerate() {
+        machine.mem.poke((RAM_START + offset) as u16, *value);
+    }
+
+    // PC was pushed when the snapshot was taken, leave as a RETN would
+    let cpu = &mut machine.cpu;
+    let lo = machine.mem.peek(cpu.sp);
+    let hi = machine.mem.peek(cpu.sp.wrapping_add(1));
+    cpu.pc = ((hi as u16) << 8) | lo as u16;
+    cpu.sp = cpu.sp.wrapping_add(2);
+    cpu.iff1 = cpu.iff2;
+    machine.sync_sound();
+
+    Ok(())
+}
+
+pub fn save<P: AsRef<Path>>(machine: &Machine, path: P) -> io::Result<()> {
+    let bytes = save_bytes(machine);
+    File::create(path)?.write_all(&bytes)
+}
+
+// Builds the snapshot without touching the machine: on a 48K PC is pushed
+// on the stack of the saved RAM image only
+pub fn save_bytes(machine: &Machine) -> Vec<u8> {
+    let cpu = &machine.cpu;
+    let mut ram: Vec<u8> = (RAM_START..RAM_START + RAM_LENGTH)
+        .map(|addr| machine.mem.peek(addr as u16))
+        .collect();
+
+    let pc_hi = (cpu.pc >> 8) as u8;
+    let pc_lo = (cpu.pc & 0xFF) as u8;
+    let is_128k = machine.model.has_paging();
+    let sp = if is_128k {
+        cpu.sp
+    } else {
+        let sp = cpu.sp.wrapping_sub(2);
+        for (addr, value) in [(sp, pc_lo), (sp.wrapping_add(1), pc_hi)].iter() {
+            if *addr as usize >= RAM_START {
+                ram[*addr as usize - RAM_START] = *value;
+            }
+        }
+        sp
+    };
+
+    let mut bytes = Vec::with_capacity(SNA_48K_LENGTH);
+    bytes.push(cpu.i);
+    write_word(&mut bytes, cpu.h_alt, cpu.l_alt);
+    write_word(&mut bytes, cpu.d_alt, cpu.e_alt);
+    write_word(&mut bytes, cpu.b_alt, cpu.c_alt);
+    write_word(&mut bytes, cpu.a_alt, cpu.f_alt);
+    write_word(&mut bytes, cpu.h, cpu.l);
+    write_word(&mut bytes, cpu.d, cpu.e);
+    write_word(&mut bytes, cpu.b, cpu.c);
+    write_word(&mut bytes, cpu.iy_h, cpu.iy_l);
+    write_word(&mut bytes, cpu.ix_h, cpu.ix_l);
+    bytes.push(if cpu.iff2 { IFF2_BIT } else { 0 });
+    bytes.push(cpu.r);
+    write_word(&mut bytes, cpu.a, cpu.f);
+    write_word(&mut bytes, (sp >> 8) as u8, (sp & 0xFF) as u8);
+    bytes.push(cpu.im);
+    bytes.push(machine.io.border);
+    bytes.extend_from_slice(&ram);
+
+    if is_128k {
+        let last_7ffd = machine.mem.last_7ffd();
+        bytes.extend_from_slice(&[pc_lo, pc_hi, last_7ffd, 0]);
+        for bank in high_banks(last_7ffd) {
+            bytes.extend_from_slice(machine.mem.ram_bank(bank));
+        }
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_registers(machine: &mut Machine) {
+        let cpu = &mut machine.cpu;
+        cpu.a = 0x12;
+        cpu.f = 0x34;
+        cpu.b = 0x56;
+        cpu.c = 0x78;
+        cpu.d = 0x9A;
+        cpu.e = 0xBC;
+        cpu.h = 0xDE;
+        cpu.l = 0xF0;
+        cpu.a_alt = 0x21;
+        cpu.f_alt = 0x43;
+        cpu.b_alt = 0x65;
+        cpu.c_alt = 0x87;
+        cpu.d_alt = 0xA9;
+        cpu.e_alt = 0xCB;
+        cpu.h_alt = 0xED;
+        cpu.l_alt = 0x0F;
+        cpu.ix_h = 0x11;
+        cpu.ix_l = 0x22;
+        cpu.iy_h = 0x33;
+        cpu.iy_l = 0x44;
+        cpu.i = 0x3F;
+        cpu.r = 0x55;
+        cpu.sp = 0xFF00;
+        cpu.pc = 0x8123;
+        cpu.im = 1;
+        cpu.iff1 = true;
+        cpu.iff2 = true;
+        machine.io.border = 5;
+    }
+
+    fn assert_registers(saved: &Machine, loaded: &Machine) {
+        let (a, b) = (&saved.cpu, &loaded.cpu);
+        assert_eq!((a.a, a.f, a.b, a.c), (b.a, b.f, b.b, b.c));
+        assert_eq!((a.d, a.e, a.h, a.l), (b.d, b.e, b.h, b.l));
+        assert_eq!(
+            (a.a_alt, a.f_alt, a.b_alt, a.c_alt),
+            (b.a_alt, b.f_alt, b.b_alt, b.c_alt)
+        );
+        assert_eq!(
+            (a.d_alt, a.e_alt, a.h_alt, a.l_alt),
+            (b.d_alt, b.e_alt, b.h_alt, b.l_alt)
+        );
+        assert_eq!(
+            (a.ix_h, a.ix_l, a.iy_h, a.iy_l),
+            (b.ix_h, b.ix_l, b.iy_h, b.iy_l)
+        );
+        assert_eq!(
+            (a.i, a.r, a.im, a.iff1, a.iff2),
+            (b.i, b.r, b.im, b.iff1, b.iff2)
+        );
+        assert_eq!((a.sp, a.pc), (b.sp, b.pc));
+        assert_eq!(saved.io.border, loaded.io.border);
+    }
+
+    #[test]
+    fn round_trip_48k() {
+        let mut machine = Machine::with_model(Model::Spectrum48K);
+        set_registers(&mut machine);
+        for addr in RAM_START..RAM_START + RAM_LENGTH {
+            machine.mem.poke(addr as u16, ((addr * 13) >> 3) as u8);
+        }
+        let bytes = save_bytes(&machine);
+        assert_eq!(bytes.len(), SNA_48K_LENGTH);
+
+        let mut loaded = Machine::with_model(Model::Spectrum128K);
+        load_bytes(&mut loaded, &bytes).unwrap();
+        assert_eq!(loaded.model, Model::Spectrum48K);
+        assert_registers(&machine, &loaded);
+        // All but the two bytes PC was pushed into
+        for addr in RAM_START..RAM_START + RAM_LENGTH {
+            if addr != 0xFEFE && addr != 0xFEFF {
+                assert_eq!(loaded.mem.peek(addr as u16), machine.mem.peek(addr as u16));
+            }
+        }
+        assert_eq!(loaded.mem.peek(0xFEFE), 0x23);
+        assert_eq!(loaded.mem.peek(0xFEFF), 0x81);
+    }
+
+    fn round_trip_128k(last_7ffd: u8, length: usize) {
+        let mut machine = Machine::with_model(Model::Spectrum128K);
+        set_registers(&mut machine);
+        machine.mem.restore_7ffd(last_7ffd);
+        for bank in 0..8 {
+            for (i, byte) in machine.mem.ram_bank_mut(bank).iter_mut().enumerate() {
+                *byte = (i as u8) ^ (bank as u8 * 0x11);
+            }
+        }
+        let bytes = save_bytes(&machine);
+        assert_eq!(bytes.len(), length);
+
+        let mut loaded = Machine::with_model(Model::Spectrum48K);
+        load_bytes(&mut loaded, &bytes).unwrap();
+        assert_eq!(loaded.model, Model::Spectrum128K);
+        assert_eq!(loaded.mem.last_7ffd(), last_7ffd);
+        assert_registers(&machine, &loaded);
+        for bank in 0..8 {
+            assert_eq!(loaded.mem.ram_bank(bank), machine.mem.ram_bank(bank));
+        }
+    }
+
+    #[test]
+    fn round_trip_128k_paged_bank() {
+        round_trip_128k(0x13, SNA_128K_LENGTH);
+    }
+
+    #[test]
+    fn round_trip_128k_bank_stored_twice() {
+        round_trip_128k(0x05, SNA_128K_LONG_LENGTH);
+    }
+
+    #[test]
+    fn rejects_bad_length() {
+        let mut machine = Machine::new();
+        assert!(load_bytes(&mut machine, &[0; 100]).is_err());
+    }
+}
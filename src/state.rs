@@ -0,0 +1,316 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use machine::{Machine, Model};
+
+// Save states hold the machine exactly as the emulator keeps it, down to
+// the instruction half decoded, so running on from one is the same as never
+// having stopped. Unlike snapshots they are only meant for this emulator
+// and only for the version that wrote them.
+
+const MAGIC: &[u8; 8] = b"Z80STATE";
+const VERSION: u8 = 3;
+
+const MODELS: [Model; 6] = [
+    Model::Spectrum16K,
+    Model::Spectrum48K,
+    Model::Spectrum128K,
+    Model::Pentagon128,
+    Model::SpectrumPlus2A,
+    Model::SpectrumPlus3,
+];
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Little endian values appended one after another
+#[derive(Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { bytes: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+
+    // Fixed size data, whose length the reader knows
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // Data of any length, preceded by it
+    pub fn block(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return Err(invalid_data("estado incompleto"));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn block(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
+
+// Parts of the machine that go into a save state. Settings that come from
+// the model or the command line, such as the sample rate, are not saved
+// and neither are the files being written.
+pub trait SaveState {
+    fn write_state(&self, state: &mut StateWriter);
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()>;
+}
+
+pub fn save_bytes(machine: &Machine) -> Vec<u8> {
+    let mut state = StateWriter::new();
+    state.bytes(MAGIC);
+    state.u8(VERSION);
+    let model = MODELS.iter().position(|&m| m == machine.model).unwrap();
+    state.u8(model as u8);
+    machine.write_state(&mut state);
+    state.into_bytes()
+}
+
+// The ROMs are not in the state, the machine keeps the ones it has if the
+// model is the same
+pub fn load_bytes(machine: &mut Machine, bytes: &[u8]) -> io::Result<()> {
+    let mut state = StateReader::new(bytes);
+    if state.bytes(MAGIC.len())? != MAGIC {
+        return Err(invalid_data("no es un estado guardado"));
+    }
+    if state.u8()? != VERSION {
+        return Err(invalid_data("estado guardado por otra versión"));
+    }
+    let model = *MODELS
+        .get(state.u8()? as usize)
+        .ok_or_else(|| invalid_data("modelo desconocido"))?;
+    machine.set_model(model);
+    machine.read_state(&mut state)?;
+    if !state.finished() {
+        return Err(invalid_data("datos de más al final del estado"));
+    }
+    Ok(())
+}
+
+pub fn save<P: AsRef<Path>>(machine: &Machine, path: P) -> io::Result<()> {
+    File::create(path)?.write_all(&save_bytes(machine))
+}
+
+pub fn load<P: AsRef<Path>>(machine: &mut Machine, path: P) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    load_bytes(machine, &bytes)
+}
+
+// States taken every `interval` frames, the oldest dropped once there are
+// `capacity` of them
+pub struct RewindBuffer {
+    states: VecDeque<(u64, Vec<u8>)>,
+    interval: u64,
+    capacity: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u64, capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            states: VecDeque::with_capacity(capacity),
+            interval: interval.max(1),
+            capacity,
+        }
+    }
+
+    // Call once per frame
+    pub fn record(&mut self, machine: &Machine) {
+        if self.capacity == 0 || !machine.frames.is_multiple_of(self.interval) {
+            return;
+        }
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states.push_back((machine.frames, save_bytes(machine)));
+    }
+
+    // Goes back to the newest state at least `frames` old, or the oldest
+    // one there is. Returns false when there is nothing to go back to.
+    pub fn rewind(&mut self, machine: &mut Machine, frames: u64) -> io::Result<bool> {
+        let target = machine.frames.saturating_sub(frames);
+        while self.states.len() > 1 && self.states.back().is_some_and(|s| s.0 > target) {
+            self.states.pop_back();
+        }
+        // The state kept stays, so the next rewind can go further back
+        match self.states.back() {
+            Some((_, bytes)) => load_bytes(machine, bytes).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn booted() -> Machine {
+        let mut machine = Machine::new();
+        for _ in 0..100 {
+            machine.run_frame();
+        }
+        machine
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut machine = booted();
+        let bytes = save_bytes(&machine);
+        let mut loaded = Machine::with_model(Model::Spectrum128K);
+        load_bytes(&mut loaded, &bytes).unwrap();
+        assert_eq!(loaded.model, Model::Spectrum48K);
+        assert_eq!(save_bytes(&loaded), bytes);
+
+        // And both run on the same
+        for _ in 0..10 {
+            machine.run_frame();
+            loaded.run_frame();
+        }
+        assert_eq!(save_bytes(&loaded), save_bytes(&machine));
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let machine = booted();
+        let bytes = save_bytes(&machine);
+        let mut other = Machine::with_model(Model::Spectrum128K);
+        let before = save_bytes(&other);
+        for &version in &[0, VERSION - 1, VERSION + 1] {
+            let mut old = bytes.clone();
+            old[MAGIC.len()] = version;
+            let error = load_bytes(&mut other, &old).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            // Nothing is touched
+            assert_eq!(save_bytes(&other), before);
+        }
+        let error = load_bytes(&mut other, &bytes[..bytes.len() / 2]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rewind_keeps_the_newest_states() {
+        let mut machine = Machine::new();
+        let mut rewind = RewindBuffer::new(2, 3);
+        assert!(!rewind.rewind(&mut machine, 1).unwrap());
+        for _ in 0..10 {
+            machine.run_frame();
+            rewind.record(&machine);
+        }
+        // Frames 2 and 4 have been dropped to keep 6, 8 and 10
+        assert_eq!(
+            rewind.states.iter().map(|s| s.0).collect::<Vec<_>>(),
+            [6, 8, 10]
+        );
+
+        assert!(rewind.rewind(&mut machine, 3).unwrap());
+        assert_eq!(machine.frames, 6);
+        // The oldest is as far as it goes, and it stays
+        assert!(rewind.rewind(&mut machine, 100).unwrap());
+        assert_eq!(machine.frames, 6);
+        assert_eq!(rewind.states.len(), 1);
+
+        rewind.clear();
+        assert!(!rewind.rewind(&mut machine, 1).unwrap());
+    }
+}
@@ -0,0 +1,543 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use ay;
+use joystick::{Joystick, JoystickKind};
+use machine::{Machine, Model};
+use tape::TapReader;
+use zlib;
+
+const MAGIC: &[u8; 4] = b"ZXST";
+const MAJOR_VERSION: u8 = 1;
+const MINOR_VERSION: u8 = 4;
+const HEADER_LENGTH: usize = 8;
+
+const MACHINE_16K: u8 = 0;
+const MACHINE_48K: u8 = 1;
+const MACHINE_128K: u8 = 2;
+const MACHINE_PLUS2: u8 = 3;
+const MACHINE_PLUS2A: u8 = 4;
+const MACHINE_PLUS3: u8 = 5;
+const MACHINE_PENTAGON128: u8 = 7;
+
+const Z80R_LENGTH: usize = 37;
+const SPCR_LENGTH: usize = 8;
+const AY_LENGTH: usize = 2 + ay::REGISTER_COUNT;
+const KEYB_LENGTH: usize = 5;
+// Up to the file extension, the data follows
+const TAPE_HEADER_LENGTH: usize = 28;
+
+// Flags of the Z80R block
+const HALTED: u8 = 0x02;
+// Flags of the AY block: an AY on a 48K, or the one built into a 128K
+const AY_FLAG_48K: u8 = 0x02;
+// Flags of the RAMP block
+const RAMP_COMPRESSED: u16 = 0x0001;
+// Flags of the TAPE block: the file is in the block rather than named by
+// it, and compressed
+const TAPE_EMBEDDED: u16 = 0x0001;
+const TAPE_COMPRESSED: u16 = 0x0002;
+
+// Joysticks of the KEYB block
+const JOYSTICK_KEMPSTON: u8 = 0;
+const JOYSTICK_FULLER: u8 = 1;
+const JOYSTICK_CURSOR: u8 = 2;
+const JOYSTICK_SINCLAIR1: u8 = 3;
+const JOYSTICK_SINCLAIR2: u8 = 4;
+const JOYSTICK_NONE: u8 = 8;
+
+const PAGE_LENGTH: usize = 16 * 1024;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_word(bytes: &[u8], offset: usize) -> u16 {
+    ((bytes[offset + 1] as u16) << 8) | bytes[offset] as u16
+}
+
+fn read_dword(bytes: &[u8], offset: usize) -> u32 {
+    (read_word(bytes, offset + 2) as u32) << 16 | read_word(bytes, offset) as u32
+}
+
+fn write_word(bytes: &mut Vec<u8>, word: u16) {
+    bytes.push((word & 0xFF) as u8);
+    bytes.push((word >> 8) as u8);
+}
+
+fn write_pair(bytes: &mut Vec<u8>, hi: u8, lo: u8) {
+    bytes.push(lo);
+    bytes.push(hi);
+}
+
+fn write_dword(bytes: &mut Vec<u8>, dword: u32) {
+    write_word(bytes, (dword & 0xFFFF) as u16);
+    write_word(bytes, (dword >> 16) as u16);
+}
+
+fn write_block(bytes: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(id);
+    write_dword(bytes, data.len() as u32);
+    bytes.extend_from_slice(data);
+}
+
+pub fn load<P: AsRef<Path>>(machine: &mut Machine, path: P) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    load_bytes(machine, &bytes)
+}
+
+pub fn load_bytes(machine: &mut Machine, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() < HEADER_LENGTH || &bytes[..4] != MAGIC {
+        return Err(invalid_data("no es un fichero SZX".to_string()));
+    }
+    let machine_id = bytes[6];
+    let model = match machine_id {
+        MACHINE_16K => Model::Spectrum16K,
+        MACHINE_48K => Model::Spectrum48K,
+        MACHINE_128K | MACHINE_PLUS2 => Model::Spectrum128K,
+        MACHINE_PLUS2A => Model::SpectrumPlus2A,
+        MACHINE_PLUS3 => Model::SpectrumPlus3,
+        MACHINE_PENTAGON128 => Model::Pentagon128,
+        _ => {
+            return Err(invalid_data(format!(
+                "modelo {} del SZX no soportado",
+                machine_id
+            )))
+        }
+    };
+    machine.set_model(model);
+
+    let mut data = &bytes[HEADER_LENGTH..];
+    while data.len() >= 8 {
+        let id = &data[..4];
+        let len = read_dword(data, 4) as usize;
+        if data.len() < 8 + len {
+            return Err(invalid_data(format!(
+                "bloque {} incompleto",
+                String::from_utf8_lossy(id)
+            )));
+        }
+        let block = &data[8..8 + len];
+        match id {
+            b"Z80R" => load_z80r(machine, block)?,
+            b"SPCR" => load_spcr(machine, block)?,
+            b"RAMP" => load_ramp(machine, block)?,
+            b"AY\0\0" => load_ay(machine, block)?,
+            b"KEYB" => load_keyb(machine, block)?,
+            b"TAPE" => load_tape(machine, block)?,
+            // Blocks for hardware this emulator does not have are skipped
+            _ => {}
+        }
+        data = &data[8 + len..];
+    }
+
+    machine.sync_sound();
+    Ok(())
+}
+
+fn load_z80r(machine: &mut Machine, block: &[u8]) -> io::Result<()> {
+    if block.len() < Z80R_LENGTH {
+        return Err(invalid_data("bloque Z80R incompleto".to_string()));
+    }
+    let cpu = &mut machine.cpu;
+    cpu.f = block[0];
+    cpu.a = block[1];
+    cpu.c = block[2];
+    cpu.b = block[3];
+    cpu.e = block[4];
+    cpu.d = block[5];
+    cpu.l = block[6];
+    cpu.h = block[7];
+    cpu.f_alt = block[8];
+    cpu.a_alt = block[9];
+    cpu.c_alt = block[10];
+    cpu.b_alt = block[11];
+    cpu.e_alt = block[12];
+    cpu.d_alt = block[13];
+    cpu.l_alt = block[14];
+    cpu.h_alt = block[15];
+    cpu.ix_l = block[16];
+    cpu.ix_h = block[17];
+    cpu.iy_l = block[18];
+    cpu.iy_h = block[19];
+    cpu.sp = read_word(block, 20);
+    cpu.pc = read_word(block, 22);
+    cpu.i = block[24];
+    cpu.r = block[25];
+    cpu.iff1 = block[26] != 0;
+    cpu.iff2 = block[27] != 0;
+    cpu.im = block[28] & 0x03;
+    cpu.t_states = machine.frame_start + read_dword(block, 29) as u64;
+    cpu.halted = block[34] & HALTED != 0;
+    cpu.memptr = read_word(block, 35);
+    Ok(())
+}
+
+fn load_spcr(machine: &mut Machine, block: &[u8]) -> io::Result<()> {
+    if block.len() < SPCR_LENGTH {
+        return Err(invalid_data("bloque SPCR incompleto".to_string()));
+    }
+    let last_fe = block[3];
+    machine.mem.restore_7ffd(block[1]);
+    machine.mem.restore_1ffd(block[2]);
+    machine.io.border = block[0] & 0x07;
+    machine.io.mic = last_fe & 0x08 != 0;
+    machine.io.ear = last_fe & 0x10 != 0;
+    Ok(())
+}
+
+fn load_ay(machine: &mut Machine, block: &[u8]) -> io::Result<()> {
+    if block.len() < AY_LENGTH {
+        return Err(invalid_data("bloque AY incompleto".to_string()));
+    }
+    if machine.io.ay.is_none() {
+        machine.attach_ay();
+    }
+    if let Some(ref mut ay) = machine.io.ay {
+        for (reg, value) in block[2..AY_LENGTH].iter().enumerate() {
+            ay.set_register(reg, *value);
+        }
+        ay.select_register(block[1]);
+    }
+    Ok(())
+}
+
+// The keyboard is always an issue 3 one, so only the joystick is used
+fn load_keyb(machine: &mut Machine, block: &[u8]) -> io::Result<()> {
+    if block.len() < KEYB_LENGTH {
+        return Err(invalid_data("bloque KEYB incompleto".to_string()));
+    }
+    let kind = match block[4] {
+        JOYSTICK_KEMPSTON => JoystickKind::Kempston,
+        JOYSTICK_FULLER => JoystickKind::Fuller,
+        JOYSTICK_CURSOR => JoystickKind::Cursor,
+        JOYSTICK_SINCLAIR1 => JoystickKind::Sinclair1,
+        JOYSTICK_SINCLAIR2 => JoystickKind::Sinclair2,
+        _ => return Ok(()),
+    };
+    // Joysticks already plugged in stay
+    let joysticks = &mut machine.io.joysticks;
+    if !joysticks.iter().any(|joystick| joystick.kind == kind) {
+        joysticks.push(Joystick::new(kind));
+    }
+    Ok(())
+}
+
+// Only TAP files can be loaded, embedded or named by the block
+fn load_tape(machine: &mut Machine, block: &[u8]) -> io::Result<()> {
+    if block.len() < TAPE_HEADER_LENGTH {
+        return Err(invalid_data("bloque TAPE incompleto".to_string()));
+    }
+    let position = read_word(block, 0) as usize;
+    let flags = read_word(block, 2);
+    let compressed_length = read_dword(block, 8) as usize;
+    let extension = &block[12..TAPE_HEADER_LENGTH];
+    let extension = String::from_utf8_lossy(extension.split(|&byte| byte == 0).next().unwrap());
+    let data = &block[TAPE_HEADER_LENGTH..];
+
+    let mut tap = if flags & TAPE_EMBEDDED != 0 {
+        if !extension.eq_ignore_ascii_case("tap") {
+            eprintln!("La cinta {} del SZX no está soportada", extension);
+            return Ok(());
+        }
+        let bytes = if flags & TAPE_COMPRESSED != 0 {
+            zlib::decompress(&data[..compressed_length.min(data.len())])?
+        } else {
+            data.to_vec()
+        };
+        TapReader::from_bytes(&bytes)?
+    } else {
+        let name = String::from_utf8_lossy(data.split(|&byte| byte == 0).next().unwrap());
+        if !name.to_lowercase().ends_with(".tap") {
+            eprintln!("La cinta {} del SZX no está soportada", name);
+            return Ok(());
+        }
+        match TapReader::open(name.as_ref()) {
+            Ok(tap) => tap,
+            Err(e) => {
+                eprintln!("No he podido abrir la cinta {} del SZX: {}", name, e);
+                return Ok(());
+            }
+        }
+    };
+    tap.set_position(position);
+    machine.load_trap = Some(tap);
+    Ok(())
+}
+
+fn load_ramp(machine: &mut Machine, block: &[u8]) -> io::Result<()> {
+    if block.len() < 3 {
+        return Err(invalid_data("bloque RAMP incompleto".to_string()));
+    }
+    let flags = read_word(block, 0);
+    let number = block[2];
+    let page = if flags & RAMP_COMPRESSED != 0 {
+        zlib::decompress(&block[3..])?
+    } else {
+        block[3..].to_vec()
+    };
+    if page.len() != PAGE_LENGTH {
+        return Err(invalid_data(format!(
+            "página {} de {} bytes",
+            number,
+            page.len()
+        )));
+    }
+
+    let bank = number as usize;
+    if machine.model.ram_banks().contains(&bank) {
+        machine.mem.ram_bank_mut(bank).copy_from_slice(&page);
+    }
+    Ok(())
+}
+
+pub fn save<P: AsRef<Path>>(machine: &Machine, path: P) -> io::Result<()> {
+    let bytes = save_bytes(machine);
+    File::create(path)?.write_all(&bytes)
+}
+
+// RAM pages are stored uncompressed
+pub fn save_bytes(machine: &Machine) -> Vec<u8> {
+    let cpu = &machine.cpu;
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    let machine_id = match machine.model {
+        Model::Spectrum16K => MACHINE_16K,
+        Model::Spectrum48K => MACHINE_48K,
+        Model::Spectrum128K => MACHINE_128K,
+        Model::Pentagon128 => MACHINE_PENTAGON128,
+        Model::SpectrumPlus2A => MACHINE_PLUS2A,
+        Model::SpectrumPlus3 => MACHINE_PLUS3,
+    };
+    bytes.extend_from_slice(&[MAJOR_VERSION, MINOR_VERSION, machine_id, 0]);
+
+    let mut z80r = Vec::with_capacity(Z80R_LENGTH);
+    write_pair(&mut z80r, cpu.a, cpu.f);
+    write_pair(&mut z80r, cpu.b, cpu.c);
+    write_pair(&mut z80r, cpu.d, cpu.e);
+    write_pair(&mut z80r, cpu.h, cpu.l);
+    write_pair(&mut z80r, cpu.a_alt, cpu.f_alt);
+    write_pair(&mut z80r, cpu.b_alt, cpu.c_alt);
+    write_pair(&mut z80r, cpu.d_alt, cpu.e_alt);
+    write_pair(&mut z80r, cpu.h_alt, cpu.l_alt);
+    write_pair(&mut z80r, cpu.ix_h, cpu.ix_l);
+    write_pair(&mut z80r, cpu.iy_h, cpu.iy_l);
+    write_word(&mut z80r, cpu.sp);
+    write_word(&mut z80r, cpu.pc);
+    z80r.push(cpu.i);
+    z80r.push(cpu.r);
+    z80r.push(cpu.iff1 as u8);
+    z80r.push(cpu.iff2 as u8);
+    z80r.push(cpu.im);
+    let frame_t_states = cpu.t_states.saturating_sub(machine.frame_start) as u32;
+    write_word(&mut z80r, (frame_t_states & 0xFFFF) as u16);
+    write_word(&mut z80r, (frame_t_states >> 16) as u16);
+    z80r.push(machine.model.timing().interrupt_length as u8);
+    z80r.push(if cpu.halted { HALTED } else { 0 });
+    write_word(&mut z80r, cpu.memptr);
+    write_block(&mut bytes, b"Z80R", &z80r);
+
+    let io = &machine.io;
+    let last_fe = io.border | if io.mic { 0x08 } else { 0 } | if io.ear { 0x10 } else { 0 };
+    let last_7ffd = machine.mem.last_7ffd();
+    let last_1ffd = machine.mem.last_1ffd();
+    write_block(
+        &mut bytes,
+        b"SPCR",
+        &[io.border, last_7ffd, last_1ffd, last_fe, 0, 0, 0, 0],
+    );
+
+    // Only the first joystick fits
+    let joystick = match machine.io.joysticks.first().map(|joystick| joystick.kind) {
+        Some(JoystickKind::Kempston) => JOYSTICK_KEMPSTON,
+        Some(JoystickKind::Fuller) => JOYSTICK_FULLER,
+        Some(JoystickKind::Cursor) => JOYSTICK_CURSOR,
+        Some(JoystickKind::Sinclair1) => JOYSTICK_SINCLAIR1,
+        Some(JoystickKind::Sinclair2) => JOYSTICK_SINCLAIR2,
+        None => JOYSTICK_NONE,
+    };
+    let mut keyb = Vec::with_capacity(KEYB_LENGTH);
+    write_dword(&mut keyb, 0);
+    keyb.push(joystick);
+    write_block(&mut bytes, b"KEYB", &keyb);
+
+    if let Some(ref ay) = machine.io.ay {
+        let flags = if machine.model.has_ay() { 0 } else { AY_FLAG_48K };
+        let mut block = vec![flags, ay.selected_register()];
+        block.extend((0..ay::REGISTER_COUNT).map(|reg| ay.register(reg)));
+        write_block(&mut bytes, b"AY\0\0", &block);
+    }
+
+    for &bank in machine.model.ram_banks() {
+        let mut ramp = Vec::with_capacity(PAGE_LENGTH + 3);
+        write_word(&mut ramp, 0);
+        ramp.push(bank as u8);
+        ramp.extend_from_slice(machine.mem.ram_bank(bank));
+        write_block(&mut bytes, b"RAMP", &ramp);
+    }
+
+    // The tape being loaded goes in whole, so it does not depend on files
+    // around the snapshot
+    if let Some(ref tap) = machine.load_trap {
+        let tap_bytes = tap.to_bytes();
+        let compressed = zlib::compress(&tap_bytes);
+        let mut block = Vec::with_capacity(TAPE_HEADER_LENGTH + compressed.len());
+        write_word(&mut block, tap.position() as u16);
+        write_word(&mut block, TAPE_EMBEDDED | TAPE_COMPRESSED);
+        write_dword(&mut block, tap_bytes.len() as u32);
+        write_dword(&mut block, compressed.len() as u32);
+        let mut extension = [0; 16];
+        extension[..3].copy_from_slice(b"tap");
+        block.extend_from_slice(&extension);
+        block.extend_from_slice(&compressed);
+        write_block(&mut bytes, b"TAPE", &block);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(machine: &mut Machine) {
+        let cpu = &mut machine.cpu;
+        cpu.a = 0x12;
+        cpu.f = 0x34;
+        cpu.b = 0x56;
+        cpu.c = 0x78;
+        cpu.h_alt = 0xED;
+        cpu.l_alt = 0x0F;
+        cpu.ix_h = 0x11;
+        cpu.iy_l = 0x44;
+        cpu.i = 0x3F;
+        cpu.r = 0xD5;
+        cpu.sp = 0xFF00;
+        cpu.pc = 0x8123;
+        cpu.im = 2;
+        cpu.iff1 = true;
+        cpu.memptr = 0xBEEF;
+        cpu.halted = true;
+        cpu.t_states = machine.frame_start + 12_345;
+        machine.io.border = 6;
+        machine.io.ear = true;
+        for &bank in machine.model.ram_banks() {
+            for (i, byte) in machine.mem.ram_bank_mut(bank).iter_mut().enumerate() {
+                *byte = (i as u8) ^ (bank as u8 * 0x21);
+            }
+        }
+    }
+
+    fn round_trip(machine: &Machine) -> Machine {
+        let bytes = save_bytes(machine);
+        let mut loaded = Machine::new();
+        load_bytes(&mut loaded, &bytes).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn round_trip_128k() {
+        let mut machine = Machine::with_model(Model::Spectrum128K);
+        fill(&mut machine);
+        machine.mem.restore_7ffd(0x13);
+        if let Some(ref mut ay) = machine.io.ay {
+            ay.set_register(8, 0x0F);
+            ay.select_register(8);
+        }
+        let loaded = round_trip(&machine);
+
+        assert_eq!(loaded.model, Model::Spectrum128K);
+        let (a, b) = (&machine.cpu, &loaded.cpu);
+        assert_eq!((a.a, a.f, a.b, a.c), (b.a, b.f, b.b, b.c));
+        assert_eq!(
+            (a.h_alt, a.l_alt, a.ix_h, a.iy_l),
+            (b.h_alt, b.l_alt, b.ix_h, b.iy_l)
+        );
+        assert_eq!(
+            (a.i, a.r, a.im, a.iff1, a.iff2),
+            (b.i, b.r, b.im, b.iff1, b.iff2)
+        );
+        assert_eq!(
+            (a.sp, a.pc, a.memptr, a.halted),
+            (b.sp, b.pc, b.memptr, b.halted)
+        );
+        assert_eq!(
+            a.t_states - machine.frame_start,
+            b.t_states - loaded.frame_start
+        );
+        assert_eq!((loaded.io.border, loaded.io.ear), (6, true));
+        assert_eq!(loaded.mem.last_7ffd(), 0x13);
+        for &bank in machine.model.ram_banks() {
+            assert_eq!(loaded.mem.ram_bank(bank), machine.mem.ram_bank(bank));
+        }
+        let ay = loaded.io.ay.as_ref().unwrap();
+        assert_eq!((ay.register(8), ay.selected_register()), (0x0F, 8));
+    }
+
+    #[test]
+    fn round_trip_48k() {
+        let mut machine = Machine::with_model(Model::Spectrum48K);
+        fill(&mut machine);
+        let loaded = round_trip(&machine);
+        assert_eq!(loaded.model, Model::Spectrum48K);
+        assert!(loaded.io.ay.is_none());
+        for &bank in machine.model.ram_banks() {
+            assert_eq!(loaded.mem.ram_bank(bank), machine.mem.ram_bank(bank));
+        }
+    }
+
+    #[test]
+    fn keyb_joystick() {
+        let mut machine = Machine::with_model(Model::Spectrum48K);
+        machine
+            .io
+            .joysticks
+            .push(Joystick::new(JoystickKind::Sinclair2));
+        let loaded = round_trip(&machine);
+        let kinds: Vec<_> = loaded.io.joysticks.iter().map(|j| j.kind).collect();
+        assert_eq!(kinds, [JoystickKind::Sinclair2]);
+
+        // Plugged once only
+        let mut machine = Machine::with_model(Model::Spectrum48K);
+        machine
+            .io
+            .joysticks
+            .push(Joystick::new(JoystickKind::Kempston));
+        let bytes = save_bytes(&machine);
+        load_bytes(&mut machine, &bytes).unwrap();
+        assert_eq!(machine.io.joysticks.len(), 1);
+    }
+
+    #[test]
+    fn tape_embedded() {
+        let mut tap_bytes = Vec::new();
+        for (flag, data) in [(0x00, &b"header"[..]), (0xFF, &[0xED; 300][..])].iter() {
+            let block = ::tape::make_block(*flag, data);
+            tap_bytes.extend_from_slice(&[block.len() as u8, (block.len() >> 8) as u8]);
+            tap_bytes.extend_from_slice(&block);
+        }
+        let mut machine = Machine::with_model(Model::Spectrum48K);
+        let mut tap = TapReader::from_bytes(&tap_bytes).unwrap();
+        tap.next_block();
+        machine.load_trap = Some(tap);
+
+        let loaded = round_trip(&machine);
+        let tap = loaded.load_trap.as_ref().unwrap();
+        assert_eq!(tap.position(), 1);
+        assert_eq!(tap.to_bytes(), tap_bytes);
+
+        // No tape, no block
+        let loaded = round_trip(&Machine::new());
+        assert!(loaded.load_trap.is_none());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut machine = Machine::new();
+        assert!(load_bytes(&mut machine, b"ZXSX\x01\x04\x01\x00").is_err());
+        assert!(load_bytes(&mut machine, b"ZXST\x01\x04\x63\x00").is_err());
+    }
+}
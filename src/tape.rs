@@ -0,0 +1,451 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use memory::Memory;
+use z80::Z80;
+
+// Entry point of the ROM routine SA-BYTES
+pub const SA_BYTES: u16 = 0x04C2;
+// Entry point of the ROM routine LD-BYTES
+pub const LD_BYTES: u16 = 0x0556;
+// RET just before SA/LD-RET (0x053F). The traps leave through it, which
+// goes straight back to the caller of SA-BYTES or LD-BYTES, so they do
+// themselves what SA/LD-RET would: restore the border from BORDCR,
+// enable interrupts and leave the carry flag set on success.
+const RET_BEFORE_SA_LD_RET: u16 = 0x053E;
+// System variable with the border colour in bits 3 to 5
+const BORDCR: u16 = 0x5C48;
+const CARRY: u8 = 0x01;
+
+// Pulse lengths, in T-states, used by the ROM saver
+const PILOT_PULSE: u64 = 2168;
+const ZERO_PULSE: u64 = 855;
+const ONE_PULSE: u64 = 1710;
+
+// Pilot pulses needed before accepting a sync pulse
+const MIN_PILOT_PULSES: u32 = 256;
+// Any pulse longer than this ends the block being recorded
+const MAX_PULSE: u64 = 3 * PILOT_PULSE;
+
+// Builds the tape block for `data`: flag byte, data and checksum
+pub fn make_block(flag: u8, data: &[u8]) -> Vec<u8> {
+    let mut block = Vec::with_capacity(data.len() + 2);
+    block.push(flag);
+    block.extend_from_slice(data);
+    let parity = checksum(&block);
+    block.push(parity);
+    block
+}
+
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |parity, byte| parity ^ byte)
+}
+
+pub struct TapWriter {
+    file: File,
+}
+
+impl TapWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<TapWriter> {
+        let file = File::create(path)?;
+        Ok(TapWriter { file })
+    }
+
+    // Appends a block that already carries its flag byte and checksum
+    pub fn write_block(&mut self, block: &[u8]) -> io::Result<()> {
+        let len = block.len() as u16;
+        self.file
+            .write_all(&[(len & 0xFF) as u8, (len >> 8) as u8])?;
+        self.file.write_all(block)?;
+        self.file.flush()
+    }
+}
+
+// Blocks of a TAP file, each with its flag byte and checksum, handed to
+// LD-BYTES in turn
+pub struct TapReader {
+    blocks: Vec<Vec<u8>>,
+    next: usize,
+}
+
+impl TapReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<TapReader> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        TapReader::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<TapReader> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset + 2 <= bytes.len() {
+            let len = ((bytes[offset + 1] as usize) << 8) | bytes[offset] as usize;
+            offset += 2;
+            if offset + len > bytes.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bloque de la cinta incompleto",
+                ));
+            }
+            blocks.push(bytes[offset..offset + len].to_vec());
+            offset += len;
+        }
+        Ok(TapReader { blocks, next: 0 })
+    }
+
+    pub fn next_block(&mut self) -> Option<&[u8]> {
+        let block = self.blocks.get(self.next)?;
+        self.next += 1;
+        Some(block)
+    }
+
+    pub fn rewind(&mut self) {
+        self.next = 0;
+    }
+
+    // Index of the next block, for save states
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    pub fn set_position(&mut self, block: usize) {
+        self.next = block.min(self.blocks.len());
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.blocks.len()
+    }
+
+    // The TAP file the blocks came from
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for block in self.blocks.iter() {
+            bytes.extend_from_slice(&[(block.len() & 0xFF) as u8, (block.len() >> 8) as u8]);
+            bytes.extend_from_slice(block);
+        }
+        bytes
+    }
+}
+
+// Feeds the next block to LD-BYTES and leaves the routine. On entry A
+// holds the flag byte expected, IX the address, DE the length and the
+// carry flag tells LOAD from VERIFY. Returns false, leaving the ROM to
+// wait for a signal, when the tape has run out.
+pub fn load_trap(cpu: &mut Z80, mem: &mut Memory, border: &mut u8, tap: &mut TapReader) -> bool {
+    let block = match tap.next_block() {
+        Some(block) => block,
+        None => return false,
+    };
+    let mut addr = ((cpu.ix_h as u16) << 8) | cpu.ix_l as u16;
+    let mut len = ((cpu.d as u16) << 8) | cpu.e as u16;
+    let verify = cpu.f & CARRY == 0;
+
+    let mut ok = block.first() == Some(&cpu.a);
+    if ok {
+        // Data between the flag byte and the checksum
+        let data = &block[1..block.len().saturating_sub(1).max(1)];
+        for &value in data.iter().take(len as usize) {
+            if verify {
+                ok &= mem.peek(addr) == value;
+            } else {
+                mem.poke(addr, value);
+            }
+            addr = addr.wrapping_add(1);
+            len -= 1;
+        }
+        ok &= len == 0 && checksum(block) == 0;
+    }
+
+    cpu.ix_h = (addr >> 8) as u8;
+    cpu.ix_l = (addr & 0xFF) as u8;
+    cpu.d = (len >> 8) as u8;
+    cpu.e = (len & 0xFF) as u8;
+    leave(cpu, mem, border, ok);
+    true
+}
+
+// What SA/LD-RET does before returning, the break key aside
+fn leave(cpu: &mut Z80, mem: &Memory, border: &mut u8, ok: bool) {
+    *border = (mem.peek(BORDCR) & 0x38) >> 3;
+    if ok {
+        cpu.f |= CARRY;
+    } else {
+        cpu.f &= !CARRY;
+    }
+    cpu.iff1 = true;
+    cpu.iff2 = true;
+    cpu.pc = RET_BEFORE_SA_LD_RET;
+}
+
+// Saves the block SA-BYTES has been asked for and leaves the routine.
+// On entry A holds the flag byte, IX the start address and DE the length.
+pub fn save_trap(
+    cpu: &mut Z80,
+    mem: &Memory,
+    border: &mut u8,
+    tap: &mut TapWriter,
+) -> io::Result<()> {
+    let start = ((cpu.ix_h as u16) << 8) | cpu.ix_l as u16;
+    let len = ((cpu.d as u16) << 8) | cpu.e as u16;
+
+    let data: Vec<u8> = (0..len).map(|i| mem.peek(start.wrapping_add(i))).collect();
+    tap.write_block(&make_block(cpu.a, &data))?;
+
+    leave(cpu, mem, border, true);
+    Ok(())
+}
+
+enum RecorderState {
+    Pilot,
+    Sync,
+    Data,
+}
+
+// Rebuilds tape blocks from the edges a program produces on the MIC bit,
+// so savers that do not go through the ROM can be captured as well.
+// The bit threshold is scaled from the measured pilot pulse to cope with
+// savers that use faster timings than the ROM.
+pub struct MicRecorder {
+    tap: TapWriter,
+    state: RecorderState,
+    last_edge: u64,
+
+    pilot_pulses: u32,
+    pilot_length: u64,
+
+    half_bit: Option<u64>,
+    bit_count: u8,
+    byte: u8,
+    bytes: Vec<u8>,
+}
+
+impl MicRecorder {
+    pub fn new(tap: TapWriter) -> MicRecorder {
+        MicRecorder {
+            tap,
+            state: RecorderState::Pilot,
+            last_edge: 0,
+            pilot_pulses: 0,
+            pilot_length: 0,
+            half_bit: None,
+            bit_count: 0,
+            byte: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    pub fn edge(&mut self, t_states: u64) {
+        let pulse = t_states.saturating_sub(self.last_edge);
+        self.last_edge = t_states;
+
+        if pulse > MAX_PULSE {
+            self.finish();
+            return;
+        }
+
+        match self.state {
+            RecorderState::Pilot => self.pilot_pulse(pulse),
+            RecorderState::Sync => {
+                // Second sync pulse, the data starts with the next edge
+                self.state = RecorderState::Data;
+            }
+            RecorderState::Data => self.data_pulse(pulse),
+        }
+    }
+
+    fn pilot_pulse(&mut self, pulse: u64) {
+        let similar = pulse * 4 > self.pilot_length * 3 && pulse * 4 < self.pilot_length * 5;
+        if self.pilot_pulses > 0 && similar {
+            self.pilot_length = (self.pilot_length * self.pilot_pulses as u64 + pulse)
+                / (self.pilot_pulses as u64 + 1);
+            self.pilot_pulses += 1;
+        } else if self.pilot_pulses >= MIN_PILOT_PULSES && pulse * 4 < self.pilot_length * 3 {
+            self.state = RecorderState::Sync;
+        } else {
+            self.pilot_pulses = 1;
+            self.pilot_length = pulse;
+        }
+    }
+
+    fn data_pulse(&mut self, pulse: u64) {
+        match self.half_bit.take() {
+            None => self.half_bit = Some(pulse),
+            Some(first) => self.push_bit(first + pulse),
+        }
+    }
+
+    fn push_bit(&mut self, length: u64) {
+        // Halfway between a zero and a one, relative to the pilot pulse
+        let threshold = self.pilot_length * (ZERO_PULSE + ONE_PULSE) / PILOT_PULSE;
+
+        self.byte <<= 1;
+        if length > threshold {
+            self.byte |= 1;
+        }
+        self.bit_count += 1;
+        if self.bit_count == 8 {
+            self.bytes.push(self.byte);
+            self.bit_count = 0;
+            self.byte = 0;
+        }
+    }
+
+    // Writes the block being recorded, if any. The last half bit of a
+    // block is not followed by an edge, so it is assumed symmetric.
+    pub fn finish(&mut self) {
+        if let Some(first) = self.half_bit.take() {
+            self.push_bit(2 * first);
+        }
+        if self.bytes.len() >= 2 {
+            if let Err(e) = self.tap.write_block(&self.bytes) {
+                eprintln!("No he podido grabar el bloque: {}", e);
+            }
+        }
+
+        self.state = RecorderState::Pilot;
+        self.pilot_pulses = 0;
+        self.pilot_length = 0;
+        self.bit_count = 0;
+        self.byte = 0;
+        self.bytes.clear();
+    }
+}
+
+impl Drop for MicRecorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine::Model;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("z80-{}-{}.tap", name, std::process::id()))
+    }
+
+    // Edges of a block as SA-BYTES makes them
+    fn rom_edges(block: &[u8]) -> Vec<u64> {
+        let mut pulses = vec![PILOT_PULSE; 3223];
+        pulses.extend_from_slice(&[667, 735]);
+        for &byte in block {
+            for bit in (0..8).rev() {
+                let pulse = if byte & (1 << bit) != 0 {
+                    ONE_PULSE
+                } else {
+                    ZERO_PULSE
+                };
+                pulses.extend_from_slice(&[pulse, pulse]);
+            }
+        }
+        let mut t_states = 100_000;
+        let mut edges = vec![t_states];
+        for pulse in pulses {
+            t_states += pulse;
+            edges.push(t_states);
+        }
+        edges
+    }
+
+    #[test]
+    fn mic_recorder_decodes_rom_pulses() {
+        let path = temp_path("mic");
+        let block = make_block(0xFF, b"Hello, tape");
+        {
+            let mut recorder = MicRecorder::new(TapWriter::create(&path).unwrap());
+            let edges = rom_edges(&block);
+            for &t_states in edges.iter() {
+                recorder.edge(t_states);
+            }
+            // The silence after the block ends it
+            recorder.edge(edges.last().unwrap() + 1_000_000);
+        }
+        let mut tap = TapReader::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tap.next_block(), Some(&block[..]));
+        assert!(tap.finished());
+    }
+
+    #[test]
+    fn mic_recorder_follows_faster_timings() {
+        let path = temp_path("turbo");
+        let block = make_block(0x00, &[0x00, 0xFF, 0x55, 0xAA]);
+        {
+            let mut recorder = MicRecorder::new(TapWriter::create(&path).unwrap());
+            // Every pulse two thirds as long
+            for t_states in rom_edges(&block) {
+                recorder.edge(t_states * 2 / 3);
+            }
+        }
+        let mut tap = TapReader::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tap.next_block(), Some(&block[..]));
+    }
+
+    fn set_block(cpu: &mut Z80, flag: u8, addr: u16, len: u16) {
+        cpu.a = flag;
+        cpu.ix_h = (addr >> 8) as u8;
+        cpu.ix_l = addr as u8;
+        cpu.d = (len >> 8) as u8;
+        cpu.e = len as u8;
+    }
+
+    #[test]
+    fn save_trap_round_trip() {
+        let path = temp_path("trap");
+        let data: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+
+        let mut mem = Memory::for_model(Model::Spectrum48K);
+        for (i, &byte) in data.iter().enumerate() {
+            mem.poke(0x8000 + i as u16, byte);
+        }
+        let mut cpu = Z80::new();
+        let mut border = 0;
+        set_block(&mut cpu, 0xFF, 0x8000, data.len() as u16);
+        let mut writer = TapWriter::create(&path).unwrap();
+        save_trap(&mut cpu, &mem, &mut border, &mut writer).unwrap();
+        drop(writer);
+        assert_eq!(cpu.pc, RET_BEFORE_SA_LD_RET);
+        assert!(cpu.f & CARRY != 0);
+
+        let mut tap = TapReader::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut mem = Memory::for_model(Model::Spectrum48K);
+        // White border
+        mem.poke(BORDCR, 0x38);
+        let mut cpu = Z80::new();
+        set_block(&mut cpu, 0xFF, 0x9000, data.len() as u16);
+        // Carry set asks for LOAD
+        cpu.f = CARRY;
+        assert!(load_trap(&mut cpu, &mut mem, &mut border, &mut tap));
+
+        assert!(cpu.f & CARRY != 0);
+        assert_eq!(cpu.pc, RET_BEFORE_SA_LD_RET);
+        assert!(cpu.iff1 && cpu.iff2);
+        assert_eq!(border, 7);
+        assert_eq!((cpu.d, cpu.e), (0, 0));
+        assert_eq!((cpu.ix_h, cpu.ix_l), (0x91, 0x2C));
+        for (i, &byte) in data.iter().enumerate() {
+            assert_eq!(mem.peek(0x9000 + i as u16), byte);
+        }
+        // The tape has run out
+        assert!(!load_trap(&mut cpu, &mut mem, &mut border, &mut tap));
+    }
+
+    #[test]
+    fn load_trap_rejects_wrong_flag() {
+        let mut tap = TapReader::from_bytes(&[3, 0, 0x00, 0x42, 0x42]).unwrap();
+        let mut mem = Memory::for_model(Model::Spectrum48K);
+        let mut cpu = Z80::new();
+        let mut border = 0;
+        set_block(&mut cpu, 0xFF, 0x8000, 1);
+        cpu.f = CARRY;
+        assert!(load_trap(&mut cpu, &mut mem, &mut border, &mut tap));
+        assert!(cpu.f & CARRY == 0);
+        assert_eq!(mem.peek(0x8000), 0);
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+// The part of TOML the configuration file needs: tables, dotted and quoted
+// keys, basic and literal strings, integers, floats, booleans and arrays,
+// which may span several lines. Inline tables, dates and multi-line
+// strings are not supported.
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Table> {
+        match *self {
+            Value::Table(ref table) => Some(table),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::String(_) => "texto",
+            Value::Integer(_) => "entero",
+            Value::Float(_) => "decimal",
+            Value::Boolean(_) => "booleano",
+            Value::Array(_) => "lista",
+            Value::Table(_) => "tabla",
+        }
+    }
+}
+
+// Written back as TOML, for messages
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::String(ref s) => write!(f, "{:?}", s),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Array(ref values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Value::Table(_) => write!(f, "{{...}}"),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("línea {}: {}", self.line, msg),
+        )
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if let Some(c) = c {
+            self.pos += 1;
+            if c == '\n' {
+                self.line += 1;
+            }
+        }
+        c
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    // Spaces and tabs within a line
+    fn skip_blanks(&mut self) {
+        while let Some(' ') | Some('\t') = self.peek() {
+            self.next();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while let Some(c) = self.peek() {
+                if c == '\n' {
+                    break;
+                }
+                self.next();
+            }
+        }
+    }
+
+    // Blanks, comments and line ends, as allowed inside arrays
+    fn skip_whitespace(&mut self) {
+        loop {
+            self.skip_blanks();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    // Nothing but a comment may follow a key/value pair or a header
+    fn end_of_line(&mut self) -> io::Result<()> {
+        self.skip_blanks();
+        self.skip_comment();
+        self.eat('\r');
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some(_) => Err(self.error("se esperaba el final de la línea")),
+        }
+    }
+
+    fn key_part(&mut self) -> io::Result<String> {
+        self.skip_blanks();
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        key.push(c);
+                        self.next();
+                    } else {
+                        break;
+                    }
+                }
+                if key.is_empty() {
+                    Err(self.error("se esperaba un nombre"))
+                } else {
+                    Ok(key)
+                }
+            }
+        }
+    }
+
+    // a.b."c" becomes ["a", "b", "c"]
+    fn key(&mut self) -> io::Result<Vec<String>> {
+        let mut parts = vec![self.key_part()?];
+        loop {
+            self.skip_blanks();
+            if !self.eat('.') {
+                return Ok(parts);
+            }
+            parts.push(self.key_part()?);
+        }
+    }
+
+    fn basic_string(&mut self) -> io::Result<String> {
+        self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(self.error("texto sin cerrar")),
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => self.unicode_escape(4)?,
+                        Some('U') => self.unicode_escape(8)?,
+                        _ => return Err(self.error("secuencia de escape no válida")),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self, digits: usize) -> io::Result<char> {
+        let mut code = 0;
+        for _ in 0..digits {
+            let digit = self.next().and_then(|c| c.to_digit(16));
+            match digit {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(self.error("secuencia de escape no válida")),
+            }
+        }
+        std::char::from_u32(code).ok_or_else(|| self.error("carácter no válido"))
+    }
+
+    fn literal_string(&mut self) -> io::Result<String> {
+        self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(self.error("texto sin cerrar")),
+                Some('\'') => return Ok(s),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> io::Result<Value> {
+        self.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            if !self.eat(',') {
+                return Err(self.error("se esperaba , o ] en la lista"));
+            }
+        }
+    }
+
+    // Numbers, booleans and anything else written without quotes
+    fn bare_value(&mut self) -> io::Result<Value> {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || "+-._".contains(c) {
+                text.push(c);
+                self.next();
+            } else {
+                break;
+            }
+        }
+        match text.as_str() {
+            "true" => return Ok(Value::Boolean(true)),
+            "false" => return Ok(Value::Boolean(false)),
+            _ => {}
+        }
+        let digits = text.replace('_', "");
+        let (negative, unsigned) = match digits.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, digits.strip_prefix('+').unwrap_or(&digits)),
+        };
+        let radix = match unsigned.get(..2) {
+            Some("0x") => Some(16),
+            Some("0o") => Some(8),
+            Some("0b") => Some(2),
+            _ => None,
+        };
+        let integer = match radix {
+            Some(radix) => i64::from_str_radix(&unsigned[2..], radix).ok(),
+            None => unsigned.parse::<i64>().ok(),
+        };
+        if let Some(n) = integer {
+            return Ok(Value::Integer(if negative { -n } else { n }));
+        }
+        match digits.parse::<f64>() {
+            Ok(x) if radix.is_none() => Ok(Value::Float(x)),
+            _ => Err(self.error(&format!("valor no válido: {}", text))),
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => Err(self.error("las tablas en línea no están soportadas")),
+            _ => self.bare_value(),
+        }
+    }
+}
+
+// Walks down `path` from `root`, creating the tables that are missing
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    let mut table = root;
+    for part in path {
+        let entry = table
+            .entry(part.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match *entry {
+            Value::Table(ref mut inner) => inner,
+            _ => return Err(format!("{} no es una tabla", part)),
+        };
+    }
+    Ok(table)
+}
+
+pub fn parse(text: &str) -> io::Result<Table> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    let mut root = Table::new();
+    let mut current: Vec<String> = Vec::new();
+
+    loop {
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => return Ok(root),
+            Some('[') => {
+                parser.next();
+                if parser.peek() == Some('[') {
+                    return Err(parser.error("las listas de tablas no están soportadas"));
+                }
+                current = parser.key()?;
+                parser.skip_blanks();
+                if !parser.eat(']') {
+                    return Err(parser.error("se esperaba ]"));
+                }
+                table_at(&mut root, &current).map_err(|e| parser.error(&e))?;
+                parser.end_of_line()?;
+            }
+            Some(_) => {
+                let mut key = parser.key()?;
+                parser.skip_blanks();
+                if !parser.eat('=') {
+                    return Err(parser.error("se esperaba ="));
+                }
+                parser.skip_blanks();
+                let value = parser.value()?;
+
+                // Before the line ends, for errors to point at it
+                let name = key.pop().unwrap();
+                let mut path = current.clone();
+                path.extend(key);
+                let table = table_at(&mut root, &path).map_err(|e| parser.error(&e))?;
+                if table.contains_key(&name) {
+                    return Err(parser.error(&format!("{} está repetido", name)));
+                }
+                table.insert(name, value);
+                parser.end_of_line()?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_line(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn values() {
+        let root = parse(
+            "a = 1\nb = -0x10\nc = 1_000\nd = 2.5\ne = true\n\
+             f = [1, 'dos',\n  # comentario\n  3,\n]\n",
+        )
+        .unwrap();
+        assert_eq!(root["a"], Value::Integer(1));
+        assert_eq!(root["b"], Value::Integer(-16));
+        assert_eq!(root["c"], Value::Integer(1000));
+        assert_eq!(root["d"], Value::Float(2.5));
+        assert_eq!(root["e"], Value::Boolean(true));
+        let f = root["f"].as_array().unwrap();
+        assert_eq!(f.len(), 3);
+        assert_eq!(f[1].as_str(), Some("dos"));
+    }
+
+    #[test]
+    fn strings() {
+        let root =
+            parse("a = \"x\\ty\\n\\\"z\\\\\"\nb = 'c:\\dir\\'\nc = \"\\u00f1\\U0001F600\"\n")
+                .unwrap();
+        assert_eq!(root["a"].as_str(), Some("x\ty\n\"z\\"));
+        assert_eq!(root["b"].as_str(), Some("c:\\dir\\"));
+        assert_eq!(root["c"].as_str(), Some("\u{f1}\u{1F600}"));
+    }
+
+    #[test]
+    fn tables() {
+        let root = parse(
+            "top = 1\n[machine]\nmodel = \"128k\"\n\
+             [keys.joystick]\nfire = \"space\"\n\
+             [keys]\n\"quoted key\".inner = 2 # comentario\n",
+        )
+        .unwrap();
+        assert_eq!(root["top"], Value::Integer(1));
+        let machine = root["machine"].as_table().unwrap();
+        assert_eq!(machine["model"].as_str(), Some("128k"));
+        let keys = root["keys"].as_table().unwrap();
+        let joystick = keys["joystick"].as_table().unwrap();
+        assert_eq!(joystick["fire"].as_str(), Some("space"));
+        let quoted = keys["quoted key"].as_table().unwrap();
+        assert_eq!(quoted["inner"], Value::Integer(2));
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        assert_eq!(error_line("a = 1\nb = \"x"), "línea 2: texto sin cerrar");
+        assert_eq!(error_line("a = 1\na = 2\n"), "línea 2: a está repetido");
+        assert_eq!(error_line("a = 1\n\n[a]\n"), "línea 3: a no es una tabla");
+        assert_eq!(
+            error_line("a = 1 2\n"),
+            "línea 1: se esperaba el final de la línea"
+        );
+        assert_eq!(
+            error_line("a = \"\\q\"\n"),
+            "línea 1: secuencia de escape no válida"
+        );
+        assert_eq!(
+            error_line("x = [1,\n2\n3]\n"),
+            "línea 3: se esperaba , o ] en la lista"
+        );
+        assert!(error_line("a = {b = 1}\n").contains("tablas en línea"));
+        assert_eq!(error_line("a = nope\n"), "línea 1: valor no válido: nope");
+    }
+}
@@ -0,0 +1,207 @@
+// Frame timing and display generation of the ULA
+
+pub struct UlaTiming {
+    pub cpu_clock: u64,
+    pub frame_t_states: u64,
+    pub line_t_states: u64,
+    // T-states the interrupt request is held at the start of the frame
+    pub interrupt_length: u64,
+    // T-state at which the ULA fetches the first byte of the bitmap
+    pub display_start: u64,
+    // Whether the ULA holds the CPU off its memory while fetching, the
+    // delay for each T-state of the 8 it takes to fetch 2 bitmap bytes
+    pub contended: bool,
+    pub contention_pattern: [u8; 8],
+    // Whether I/O cycles, and the internal ones that are not memory
+    // accesses, are held too
+    pub io_contended: bool,
+    // Whether reads of unattached ports see what the ULA is fetching
+    pub floating_bus: bool,
+}
+
+pub const TIMING_48K: UlaTiming = UlaTiming {
+    cpu_clock: 3_500_000,
+    frame_t_states: 69888,
+    line_t_states: 224,
+    interrupt_length: 32,
+    display_start: 14336,
+    contended: true,
+    contention_pattern: CONTENTION_PATTERN,
+    io_contended: true,
+    floating_bus: true,
+};
+
+pub const TIMING_128K: UlaTiming = UlaTiming {
+    cpu_clock: 3_546_900,
+    frame_t_states: 70908,
+    line_t_states: 228,
+    interrupt_length: 36,
+    display_start: 14362,
+    contended: true,
+    contention_pattern: CONTENTION_PATTERN,
+    io_contended: true,
+    floating_bus: true,
+};
+
+// The Pentagon 128 clone: no contention nor floating bus, and a taller
+// frame with 80 lines between the interrupt and the bitmap
+pub const TIMING_PENTAGON: UlaTiming = UlaTiming {
+    cpu_clock: 3_500_000,
+    frame_t_states: 71680,
+    line_t_states: 224,
+    interrupt_length: 32,
+    display_start: 17988,
+    contended: false,
+    contention_pattern: CONTENTION_PATTERN,
+    io_contended: false,
+    floating_bus: false,
+};
+
+// The gate array of the +2A/+3 holds the CPU with another pattern, never
+// on I/O, and leaves nothing on the bus
+pub const TIMING_PLUS3: UlaTiming = UlaTiming {
+    cpu_clock: 3_546_900,
+    frame_t_states: 70908,
+    line_t_states: 228,
+    interrupt_length: 32,
+    display_start: 14362,
+    contended: true,
+    contention_pattern: CONTENTION_PATTERN_PLUS3,
+    io_contended: false,
+    floating_bus: false,
+};
+
+// The frame holds the 256x192 bitmap and the visible border around it
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 192;
+pub const BORDER_LEFT: usize = 48;
+pub const BORDER_RIGHT: usize = 48;
+pub const BORDER_TOP: usize = 48;
+pub const BORDER_BOTTOM: usize = 56;
+pub const FRAME_WIDTH: usize = BORDER_LEFT + SCREEN_WIDTH + BORDER_RIGHT;
+pub const FRAME_HEIGHT: usize = BORDER_TOP + SCREEN_HEIGHT + BORDER_BOTTOM;
+
+const ATTRIBUTES: usize = 0x1800;
+
+// Delay of a contended access by T-state within each group of 8 of the
+// 128 T-states per line the ULA spends fetching the display
+const CONTENTION_PATTERN: [u8; 8] = [6, 5, 4, 3, 2, 1, 0, 0];
+const CONTENTION_PATTERN_PLUS3: [u8; 8] = [1, 0, 7, 6, 5, 4, 3, 2];
+const FETCH_T_STATES: usize = 128;
+const BRIGHT: u8 = 0x40;
+const FLASH: u8 = 0x80;
+
+// Delay a contended access suffers for each T-state of the frame.
+// Contention starts one T-state before the first fetch of each line.
+pub fn contention_table(timing: &UlaTiming) -> Vec<u8> {
+    let mut table = vec![0; timing.frame_t_states as usize];
+    if timing.contended {
+        for line in 0..SCREEN_HEIGHT as u64 {
+            let start = (timing.display_start - 1 + line * timing.line_t_states) as usize;
+            for (cycle, delay) in table[start..start + FETCH_T_STATES].iter_mut().enumerate() {
+                *delay = timing.contention_pattern[cycle % 8];
+            }
+        }
+    }
+    table
+}
+
+// Byte the ULA leaves on the data bus at a T-state of the frame. In each
+// group of 8 T-states it fetches two bitmap and attribute pairs and then
+// leaves the bus idle, as it does during the border and retrace.
+pub fn floating_bus(timing: &UlaTiming, screen: &[u8], frame_t_states: u64) -> Option<u8> {
+    if !timing.floating_bus || frame_t_states < timing.display_start {
+        return None;
+    }
+    let t = frame_t_states - timing.display_start;
+    let line = (t / timing.line_t_states) as usize;
+    let cycle = (t % timing.line_t_states) as usize;
+    if line >= SCREEN_HEIGHT || cycle >= FETCH_T_STATES {
+        return None;
+    }
+
+    let column = cycle / 8 * 2;
+    match cycle % 8 {
+        0 => Some(screen[bitmap_offset(column, line)]),
+        1 => Some(screen[attribute_offset(column, line)]),
+        2 => Some(screen[bitmap_offset(column + 1, line)]),
+        3 => Some(screen[attribute_offset(column + 1, line)]),
+        _ => None,
+    }
+}
+
+// Colours 0-7 at normal and 8-15 at bright intensity, as 0xRRGGBB
+pub type Palette = [u32; 16];
+
+pub const DEFAULT_PALETTE: Palette = [
+    0x000000, 0x0000D7, 0xD70000, 0xD700D7, 0x00D700, 0x00D7D7, 0xD7D700, 0xD7D7D7,
+    0x000000, 0x0000FF, 0xFF0000, 0xFF00FF, 0x00FF00, 0x00FFFF, 0xFFFF00, 0xFFFFFF,
+];
+
+// Offset in the screen bank of the bitmap byte at column x (0-31) of line y.
+// The address has the format
+//       H
+// 0 1 0 Y7 Y6 Y2 Y1 Y0
+//       L
+// Y5 Y4 Y3 X4 X3 X2 X1 X0
+pub fn bitmap_offset(x: usize, y: usize) -> usize {
+    (y & 0xC0) << 5 | (y & 0x07) << 8 | (y & 0x38) << 2 | x
+}
+
+pub fn attribute_offset(x: usize, y: usize) -> usize {
+    ATTRIBUTES + (y >> 3) * 32 + x
+}
+
+// Draws a whole frame from the screen bank. `flash` selects the phase of
+// the flashing attributes.
+pub fn render(screen: &[u8], border: u8, flash: bool, palette: &Palette, buffer: &mut [u32]) {
+    draw(screen, palette[(border & 0x07) as usize], buffer, |attribute| {
+        let bright = if attribute & BRIGHT != 0 { 8 } else { 0 };
+        let foreground = palette[((attribute & 0x07) | bright) as usize];
+        let background = palette[(((attribute >> 3) & 0x07) | bright) as usize];
+        if flash && attribute & FLASH != 0 {
+            (background, foreground)
+        } else {
+            (foreground, background)
+        }
+    });
+}
+
+// Draws a frame with the ULAplus palette. FLASH and BRIGHT pick one of
+// four groups of 16 entries, ink from the first 8 and paper from the
+// other 8, and nothing flashes. The border is a paper of the first group.
+pub fn render_ulaplus(screen: &[u8], border: u8, palette: &[u32; 64], buffer: &mut [u32]) {
+    draw(screen, palette[8 + (border & 0x07) as usize], buffer, |attribute| {
+        let group = ((attribute & (FLASH | BRIGHT)) >> 2) as usize;
+        let ink = (attribute & 0x07) as usize;
+        let paper = ((attribute >> 3) & 0x07) as usize;
+        (palette[group + ink], palette[group + 8 + paper])
+    });
+}
+
+// `colours` gives the ink and paper of an attribute
+fn draw<F>(screen: &[u8], border_color: u32, buffer: &mut [u32], colours: F)
+where
+    F: Fn(u8) -> (u32, u32),
+{
+    for pixel in buffer.iter_mut() {
+        *pixel = border_color;
+    }
+
+    for y in 0..SCREEN_HEIGHT {
+        let line = (BORDER_TOP + y) * FRAME_WIDTH + BORDER_LEFT;
+        for x in 0..SCREEN_WIDTH / 8 {
+            let pixels = screen[bitmap_offset(x, y)];
+            let (foreground, background) = colours(screen[attribute_offset(x, y)]);
+
+            for i in 0..8 {
+                let mask = 0x80 >> i;
+                buffer[line + x * 8 + i] = if pixels & mask != 0 {
+                    foreground
+                } else {
+                    background
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// ULAplus: a 64 entry palette the attributes pick from instead of the
+// fixed colours. Port 0xBF3B selects a register and 0xFF3B writes or
+// reads it. Registers 0-63 are the palette entries, in GRB332, and the
+// mode register 0x40 turns the palette on with bit 0.
+
+use std::io;
+
+use state::{SaveState, StateReader, StateWriter};
+
+pub const PALETTE_SIZE: usize = 64;
+
+const REGISTER_PORT: u16 = 0xBF3B;
+const DATA_PORT: u16 = 0xFF3B;
+
+// Top two bits of the register port
+const GROUP_MASK: u8 = 0xC0;
+const PALETTE_GROUP: u8 = 0x00;
+const MODE_GROUP: u8 = 0x40;
+const ENTRY_MASK: u8 = 0x3F;
+
+const PALETTE_ON: u8 = 0x01;
+
+// 0-7 to 0-255
+fn expand(level: u8) -> u32 {
+    let level = level as u32;
+    (level << 5) | (level << 2) | (level >> 1)
+}
+
+// GGGRRRBB to 0xRRGGBB. The low bit of blue, which is missing, is the OR
+// of the other two.
+pub fn grb332_to_rgb(value: u8) -> u32 {
+    let green = (value >> 5) & 0x07;
+    let red = (value >> 2) & 0x07;
+    let blue = value & 0x03;
+    let blue = (blue << 1) | ((blue >> 1) | blue) & 0x01;
+    (expand(red) << 16) | (expand(green) << 8) | expand(blue)
+}
+
+pub struct UlaPlus {
+    // Last value written to the register port
+    pub register: u8,
+    pub palette: [u8; PALETTE_SIZE],
+    pub enabled: bool,
+}
+
+impl Default for UlaPlus {
+    fn default() -> UlaPlus {
+        UlaPlus::new()
+    }
+}
+
+impl UlaPlus {
+    pub fn new() -> UlaPlus {
+        UlaPlus {
+            register: 0,
+            palette: [0; PALETTE_SIZE],
+            enabled: false,
+        }
+    }
+
+    // Ports are fully decoded
+    pub fn write_port(&mut self, port: u16, value: u8) {
+        if port == REGISTER_PORT {
+            self.register = value;
+        } else if port == DATA_PORT {
+            match self.register & GROUP_MASK {
+                PALETTE_GROUP => self.palette[(self.register & ENTRY_MASK) as usize] = value,
+                MODE_GROUP => self.enabled = value & PALETTE_ON != 0,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn read_port(&self, port: u16) -> Option<u8> {
+        if port != DATA_PORT {
+            return None;
+        }
+        match self.register & GROUP_MASK {
+            PALETTE_GROUP => Some(self.palette[(self.register & ENTRY_MASK) as usize]),
+            MODE_GROUP => Some(if self.enabled { PALETTE_ON } else { 0 }),
+            _ => None,
+        }
+    }
+
+    // The palette as 0xRRGGBB colours
+    pub fn colours(&self) -> [u32; PALETTE_SIZE] {
+        let mut colours = [0; PALETTE_SIZE];
+        for (colour, &value) in colours.iter_mut().zip(self.palette.iter()) {
+            *colour = grb332_to_rgb(value);
+        }
+        colours
+    }
+}
+
+impl SaveState for UlaPlus {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.u8(self.register);
+        state.bytes(&self.palette);
+        state.bool(self.enabled);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.register = state.u8()?;
+        self.palette.copy_from_slice(state.bytes(PALETTE_SIZE)?);
+        self.enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_writes() {
+        let mut ulaplus = UlaPlus::new();
+        ulaplus.write_port(REGISTER_PORT, 0x05);
+        ulaplus.write_port(DATA_PORT, 0xE3);
+        assert_eq!(ulaplus.palette[5], 0xE3);
+        assert_eq!(ulaplus.read_port(DATA_PORT), Some(0xE3));
+        // The register stays selected
+        ulaplus.write_port(DATA_PORT, 0x1C);
+        assert_eq!(ulaplus.palette[5], 0x1C);
+        assert_eq!(ulaplus.palette[6], 0);
+        assert_eq!(ulaplus.read_port(REGISTER_PORT), None);
+
+        ulaplus.write_port(REGISTER_PORT, 0x3F);
+        ulaplus.write_port(DATA_PORT, 0xFF);
+        assert_eq!(ulaplus.colours()[63], 0xFFFFFF);
+        assert_eq!(ulaplus.colours()[5], 0xFF0000);
+    }
+
+    #[test]
+    fn mode_writes() {
+        let mut ulaplus = UlaPlus::new();
+        ulaplus.write_port(REGISTER_PORT, 0x40);
+        ulaplus.write_port(DATA_PORT, 0x01);
+        assert!(ulaplus.enabled);
+        assert_eq!(ulaplus.read_port(DATA_PORT), Some(0x01));
+        ulaplus.write_port(DATA_PORT, 0x00);
+        assert!(!ulaplus.enabled);
+
+        // Other groups and ports are left alone
+        ulaplus.write_port(REGISTER_PORT, 0x80);
+        ulaplus.write_port(DATA_PORT, 0x01);
+        assert_eq!(ulaplus.read_port(DATA_PORT), None);
+        ulaplus.write_port(REGISTER_PORT, 0x40);
+        ulaplus.write_port(0xFF3A, 0x01);
+        ulaplus.write_port(0x00FF, 0x01);
+        assert!(!ulaplus.enabled);
+        assert_eq!(ulaplus.palette, [0; PALETTE_SIZE]);
+    }
+
+    #[test]
+    fn grb332_colours() {
+        assert_eq!(grb332_to_rgb(0x00), 0x000000);
+        assert_eq!(grb332_to_rgb(0xFF), 0xFFFFFF);
+        assert_eq!(grb332_to_rgb(0xE0), 0x00FF00);
+        assert_eq!(grb332_to_rgb(0x1C), 0xFF0000);
+        assert_eq!(grb332_to_rgb(0x03), 0x0000FF);
+        // Blue gets its third bit from the other two
+        assert_eq!(grb332_to_rgb(0x01), 0x00006D);
+        assert_eq!(grb332_to_rgb(0x02), 0x0000B6);
+        assert_eq!(grb332_to_rgb(0x8C), 0x6D9200);
+    }
+}
@@ -1,6 +1,8 @@
 use memory::*;
-use std::fs::OpenOptions;
+use iobus::IoBus;
+use state::{SaveState, StateReader, StateWriter};
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 
 const C: u8 = 0x01;
@@ -12,6 +14,87 @@ const BIT_5: u8 = 0x20;
 const Z: u8 = 0x40;
 const S: u8 = 0x80;
 
+// Duration in T-states of every instruction, prefix fetches included.
+// Conditional jumps, calls, returns and repeated block instructions
+// list the not-taken time and add the difference when they are taken.
+const T_STATES_MAIN: [u8; 256] = [
+    4, 10, 7, 6, 4, 4, 7, 4, 4, 11, 7, 6, 4, 4, 7, 4,
+    8, 10, 7, 6, 4, 4, 7, 4, 12, 11, 7, 6, 4, 4, 7, 4,
+    7, 10, 16, 6, 4, 4, 7, 4, 7, 11, 16, 6, 4, 4, 7, 4,
+    7, 10, 13, 6, 11, 11, 10, 4, 7, 11, 13, 6, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    7, 7, 7, 7, 7, 7, 4, 7, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    5, 10, 10, 10, 10, 11, 7, 11, 5, 10, 10, 4, 10, 17, 7, 11,
+    5, 10, 10, 11, 10, 11, 7, 11, 5, 4, 10, 11, 10, 4, 7, 11,
+    5, 10, 10, 19, 10, 11, 7, 11, 5, 4, 10, 4, 10, 4, 7, 11,
+    5, 10, 10, 4, 10, 11, 7, 11, 5, 6, 10, 4, 10, 4, 7, 11,
+];
+
+const T_STATES_CB: [u8; 256] = [
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+    8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+    8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+    8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+    8, 8, 8, 8, 8, 8, 15, 8, 8, 8, 8, 8, 8, 8, 15, 8,
+];
+
+const T_STATES_ED: [u8; 256] = [
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    12, 12, 15, 20, 8, 14, 8, 9, 12, 12, 15, 20, 8, 14, 8, 9,
+    12, 12, 15, 20, 8, 14, 8, 9, 12, 12, 15, 20, 8, 14, 8, 9,
+    12, 12, 15, 20, 8, 14, 8, 18, 12, 12, 15, 20, 8, 14, 8, 18,
+    12, 12, 15, 20, 8, 14, 8, 8, 12, 12, 15, 20, 8, 14, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    16, 16, 16, 16, 8, 8, 8, 8, 16, 16, 16, 16, 8, 8, 8, 8,
+    16, 16, 16, 16, 8, 8, 8, 8, 16, 16, 16, 16, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+];
+
+const T_STATES_DD: [u8; 256] = [
+    8, 14, 11, 10, 8, 8, 11, 8, 8, 15, 11, 10, 8, 8, 11, 8,
+    12, 14, 11, 10, 8, 8, 11, 8, 16, 15, 11, 10, 8, 8, 11, 8,
+    11, 14, 20, 10, 8, 8, 11, 8, 11, 15, 20, 10, 8, 8, 11, 8,
+    11, 14, 17, 10, 23, 23, 19, 8, 11, 15, 17, 10, 8, 8, 11, 8,
+    8, 8, 8, 8, 8, 8, 19, 8, 8, 8, 8, 8, 8, 8, 19, 8,
+    8, 8, 8, 8, 8, 8, 19, 8, 8, 8, 8, 8, 8, 8, 19, 8,
+    8, 8, 8, 8, 8, 8, 19, 8, 8, 8, 8, 8, 8, 8, 19, 8,
+    19, 19, 19, 19, 19, 19, 8, 19, 8, 8, 8, 8, 8, 8, 19, 8,
+    8, 8, 8, 8, 8, 8, 19, 8, 8, 8, 8, 8, 8, 8, 19, 8,
+    8, 8, 8, 8, 8, 8, 19, 8, 8, 8, 8, 8, 8, 8, 19, 8,
+    8, 8, 8, 8, 8, 8, 19, 8, 8, 8, 8, 8, 8, 8, 19, 8,
+    8, 8, 8, 8, 8, 8, 19, 8, 8, 8, 8, 8, 8, 8, 19, 8,
+    9, 14, 14, 14, 14, 15, 11, 15, 9, 14, 14, 8, 14, 21, 11, 15,
+    9, 14, 14, 15, 14, 15, 11, 15, 9, 8, 14, 15, 14, 8, 11, 15,
+    9, 14, 14, 23, 14, 15, 11, 15, 9, 8, 14, 8, 14, 8, 11, 15,
+    9, 14, 14, 8, 14, 15, 11, 15, 9, 10, 14, 8, 14, 8, 11, 15,
+];
+
+
+#[derive(Clone, Copy, PartialEq)]
 enum OpCodePrefix {
     None,
     DD,
@@ -22,44 +105,75 @@ enum OpCodePrefix {
     ED,
 }
 
+// In the order save states number them
+const PREFIXES: [OpCodePrefix; 7] = [
+    OpCodePrefix::None,
+    OpCodePrefix::DD,
+    OpCodePrefix::FD,
+    OpCodePrefix::CB,
+    OpCodePrefix::FdCb,
+    OpCodePrefix::DdCb,
+    OpCodePrefix::ED,
+];
+
 pub struct Z80 {
     pub halt: bool,
-    pc: u16,
-    sp: u16,
-    ix_h: u8,
-    ix_l: u8,
-    iy_h: u8,
-    iy_l: u8,
+    pub pc: u16,
+    pub sp: u16,
+    pub ix_h: u8,
+    pub ix_l: u8,
+    pub iy_h: u8,
+    pub iy_l: u8,
 
-    i: u8,
-    r: u8,
+    pub i: u8,
+    pub r: u8,
 
-    a: u8,
-    f: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    h: u8,
-    l: u8,
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
 
-    a_alt: u8,
-    f_alt: u8,
-    b_alt: u8,
-    c_alt: u8,
-    d_alt: u8,
-    e_alt: u8,
-    h_alt: u8,
-    l_alt: u8,
+    pub a_alt: u8,
+    pub f_alt: u8,
+    pub b_alt: u8,
+    pub c_alt: u8,
+    pub d_alt: u8,
+    pub e_alt: u8,
+    pub h_alt: u8,
+    pub l_alt: u8,
 
-    iff1: bool,
-    iff2: bool,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: u8,
+    // Internal WZ register. The instructions leave in it what the real
+    // one does, so snapshots carry it, but the undocumented flags it
+    // shows through are not emulated.
+    pub memptr: u16,
 
-    halted: bool,
+    pub halted: bool,
+    // T-states elapsed since power on
+    pub t_states: u64,
+    // Opcode fetches since power on, counted as R counts them but without
+    // the interrupts, as input recordings measure frames
+    pub fetches: u64,
+    // Length of the instruction being executed and the part of it already
+    // spent in bus cycles and in the internal cycles taken one by one
+    instruction_t_states: u64,
+    bus_t_states: u64,
     opcode_prefix: OpCodePrefix,
 
-    log_file: File,
-    list_file: File,
+    // When set, every instruction and the registers after it are listed here
+    trace: Option<File>,
+}
+
+impl Default for Z80 {
+    fn default() -> Z80 {
+        Z80::new()
+    }
 }
 
 impl Z80 {
@@ -92,18 +206,15 @@ impl Z80 {
             l_alt: 0,
             iff1: false,
             iff2: false,
+            im: 0,
+            memptr: 0,
             halted: false,
+            t_states: 0,
+            fetches: 0,
+            instruction_t_states: 0,
+            bus_t_states: 0,
             opcode_prefix: OpCodePrefix::None,
-            log_file: OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open("../volcadoEmu/salidaMio.txt")
-                .unwrap(),
-            list_file: OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open("../volcadoEmu/listado.txt")
-                .unwrap(),
+            trace: None,
         };
 
         out
@@ -129,42 +240,44 @@ impl Z80 {
             self.reset_flag(flag);
         }
     }
+    pub fn set_trace(&mut self, file: File) {
+        self.trace = Some(file);
+    }
     fn save_state(&mut self) {
-        // let mut file = OpenOptions::new()
-        //     .append(true)
-        //     .create(true)
-        //     .open("../volcadoEmu/salidaMio.txt")
-        //     .unwrap();
-
-        if let Err(e) = writeln!(
-            self.log_file,
-            "pc:{} sp:{} ix:{} iy:{} i:{} r:{} af:{} bc:{} de:{} hl:{}",
-            format!("{0:01$x}", self.pc, 4),
-            format!("{0:01$x}", self.sp, 4),
-            format!("{0:01$x}", Z80::get_word(self.ix_h, self.ix_l), 4),
-            format!("{0:01$x}", Z80::get_word(self.iy_h, self.iy_l), 4),
-            format!("{0:01$x}", self.i, 4),
-            format!("{0:01$x}", self.r, 4),
-            format!("{0:01$x}", Z80::get_word(self.a, self.f), 4),
-            format!("{0:01$x}", Z80::get_word(self.b, self.c), 4),
-            format!("{0:01$x}", Z80::get_word(self.d, self.e), 4),
-            format!("{0:01$x}", Z80::get_word(self.h, self.l), 4)
-        ) {
-            eprintln!("No he podido escribir la línea");
-        }
+        let line = format!(
+            "pc:{:04x} sp:{:04x} ix:{:04x} iy:{:04x} i:{:04x} r:{:04x} af:{:04x} bc:{:04x} de:{:04x} hl:{:04x}",
+            self.pc,
+            self.sp,
+            Z80::get_word(self.ix_h, self.ix_l),
+            Z80::get_word(self.iy_h, self.iy_l),
+            self.i,
+            self.r,
+            Z80::get_word(self.a, self.f),
+            Z80::get_word(self.b, self.c),
+            Z80::get_word(self.d, self.e),
+            Z80::get_word(self.h, self.l)
+        );
+        self.write_trace(&line);
     }
     fn save_op(&mut self, msg: &str) {
-        // let mut file = OpenOptions::new()
-        //     .append(true)
-        //     .create(true)
-        //     .open("../volcadoEmu/listado.txt")
-        //     .unwrap();
-        if let Err(e) = writeln!(self.list_file, "{}    {}", format!("{0:01$x}", self.pc, 4), msg) {
-            eprintln!("No he podido escribir la línea");
+        if self.trace.is_none() {
+            return;
         }
-
+        let line = format!("{:04x}    {}", self.pc, msg);
+        self.write_trace(&line);
         self.save_state();
     }
+    // A failing trace is dropped rather than reported on every instruction
+    fn write_trace(&mut self, line: &str) {
+        let failed = match self.trace {
+            Some(ref mut file) => writeln!(file, "{}", line).is_err(),
+            None => false,
+        };
+        if failed {
+            eprintln!("No he podido escribir la traza, se desactiva");
+            self.trace = None;
+        }
+    }
     fn get_bytes(val: u16) -> (u8, u8) {
         let hi: u8 = (val >> 8) as u8;
         let lo: u8 = (val & 0xFF) as u8;
@@ -191,10 +304,30 @@ impl Z80 {
 
         res
     }
+    // S, Z and the copies of bits 5 and 3 of a result
+    fn sz53(value: u8) -> u8 {
+        let mut flags = value & (S | BIT_5 | BIT_3);
+        if value == 0 {
+            flags |= Z;
+        }
+        flags
+    }
+    // Same, with the parity in P/V, as logical operations leave them
+    fn sz53p(value: u8) -> u8 {
+        let mut flags = Z80::sz53(value);
+        if Z80::check_byte_parity(value) {
+            flags |= P_V;
+        }
+        flags
+    }
     fn inc_single_register(reg: u8) -> u8 {
         let s = reg as u16;
         ((s + 1) & 0xff) as u8
     }
+    fn inc_r(&mut self) {
+        // Only the lower 7 bits of R count, bit 7 keeps its value
+        self.r = (self.r & 0x80) | (Z80::inc_single_register(self.r) & 0x7F);
+    }
     fn get_h(&self) -> u8 {
         let res = match self.opcode_prefix {
             OpCodePrefix::DD | OpCodePrefix::DdCb => self.ix_h,
@@ -211,6 +344,29 @@ impl Z80 {
         };
         res
     }
+    // Registers as opcodes number them, leaving out 6 for (HL)
+    fn get_reg_code(&self, code: u8) -> u8 {
+        match code {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => self.h,
+            5 => self.l,
+            _ => self.a,
+        }
+    }
+    fn set_reg_code(&mut self, code: u8, val: u8) {
+        match code {
+            0 => self.b = val,
+            1 => self.c = val,
+            2 => self.d = val,
+            3 => self.e = val,
+            4 => self.h = val,
+            5 => self.l = val,
+            _ => self.a = val,
+        }
+    }
     fn get_hl(&self) -> u16 {
         Z80::get_word(self.get_h(), self.get_l())
     }
@@ -232,31 +388,96 @@ impl Z80 {
         let mut addr = Z80::get_word(self.get_h(), self.get_l());
         match self.opcode_prefix {
             OpCodePrefix::DD | OpCodePrefix::FD | OpCodePrefix::FdCb => {
-                addr += self.read_bus(mem) as u16
+                let displacement = self.read_bus(mem) as i8;
+                addr = addr.wrapping_add(displacement as u16);
+                self.memptr = addr;
+                // Adding the displacement, which stays on the bus
+                let displacement = self.pc.wrapping_sub(1);
+                self.internal_cycles(mem, displacement, 5);
             }
             _ => addr += 0,
         }
         addr
     }
-    pub fn exec(&mut self, mem: &mut Memory) {
-        let byte = self.read_bus(mem);
-        self.r = Z80::inc_single_register(self.r);
+    pub fn exec(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        self.bus_t_states = 0;
+        // After DD CB or FD CB comes the displacement, a plain memory read
+        // that leaves R and the fetch count alone
+        let byte = match self.opcode_prefix {
+            OpCodePrefix::FdCb | OpCodePrefix::DdCb => self.read_bus(mem),
+            _ => {
+                self.inc_r();
+                self.fetches += 1;
+                self.fetch_opcode(mem)
+            }
+        };
 
-        if self.pc == 0x1223 {
-            println!("0x1223 El opcode es {:x}", byte);
-        }
-        if self.pc == 0x1225 {
-            println!("0x1225 El opcode es {:x}", byte);
-        }
+        // The prefix bytes have already been counted as 4 T-state fetches
         match self.opcode_prefix {
-            OpCodePrefix::None => self.exec_no_prefix(mem, byte),
-            OpCodePrefix::DD | OpCodePrefix::FD => self.exec_dd_or_fd_prefix(mem, byte),
-            OpCodePrefix::CB => self.exec_cb_prefix(mem, byte),
+            OpCodePrefix::None => {
+                self.instruction_t_states = T_STATES_MAIN[byte as usize] as u64;
+                self.exec_no_prefix(mem, io, byte)
+            }
+            OpCodePrefix::DD | OpCodePrefix::FD => {
+                self.instruction_t_states = (T_STATES_DD[byte as usize] - 4) as u64;
+                self.exec_dd_or_fd_prefix(mem, io, byte)
+            }
+            OpCodePrefix::CB => {
+                self.instruction_t_states = (T_STATES_CB[byte as usize] - 4) as u64;
+                self.exec_cb_prefix(mem, byte)
+            }
             OpCodePrefix::FdCb | OpCodePrefix::DdCb => self.exec_fd_cb_prefix(mem, byte),
-            OpCodePrefix::ED => self.exec_ed_prefix(mem, byte),
+            OpCodePrefix::ED => {
+                self.instruction_t_states = (T_STATES_ED[byte as usize] - 4) as u64;
+                self.exec_ed_prefix(mem, io, byte)
+            }
         };
+
+        // The bus cycles, and the internal cycles the ULA can hold, have
+        // advanced the clock as they happened. What the table has left are
+        // the other internal cycles, taken at the end of the instruction.
+        self.t_states += self.instruction_t_states.saturating_sub(self.bus_t_states);
+    }
+    // True while an instruction is halfway through its prefixes
+    pub fn prefix_pending(&self) -> bool {
+        !matches!(self.opcode_prefix, OpCodePrefix::None)
+    }
+    // Raises the maskable interrupt. Returns false if it is disabled.
+    pub fn interrupt(&mut self, mem: &mut Memory) -> bool {
+        if !self.iff1 {
+            return false;
+        }
+        if self.halted {
+            self.halted = false;
+            self.pc = self.pc.wrapping_add(1);
+        }
+        self.iff1 = false;
+        self.iff2 = false;
+        self.inc_r();
+
+        let (pc_hi, pc_lo) = Z80::get_bytes(self.pc);
+        self.sp = self.sp.wrapping_sub(2);
+        mem.poke(self.sp.wrapping_add(1), pc_hi);
+        mem.poke(self.sp, pc_lo);
+
+        // The Spectrum leaves 0xFF on the data bus, so IM 0 runs RST 38 too
+        match self.im {
+            2 => {
+                let vector = Z80::get_word(self.i, 0xFF);
+                let lo = mem.peek(vector);
+                let hi = mem.peek(vector.wrapping_add(1));
+                self.pc = Z80::get_word(hi, lo);
+                self.t_states += 19;
+            }
+            _ => {
+                self.pc = 0x38;
+                self.t_states += 13;
+            }
+        }
+        self.memptr = self.pc;
+        true
     }
-    fn exec_no_prefix(&mut self, mem: &mut Memory, byte: u8) {
+    fn exec_no_prefix(&mut self, mem: &mut Memory, io: &mut IoBus, byte: u8) {
         let mut new_prefix = OpCodePrefix::None;
         match byte {
             0x00 => self.nop(),
@@ -470,7 +691,7 @@ impl Z80 {
             0xD0 => self.ret_nc(mem),
             0xD1 => self.pop_de(mem),
             0xD2 => self.jp_nc(mem),
-            0xD3 => self.out_n_a(mem),
+            0xD3 => self.out_n_a(mem, io),
             0xD4 => self.call_nc(mem),
             0xD5 => self.push_de(mem),
             0xD6 => self.sub_a_n(mem),
@@ -478,6 +699,7 @@ impl Z80 {
             0xD8 => self.ret_c(mem),
             0xD9 => self.exx(),
             0xDA => self.jp_c(mem),
+            0xDB => self.in_a_n(mem, io),
             0xDC => self.call_c(mem),
             0xDD => new_prefix = OpCodePrefix::DD,
             0xDE => self.sbc_a_n(mem),
@@ -514,14 +736,10 @@ impl Z80 {
             0xFD => new_prefix = OpCodePrefix::FD,
             0xFE => self.cp_n(mem),
             0xFF => self.rst_38(mem),
-            _ => {
-                println!("El opCode {:x} no está implementado", byte);
-                self.halt = true;
-            }
         }
         self.opcode_prefix = new_prefix;
     }
-    fn exec_dd_or_fd_prefix(&mut self, mem: &mut Memory, byte: u8) {
+    fn exec_dd_or_fd_prefix(&mut self, mem: &mut Memory, io: &mut IoBus, byte: u8) {
         let mut new_prefix = OpCodePrefix::None;
         match byte {
             0x09 => self.add_hl_bc(),
@@ -615,14 +833,11 @@ impl Z80 {
             0xE9 => self.jp_at_hl(),
             0xED => new_prefix = OpCodePrefix::ED,
             0xFD => new_prefix = OpCodePrefix::FD,
+            // The rest run as without the prefix, which still picks IX or
+            // IY for H, L and (HL)
             _ => {
-                let prefix = match self.opcode_prefix {
-                    OpCodePrefix::DD => "DD",
-                    OpCodePrefix::FD => "FD",
-                    _ => "otro",
-                };
-                println!("El opCode {} {:x} no está implementado", prefix, byte);
-                self.halt = true;
+                self.exec_no_prefix(mem, io, byte);
+                return;
             }
         }
         self.opcode_prefix = new_prefix;
@@ -893,10 +1108,20 @@ impl Z80 {
         self.opcode_prefix = OpCodePrefix::None;;
     }
     fn exec_fd_cb_prefix(&mut self, mem: &mut Memory, byte: u8) {
-        let mut addr = Z80::get_word(self.get_h(), self.get_l());
-        addr += byte as u16;
-        let op = mem.peek(addr);
+        let displacement = byte as i8;
+        let addr = self.get_hl().wrapping_add(displacement as u16);
+        self.memptr = addr;
+        // The opcode comes after the displacement, and the sum takes two
+        // more cycles on its address
         let op_code = self.read_bus(mem);
+        let op_code_addr = self.pc.wrapping_sub(1);
+        self.internal_cycles(mem, op_code_addr, 2);
+        let op = self.read_to_modify(mem, addr);
+        // DD CB d op takes 23 T-states (20 for BIT), 8 of them in the prefixes
+        self.instruction_t_states = match op_code {
+            0x40..=0x7F => 12,
+            _ => 15,
+        };
         match op_code {
             0x00 => self.rlc_to_b(op),
             0x01 => self.rlc_to_c(op),
@@ -996,67 +1221,204 @@ impl Z80 {
                 self.halt = true;
             }
         };
+        // Undocumented: with a register in the opcode the result goes both
+        // to memory and to that register
+        let reg = op_code & 0x07;
+        if reg != 6 {
+            let bit = (op_code >> 3) & 0x07;
+            match op_code {
+                0x00..=0x3F => {
+                    let result = self.get_reg_code(reg);
+                    self.write_mem(mem, addr, result);
+                }
+                0x80..=0xBF => self.set_reg_code(reg, Z80::res_n_r(bit, op)),
+                0xC0..=0xFF => self.set_reg_code(reg, Z80::set_n_r(bit, op)),
+                _ => {}
+            }
+        }
         self.opcode_prefix = OpCodePrefix::None;
     }
-    fn exec_ed_prefix(&mut self, mem: &mut Memory, byte: u8) {
-        let mut new_prefix = OpCodePrefix::None;
+    fn exec_ed_prefix(&mut self, mem: &mut Memory, io: &mut IoBus, byte: u8) {
         match byte {
-            //0x40 => in_b_at_c(mem),
-            //0x41 => out_at_c_b(mem),
-            0x42 => self.sbc_hl_bc(),
+            0x40 => self.in_b_at_c(mem, io),
+            0x41 => self.out_at_c_b(mem, io),
+            0x42 => self.sbc_hl_bc(mem),
             0x43 => self.ld_at_nn_bc(mem),
             0x44 => self.neg(),
             0x45 => self.retn(mem),
-            //0x46 => im_0(),
-            0x47 => self.ld_i_a(),
-            //0x48 => in_c_at_c(mem),
-            //0x49 => out_c_at_c(mem),
-            0x4A => self.adc_hl_bc(),
+            0x46 => self.im_0(),
+            0x47 => self.ld_i_a(mem),
+            0x48 => self.in_c_at_c(mem, io),
+            0x49 => self.out_at_c_c(mem, io),
+            0x4A => self.adc_hl_bc(mem),
             0x4B => self.ld_bc_at_nn(mem),
             0x4C => self.neg(),
-            0x4F => self.ld_r_a(),
-            0x52 => self.sbc_hl_de(),
+            0x4D => self.retn(mem),
+            0x4E => self.im_0(),
+            0x4F => self.ld_r_a(mem),
+            0x50 => self.in_d_at_c(mem, io),
+            0x51 => self.out_at_c_d(mem, io),
+            0x52 => self.sbc_hl_de(mem),
             0x53 => self.ld_nn_de(mem),
             0x54 => self.neg(),
             0x55 => self.retn(mem),
-            0x57 => self.ld_a_i(),
-            0x5A => self.adc_hl_de(),
+            0x56 => self.im_1(),
+            0x57 => self.ld_a_i(mem),
+            0x58 => self.in_e_at_c(mem, io),
+            0x59 => self.out_at_c_e(mem, io),
+            0x5A => self.adc_hl_de(mem),
             0x5B => self.ld_de_at_nn(mem),
             0x5C => self.neg(),
-            0x5F => self.ld_a_r(),
-            0x62 => self.sbc_hl_hl(),
+            0x5D => self.retn(mem),
+            0x5E => self.im_2(),
+            0x5F => self.ld_a_r(mem),
+            0x60 => self.in_h_at_c(mem, io),
+            0x61 => self.out_at_c_h(mem, io),
+            0x62 => self.sbc_hl_hl(mem),
             0x63 => self.ld_nn_hl(mem),
             0x64 => self.neg(),
             0x65 => self.retn(mem),
-            0x67 => self.rrd(),
-            0x6A => self.adc_hl_hl(),
+            0x66 => self.im_0(),
+            0x67 => self.rrd(mem),
+            0x68 => self.in_l_at_c(mem, io),
+            0x69 => self.out_at_c_l(mem, io),
+            0x6A => self.adc_hl_hl(mem),
             0x6B => self.ld_hl_at_nn(mem),
             0x6C => self.neg(),
-            //0x6F => self.rld(),
-            0x72 => self.sbc_hl_sp(),
+            0x6D => self.retn(mem),
+            0x6E => self.im_0(),
+            0x6F => self.rld(mem),
+            0x70 => self.in_f_at_c(mem, io),
+            0x71 => self.out_at_c_0(mem, io),
+            0x72 => self.sbc_hl_sp(mem),
             0x73 => self.ld_nn_sp(mem),
             0x74 => self.neg(),
             0x75 => self.retn(mem),
-            0x7A => self.adc_hl_sp(),
+            0x76 => self.im_1(),
+            0x78 => self.in_a_at_c(mem, io),
+            0x79 => self.out_at_c_a(mem, io),
+            0x7A => self.adc_hl_sp(mem),
             0x7B => self.ld_sp_at_nn(mem),
             0x7C => self.neg(),
+            0x7D => self.retn(mem),
+            0x7E => self.im_2(),
+            0xA0 => self.ldi(mem),
+            0xA1 => self.cpi(mem),
+            0xA2 => self.ini(mem, io),
+            0xA3 => self.outi(mem, io),
+            0xA8 => self.ldd(mem),
+            0xA9 => self.cpd(mem),
+            0xAA => self.ind(mem, io),
+            0xAB => self.outd(mem, io),
+            0xB0 => self.ldir(mem),
+            0xB1 => self.cpir(mem),
+            0xB2 => self.inir(mem, io),
+            0xB3 => self.otir(mem, io),
             0xB8 => self.lddr(mem),
-            0xCB => new_prefix = OpCodePrefix::CB,
-            0xDD => new_prefix = OpCodePrefix::DD,
-            0xED => new_prefix = OpCodePrefix::ED,
-            0xFD => new_prefix = OpCodePrefix::FD,
-            _ => {
-                println!("El opCode ED {:x} no está implementado", byte);
-                self.halt = true;
-            }
+            0xB9 => self.cpdr(mem),
+            0xBA => self.indr(mem, io),
+            0xBB => self.otdr(mem, io),
+            // The rest, prefixes included, do nothing in 8 T-states
+            _ => self.nop(),
         }
-        self.opcode_prefix = new_prefix;
+        self.opcode_prefix = OpCodePrefix::None;
     }
     fn read_bus(&mut self, mem: &Memory) -> u8 {
-        let res = mem.peek(self.pc);
-        self.pc += 1;
+        let pc = self.pc;
+        let res = self.read_mem(mem, pc);
+        self.pc = self.pc.wrapping_add(1);
         res
     }
+    // Opcode fetch: 4 T-states, the ULA can only hold the first one
+    fn fetch_opcode(&mut self, mem: &Memory) -> u8 {
+        let pc = self.pc;
+        self.contend(mem, pc);
+        self.bus_cycle(4);
+        self.pc = self.pc.wrapping_add(1);
+        mem.peek(pc)
+    }
+    // Memory read or write: 3 T-states
+    fn read_mem(&mut self, mem: &Memory, addr: u16) -> u8 {
+        self.contend(mem, addr);
+        self.bus_cycle(3);
+        mem.peek(addr)
+    }
+    fn write_mem(&mut self, mem: &mut Memory, addr: u16, value: u8) {
+        self.contend(mem, addr);
+        self.bus_cycle(3);
+        mem.poke(addr, value);
+    }
+    // Read of a value that is then changed and written back, or tested:
+    // one more cycle with the address still on the bus
+    fn read_to_modify(&mut self, mem: &Memory, addr: u16) -> u8 {
+        let value = self.read_mem(mem, addr);
+        self.internal_cycles(mem, addr, 1);
+        value
+    }
+    // Cycles of 1 T-state in which the CPU works inside and leaves `addr`
+    // on the bus. The ULA holds them like memory accesses, the gate array
+    // of the +2A and +3 only looks at the memory requests.
+    fn internal_cycles(&mut self, mem: &Memory, addr: u16, cycles: u64) {
+        for _ in 0..cycles {
+            if mem.io_contended() {
+                self.contend(mem, addr);
+            }
+            self.bus_cycle(1);
+        }
+    }
+    // What the CPU puts on the bus while refreshing, after the fetch
+    fn ir(&self) -> u16 {
+        Z80::get_word(self.i, self.r)
+    }
+    fn contend(&mut self, mem: &Memory, addr: u16) {
+        if mem.is_contended(addr) {
+            self.t_states += mem.contention(self.t_states);
+        }
+    }
+    fn bus_cycle(&mut self, t_states: u64) {
+        self.t_states += t_states;
+        self.bus_t_states += t_states;
+    }
+    // I/O cycle: 4 T-states. The ULA holds the CPU on its own ports and
+    // whenever the high byte of the port looks like contended memory.
+    // This runs the first 3, the port being read or written at T3 once
+    // the contention is over.
+    fn io_cycle(&mut self, mem: &Memory, port: u16) {
+        let ula_port = port & 0x01 == 0;
+        if !mem.io_contended() {
+            self.bus_cycle(3);
+        } else if mem.is_contended(port) {
+            if ula_port {
+                self.contend(mem, port);
+                self.bus_cycle(1);
+                self.t_states += mem.contention(self.t_states);
+                self.bus_cycle(2);
+            } else {
+                for _ in 0..3 {
+                    self.contend(mem, port);
+                    self.bus_cycle(1);
+                }
+                self.contend(mem, port);
+            }
+        } else if ula_port {
+            self.bus_cycle(1);
+            self.t_states += mem.contention(self.t_states);
+            self.bus_cycle(2);
+        } else {
+            self.bus_cycle(3);
+        }
+    }
+    fn read_port(&mut self, mem: &Memory, io: &mut IoBus, port: u16) -> u8 {
+        self.io_cycle(mem, port);
+        let val = io.read_port(mem, port, self.t_states);
+        self.bus_cycle(1);
+        val
+    }
+    fn write_port(&mut self, mem: &mut Memory, io: &mut IoBus, port: u16, val: u8) {
+        self.io_cycle(mem, port);
+        io.write_port(mem, port, val, self.t_states);
+        self.bus_cycle(1);
+    }
     fn nop(&mut self) {
         self.save_op("NOP");
     }
@@ -1066,21 +1428,14 @@ impl Z80 {
         self.iff2 = false;
     }
     fn halt(&mut self) {
+        // Keep executing the HALT until an interrupt arrives
         self.halted = true;
+        self.pc = self.pc.wrapping_sub(1);
         self.save_op("HALT");
     }
     fn xor_r(&mut self, value: u8) {
-        self.a = self.a ^ value;
-        let mut cond = (self.a as i8) < 0;
-        self.set_reset_flag(cond, S);
-        cond = self.a == 0;
-        self.set_reset_flag(cond, Z);
-        cond = (self.a & 0xF) < (self.h & 0xF);
-        self.set_reset_flag(cond, H);
-        cond = Z80::check_byte_parity(self.a);
-        self.set_reset_flag(cond, P_V);
-        self.reset_flag(N);
-        self.reset_flag(C);
+        self.a ^= value;
+        self.f = Z80::sz53p(self.a);
     }
     fn xor_b(&mut self) {
         let op = self.b;
@@ -1114,7 +1469,7 @@ impl Z80 {
     }
     fn xor_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let op = mem.peek(addr);
+        let op = self.read_mem(mem, addr);
         self.xor_r(op);
         self.save_op("XOR (HL)");
     }
@@ -1130,16 +1485,8 @@ impl Z80 {
         self.save_op(&msg);
     }
     fn or_r(&mut self, value: u8) {
-        self.a = self.a | value;
-        let mut cond = (self.a as i8) < 0;
-        self.set_reset_flag(cond, S);
-        cond = self.a == 0;
-        self.set_reset_flag(cond, Z);
-        self.reset_flag(H);
-        // TODO: Mirar cómo funciona este flag
-        self.reset_flag(P_V);
-        self.reset_flag(N);
-        self.reset_flag(C);
+        self.a |= value;
+        self.f = Z80::sz53p(self.a);
     }
     fn or_b(&mut self) {
         let op = self.b;
@@ -1173,7 +1520,7 @@ impl Z80 {
     }
     fn or_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let op = mem.peek(addr);
+        let op = self.read_mem(mem, addr);
         self.or_r(op);
         self.save_op("OR (HL)");
     }
@@ -1199,89 +1546,85 @@ impl Z80 {
     }
     fn ld_at_bc_a(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.b, self.c);
-        mem.poke(addr, self.a);
+        self.write_mem(mem, addr, self.a);
+        self.memptr = Z80::get_word(self.a, addr.wrapping_add(1) as u8);
         self.save_op("LD (BC) A");
     }
     fn ld_at_de_a(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.d, self.e);
-        mem.poke(addr, self.a);
+        self.write_mem(mem, addr, self.a);
+        self.memptr = Z80::get_word(self.a, addr.wrapping_add(1) as u8);
         self.save_op("LD (DE) A");
     }
     fn ld_at_hl_b(&mut self, mem: &mut Memory) {
         let addr = self.get_indirect_hl(mem);
-        mem.poke(addr, self.b);
+        self.write_mem(mem, addr, self.b);
         self.save_op("LD (HL) B");
     }
     fn ld_at_hl_c(&mut self, mem: &mut Memory) {
         let addr = self.get_indirect_hl(mem);
-        mem.poke(addr, self.c);
+        self.write_mem(mem, addr, self.c);
         self.save_op("LD (HL) C");
     }
     fn ld_at_hl_d(&mut self, mem: &mut Memory) {
         let addr = self.get_indirect_hl(mem);
-        mem.poke(addr, self.d);
+        self.write_mem(mem, addr, self.d);
         self.save_op("LD (HL) D");
     }
     fn ld_at_hl_e(&mut self, mem: &mut Memory) {
         let addr = self.get_indirect_hl(mem);
-        mem.poke(addr, self.e);
+        self.write_mem(mem, addr, self.e);
         self.save_op("LD (HL) E");
     }
     fn ld_at_hl_h(&mut self, mem: &mut Memory) {
         let addr = self.get_indirect_hl(mem);
-        mem.poke(addr, self.h);
+        self.write_mem(mem, addr, self.h);
         self.save_op("LD (HL) H");
     }
     fn ld_at_hl_l(&mut self, mem: &mut Memory) {
         let addr = self.get_indirect_hl(mem);
-        mem.poke(addr, self.l);
+        self.write_mem(mem, addr, self.l);
         self.save_op("LD (HL) L");
     }
     fn ld_at_hl_a(&mut self, mem: &mut Memory) {
         let addr = self.get_indirect_hl(mem);
-        mem.poke(addr, self.a);
+        self.write_mem(mem, addr, self.a);
         self.save_op("LD (HL) A");
     }
     fn ld_sp_hl(&mut self) {
-        let new_sp = Z80::get_word(self.h, self.l);
+        let new_sp = self.get_hl();
         self.sp = new_sp;
         self.save_op("LD SP HL");
     }
+    // Rotations of A keep S, Z and P/V and take bits 5 and 3 from the result
+    fn set_rotate_a_flags(&mut self, carry: bool) {
+        self.f = (self.f & (S | Z | P_V)) | (self.a & (BIT_5 | BIT_3));
+        self.set_reset_flag(carry, C);
+    }
     fn rlca(&mut self) {
         let bit = self.a & 0x80;
-        self.a = (self.a << 1) | bit;
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag(bit != 0, C);
+        self.a = self.a.rotate_left(1);
+        self.set_rotate_a_flags(bit != 0);
         self.save_op("RLCA");
     }
     fn rla(&mut self) {
         let bit = self.a & 0x80;
         let old_c = self.f & C;
         self.a = (self.a << 1) | old_c;
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag(bit != 0, C);
-        self.set_reset_flag(bit != 0, C);
+        self.set_rotate_a_flags(bit != 0);
         self.save_op("RLA");
     }
     fn rrca(&mut self) {
         let bit = self.a & 0x01;
-        self.a = (self.a >> 1) | (bit << 7);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag(bit != 0, C);
-        self.set_reset_flag(bit != 0, C);
+        self.a = self.a.rotate_right(1);
+        self.set_rotate_a_flags(bit != 0);
         self.save_op("RRCA");
     }
     fn rra(&mut self) {
         let bit = self.a & 0x01;
         let old_c = self.f & C;
-        self.a = (self.a >> 1) | (bit << old_c);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag(bit != 0, C);
-        self.set_reset_flag(bit != 0, C);
+        self.a = (self.a >> 1) | (old_c << 7);
+        self.set_rotate_a_flags(bit != 0);
         self.save_op("RRA");
     }
     fn ex_af_af_alt(&mut self) {
@@ -1305,13 +1648,15 @@ impl Z80 {
     }
     fn add_hl_ss(&mut self, op: u32) {
         let hl: u32 = self.get_hl() as u32;
+        self.memptr = (hl as u16).wrapping_add(1);
         let sum = hl + op;
         self.set_l((sum & 0xff) as u8);
         self.set_h(((sum & 0xffff) >> 8) as u8);
 
-        self.reset_flag(N);
+        // S, Z and P/V stay, bits 5 and 3 come from the high byte
+        self.f = (self.f & (S | Z | P_V)) | ((sum >> 8) as u8 & (BIT_5 | BIT_3));
         self.set_reset_flag(sum > 0xffff, C);
-        self.set_reset_flag((hl & 0x7ff) + (op & 0x7ff) < (sum & 0x7ff), H);
+        self.set_reset_flag((hl & 0xfff) + (op & 0xfff) > 0xfff, H);
     }
     fn add_hl_bc(&mut self) {
         let bc = Z80::get_word(self.b, self.c) as u32;
@@ -1333,15 +1678,42 @@ impl Z80 {
         self.add_hl_ss(sp);
         self.save_op("ADD HL SP");
     }
+    // A + value + carry in, with every flag but the copies of bits 5 and 3
+    // computed from the operands
+    fn add_with_carry(&mut self, value: u8, carry: u8) -> u8 {
+        let sum = self.a as u16 + value as u16 + carry as u16;
+        let result = sum as u8;
+        let mut flags = Z80::sz53(result);
+        if (self.a & 0xf) + (value & 0xf) + carry > 0xf {
+            flags |= H;
+        }
+        if (self.a ^ value) & 0x80 == 0 && (self.a ^ result) & 0x80 != 0 {
+            flags |= P_V;
+        }
+        if sum > 0xff {
+            flags |= C;
+        }
+        self.f = flags;
+        result
+    }
+    fn sub_with_carry(&mut self, value: u8, carry: u8) -> u8 {
+        let diff = (self.a as i16) - (value as i16) - (carry as i16);
+        let result = diff as u8;
+        let mut flags = Z80::sz53(result) | N;
+        if ((self.a & 0xf) as i16) - ((value & 0xf) as i16) - (carry as i16) < 0 {
+            flags |= H;
+        }
+        if (self.a ^ value) & 0x80 != 0 && (self.a ^ result) & 0x80 != 0 {
+            flags |= P_V;
+        }
+        if diff < 0 {
+            flags |= C;
+        }
+        self.f = flags;
+        result
+    }
     fn add_a_r(&mut self, other: u8) {
-        let sum = (self.a as i16) + (other as i16);
-        self.a = (sum & 0xff) as u8;
-        self.set_reset_flag(((sum & 0xff) as i8) < 0, S);
-        self.set_reset_flag(sum == 0, Z);
-        self.set_reset_flag((sum - 1) & 0xf == 0xf, H);
-        self.set_reset_flag(sum > (sum & 0xff), P_V);
-        self.reset_flag(N);
-        self.set_reset_flag((sum - 1) & 0xff == 0xff, C);
+        self.a = self.add_with_carry(other, 0);
     }
     fn add_a_b(&mut self) {
         let op = self.b;
@@ -1380,20 +1752,13 @@ impl Z80 {
     }
     fn add_a_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let val = mem.peek(addr);
+        let val = self.read_mem(mem, addr);
         self.add_a_r(val);
         self.save_op("ADD A (HL)");
     }
     fn adc_a_r(&mut self, other: u8) {
-        let c = (self.f & C) as i16;
-        let sum = self.a as i16 + other as i16 + c;
-        self.a = (sum & 0xff) as u8;
-        self.set_reset_flag(sum < 0, S);
-        self.set_reset_flag(sum == 0, Z);
-        self.set_reset_flag((sum - 1) & 0xf == 0xf, H);
-        self.set_reset_flag(sum > (sum & 0xff), P_V);
-        self.reset_flag(N);
-        self.set_reset_flag((sum - 1) & 0xff == 0xff, C);
+        let c = self.f & C;
+        self.a = self.add_with_carry(other, c);
     }
     fn adc_a_b(&mut self) {
         let op = self.b;
@@ -1432,7 +1797,7 @@ impl Z80 {
     }
     fn adc_a_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let val = mem.peek(addr);
+        let val = self.read_mem(mem, addr);
         self.adc_a_r(val);
         self.save_op("ADC A (HL)");
     }
@@ -1443,17 +1808,7 @@ impl Z80 {
         self.save_op(&msg);
     }
     fn sub_a_r(&mut self, other: u8) {
-        let diff = self.a as i16 - other as i16;
-        let old_a = self.a;
-        self.a = diff as u8;
-        self.set_reset_flag(diff < 0, S);
-        self.set_reset_flag(diff == 0, Z);
-        self.set_reset_flag((old_a & 0xf) < (other & 0xf), H);
-        let overflow_high = (old_a >= 0x80) & (other >= 0x80) & ((diff as i8) > 0);
-        let overflow_low = (old_a < 0x80) & (other < 0x80) & ((diff as i8) < 0);
-        self.set_reset_flag(overflow_high | overflow_low, P_V);
-        self.set_flag(N);
-        self.set_reset_flag((diff & 0x80) > 0, C);
+        self.a = self.sub_with_carry(other, 0);
     }
     fn sub_a_b(&mut self) {
         let op = self.b;
@@ -1492,7 +1847,7 @@ impl Z80 {
     }
     fn sub_a_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let val = mem.peek(addr);
+        let val = self.read_mem(mem, addr);
         self.sub_a_r(val);
         self.save_op("SUB A (HL)");
     }
@@ -1503,18 +1858,8 @@ impl Z80 {
         self.save_op(&msg);
     }
     fn sbc_a_r(&mut self, other: u8) {
-        let c = self.f & 0x01;
-        let diff = self.a as i16 - other as i16 - c as i16;
-        let old_a = self.a;
-        self.a = diff as u8;
-        self.set_reset_flag(diff < 0, S);
-        self.set_reset_flag(diff == 0, Z);
-        self.set_reset_flag((old_a & 0xf) < (other & 0xf), H);
-        let overflow_high = (old_a >= 0x80) & (other >= 0x80) & ((diff as i8) > 0);
-        let overflow_low = (old_a < 0x80) & (other < 0x80) & ((diff as i8) < 0);
-        self.set_reset_flag(overflow_high | overflow_low, P_V);
-        self.set_flag(N);
-        self.set_reset_flag((diff & 0x80) > 0, C);
+        let c = self.f & C;
+        self.a = self.sub_with_carry(other, c);
     }
     fn sbc_a_b(&mut self) {
         let op = self.b;
@@ -1553,7 +1898,7 @@ impl Z80 {
     }
     fn sbc_a_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let val = mem.peek(addr);
+        let val = self.read_mem(mem, addr);
         self.sbc_a_r(val);
         self.save_op("SBC A (HL)");
     }
@@ -1593,19 +1938,21 @@ impl Z80 {
     }
     fn ld_a_at_bc(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.b, self.c);
-        let value = mem.peek(addr);
+        let value = self.read_mem(mem, addr);
         self.a = value;
+        self.memptr = addr.wrapping_add(1);
         self.save_op("LD A (BC)");
     }
     fn ld_a_at_de(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.d, self.e);
-        let value = mem.peek(addr);
+        let value = self.read_mem(mem, addr);
         self.a = value;
+        self.memptr = addr.wrapping_add(1);
         self.save_op("LD A (DE)");
     }
     fn ld_a_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let value = mem.peek(addr);
+        let value = self.read_mem(mem, addr);
         self.a = value;
         self.save_op("LD A (HL)");
     }
@@ -1613,15 +1960,15 @@ impl Z80 {
         let x1 = self.read_bus(mem);
         let x2 = self.read_bus(mem);
         let addr = Z80::get_word(x2, x1);
-        self.a = mem.peek(addr);
+        self.a = self.read_mem(mem, addr);
+        self.memptr = addr.wrapping_add(1);
         let msg = format!("LD A ({:x})", addr);
         self.save_op(&msg);
     }
     fn inc_b(&mut self) {
-        let inc: u16 = self.b as u16 + 1;
         let init = self.b;
-        self.adjust_f_inc_n(init, inc);
-        self.b = (inc & 0xff) as u8;
+        let result = self.inc_value(init);
+        self.b = result;
         self.save_op("INC B");
     }
     fn inc_bc(&mut self) {
@@ -1634,19 +1981,15 @@ impl Z80 {
     fn inc_de(&mut self) {
         let mut de = Z80::get_word(self.d, self.e);
         de = de.wrapping_add(1);
-        self.d = (de & 0xff) as u8;
-        self.e = ((de >> 8) & 0xff) as u8;
-        self.save_op("DEC DE");
+        self.e = (de & 0xff) as u8;
+        self.d = ((de >> 8) & 0xff) as u8;
+        self.save_op("INC DE");
     }
     fn inc_at_hl(&mut self, mem: &mut Memory) {
-        let addr = Z80::get_word(self.get_h(), self.get_l());
-        let val = mem.peek(addr) + 1;
-        mem.poke(addr, val);
-        self.set_reset_flag((val as i8) < 0, S);
-        self.set_reset_flag(val == 0, Z);
-        self.set_reset_flag((val - 1) & 0xf == 0xf, H);
-        self.set_reset_flag((val - 1) == 0x7f, P_V);
-        self.reset_flag(N);
+        let addr = self.get_indirect_hl(mem);
+        let value = self.read_to_modify(mem, addr);
+        let result = self.inc_value(value);
+        self.write_mem(mem, addr, result);
         self.save_op("INC (HL)");
     }
     fn dec_bc(&mut self) {
@@ -1659,8 +2002,8 @@ impl Z80 {
     fn dec_de(&mut self) {
         let mut de = Z80::get_word(self.d, self.e);
         de = de.wrapping_sub(1);
-        self.d = (de & 0xff) as u8;
-        self.e = ((de >> 8) & 0xff) as u8;
+        self.e = (de & 0xff) as u8;
+        self.d = ((de >> 8) & 0xff) as u8;
         self.save_op("DEC DE");
     }
     fn dec_hl(&mut self) {
@@ -1683,122 +2026,138 @@ impl Z80 {
     fn daa(&mut self) {
         let c = (self.f & C) == C;
         let h = (self.f & H) == H;
-        if ((self.a & 0x0f) > 0x09) | h {
-            self.a += 0x06;
+        let subtract = (self.f & N) == N;
+        let mut correction = 0;
+        let mut carry = c;
+        if h || (self.a & 0x0f) > 0x09 {
+            correction |= 0x06;
         }
-        if ((self.a & 0xf0) > 0x90) | c {
-            self.a += 0x60;
+        if c || self.a > 0x99 {
+            correction |= 0x60;
+            carry = true;
         }
+        let half = if subtract {
+            h && (self.a & 0x0f) < 0x06
+        } else {
+            (self.a & 0x0f) > 0x09
+        };
+        self.a = if subtract {
+            self.a.wrapping_sub(correction)
+        } else {
+            self.a.wrapping_add(correction)
+        };
+        self.f = Z80::sz53p(self.a) | (self.f & N);
+        self.set_reset_flag(half, H);
+        self.set_reset_flag(carry, C);
         self.save_op("DAA");
     }
     fn cpl(&mut self) {
         self.a = !self.a;
+        self.f = (self.f & (S | Z | P_V | C)) | (self.a & (BIT_5 | BIT_3)) | H | N;
         self.save_op("CPL");
     }
     fn inc_c(&mut self) {
-        let inc: u16 = self.c as u16 + 1;
         let init = self.c;
-        self.adjust_f_inc_n(init, inc);
-        self.c = (inc & 0xff) as u8;
+        let result = self.inc_value(init);
+        self.c = result;
         self.save_op("INC C");
     }
     fn inc_d(&mut self) {
-        let inc: u16 = self.d as u16 + 1;
         let init = self.d;
-        self.adjust_f_inc_n(init, inc);
-        self.d = (inc & 0xff) as u8;
+        let result = self.inc_value(init);
+        self.d = result;
         self.save_op("INC D");
     }
     fn inc_e(&mut self) {
-        let inc: u16 = self.e as u16 + 1;
         let init = self.e;
-        self.adjust_f_inc_n(init, inc);
-        self.e = (inc & 0xff) as u8;
+        let result = self.inc_value(init);
+        self.e = result;
         self.save_op("INC E");
     }
     fn inc_h(&mut self) {
-        let inc: u16 = self.get_h() as u16 + 1;
-        let init = self.h;
-        self.adjust_f_inc_n(init, inc);
-        self.set_h((inc & 0xff) as u8);
+        let init = self.get_h();
+        let result = self.inc_value(init);
+        self.set_h(result);
         self.save_op("INC H");
     }
     fn inc_l(&mut self) {
         let init = self.get_l();
-        let inc: u16 = init as u16 + 1;
-        self.adjust_f_inc_n(init, inc);
-        self.set_l((inc & 0xff) as u8);
-        self.save_op("INC C");
+        let result = self.inc_value(init);
+        self.set_l(result);
+        self.save_op("INC L");
     }
     fn inc_a(&mut self) {
-        let inc: u16 = self.a as u16 + 1;
         let init = self.a;
-        self.adjust_f_inc_n(init, inc);
-        self.a = (inc & 0xff) as u8;
+        let result = self.inc_value(init);
+        self.a = result;
         self.save_op("INC A");
     }
     fn dec_b(&mut self) {
-        let inc: u16 = self.b as u16 - 1;
         let init = self.b;
-        self.adjust_f_dec_n(init, inc);
-        self.b = (inc & 0xff) as u8;
+        let result = self.dec_value(init);
+        self.b = result;
         self.save_op("DEC B");
     }
     fn dec_c(&mut self) {
-        let inc: u16 = self.c as u16 - 1;
         let init = self.c;
-        self.adjust_f_dec_n(init, inc);
-        self.c = (inc & 0xff) as u8;
+        let result = self.dec_value(init);
+        self.c = result;
         self.save_op("DEC C");
     }
     fn dec_d(&mut self) {
-        let inc: u16 = self.d as u16 - 1;
         let init = self.d;
-        self.adjust_f_dec_n(init, inc);
-        self.d = (inc & 0xff) as u8;
+        let result = self.dec_value(init);
+        self.d = result;
         self.save_op("DEC D");
     }
     fn dec_e(&mut self) {
-        let inc: u16 = self.e as u16 - 1;
         let init = self.e;
-        self.adjust_f_dec_n(init, inc);
-        self.e = (inc & 0xff) as u8;
+        let result = self.dec_value(init);
+        self.e = result;
         self.save_op("DEC E");
     }
     fn dec_h(&mut self) {
-        let inc: u16 = self.get_h() as u16 - 1;
-        let init = self.h;
-        self.adjust_f_dec_n(init, inc);
-        self.set_h((inc & 0xff) as u8);
+        let init = self.get_h();
+        let result = self.dec_value(init);
+        self.set_h(result);
         self.save_op("DEC H");
     }
     fn dec_l(&mut self) {
         let init = self.get_l();
-        let inc: u16 = init as u16 - 1;
-        self.adjust_f_dec_n(init, inc);
-        self.set_l((inc & 0xff) as u8);
+        let result = self.dec_value(init);
+        self.set_l(result);
         self.save_op("DEC L");
     }
     fn dec_a(&mut self) {
-        let inc: u16 = self.a as u16 - 1;
         let init = self.a;
-        self.adjust_f_dec_n(init, inc);
-        self.a = (inc & 0xff) as u8;
+        let result = self.dec_value(init);
+        self.a = result;
         self.save_op("DEC A");
     }
-    fn adjust_f_inc_n(&mut self, initial: u8, result: u16) {
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(initial == 0xff, H);
-        self.set_reset_flag(initial == 0x7f, P_V);
-        self.reset_flag(N);
+    // INC and DEC of 8 bits leave the carry alone
+    fn inc_value(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        let mut flags = (self.f & C) | Z80::sz53(result);
+        if value & 0xf == 0xf {
+            flags |= H;
+        }
+        if value == 0x7f {
+            flags |= P_V;
+        }
+        self.f = flags;
+        result
     }
-    fn adjust_f_dec_n(&mut self, initial: u8, result: u16) {
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag((initial & 0xf) < (result as u8 & 0xf), H);
-        self.set_reset_flag(initial == 0x80, P_V);
-        self.reset_flag(N);
+    fn dec_value(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        let mut flags = (self.f & C) | Z80::sz53(result) | N;
+        if value & 0xf == 0 {
+            flags |= H;
+        }
+        if value == 0x80 {
+            flags |= P_V;
+        }
+        self.f = flags;
+        result
     }
     fn ld_de(&mut self, mem: &Memory) {
         let x1 = self.read_bus(mem);
@@ -1811,20 +2170,29 @@ impl Z80 {
     }
 
     fn jr_nz_e(&mut self, mem: &Memory) {
-        let x1 = self.read_bus(mem) as i8;
-        let x2 = self.pc as i16 + x1 as i16;
-        if !Z80::check_flag(self.f, Z) {
-            self.pc = x2 as u16;
+        let cond = !Z80::check_flag(self.f, Z);
+        self.jr_cc_e(mem, cond);
+        self.save_op("JR NZ");
+    }
+    // Relative jump: 5 internal cycles adding the offset, with its
+    // address still on the bus, when the condition holds
+    fn jr_cc_e(&mut self, mem: &Memory, cond: bool) {
+        let offset = self.read_bus(mem) as i8;
+        if cond {
+            let last = self.pc.wrapping_sub(1);
+            self.internal_cycles(mem, last, 5);
+            self.pc = self.pc.wrapping_add(offset as u16);
+            self.memptr = self.pc;
+            self.instruction_t_states += 5;
         }
-        let msg = format!("JR NZ {:x}", x1);
-        self.save_op(&msg);
     }
     fn jr_e(&mut self, mem: &Memory) {
-        let x1 = self.read_bus(mem) as i8;
-        let x2 = self.pc as i16 + x1 as i16;
-        self.pc = x2 as u16;
-        let msg = format!("JR {:x}", x1);
-        self.save_op(&msg);
+        let offset = self.read_bus(mem) as i8;
+        let last = self.pc.wrapping_sub(1);
+        self.internal_cycles(mem, last, 5);
+        self.pc = self.pc.wrapping_add(offset as u16);
+        self.memptr = self.pc;
+        self.save_op("JR");
     }
     fn ld_hl(&mut self, mem: &Memory) {
         let x1 = self.read_bus(mem);
@@ -1837,10 +2205,8 @@ impl Z80 {
         self.save_op(&msg);
     }
     fn inc_hl(&mut self) {
-        let mut hl: u32 = Z80::get_word(self.get_h(), self.get_l()) as u32;
-        hl += 1;
-        let hi = ((hl >> 8) & 0xff) as u8;
-        let lo = (hl & 0xff) as u8;
+        let hl = self.get_hl().wrapping_add(1);
+        let (hi, lo) = Z80::get_bytes(hl);
         self.set_h(hi);
         self.set_l(lo);
         self.save_op("INC HL")
@@ -1848,33 +2214,37 @@ impl Z80 {
     fn ld_sp(&mut self, mem: &Memory) {
         let x1 = self.read_bus(mem);
         let x2 = self.read_bus(mem);
-        let dir = Z80::get_word(x1, x2);
+        let dir = Z80::get_word(x2, x1);
         self.sp = dir;
-        let msg = format!("LD SP {:x} {:x}", x1, x2);
+        let msg = format!("LD SP {:x}", dir);
         self.save_op(&msg);
     }
     fn dec_at_hl(&mut self, mem: &mut Memory) {
-        let dir = Z80::get_word(self.get_h(), self.get_l());
-        let value = mem.peek(dir) as i8;
-        let res = (value as i16) - 1;
-        let to_mem = (res & 0xff) as u8;
-        mem.poke(dir, to_mem);
-        let mut cond = res < 0;
-        self.set_reset_flag(cond, S);
-        cond = res == 0;
-        self.set_reset_flag(cond, Z);
-        cond = (value & 0xf) < ((res & 0xf) as i8);
-        self.set_reset_flag(cond, H);
-        cond = value as u8 == 0x80;
-        self.set_reset_flag(cond, P_V);
-        self.set_flag(N);
+        let addr = self.get_indirect_hl(mem);
+        let value = self.read_to_modify(mem, addr);
+        let result = self.dec_value(value);
+        self.write_mem(mem, addr, result);
         self.save_op("DEC (HL)");
     }
     fn ld_hl_n(&mut self, mem: &mut Memory) {
-        let address = Z80::get_word(self.get_h(), self.get_l());
+        let address = match self.opcode_prefix {
+            OpCodePrefix::DD | OpCodePrefix::FD => {
+                // The displacement comes before the value, and the address
+                // is added up while the value is being read
+                let displacement = self.read_bus(mem) as i8;
+                let address = self.get_hl().wrapping_add(displacement as u16);
+                self.memptr = address;
+                address
+            }
+            _ => self.get_hl(),
+        };
         let n = self.read_bus(mem);
-        mem.poke(address, n);
-        let msg = format!("LD HL {:x}", n);
+        if self.opcode_prefix != OpCodePrefix::None {
+            let last = self.pc.wrapping_sub(1);
+            self.internal_cycles(mem, last, 2);
+        }
+        self.write_mem(mem, address, n);
+        let msg = format!("LD (HL) {:x}", n);
         self.save_op(&msg);
     }
     fn jp_nn(&mut self, mem: &Memory) {
@@ -1883,6 +2253,7 @@ impl Z80 {
         let dir = ((x2 as u16) << 8) + (x1 as u16);
 
         self.pc = dir;
+        self.memptr = dir;
         let msg = format!("JP {:x} {:x}", x2, x1);
         self.save_op(&msg);
     }
@@ -1912,7 +2283,7 @@ impl Z80 {
     }
     fn ld_b_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let byte = mem.peek(addr);
+        let byte = self.read_mem(mem, addr);
         self.b = byte;
         self.save_op("LD B HL");
     }
@@ -1946,7 +2317,7 @@ impl Z80 {
     }
     fn ld_c_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let byte = mem.peek(addr);
+        let byte = self.read_mem(mem, addr);
         self.c = byte;
         self.save_op("LD C HL");
     }
@@ -1982,7 +2353,7 @@ impl Z80 {
     }
     fn ld_d_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let byte = mem.peek(addr);
+        let byte = self.read_mem(mem, addr);
         self.d = byte;
         self.save_op("LD D HL");
     }
@@ -2018,7 +2389,7 @@ impl Z80 {
     }
     fn ld_e_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let byte = mem.peek(addr);
+        let byte = self.read_mem(mem, addr);
         self.e = byte;
         self.save_op("LD E HL");
     }
@@ -2061,7 +2432,7 @@ impl Z80 {
     }
     fn ld_h_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let byte = mem.peek(addr);
+        let byte = self.read_mem(mem, addr);
         self.h = byte;
         self.save_op("LD H HL");
     }
@@ -2102,12 +2473,12 @@ impl Z80 {
     }
     fn ld_l_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let byte = mem.peek(addr);
+        let byte = self.read_mem(mem, addr);
         self.l = byte;
         self.save_op("LD L HL");
     }
     fn ld_l_a(&mut self) {
-        let op = self.e;
+        let op = self.a;
         self.set_l(op);
         self.save_op("LD L A");
     }
@@ -2151,60 +2522,40 @@ impl Z80 {
         let x1 = self.read_bus(mem);
         let x2 = self.read_bus(mem);
         let addr = Z80::get_word(x2, x1);
-        mem.poke(addr, self.a);
+        self.write_mem(mem, addr, self.a);
+        self.memptr = Z80::get_word(self.a, addr.wrapping_add(1) as u8);
         let msg = format!("LD ({:x}) A", x1);
         self.save_op(&msg);
     }
     fn jr_z_e(&mut self, mem: &Memory) {
-        let x1 = self.read_bus(mem) as i8;
-        let x2 = (self.pc as i16).wrapping_add(x1 as i16);
-        if Z80::check_flag(self.f, Z) {
-            self.pc = x2 as u16;
-        }
-        let msg = format!("JR Z {:x}", x1);
-        self.save_op(&msg);
+        let cond = Z80::check_flag(self.f, Z);
+        self.jr_cc_e(mem, cond);
+        self.save_op("JR Z");
     }
     fn djnz_e(&mut self, mem: &Memory) {
-        let x1 = self.read_bus(mem) as i16;
-        let x2 = self.pc as i16 + x1;
-        self.b -= 1;
-        if self.b == 0 {
-            self.pc = x2 as u16;
-        }
-        let msg = format!("DJNZ {:x}", x1);
-        self.save_op(&msg);
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
+        self.b = self.b.wrapping_sub(1);
+        let cond = self.b != 0;
+        self.jr_cc_e(mem, cond);
+        self.save_op("DJNZ");
     }
     fn ld_hl_nn(&mut self, mem: &Memory) {
         let x1 = self.read_bus(mem);
         let x2 = self.read_bus(mem);
         let addr = Z80::get_word(x2, x1);
-        let lo = mem.peek(addr);
-        let hi = mem.peek(addr + 1);
+        let lo = self.read_mem(mem, addr);
+        let hi = self.read_mem(mem, addr.wrapping_add(1));
+        self.memptr = addr.wrapping_add(1);
         self.set_h(hi);
         self.set_l(lo);
         let msg = format!("LD HL {:x}{:x}", x2, x1);
         self.save_op(&msg);
     }
+    // Like SUB without keeping the result, bits 5 and 3 come from the operand
     fn cp_r(&mut self, other: u8) {
-        let diff = (self.a as i16) - (other as i16);
-        let mut cond = (diff & 0x80) > 0;
-        self.set_reset_flag(cond, S);
-        cond = diff == 0;
-        self.set_reset_flag(cond, Z);
-        cond = (self.a & 0xF) < (other & 0xF);
-        self.set_reset_flag(cond, H);
-        cond = ((self.a > 0x80) & (other > 0x80) & (diff > 0))
-            | ((self.a < 0x80) & (other < 0x80) & (diff < 0));
-        self.set_reset_flag(cond, P_V);
-        self.set_flag(N);
-
-        cond = (other & BIT_3) > 0;
-        self.set_reset_flag(cond, BIT_3);
-        cond = (other & BIT_5) > 0;
-        self.set_reset_flag(cond, BIT_5);
-
-        cond = diff & 0x100 != 0;
-        self.set_reset_flag(cond, C);
+        self.sub_with_carry(other, 0);
+        self.f = (self.f & !(BIT_5 | BIT_3)) | (other & (BIT_5 | BIT_3));
     }
     fn cp_b(&mut self) {
         let op = self.b;
@@ -2238,7 +2589,7 @@ impl Z80 {
     }
     fn cp_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let op = mem.peek(addr);
+        let op = self.read_mem(mem, addr);
         self.cp_r(op);
         self.save_op("CP (HL)");
     }
@@ -2260,30 +2611,30 @@ let msg = format!("LD H {:x}", x1);
         self.save_op(&msg);
     }
     fn jr_c_e(&mut self, mem: &Memory) {
-        let e = self.read_bus(mem);
-        if self.f & 0x01 == 1 {
-            self.pc += e as u16;
-        }
-        let msg = format!("JR C {:x}", e);
-        self.save_op(&msg);
+        let cond = Z80::check_flag(self.f, C);
+        self.jr_cc_e(mem, cond);
+        self.save_op("JR C");
     }
     fn jr_nc_e(&mut self, mem: &Memory) {
-        let e = self.read_bus(mem);
-        if self.f & 0x01 == 0 {
-            self.pc += e as u16;
-        }
-        let msg = format!("JR NC {:x}", e);
-        self.save_op(&msg);
+        let cond = !Z80::check_flag(self.f, C);
+        self.jr_cc_e(mem, cond);
+        self.save_op("JR NC");
     }
-    fn out_n_a(&mut self, mem: &Memory) {
+    fn out_n_a(&mut self, mem: &mut Memory, io: &mut IoBus) {
         let x1 = self.read_bus(mem);
         let dir = Z80::get_word(self.a, x1);
+        self.write_port(mem, io, dir, self.a);
+        self.memptr = Z80::get_word(self.a, x1.wrapping_add(1));
         let msg = format!("out {:x} A", x1);
         self.save_op(&msg);
-        println!(
-            "  TODO: poner el valor {} en la dirección {:x}",
-            self.a, dir
-        );
+    }
+    fn in_a_n(&mut self, mem: &Memory, io: &mut IoBus) {
+        let x1 = self.read_bus(mem);
+        let dir = Z80::get_word(self.a, x1);
+        self.a = self.read_port(mem, io, dir);
+        self.memptr = dir.wrapping_add(1);
+        let msg = format!("in A {:x}", x1);
+        self.save_op(&msg);
     }
     fn exx(&mut self) {
         let mut swap = self.b;
@@ -2326,14 +2677,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn and_r(&mut self, other: u8) {
         self.a &= other;
-        let mut cond = (self.a as i8) < 0;
-        self.set_reset_flag(cond, S);
-        cond = self.a == 0;
-        self.set_reset_flag(cond, Z);
-        self.set_flag(H);
-        self.reset_flag(P_V);
-        self.reset_flag(N);
-        self.reset_flag(C);
+        self.f = Z80::sz53p(self.a) | H;
     }
     fn and_b(&mut self) {
         let op = self.b;
@@ -2367,7 +2711,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn and_at_hl(&mut self, mem: &Memory) {
         let addr = self.get_indirect_hl(mem);
-        let op = mem.peek(addr);
+        let op = self.read_mem(mem, addr);
         self.and_r(op);
         self.save_op("AND (HL)");
     }
@@ -2383,158 +2727,210 @@ let msg = format!("LD H {:x}", x1);
         self.save_op(&msg);
     }
     fn ld_at_nn_bc(&mut self, mem: &mut Memory) {
-        let addr = Z80::get_word(self.read_bus(mem), self.read_bus(mem));
-        mem.poke(addr, self.c);
-        mem.poke(addr + 1, self.b);
+        let lo = self.read_bus(mem);
+        let hi = self.read_bus(mem);
+        let addr = Z80::get_word(hi, lo);
+        let (value_hi, value_lo) = (self.b, self.c);
+        self.write_mem(mem, addr, value_lo);
+        self.write_mem(mem, addr.wrapping_add(1), value_hi);
+        self.memptr = addr.wrapping_add(1);
         self.save_op("LD (nn) BC");
     }
     fn ld_bc_at_nn(&mut self, mem: &Memory) {
         let lo = self.read_bus(mem);
         let hi = self.read_bus(mem);
         let addr = Z80::get_word(hi, lo);
-        self.c = mem.peek(addr);
-        self.b = mem.peek(addr + 1);
+        let value_lo = self.read_mem(mem, addr);
+        let value_hi = self.read_mem(mem, addr.wrapping_add(1));
+        self.c = value_lo;
+        self.b = value_hi;
+        self.memptr = addr.wrapping_add(1);
+        self.save_op("LD BC (nn)");
     }
     fn ld_de_at_nn(&mut self, mem: &Memory) {
         let lo = self.read_bus(mem);
         let hi = self.read_bus(mem);
         let addr = Z80::get_word(hi, lo);
-        self.d = mem.peek(addr);
-        self.e = mem.peek(addr + 1);
+        let value_lo = self.read_mem(mem, addr);
+        let value_hi = self.read_mem(mem, addr.wrapping_add(1));
+        self.e = value_lo;
+        self.d = value_hi;
+        self.memptr = addr.wrapping_add(1);
+        self.save_op("LD DE (nn)");
     }
     fn ld_hl_at_nn(&mut self, mem: &Memory) {
         let lo = self.read_bus(mem);
         let hi = self.read_bus(mem);
         let addr = Z80::get_word(hi, lo);
-        self.h = mem.peek(addr);
-        self.l = mem.peek(addr + 1);
+        let value_lo = self.read_mem(mem, addr);
+        let value_hi = self.read_mem(mem, addr.wrapping_add(1));
+        self.l = value_lo;
+        self.h = value_hi;
+        self.memptr = addr.wrapping_add(1);
+        self.save_op("LD HL (nn)");
     }
     fn ld_sp_at_nn(&mut self, mem: &Memory) {
         let lo = self.read_bus(mem);
         let hi = self.read_bus(mem);
         let addr = Z80::get_word(hi, lo);
-        let sp_h = mem.peek(addr);
-        let sp_l = mem.peek(addr + 1);
-        self.sp = Z80::get_word(sp_h, sp_l);
-    }
-    fn ld_i_a(&mut self) {
+        let value_lo = self.read_mem(mem, addr);
+        let value_hi = self.read_mem(mem, addr.wrapping_add(1));
+        let sp_lo = value_lo;
+        self.sp = Z80::get_word(value_hi, sp_lo);
+        self.memptr = addr.wrapping_add(1);
+        self.save_op("LD SP (nn)");
+    }
+    fn ld_i_a(&mut self, mem: &Memory) {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
         self.i = self.a;
         self.save_op("LD I A");
     }
-    fn ld_r_a(&mut self) {
+    fn ld_r_a(&mut self, mem: &Memory) {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
         self.r = self.a;
         self.save_op("LD R A");
     }
-    fn ld_a_i(&mut self) {
+    // P/V shows IFF2, the state the interrupts will return to
+    fn ld_a_i(&mut self, mem: &Memory) {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
         self.a = self.i;
+        self.f = (self.f & C) | Z80::sz53(self.a);
+        let iff2 = self.iff2;
+        self.set_reset_flag(iff2, P_V);
         self.save_op("LD A I");
     }
-    fn ld_a_r(&mut self) {
+    fn ld_a_r(&mut self, mem: &Memory) {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
         self.a = self.r;
+        self.f = (self.f & C) | Z80::sz53(self.a);
+        let iff2 = self.iff2;
+        self.set_reset_flag(iff2, P_V);
         self.save_op("LD A R");
     }
-    fn sbc_hl_r(&mut self, hi: u8, lo: u8) {
-        //let hl = ((self.h as i16) << 8) + (self.l as i16);
-        //let r = ((hi as i16) << 8) + (lo as i16);
-        let hl = Z80::get_word(self.get_h(), self.get_l()) as i16;
-        let r = Z80::get_word(hi, lo) as i16;
-        let c = (self.f & 0x01) as i16;
+    // ADC and SBC of 16 bits work a byte at a time, the flags come from
+    // the high one except Z, that looks at the whole result
+    fn sbc_hl_r(&mut self, mem: &Memory, hi: u8, lo: u8) {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 7);
+        let hl = self.get_hl();
+        self.memptr = hl.wrapping_add(1);
+        let value = Z80::get_word(hi, lo);
+        let c = self.get_flag(C) as i32;
 
-        let diff = (hl as i32) - (r as i32) - (c as i32);
-        self.set_l((diff & 0xff) as u8);
-        self.set_h(((diff >> 8) & 0xff) as u8);
-        self.set_reset_flag(diff < 0, S);
-        self.set_reset_flag(diff == 0, Z);
-        let cond = self.l < (lo + (c as u8));
-        self.set_reset_flag(cond, H);
-        self.set_reset_flag((diff < 0) | (diff > 255), P_V);
-        self.set_flag(N);
-        self.set_reset_flag((diff as u16) > (hl as u16), C);
-    }
-    fn sbc_hl_de(&mut self) {
+        let diff = (hl as i32) - (value as i32) - c;
+        let result = diff as u16;
+        let (res_hi, res_lo) = Z80::get_bytes(result);
+        self.set_h(res_hi);
+        self.set_l(res_lo);
+        self.f = (res_hi & (S | BIT_5 | BIT_3)) | N;
+        self.set_reset_flag(result == 0, Z);
+        self.set_reset_flag(((hl & 0xfff) as i32) - ((value & 0xfff) as i32) - c < 0, H);
+        self.set_reset_flag((hl ^ value) & 0x8000 != 0 && (hl ^ result) & 0x8000 != 0, P_V);
+        self.set_reset_flag(diff < 0, C);
+    }
+    fn sbc_hl_de(&mut self, mem: &Memory) {
         let hi = self.d;
         let lo = self.e;
-        self.sbc_hl_r(hi, lo);
+        self.sbc_hl_r(mem, hi, lo);
         self.save_op("SBC HL DE")
     }
-    fn sbc_hl_bc(&mut self) {
+    fn sbc_hl_bc(&mut self, mem: &Memory) {
         let hi = self.b;
         let lo = self.c;
-        self.sbc_hl_r(hi, lo);
+        self.sbc_hl_r(mem, hi, lo);
         self.save_op("SBC HL BC")
     }
-    fn sbc_hl_hl(&mut self) {
+    fn sbc_hl_hl(&mut self, mem: &Memory) {
         let hi = self.h;
         let lo = self.l;
-        self.sbc_hl_r(hi, lo);
+        self.sbc_hl_r(mem, hi, lo);
         self.save_op("SBC HL HL")
     }
-    fn sbc_hl_sp(&mut self) {
-        let bytes = Z80::get_bytes(self.sp);
-        self.sbc_hl_r(bytes.0, bytes.1);
+    fn sbc_hl_sp(&mut self, mem: &Memory) {
+        let (hi, lo) = Z80::get_bytes(self.sp);
+        self.sbc_hl_r(mem, hi, lo);
         self.save_op("SBC HL SP")
     }
-    fn adc_hl_r(&mut self, hi: u8, lo: u8) {
-        let hl = Z80::get_word(self.h, self.l) as i16;
-        let r = Z80::get_word(hi, lo) as i16;
-        let c = self.get_flag(C) as i16;
+    fn adc_hl_r(&mut self, mem: &Memory, hi: u8, lo: u8) {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 7);
+        let hl = self.get_hl();
+        self.memptr = hl.wrapping_add(1);
+        let value = Z80::get_word(hi, lo);
+        let c = self.get_flag(C) as u32;
 
-        let diff = hl + r + c;
-        self.l = (diff & 0xff) as u8;
-        self.h = ((diff >> 8) & 0xff) as u8;
-        self.set_reset_flag(diff < 0, S);
-        self.set_reset_flag(diff == 0, Z);
-        let cond = self.l < (lo + (c as u8));
-        self.set_reset_flag(cond, H);
-        self.set_reset_flag((diff < 0) | (diff > 255), P_V);
-        self.set_flag(N);
-        self.set_reset_flag((diff as u16) > (hl as u16), C);
-    }
-    fn adc_hl_de(&mut self) {
+        let sum = (hl as u32) + (value as u32) + c;
+        let result = sum as u16;
+        let (res_hi, res_lo) = Z80::get_bytes(result);
+        self.set_h(res_hi);
+        self.set_l(res_lo);
+        self.f = res_hi & (S | BIT_5 | BIT_3);
+        self.set_reset_flag(result == 0, Z);
+        self.set_reset_flag(((hl & 0xfff) as u32) + ((value & 0xfff) as u32) + c > 0xfff, H);
+        self.set_reset_flag((hl ^ value) & 0x8000 == 0 && (hl ^ result) & 0x8000 != 0, P_V);
+        self.set_reset_flag(sum > 0xffff, C);
+    }
+    fn adc_hl_de(&mut self, mem: &Memory) {
         let hi = self.d;
         let lo = self.e;
-        self.adc_hl_r(hi, lo);
-        self.save_op("SBC HL DE")
+        self.adc_hl_r(mem, hi, lo);
+        self.save_op("ADC HL DE")
     }
-    fn adc_hl_bc(&mut self) {
+    fn adc_hl_bc(&mut self, mem: &Memory) {
         let hi = self.b;
         let lo = self.c;
-        self.adc_hl_r(hi, lo);
-        self.save_op("SBC HL BC")
+        self.adc_hl_r(mem, hi, lo);
+        self.save_op("ADC HL BC")
     }
-    fn adc_hl_hl(&mut self) {
+    fn adc_hl_hl(&mut self, mem: &Memory) {
         let hi = self.h;
         let lo = self.l;
-        self.adc_hl_r(hi, lo);
-        self.save_op("SBC HL HL")
+        self.adc_hl_r(mem, hi, lo);
+        self.save_op("ADC HL HL")
     }
-    fn adc_hl_sp(&mut self) {
-        let bytes = Z80::get_bytes(self.sp);
-        self.adc_hl_r(bytes.0, bytes.1);
-        self.save_op("SBC HL SP")
+    fn adc_hl_sp(&mut self, mem: &Memory) {
+        let (hi, lo) = Z80::get_bytes(self.sp);
+        self.adc_hl_r(mem, hi, lo);
+        self.save_op("ADC HL SP")
     }
     fn ld_nn_de(&mut self, mem: &mut Memory) {
-        let addr = Z80::get_word(self.read_bus(mem), self.read_bus(mem));
-        mem.poke(addr, self.e);
-        mem.poke(addr + 1, self.d);
+        let lo = self.read_bus(mem);
+        let hi = self.read_bus(mem);
+        let addr = Z80::get_word(hi, lo);
+        let (value_hi, value_lo) = (self.d, self.e);
+        self.write_mem(mem, addr, value_lo);
+        self.write_mem(mem, addr.wrapping_add(1), value_hi);
+        self.memptr = addr.wrapping_add(1);
         self.save_op("LD (nn) DE");
     }
     fn ld_nn_hl(&mut self, mem: &mut Memory) {
-        let addr = Z80::get_word(self.read_bus(mem), self.read_bus(mem));
-        mem.poke(addr, self.get_l());
-        mem.poke(addr + 1, self.get_h());
-
+        let lo = self.read_bus(mem);
+        let hi = self.read_bus(mem);
+        let addr = Z80::get_word(hi, lo);
+        let (value_hi, value_lo) = (self.get_h(), self.get_l());
+        self.write_mem(mem, addr, value_lo);
+        self.write_mem(mem, addr.wrapping_add(1), value_hi);
+        self.memptr = addr.wrapping_add(1);
         self.save_op("LD (nn) HL");
     }
     fn ld_nn_sp(&mut self, mem: &mut Memory) {
-        let addr = Z80::get_word(self.read_bus(mem), self.read_bus(mem));
-        mem.poke(addr, (self.sp & 0xff) as u8);
-        mem.poke(addr + 1, ((self.sp >> 8) & 0xff) as u8);
-        self.save_op("LD (nn) HL");
+        let lo = self.read_bus(mem);
+        let hi = self.read_bus(mem);
+        let addr = Z80::get_word(hi, lo);
+        let (value_hi, value_lo) = ((self.sp >> 8) as u8, self.sp as u8);
+        self.write_mem(mem, addr, value_lo);
+        self.write_mem(mem, addr.wrapping_add(1), value_hi);
+        self.memptr = addr.wrapping_add(1);
+        self.save_op("LD (nn) SP");
     }
     fn ret_cc(&mut self, mem: &Memory, cond: bool) {
         if cond {
             self.ret(mem);
+            self.instruction_t_states += 6;
         }
         self.save_op("RET CC");
     }
@@ -2569,43 +2965,35 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("RET PE");
     }
     fn ret_p(&mut self, mem: &Memory) {
-        let cond = self.f & S != 0;
+        let cond = self.f & S == 0;
         self.ret_cc(mem, cond);
         self.save_op("RET P");
     }
     fn ret_m(&mut self, mem: &Memory) {
-        let cond = self.f & S == 0;
+        let cond = self.f & S != 0;
         self.ret_cc(mem, cond);
         self.save_op("RET M");
     }
     fn pop_bc(&mut self, mem: &Memory) {
-        let lo = mem.peek(self.sp);
-        let hi = mem.peek(self.sp + 1);
-        self.sp += 2;
+        let (hi, lo) = self.pop_qq(mem);
         self.b = hi;
         self.c = lo;
         self.save_op("POP BC");
     }
     fn pop_de(&mut self, mem: &Memory) {
-        let lo = mem.peek(self.sp);
-        let hi = mem.peek(self.sp + 1);
-        self.sp += 2;
+        let (hi, lo) = self.pop_qq(mem);
         self.d = hi;
         self.e = lo;
         self.save_op("POP DE");
     }
     fn pop_hl(&mut self, mem: &Memory) {
-        let lo = mem.peek(self.sp);
-        let hi = mem.peek(self.sp + 1);
-        self.sp += 2;
+        let (hi, lo) = self.pop_qq(mem);
         self.set_h(hi);
         self.set_l(lo);
         self.save_op("POP HL");
     }
     fn pop_af(&mut self, mem: &Memory) {
-        let lo = mem.peek(self.sp);
-        let hi = mem.peek(self.sp + 1);
-        self.sp += 2;
+        let (hi, lo) = self.pop_qq(mem);
         self.a = hi;
         self.f = lo;
         self.save_op("POP AF");
@@ -2613,8 +3001,9 @@ let msg = format!("LD H {:x}", x1);
     fn jp_cc(&mut self, cond: bool, mem: &Memory) {
         let lo = self.read_bus(mem);
         let hi = self.read_bus(mem);
+        self.memptr = Z80::get_word(hi, lo);
         if cond {
-            self.pc = Z80::get_word(hi, lo);
+            self.pc = self.memptr;
         }
     }
     fn jp_nz(&mut self, mem: &Memory) {
@@ -2648,75 +3037,91 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("JP PE");
     }
     fn jp_m(&mut self, mem: &Memory) {
-        let cond = self.f & S == 0;
+        let cond = self.f & S != 0;
         self.jp_cc(cond, mem);
         self.save_op("JP M");
     }
     fn jp_p(&mut self, mem: &Memory) {
-        let cond = self.f & S != 0;
+        let cond = self.f & S == 0;
         self.jp_cc(cond, mem);
         self.save_op("JP P");
     }
     fn jp_at_hl(&mut self) {
-        let addr = Z80::get_word(self.get_h(), self.get_l());
-        self.sp = addr;
+        self.pc = self.get_hl();
         self.save_op("JP (HL)");
     }
     fn call_cc_nn(&mut self, cond: bool, mem: &mut Memory) {
         let lo = self.read_bus(mem);
         let hi = self.read_bus(mem);
+        self.memptr = Z80::get_word(hi, lo);
         if cond {
-            let pc_lo = (self.pc & 0xff) as u8;
-            let pc_hi = ((self.pc >> 8) & 0xff) as u8;
-            mem.poke(self.sp - 1, pc_lo);
-            mem.poke(self.sp - 2, pc_hi);
-            self.sp -= 2;
+            let last = self.pc.wrapping_sub(1);
+            self.internal_cycles(mem, last, 1);
+            let (pc_hi, pc_lo) = Z80::get_bytes(self.pc);
+            self.push_bytes(mem, pc_hi, pc_lo);
             self.pc = Z80::get_word(hi, lo);
+            self.instruction_t_states += 7;
         }
     }
     fn call_nz(&mut self, mem: &mut Memory) {
         let cond = self.f & Z == 0;
         self.call_cc_nn(cond, mem);
+        self.save_op("CALL NZ");
     }
     fn call_z(&mut self, mem: &mut Memory) {
         let cond = self.f & Z != 0;
         self.call_cc_nn(cond, mem);
-        self.save_op("JP Z");
+        self.save_op("CALL Z");
     }
     fn call_nc(&mut self, mem: &mut Memory) {
         let cond = self.f & C == 0;
         self.call_cc_nn(cond, mem);
-        self.save_op("JP NC");
+        self.save_op("CALL NC");
     }
     fn call_c(&mut self, mem: &mut Memory) {
         let cond = self.f & C != 0;
         self.call_cc_nn(cond, mem);
-        self.save_op("JP C");
+        self.save_op("CALL C");
     }
     fn call_po(&mut self, mem: &mut Memory) {
         let cond = self.f & P_V == 0;
         self.call_cc_nn(cond, mem);
-        self.save_op("JP PO");
+        self.save_op("CALL PO");
     }
     fn call_pe(&mut self, mem: &mut Memory) {
         let cond = self.f & P_V != 0;
         self.call_cc_nn(cond, mem);
-        self.save_op("JP PE");
+        self.save_op("CALL PE");
     }
     fn call_m(&mut self, mem: &mut Memory) {
-        let cond = self.f & S == 0;
+        let cond = self.f & S != 0;
         self.call_cc_nn(cond, mem);
-        self.save_op("JP M");
+        self.save_op("CALL M");
     }
     fn call_p(&mut self, mem: &mut Memory) {
-        let cond = self.f & S != 0;
+        let cond = self.f & S == 0;
         self.call_cc_nn(cond, mem);
-        self.save_op("JP P");
+        self.save_op("CALL P");
     }
     fn push_qq(&mut self, mem: &mut Memory, hi: u8, lo: u8) {
-        mem.poke(self.sp - 1, lo);
-        mem.poke(self.sp - 2, hi);
-        self.sp -= 2;
+        // A cycle decrementing SP, with IR on the bus
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
+        self.push_bytes(mem, hi, lo);
+    }
+    // The two writes of a push, high byte first
+    fn push_bytes(&mut self, mem: &mut Memory, hi: u8, lo: u8) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_mem(mem, self.sp, hi);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_mem(mem, self.sp, lo);
+    }
+    fn pop_qq(&mut self, mem: &Memory) -> (u8, u8) {
+        let lo = self.read_mem(mem, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let hi = self.read_mem(mem, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        (hi, lo)
     }
     fn push_bc(&mut self, mem: &mut Memory) {
         let hi = self.b;
@@ -2744,24 +3149,17 @@ let msg = format!("LD H {:x}", x1);
     }
     fn add_a_n(&mut self, mem: &Memory) {
         let n: u8 = self.read_bus(mem);
-        let sum: i16 = (self.a as i16) + (n as i16);
-        self.a = (sum & 0xff) as u8;
-        self.set_reset_flag(((sum & 0xff) as i8) < 0, S);
-        self.set_reset_flag(sum == 0, Z);
-        self.set_reset_flag((sum - 1) & 0xf == 0xf, H);
-        self.set_reset_flag(sum > (sum & 0xff), P_V);
-        self.reset_flag(N);
-        self.set_reset_flag((sum - 1) & 0xff == 0xff, C);
+        self.add_a_r(n);
+        let msg = format!("ADD A {:x}", n);
+        self.save_op(&msg);
     }
     fn rst_n(&mut self, mem: &mut Memory, new_pc: u16) {
-        self.sp -= 1;
-        let mut val = (self.pc >> 8) as u8;
-        let mut addr = self.sp;
-        mem.poke(addr, val);
-        val = (self.pc & 0xff) as u8;
-        addr -= 1;
-        mem.poke(addr, val);
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
+        let (hi, lo) = Z80::get_bytes(self.pc);
+        self.push_bytes(mem, hi, lo);
         self.pc = new_pc;
+        self.memptr = new_pc;
     }
     fn rst_0(&mut self, mem: &mut Memory) {
         self.rst_n(mem, 0);
@@ -2772,61 +3170,62 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("RST 08");
     }
     fn rst_10(&mut self, mem: &mut Memory) {
-        self.rst_n(mem, 10);
+        self.rst_n(mem, 0x10);
         self.save_op("RST 10");
     }
     fn rst_18(&mut self, mem: &mut Memory) {
-        self.rst_n(mem, 18);
+        self.rst_n(mem, 0x18);
         self.save_op("RST 18");
     }
     fn rst_20(&mut self, mem: &mut Memory) {
-        self.rst_n(mem, 20);
+        self.rst_n(mem, 0x20);
         self.save_op("RST 20");
     }
     fn rst_28(&mut self, mem: &mut Memory) {
-        self.rst_n(mem, 28);
+        self.rst_n(mem, 0x28);
         self.save_op("RST 28");
     }
     fn rst_30(&mut self, mem: &mut Memory) {
-        self.rst_n(mem, 30);
+        self.rst_n(mem, 0x30);
         self.save_op("RST 30");
     }
     fn rst_38(&mut self, mem: &mut Memory) {
-        self.rst_n(mem, 38);
+        self.rst_n(mem, 0x38);
         self.save_op("RST 38");
     }
     fn ret(&mut self, mem: &Memory) {
-        let lo = mem.peek(self.sp);
-        let hi = mem.peek(self.sp + 1);
+        let (hi, lo) = self.pop_qq(mem);
         let new_pc = Z80::get_word(hi, lo);
         self.pc = new_pc;
-        self.sp += 2;
+        self.memptr = new_pc;
         self.save_op("RET");
     }
     fn call_nn(&mut self, mem: &mut Memory) {
-        let lo = mem.peek(self.sp);
-        let hi = mem.peek(self.sp + 1);
-        let mut val = (self.pc >> 8) as u8;
-        let mut addr = self.sp - 1;
-        mem.poke(addr, val);
-        val = (self.pc & 0xff) as u8;
-        addr = self.sp - 2;
-        mem.poke(addr, val);
-        self.sp -= 2;
+        let lo = self.read_bus(mem);
+        let hi = self.read_bus(mem);
+        let last = self.pc.wrapping_sub(1);
+        self.internal_cycles(mem, last, 1);
+        let (pc_hi, pc_lo) = Z80::get_bytes(self.pc);
+        self.push_bytes(mem, pc_hi, pc_lo);
 
         let new_pc = Z80::get_word(hi, lo);
         self.pc = new_pc;
+        self.memptr = new_pc;
         self.save_op("CALL nn");
     }
     fn ex_at_sp_hl(&mut self, mem: &mut Memory) {
-        let new_l = mem.peek(self.sp);
-        let old_l = self.get_l();
-        self.set_l(new_l);
-        mem.poke(self.sp, old_l);
-        let new_h = mem.peek(self.sp + 1);
-        let old_h = self.get_h();
+        // Both bytes are read, then written back the other way round
+        let sp = self.sp;
+        let sp_hi = sp.wrapping_add(1);
+        let new_l = self.read_mem(mem, sp);
+        let new_h = self.read_to_modify(mem, sp_hi);
+        let (old_h, old_l) = (self.get_h(), self.get_l());
+        self.write_mem(mem, sp_hi, old_h);
+        self.write_mem(mem, sp, old_l);
+        self.internal_cycles(mem, sp, 2);
         self.set_h(new_h);
-        mem.poke(self.sp, old_h);
+        self.set_l(new_l);
+        self.memptr = self.get_hl();
 
         self.save_op("EX (SP) HL");
     }
@@ -2836,14 +3235,10 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("EI");
     }
     fn rlc_r(&mut self, r: u8) -> u8 {
-        let new_bit_0 = (r & 0x80) >> 7;
-        let result = (r << 1) + new_bit_0;
-        self.set_reset_flag(new_bit_0 > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        let carry = (r & 0x80) != 0;
+        let result = r.rotate_left(1);
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn rlc_b(&mut self) {
@@ -2878,9 +3273,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rlc_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.rlc_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("RLC L");
     }
     fn rlc_a(&mut self) {
@@ -2889,14 +3284,10 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("RLC L");
     }
     fn rrc_r(&mut self, r: u8) -> u8 {
-        let new_bit_8 = r & 0x01;
-        let result = (r >> 1) + (new_bit_8 << 7);
-        self.set_reset_flag(new_bit_8 > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        let carry = (r & 0x01) != 0;
+        let result = r.rotate_right(1);
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn rrc_b(&mut self) {
@@ -2931,9 +3322,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rrc_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.rrc_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("RLC L");
     }
     fn rrc_a(&mut self) {
@@ -2942,15 +3333,10 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("RLC L");
     }
     fn rl_r(&mut self, r: u8) -> u8 {
-        let new_bit_0 = self.get_flag(C);
-        let new_carry = (r & 0x80) >> 7;
-        let result = (r << 1) + new_bit_0;
-        self.set_reset_flag(new_carry > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        let carry = (r & 0x80) != 0;
+        let result = (r << 1) | self.get_flag(C);
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn rl_b(&mut self) {
@@ -2985,9 +3371,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rl_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.rl_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("RL L");
     }
     fn rl_a(&mut self) {
@@ -2996,15 +3382,10 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("RL L");
     }
     fn rr_r(&mut self, r: u8) -> u8 {
-        let new_bit_8 = self.get_flag(C);
-        let new_carry = r & 0x01;
-        let result = (r >> 1) + (new_bit_8 << 7);
-        self.set_reset_flag(new_carry > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        let carry = (r & 0x01) != 0;
+        let result = (r >> 1) | (self.get_flag(C) << 7);
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn rr_b(&mut self) {
@@ -3039,9 +3420,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rr_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.rr_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("RR L");
     }
     fn rr_a(&mut self) {
@@ -3050,14 +3431,10 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("RR L");
     }
     fn sla_r(&mut self, r: u8) -> u8 {
-        let new_carry = (r & 0x80) >> 7;
+        let carry = (r & 0x80) != 0;
         let result = r << 1;
-        self.set_reset_flag(new_carry > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn sla_b(&mut self) {
@@ -3092,9 +3469,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn sla_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.sla_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("SLA L");
     }
     fn sla_a(&mut self) {
@@ -3103,15 +3480,10 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("SLA L");
     }
     fn sra_r(&mut self, r: u8) -> u8 {
-        let new_carry = r & 0x01;
-        let new_bit_8 = r & 0x80;
-        let result = r >> 1 + new_bit_8;
-        self.set_reset_flag(new_carry > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        let carry = (r & 0x01) != 0;
+        let result = (r >> 1) | (r & 0x80);
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn sra_b(&mut self) {
@@ -3146,9 +3518,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn sra_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.sra_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("SRA L");
     }
     fn sra_a(&mut self) {
@@ -3156,15 +3528,12 @@ let msg = format!("LD H {:x}", x1);
         self.a = self.sra_r(op);
         self.save_op("SRA L");
     }
+    // Undocumented: shifts left and sets bit 0
     fn sll_r(&mut self, r: u8) -> u8 {
-        let new_carry = (r & 0x80) >> 7;
-        let result = r >> 1 + 1;
-        self.set_reset_flag(new_carry > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        let carry = (r & 0x80) != 0;
+        let result = (r << 1) | 0x01;
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn sll_b(&mut self) {
@@ -3199,9 +3568,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn sll_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.sll_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("SLL L");
     }
     fn sll_a(&mut self) {
@@ -3210,14 +3579,10 @@ let msg = format!("LD H {:x}", x1);
         self.save_op("SLL A");
     }
     fn srl_r(&mut self, r: u8) -> u8 {
-        let new_carry = r & 0x01;
+        let carry = (r & 0x01) != 0;
         let result = r >> 1;
-        self.set_reset_flag(new_carry > 0, C);
-        self.reset_flag(H);
-        self.reset_flag(N);
-        self.set_reset_flag((result as i8) < 0, S);
-        self.set_reset_flag(result == 0, Z);
-        self.set_reset_flag(Z80::check_byte_parity(result), P_V);
+        self.f = Z80::sz53p(result);
+        self.set_reset_flag(carry, C);
         result
     }
     fn srl_b(&mut self) {
@@ -3252,9 +3617,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn srl_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.get_h(), self.get_l());
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_data = self.srl_r(op);
-        mem.poke(addr, new_data);
+        self.write_mem(mem, addr, new_data);
         self.save_op("SRL (HL)");
     }
     fn srl_a(&mut self) {
@@ -3270,13 +3635,12 @@ let msg = format!("LD H {:x}", x1);
         let mask = 0x1 << bit_num;
         val | mask
     }
+    // Z and P/V tell if the bit is clear, S if it is bit 7 and it is set
     fn bit_n_r(&mut self, n: u8, r: u8) {
-        let mask = 0x01 << n;
-        let comparison = r & mask;
-        let new_z = comparison >> n;
-        self.set_reset_flag(new_z > 0, Z);
-        self.set_flag(H);
-        self.reset_flag(N);
+        let bit = r & (0x01 << n);
+        self.f = (self.f & C) | H | (bit & S) | (r & (BIT_5 | BIT_3));
+        self.set_reset_flag(bit == 0, Z);
+        self.set_reset_flag(bit == 0, P_V);
     }
     fn bit_0_b(&mut self) {
         let op = self.b;
@@ -3310,7 +3674,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_0_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(0, op);
         self.save_op("BIT 0 (HL)")
     }
@@ -3351,7 +3715,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_1_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(1, op);
         self.save_op("BIT 1 (HL)")
     }
@@ -3392,7 +3756,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_2_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(2, op);
         self.save_op("BIT 2 (HL)")
     }
@@ -3433,7 +3797,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_3_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(3, op);
         self.save_op("BIT 3 (HL)")
     }
@@ -3474,7 +3838,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_4_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(4, op);
         self.save_op("BIT 4 (HL)")
     }
@@ -3515,7 +3879,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_5_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(5, op);
         self.save_op("BIT 5 (HL)")
     }
@@ -3556,7 +3920,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_6_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(6, op);
         self.save_op("BIT 6 (HL)")
     }
@@ -3597,7 +3961,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn bit_7_at_hl(&mut self, mem: &Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         self.bit_n_r(7, op);
         self.save_op("BIT 7 (HL)")
     }
@@ -3638,9 +4002,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_0_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(0, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 0 (HL)")
     }
     fn res_0_a(&mut self) {
@@ -3680,9 +4044,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_1_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(1, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 1 (HL)")
     }
     fn res_1_a(&mut self) {
@@ -3722,9 +4086,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_2_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(2, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 2 (HL)")
     }
     fn res_2_a(&mut self) {
@@ -3764,9 +4128,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_3_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(3, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 3 (HL)")
     }
     fn res_3_a(&mut self) {
@@ -3806,9 +4170,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_4_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(4, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 4 (HL)")
     }
     fn res_4_a(&mut self) {
@@ -3848,9 +4212,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_5_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(5, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 5 (HL)")
     }
     fn res_5_a(&mut self) {
@@ -3890,9 +4254,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_6_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(6, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 6 (HL)")
     }
     fn res_6_a(&mut self) {
@@ -3932,9 +4296,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_7_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::res_n_r(7, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("res 7 (HL)")
     }
     fn res_7_a(&mut self) {
@@ -3974,9 +4338,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_7_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(7, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 7 (HL)")
     }
     fn set_7_a(&mut self) {
@@ -4016,9 +4380,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_6_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(6, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 6 (HL)")
     }
     fn set_6_a(&mut self) {
@@ -4058,9 +4422,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_5_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(5, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 5 (HL)")
     }
     fn set_5_a(&mut self) {
@@ -4100,9 +4464,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_4_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(4, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 4 (HL)")
     }
     fn set_4_a(&mut self) {
@@ -4142,9 +4506,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_3_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(3, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 3 (HL)")
     }
     fn set_3_a(&mut self) {
@@ -4184,9 +4548,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_2_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(2, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 2 (HL)")
     }
     fn set_2_a(&mut self) {
@@ -4226,9 +4590,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_1_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(1, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 1 (HL)")
     }
     fn set_1_a(&mut self) {
@@ -4268,9 +4632,9 @@ let msg = format!("LD H {:x}", x1);
     }
     fn set_0_at_hl(&mut self, mem: &mut Memory) {
         let addr = Z80::get_word(self.h, self.l);
-        let op = mem.peek(addr);
+        let op = self.read_to_modify(mem, addr);
         let new_val = Z80::set_n_r(0, op);
-        mem.poke(addr, new_val);
+        self.write_mem(mem, addr, new_val);
         self.save_op("set 0 (HL)")
     }
     fn set_0_a(&mut self) {
@@ -4304,7 +4668,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rlc_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.rlc_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("RLC (IX + d)");
     }
     fn rlc_to_a(&mut self, op: u8) {
@@ -4337,7 +4701,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rrc_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.rrc_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("RRC (IX + d)");
     }
     fn rrc_to_a(&mut self, op: u8) {
@@ -4370,7 +4734,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rl_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.rl_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("RL (IX + d)")
     }
     fn rl_to_a(&mut self, op: u8) {
@@ -4403,7 +4767,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn rr_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.rr_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("RR (IX + d)");
     }
     fn rr_to_a(&mut self, op: u8) {
@@ -4436,7 +4800,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn sla_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.sla_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("SLA (IX + d)");
     }
     fn sla_to_a(&mut self, op: u8) {
@@ -4469,7 +4833,7 @@ let msg = format!("LD H {:x}", x1);
     }
     fn sra_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.sra_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("SRA (IX + d)");
     }
     fn sra_to_a(&mut self, op: u8) {
@@ -4502,45 +4866,45 @@ let msg = format!("LD H {:x}", x1);
     }
     fn sll_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.sll_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("SLL (IX + d)");
     }
     fn sll_to_a(&mut self, op: u8) {
         self.a = self.sll_r(op);
-        self.save_op("SLL (IX + d) A");
+        self.save_op("SRL (IX + d) A");
     }
     fn srl_to_b(&mut self, op: u8) {
         self.b = self.srl_r(op);
-        self.save_op("SLL (IX + d) B");
+        self.save_op("SRL (IX + d) B");
     }
     fn srl_to_c(&mut self, op: u8) {
         self.c = self.srl_r(op);
-        self.save_op("SLL (IX + d) C");
+        self.save_op("SRL (IX + d) C");
     }
     fn srl_to_d(&mut self, op: u8) {
         self.d = self.srl_r(op);
-        self.save_op("SLL (IX + d) D");
+        self.save_op("SRL (IX + d) D");
     }
     fn srl_to_e(&mut self, op: u8) {
         self.e = self.srl_r(op);
-        self.save_op("SLL (IX + d) E");
+        self.save_op("SRL (IX + d) E");
     }
     fn srl_to_h(&mut self, op: u8) {
         self.h = self.srl_r(op);
-        self.save_op("SLL (IX + d) H");
+        self.save_op("SRL (IX + d) H");
     }
     fn srl_to_l(&mut self, op: u8) {
-        self.l = self.sll_r(op);
-        self.save_op("SLL (IX + d) L");
+        self.l = self.srl_r(op);
+        self.save_op("SRL (IX + d) L");
     }
     fn srl_at_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = self.srl_r(op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("SRL (IX + d)");
     }
     fn srl_to_a(&mut self, op: u8) {
         self.a = self.srl_r(op);
-        self.save_op("SLL (IX + d) A");
+        self.save_op("SRL (IX + d) A");
     }
     fn bit_0_ixy(&mut self, op: u8) {
         self.bit_n_r(0, op);
@@ -4576,128 +4940,988 @@ let msg = format!("LD H {:x}", x1);
     }
     fn res_0_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(0, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 0 (IX + d)");
     }
     fn res_1_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(1, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 1 (IX + d)");
     }
     fn res_2_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(2, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 2 (IX + d)");
     }
     fn res_3_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(3, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 3 (IX + d)");
     }
     fn res_4_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(4, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 4 (IX + d)");
     }
     fn res_5_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(5, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 5 (IX + d)");
     }
     fn res_6_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(6, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 6 (IX + d)");
     }
     fn res_7_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::res_n_r(7, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("res 7 (IX + d)");
     }
     fn set_0_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(0, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 0 (IX + d)");
     }
     fn set_1_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(1, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 1 (IX + d)");
     }
     fn set_2_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(2, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 2 (IX + d)");
     }
     fn set_3_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(3, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 3 (IX + d)");
     }
     fn set_4_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(4, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 4 (IX + d)");
     }
     fn set_5_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(5, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 5 (IX + d)");
     }
     fn set_6_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(6, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 6 (IX + d)");
     }
     fn set_7_ixy(&mut self, mem: &mut Memory, addr: u16, op: u8) {
         let new_op = Z80::set_n_r(7, op);
-        mem.poke(addr, new_op);
+        self.write_mem(mem, addr, new_op);
         self.save_op("set 7 (IX + d)");
     }
-    fn lddr(&mut self, mem: &mut Memory) {
-        let mut source_addr = Z80::get_word(self.h, self.l);
-        let mut dest_addr = Z80::get_word(self.d, self.e);
-        let mut counter = Z80::get_word(self.b, self.c);
-        let val = mem.peek(source_addr);
-        mem.poke(dest_addr, val);
+    // The block instructions move HL, and DE for the copies, by `step`.
+    // The repeating forms go back over the instruction while they have
+    // something left, with 5 more internal cycles.
+    fn repeat_block(&mut self, mem: &Memory, addr: u16) {
+        self.internal_cycles(mem, addr, 5);
+        self.pc = self.pc.wrapping_sub(2);
+        self.instruction_t_states += 5;
+    }
+    fn set_bc(&mut self, bc: u16) {
+        let (b, c) = Z80::get_bytes(bc);
+        self.b = b;
+        self.c = c;
+    }
+    fn set_hl_step(&mut self, step: i8) {
+        let hl = Z80::get_word(self.h, self.l).wrapping_add(step as u16);
+        let (h, l) = Z80::get_bytes(hl);
+        self.h = h;
+        self.l = l;
+    }
+    // LDI and LDD. Returns true while BC has not reached 0.
+    fn ld_block(&mut self, mem: &mut Memory, step: i8) -> bool {
+        let source = Z80::get_word(self.h, self.l);
+        let dest = Z80::get_word(self.d, self.e);
+        let val = self.read_mem(mem, source);
+        self.write_mem(mem, dest, val);
+        self.internal_cycles(mem, dest, 2);
 
-        source_addr = source_addr.wrapping_sub(1);
-        let new_h_l = Z80::get_bytes(source_addr);
-        self.h = new_h_l.0;
-        self.l = new_h_l.1;
-        dest_addr = dest_addr.wrapping_sub(1);
-        let new_h_l = Z80::get_bytes(dest_addr);
-        self.d = new_h_l.0;
-        self.e = new_h_l.1;
-        counter = counter.wrapping_sub(1);
-        let new_h_l = Z80::get_bytes(counter);
-        self.b = new_h_l.0;
-        self.c = new_h_l.1;
-        if counter > 0 {
-            self.pc = self.pc.wrapping_sub(2);
+        self.set_hl_step(step);
+        let (d, e) = Z80::get_bytes(dest.wrapping_add(step as u16));
+        self.d = d;
+        self.e = e;
+        let counter = Z80::get_word(self.b, self.c).wrapping_sub(1);
+        self.set_bc(counter);
+
+        // Bits 5 and 3 are bits 1 and 3 of the byte plus A
+        let n = val.wrapping_add(self.a);
+        self.f = (self.f & (S | Z | C)) | (n & BIT_3) | ((n << 4) & BIT_5);
+        self.set_reset_flag(counter != 0, P_V);
+        counter != 0
+    }
+    fn ldi(&mut self, mem: &mut Memory) {
+        self.ld_block(mem, 1);
+        self.save_op("LDI");
+    }
+    fn ldd(&mut self, mem: &mut Memory) {
+        self.ld_block(mem, -1);
+        self.save_op("LDD");
+    }
+    fn ldir(&mut self, mem: &mut Memory) {
+        if self.ld_block(mem, 1) {
+            // Going back to repeat, with DE still on the bus
+            let written = Z80::get_word(self.d, self.e).wrapping_sub(1);
+            self.repeat_block(mem, written);
+            self.memptr = self.pc.wrapping_add(1);
+        }
+        self.save_op("LDIR");
+    }
+    fn lddr(&mut self, mem: &mut Memory) {
+        if self.ld_block(mem, -1) {
+            let written = Z80::get_word(self.d, self.e).wrapping_add(1);
+            self.repeat_block(mem, written);
+            self.memptr = self.pc.wrapping_add(1);
         }
         self.save_op("LDDR");
     }
+    // CPI and CPD. Returns true while BC has not reached 0 and the byte
+    // is not the one searched.
+    fn cp_block(&mut self, mem: &Memory, step: i8) -> bool {
+        let addr = Z80::get_word(self.h, self.l);
+        let val = self.read_mem(mem, addr);
+        self.internal_cycles(mem, addr, 5);
+        let result = self.a.wrapping_sub(val);
+        let half = (self.a & 0xf) < (val & 0xf);
+
+        self.set_hl_step(step);
+        let counter = Z80::get_word(self.b, self.c).wrapping_sub(1);
+        self.set_bc(counter);
+        self.memptr = self.memptr.wrapping_add(step as u16);
+
+        // Bits 5 and 3 are bits 1 and 3 of the result less H
+        let n = result.wrapping_sub(half as u8);
+        self.f = (self.f & C) | N | (result & S) | (n & BIT_3) | ((n << 4) & BIT_5);
+        self.set_reset_flag(result == 0, Z);
+        self.set_reset_flag(half, H);
+        self.set_reset_flag(counter != 0, P_V);
+        counter != 0 && result != 0
+    }
+    fn cpi(&mut self, mem: &Memory) {
+        self.cp_block(mem, 1);
+        self.save_op("CPI");
+    }
+    fn cpd(&mut self, mem: &Memory) {
+        self.cp_block(mem, -1);
+        self.save_op("CPD");
+    }
+    fn cpir(&mut self, mem: &Memory) {
+        if self.cp_block(mem, 1) {
+            let read = Z80::get_word(self.h, self.l).wrapping_sub(1);
+            self.repeat_block(mem, read);
+            self.memptr = self.pc.wrapping_add(1);
+        }
+        self.save_op("CPIR");
+    }
+    fn cpdr(&mut self, mem: &Memory) {
+        if self.cp_block(mem, -1) {
+            let read = Z80::get_word(self.h, self.l).wrapping_add(1);
+            self.repeat_block(mem, read);
+            self.memptr = self.pc.wrapping_add(1);
+        }
+        self.save_op("CPDR");
+    }
+    // Flags of the block I/O: B as the result, N from bit 7 of the byte and
+    // H, C and P/V from the byte plus `k_base`
+    fn set_io_block_flags(&mut self, val: u8, k_base: u8) {
+        let k = val as u16 + k_base as u16;
+        self.f = Z80::sz53(self.b);
+        self.set_reset_flag(val & 0x80 != 0, N);
+        self.set_reset_flag(k > 0xff, H);
+        self.set_reset_flag(k > 0xff, C);
+        let parity = Z80::check_byte_parity((k as u8 & 0x07) ^ self.b);
+        self.set_reset_flag(parity, P_V);
+    }
+    // INI and IND. Returns true while B has not reached 0.
+    fn in_block(&mut self, mem: &mut Memory, io: &mut IoBus, step: i8) -> bool {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
+        let port = Z80::get_word(self.b, self.c);
+        let val = self.read_port(mem, io, port);
+        self.memptr = port.wrapping_add(step as u16);
+        let addr = Z80::get_word(self.h, self.l);
+        self.write_mem(mem, addr, val);
+
+        self.b = self.b.wrapping_sub(1);
+        self.set_hl_step(step);
+        let k_base = self.c.wrapping_add(step as u8);
+        self.set_io_block_flags(val, k_base);
+        self.b != 0
+    }
+    fn ini(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        self.in_block(mem, io, 1);
+        self.save_op("INI");
+    }
+    fn ind(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        self.in_block(mem, io, -1);
+        self.save_op("IND");
+    }
+    fn inir(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        if self.in_block(mem, io, 1) {
+            let written = Z80::get_word(self.h, self.l).wrapping_sub(1);
+            self.repeat_block(mem, written);
+        }
+        self.save_op("INIR");
+    }
+    fn indr(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        if self.in_block(mem, io, -1) {
+            let written = Z80::get_word(self.h, self.l).wrapping_add(1);
+            self.repeat_block(mem, written);
+        }
+        self.save_op("INDR");
+    }
+    // OUTI and OUTD. B goes down before it reaches the port.
+    fn out_block(&mut self, mem: &mut Memory, io: &mut IoBus, step: i8) -> bool {
+        let ir = self.ir();
+        self.internal_cycles(mem, ir, 1);
+        let addr = Z80::get_word(self.h, self.l);
+        let val = self.read_mem(mem, addr);
+        self.b = self.b.wrapping_sub(1);
+        let port = Z80::get_word(self.b, self.c);
+        self.write_port(mem, io, port, val);
+        self.memptr = port.wrapping_add(step as u16);
+
+        self.set_hl_step(step);
+        let l = self.l;
+        self.set_io_block_flags(val, l);
+        self.b != 0
+    }
+    fn outi(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        self.out_block(mem, io, 1);
+        self.save_op("OUTI");
+    }
+    fn outd(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        self.out_block(mem, io, -1);
+        self.save_op("OUTD");
+    }
+    fn otir(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        if self.out_block(mem, io, 1) {
+            let port = Z80::get_word(self.b, self.c);
+            self.repeat_block(mem, port);
+        }
+        self.save_op("OTIR");
+    }
+    fn otdr(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        if self.out_block(mem, io, -1) {
+            let port = Z80::get_word(self.b, self.c);
+            self.repeat_block(mem, port);
+        }
+        self.save_op("OTDR");
+    }
     fn neg(&mut self) {
-        let old_a = self.a;
-        let new_a = !old_a;
-        self.a = new_a;
-        self.set_reset_flag((new_a as i8) < 0, S);
-        self.set_reset_flag(new_a == 0, Z);
-        self.set_reset_flag((new_a & 0xf) < (old_a & 0xf), H);
-        self.set_reset_flag(old_a == 0x80, P_V);
-        self.set_flag(N);
-        self.set_reset_flag(old_a != 0x00, C);
+        let value = self.a;
+        self.a = 0;
+        self.a = self.sub_with_carry(value, 0);
+        self.save_op("NEG");
     }
     fn retn(&mut self, mem: &Memory) {
-        let lo = mem.peek(self.sp);
-        let hi = mem.peek(self.sp + 1);
+        let (hi, lo) = self.pop_qq(mem);
         self.pc = Z80::get_word(hi, lo);
-        self.sp += 2;
+        self.memptr = self.pc;
+        self.iff1 = self.iff2;
+        self.save_op("RETN");
     }
-    fn rrd(&mut self) {
-        let old_a = self.a & 0xf;
-        self.a = self.l;
-        self.h = old_a;
+    fn im_0(&mut self) {
+        self.im = 0;
+        self.save_op("IM 0");
+    }
+    fn im_1(&mut self) {
+        self.im = 1;
+        self.save_op("IM 1");
+    }
+    fn im_2(&mut self) {
+        self.im = 2;
+        self.save_op("IM 2");
+    }
+    // RRD and RLD turn the nibbles of A and (HL) round, in 4 internal
+    // cycles with HL on the bus
+    fn rrd(&mut self, mem: &mut Memory) {
+        let addr = Z80::get_word(self.h, self.l);
+        let val = self.read_mem(mem, addr);
+        self.internal_cycles(mem, addr, 4);
+        self.write_mem(mem, addr, (self.a << 4) | (val >> 4));
+        self.a = (self.a & 0xf0) | (val & 0x0f);
+        self.f = (self.f & C) | Z80::sz53p(self.a);
+        self.memptr = addr.wrapping_add(1);
+        self.save_op("RRD");
+    }
+    fn rld(&mut self, mem: &mut Memory) {
+        let addr = Z80::get_word(self.h, self.l);
+        let val = self.read_mem(mem, addr);
+        self.internal_cycles(mem, addr, 4);
+        self.write_mem(mem, addr, (val << 4) | (self.a & 0x0f));
+        self.a = (self.a & 0xf0) | (val >> 4);
+        self.f = (self.f & C) | Z80::sz53p(self.a);
+        self.memptr = addr.wrapping_add(1);
+        self.save_op("RLD");
+    }
+    fn in_r_at_c(&mut self, mem: &Memory, io: &mut IoBus) -> u8 {
+        let dir = Z80::get_word(self.b, self.c);
+        let val = self.read_port(mem, io, dir);
+        self.memptr = dir.wrapping_add(1);
+        self.set_reset_flag((val as i8) < 0, S);
+        self.set_reset_flag(val == 0, Z);
+        self.reset_flag(H);
+        self.set_reset_flag(Z80::check_byte_parity(val), P_V);
+        self.reset_flag(N);
+        val
+    }
+    fn in_b_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.b = self.in_r_at_c(mem, io);
+        self.save_op("IN B (C)");
+    }
+    fn in_c_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.c = self.in_r_at_c(mem, io);
+        self.save_op("IN C (C)");
+    }
+    fn in_d_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.d = self.in_r_at_c(mem, io);
+        self.save_op("IN D (C)");
+    }
+    fn in_e_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.e = self.in_r_at_c(mem, io);
+        self.save_op("IN E (C)");
+    }
+    fn in_h_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.h = self.in_r_at_c(mem, io);
+        self.save_op("IN H (C)");
+    }
+    fn in_l_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.l = self.in_r_at_c(mem, io);
+        self.save_op("IN L (C)");
+    }
+    fn in_a_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.a = self.in_r_at_c(mem, io);
+        self.save_op("IN A (C)");
+    }
+    fn in_f_at_c(&mut self, mem: &Memory, io: &mut IoBus) {
+        self.in_r_at_c(mem, io);
+        self.save_op("IN F (C)");
+    }
+    fn out_at_c_r(&mut self, mem: &mut Memory, io: &mut IoBus, val: u8) {
+        let dir = Z80::get_word(self.b, self.c);
+        self.write_port(mem, io, dir, val);
+        self.memptr = dir.wrapping_add(1);
+    }
+    fn out_at_c_b(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        let val = self.b;
+        self.out_at_c_r(mem, io, val);
+        self.save_op("OUT (C) B");
+    }
+    fn out_at_c_c(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        let val = self.c;
+        self.out_at_c_r(mem, io, val);
+        self.save_op("OUT (C) C");
+    }
+    fn out_at_c_d(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        let val = self.d;
+        self.out_at_c_r(mem, io, val);
+        self.save_op("OUT (C) D");
+    }
+    fn out_at_c_e(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        let val = self.e;
+        self.out_at_c_r(mem, io, val);
+        self.save_op("OUT (C) E");
+    }
+    fn out_at_c_h(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        let val = self.h;
+        self.out_at_c_r(mem, io, val);
+        self.save_op("OUT (C) H");
+    }
+    fn out_at_c_l(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        let val = self.l;
+        self.out_at_c_r(mem, io, val);
+        self.save_op("OUT (C) L");
+    }
+    fn out_at_c_a(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        let val = self.a;
+        self.out_at_c_r(mem, io, val);
+        self.save_op("OUT (C) A");
+    }
+    fn out_at_c_0(&mut self, mem: &mut Memory, io: &mut IoBus) {
+        self.out_at_c_r(mem, io, 0);
+        self.save_op("OUT (C) 0");
+    }
+}
+
+// Everything but the trace file
+impl SaveState for Z80 {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.halt);
+        state.u16(self.pc);
+        state.u16(self.sp);
+        state.bytes(&[self.ix_h, self.ix_l, self.iy_h, self.iy_l, self.i, self.r]);
+        state.bytes(&[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l]);
+        state.bytes(&[
+            self.a_alt, self.f_alt, self.b_alt, self.c_alt,
+            self.d_alt, self.e_alt, self.h_alt, self.l_alt,
+        ]);
+        state.bool(self.iff1);
+        state.bool(self.iff2);
+        state.u8(self.im);
+        state.u16(self.memptr);
+        state.bool(self.halted);
+        state.u64(self.t_states);
+        state.u64(self.fetches);
+        state.u64(self.instruction_t_states);
+        state.u64(self.bus_t_states);
+        let prefix = PREFIXES.iter().position(|&p| p == self.opcode_prefix).unwrap();
+        state.u8(prefix as u8);
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.halt = state.bool()?;
+        self.pc = state.u16()?;
+        self.sp = state.u16()?;
+        let index = state.bytes(6)?;
+        self.ix_h = index[0];
+        self.ix_l = index[1];
+        self.iy_h = index[2];
+        self.iy_l = index[3];
+        self.i = index[4];
+        self.r = index[5];
+        let main = state.bytes(8)?;
+        self.a = main[0];
+        self.f = main[1];
+        self.b = main[2];
+        self.c = main[3];
+        self.d = main[4];
+        self.e = main[5];
+        self.h = main[6];
+        self.l = main[7];
+        let alt = state.bytes(8)?;
+        self.a_alt = alt[0];
+        self.f_alt = alt[1];
+        self.b_alt = alt[2];
+        self.c_alt = alt[3];
+        self.d_alt = alt[4];
+        self.e_alt = alt[5];
+        self.h_alt = alt[6];
+        self.l_alt = alt[7];
+        self.iff1 = state.bool()?;
+        self.iff2 = state.bool()?;
+        self.im = state.u8()?;
+        self.memptr = state.u16()?;
+        self.halted = state.bool()?;
+        self.t_states = state.u64()?;
+        self.fetches = state.u64()?;
+        self.instruction_t_states = state.u64()?;
+        self.bus_t_states = state.u64()?;
+        self.opcode_prefix = *PREFIXES.get(state.u8()? as usize).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "prefijo desconocido")
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine::Model;
+
+    const CODE: u16 = 0x8000;
+    // Code the ULA holds, run from the first T-state it holds the most
+    const CONTENDED_CODE: u16 = 0x6000;
+    const CONTENDED_START: u64 = 14335;
+
+    // Runs the instruction at `at`, prefixes included
+    fn execute(at: u16, code: &[u8], setup: &dyn Fn(&mut Z80)) -> (Z80, Memory) {
+        let mut mem = Memory::for_model(Model::Spectrum48K);
+        let mut io = IoBus::new();
+        for (i, &byte) in code.iter().enumerate() {
+            mem.poke(at + i as u16, byte);
+        }
+        let mut cpu = Z80::new();
+        cpu.pc = at;
+        setup(&mut cpu);
+        cpu.exec(&mut mem, &mut io);
+        while cpu.prefix_pending() {
+            cpu.exec(&mut mem, &mut io);
+        }
+        (cpu, mem)
+    }
+
+    fn run(code: &[u8], setup: &dyn Fn(&mut Z80)) -> Z80 {
+        execute(CODE, code, setup).0
+    }
+
+    // T-states the instruction at CONTENDED_CODE takes
+    fn contended_t_states(code: &[u8], setup: &dyn Fn(&mut Z80)) -> u64 {
+        let (cpu, _) = execute(CONTENDED_CODE, code, &|cpu| {
+            cpu.t_states = CONTENDED_START;
+            setup(cpu)
+        });
+        cpu.t_states - CONTENDED_START
+    }
+
+    // Code, registers and the cycles it should take
+    type Timing<'a> = (&'a [u8], &'a dyn Fn(&mut Z80), Vec<(u16, u64)>);
+
+    // T-states taken by cycles of the given lengths on the given
+    // addresses, each one held by the ULA as it starts
+    fn expected(cycles: &[(u16, u64)]) -> u64 {
+        let mem = Memory::for_model(Model::Spectrum48K);
+        let mut t = CONTENDED_START;
+        for &(addr, length) in cycles {
+            if mem.is_contended(addr) {
+                t += mem.contention(t);
+            }
+            t += length;
+        }
+        t - CONTENDED_START
+    }
+
+    #[test]
+    fn memptr() {
+        let memptr = |code: &[u8], setup: &dyn Fn(&mut Z80)| run(code, setup).memptr;
+        // LD A,(nn) and LD (nn),A
+        assert_eq!(memptr(&[0x3A, 0x00, 0x90], &|_| {}), 0x9001);
+        assert_eq!(memptr(&[0x32, 0xFF, 0x90], &|cpu| cpu.a = 0x55), 0x5500);
+        // LD A,(BC)
+        assert_eq!(
+            memptr(&[0x0A], &|cpu| {
+                cpu.b = 0x90;
+                cpu.c = 0x10
+            }),
+            0x9011
+        );
+        // JP nn, and JP NZ,nn not taken
+        assert_eq!(memptr(&[0xC3, 0x34, 0x12], &|_| {}), 0x1234);
+        assert_eq!(memptr(&[0xC2, 0x34, 0x12], &|cpu| cpu.f = Z), 0x1234);
+        // ADD HL,BC
+        assert_eq!(
+            memptr(&[0x09], &|cpu| {
+                cpu.h = 0x40;
+                cpu.l = 0xFF
+            }),
+            0x4100
+        );
+        // IN A,(n)
+        assert_eq!(memptr(&[0xDB, 0xFE], &|cpu| cpu.a = 0x7F), 0x7FFF);
+        // LD HL,(nn)
+        assert_eq!(memptr(&[0x2A, 0x00, 0xA0], &|_| {}), 0xA001);
+        // LD A,(IX+5)
+        assert_eq!(
+            memptr(&[0xDD, 0x7E, 0x05], &|cpu| {
+                cpu.ix_h = 0x90;
+                cpu.ix_l = 0x00
+            }),
+            0x9005
+        );
+    }
+
+    #[test]
+    fn fetches_count_m1_cycles() {
+        let fetches = |code: &[u8]| {
+            let cpu = run(code, &|cpu| cpu.r = 0x7F);
+            (cpu.fetches, cpu.r)
+        };
+        // R keeps bit 7 and wraps the other seven
+        assert_eq!(fetches(&[0x00]), (1, 0x00));
+        assert_eq!(fetches(&[0xCB, 0x00]), (2, 0x01));
+        assert_eq!(fetches(&[0xDD, 0x7E, 0x05]), (2, 0x01));
+        assert_eq!(fetches(&[0xED, 0x44]), (2, 0x01));
+        // Neither the displacement nor the opcode after it are fetches
+        assert_eq!(fetches(&[0xDD, 0xCB, 0x05, 0x06]), (2, 0x01));
+        assert_eq!(fetches(&[0xFD, 0xCB, 0x05, 0xC6]), (2, 0x01));
+    }
+
+    #[test]
+    fn internal_cycles_are_contended_where_they_happen() {
+        let pc = CONTENDED_CODE;
+        let sp = 0x4010;
+        let ix = 0x7000;
+        let hl = 0x4100;
+        let de = 0x4200;
+        let cases: Vec<Timing> = vec![
+            // INC (HL)
+            (
+                &[0x34],
+                &|cpu| {
+                    cpu.h = 0x41;
+                    cpu.l = 0x00
+                },
+                vec![(pc, 4), (hl, 3), (hl, 1), (hl, 3)],
+            ),
+            // PUSH BC, with IR in contended memory
+            (
+                &[0xC5],
+                &|cpu| {
+                    cpu.sp = 0x4010;
+                    cpu.i = 0x40
+                },
+                vec![(pc, 4), (0x4001, 1), (sp - 1, 3), (sp - 2, 3)],
+            ),
+            // RST 38
+            (
+                &[0xFF],
+                &|cpu| {
+                    cpu.sp = 0x4010;
+                    cpu.i = 0x40
+                },
+                vec![(pc, 4), (0x4001, 1), (sp - 1, 3), (sp - 2, 3)],
+            ),
+            // CALL nn
+            (
+                &[0xCD, 0x00, 0x90],
+                &|cpu| cpu.sp = 0x4010,
+                vec![
+                    (pc, 4),
+                    (pc + 1, 3),
+                    (pc + 2, 3),
+                    (pc + 2, 1),
+                    (sp - 1, 3),
+                    (sp - 2, 3),
+                ],
+            ),
+            // LD A,(IX+5)
+            (
+                &[0xDD, 0x7E, 0x05],
+                &|cpu| {
+                    cpu.ix_h = 0x70;
+                    cpu.ix_l = 0x00
+                },
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (pc + 2, 3),
+                    (pc + 2, 1),
+                    (pc + 2, 1),
+                    (pc + 2, 1),
+                    (pc + 2, 1),
+                    (pc + 2, 1),
+                    (ix + 5, 3),
+                ],
+            ),
+            // RLC (IX+5)
+            (
+                &[0xDD, 0xCB, 0x05, 0x06],
+                &|cpu| {
+                    cpu.ix_h = 0x70;
+                    cpu.ix_l = 0x00
+                },
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (pc + 2, 3),
+                    (pc + 3, 3),
+                    (pc + 3, 1),
+                    (pc + 3, 1),
+                    (ix + 5, 3),
+                    (ix + 5, 1),
+                    (ix + 5, 3),
+                ],
+            ),
+            // BIT 0,(HL)
+            (
+                &[0xCB, 0x46],
+                &|cpu| {
+                    cpu.h = 0x41;
+                    cpu.l = 0x00
+                },
+                vec![(pc, 4), (pc + 1, 4), (hl, 3), (hl, 1)],
+            ),
+            // EX (SP),HL
+            (
+                &[0xE3],
+                &|cpu| cpu.sp = 0x4010,
+                vec![
+                    (pc, 4),
+                    (sp, 3),
+                    (sp + 1, 3),
+                    (sp + 1, 1),
+                    (sp + 1, 3),
+                    (sp, 3),
+                    (sp, 1),
+                    (sp, 1),
+                ],
+            ),
+            // LDDR, repeating
+            (
+                &[0xED, 0xB8],
+                &|cpu| {
+                    cpu.h = 0x41;
+                    cpu.l = 0x00;
+                    cpu.d = 0x42;
+                    cpu.e = 0x00;
+                    cpu.c = 2
+                },
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (hl, 3),
+                    (de, 3),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                ],
+            ),
+        ];
+        for (code, setup, cycles) in cases {
+            assert_eq!(
+                contended_t_states(code, setup),
+                expected(&cycles),
+                "{:02X?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn ed_instructions_are_contended_per_cycle() {
+        let pc = CONTENDED_CODE;
+        let hl = 0x4100;
+        let de = 0x4200;
+        let ir = 0x4002;
+        let cases: Vec<Timing> = vec![
+            // LDIR, repeating
+            (
+                &[0xED, 0xB0],
+                &|cpu| {
+                    cpu.h = 0x41;
+                    cpu.d = 0x42;
+                    cpu.c = 2
+                },
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (hl, 3),
+                    (de, 3),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                    (de, 1),
+                ],
+            ),
+            // CPIR, repeating
+            (
+                &[0xED, 0xB1],
+                &|cpu| {
+                    cpu.h = 0x41;
+                    cpu.a = 0xAA;
+                    cpu.c = 2
+                },
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (hl, 3),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                ],
+            ),
+            // INIR, repeating, from a port the ULA leaves alone
+            (
+                &[0xED, 0xB2],
+                &|cpu| {
+                    cpu.h = 0x41;
+                    cpu.b = 2;
+                    cpu.c = 0xFF;
+                    cpu.i = 0x40
+                },
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (ir, 1),
+                    (0x02FF, 4),
+                    (hl, 3),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                ],
+            ),
+            // OTIR, repeating, B already down when the port is written
+            (
+                &[0xED, 0xB3],
+                &|cpu| {
+                    cpu.h = 0x41;
+                    cpu.b = 2;
+                    cpu.c = 0xFF;
+                    cpu.i = 0x40
+                },
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (ir, 1),
+                    (hl, 3),
+                    (0x01FF, 4),
+                    (0x01FF, 1),
+                    (0x01FF, 1),
+                    (0x01FF, 1),
+                    (0x01FF, 1),
+                    (0x01FF, 1),
+                ],
+            ),
+            // RLD
+            (
+                &[0xED, 0x6F],
+                &|cpu| cpu.h = 0x41,
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (hl, 3),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 1),
+                    (hl, 3),
+                ],
+            ),
+            // LD A,I
+            (
+                &[0xED, 0x57],
+                &|cpu| cpu.i = 0x40,
+                vec![(pc, 4), (pc + 1, 4), (ir, 1)],
+            ),
+            // ADC HL,BC
+            (
+                &[0xED, 0x4A],
+                &|cpu| cpu.i = 0x40,
+                vec![
+                    (pc, 4),
+                    (pc + 1, 4),
+                    (ir, 1),
+                    (ir, 1),
+                    (ir, 1),
+                    (ir, 1),
+                    (ir, 1),
+                    (ir, 1),
+                    (ir, 1),
+                ],
+            ),
+        ];
+        for (code, setup, cycles) in cases {
+            assert_eq!(
+                contended_t_states(code, setup),
+                expected(&cycles),
+                "{:02X?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn block_instructions_repeat_until_done() {
+        let mut mem = Memory::for_model(Model::Spectrum48K);
+        let mut io = IoBus::new();
+        let mut cpu = Z80::new();
+        let run_block = |cpu: &mut Z80, mem: &mut Memory, io: &mut IoBus, code: &[u8]| {
+            for (i, &byte) in code.iter().enumerate() {
+                mem.poke(CODE + i as u16, byte);
+            }
+            cpu.pc = CODE;
+            while cpu.pc == CODE {
+                cpu.exec(mem, io);
+                while cpu.prefix_pending() {
+                    cpu.exec(mem, io);
+                }
+            }
+        };
+
+        // LDIR copies BC bytes and leaves P/V reset
+        for (i, &byte) in [0x11, 0x22, 0x33].iter().enumerate() {
+            mem.poke(0x9000 + i as u16, byte);
+        }
+        cpu.h = 0x90;
+        cpu.l = 0x00;
+        cpu.d = 0xA0;
+        cpu.e = 0x00;
+        cpu.b = 0;
+        cpu.c = 3;
+        run_block(&mut cpu, &mut mem, &mut io, &[0xED, 0xB0]);
+        assert_eq!((mem.peek(0xA000), mem.peek(0xA002)), (0x11, 0x33));
+        assert_eq!((cpu.b, cpu.c, cpu.e, cpu.l), (0, 0, 0x03, 0x03));
+        assert_eq!(cpu.f & P_V, 0);
+
+        // CPIR stops on the byte it looks for, with HL past it
+        cpu.h = 0x90;
+        cpu.l = 0x00;
+        cpu.c = 3;
+        cpu.a = 0x22;
+        run_block(&mut cpu, &mut mem, &mut io, &[0xED, 0xB1]);
+        assert_eq!((cpu.l, cpu.c), (0x02, 1));
+        assert_eq!(cpu.f & (Z | P_V), Z | P_V);
+
+        // RLD takes the high nibble of (HL) into A
+        cpu.h = 0x90;
+        cpu.l = 0x00;
+        cpu.a = 0xF5;
+        run_block(&mut cpu, &mut mem, &mut io, &[0xED, 0x6F]);
+        assert_eq!((cpu.a, mem.peek(0x9000)), (0xF1, 0x15));
+    }
+
+    #[test]
+    fn floating_bus_is_read_at_t3() {
+        let display_start = Model::Spectrum48K.timing().display_start;
+        // IN A,(0xFF) with A = 0xFF, a port nothing holds: fetch 4, n 3,
+        // and the port is read 3 T-states into the I/O cycle
+        let read_at = 10;
+        let screen = [(0x4000, 0x11), (0x5800, 0x22), (0x4001, 0x33), (0x5801, 0x44)];
+        // Bitmap and attributes of two cells, then the idle bus
+        let expected = [0x11, 0x22, 0x33, 0x44, 0xFF];
+        for (offset, &value) in expected.iter().enumerate() {
+            let mut mem = Memory::for_model(Model::Spectrum48K);
+            let mut io = IoBus::new();
+            for &(addr, byte) in screen.iter() {
+                mem.poke(addr, byte);
+            }
+            mem.poke(CODE, 0xDB);
+            mem.poke(CODE + 1, 0xFF);
+            let mut cpu = Z80::new();
+            cpu.pc = CODE;
+            cpu.a = 0xFF;
+            cpu.t_states = display_start + offset as u64 - read_at;
+            cpu.exec(&mut mem, &mut io);
+            assert_eq!(cpu.a, value, "T-state {}", display_start + offset as u64);
+            assert_eq!(cpu.t_states, display_start + offset as u64 + 1);
+        }
+    }
+
+    #[test]
+    fn reordered_accesses_keep_their_values() {
+        // CALL takes its address from after the opcode
+        let (cpu, mem) = execute(CODE, &[0xCD, 0x34, 0x12], &|cpu| cpu.sp = 0x9000);
+        assert_eq!((cpu.pc, cpu.sp), (0x1234, 0x8FFE));
+        assert_eq!((mem.peek(0x8FFF), mem.peek(0x8FFE)), (0x80, 0x03));
+
+        // EX (SP),HL swaps both bytes
+        let (cpu, mem) = execute(CODE, &[0xE3], &|cpu| {
+            cpu.sp = 0x9000;
+            cpu.h = 0x12;
+            cpu.l = 0x34
+        });
+        assert_eq!((cpu.h, cpu.l), (0x00, 0x00));
+        assert_eq!((mem.peek(0x9000), mem.peek(0x9001)), (0x34, 0x12));
+
+        // INC (IX+5) takes the displacement
+        let (cpu, mem) = execute(CODE, &[0xDD, 0x34, 0x05], &|cpu| {
+            cpu.ix_h = 0x90;
+            cpu.ix_l = 0x00
+        });
+        assert_eq!((cpu.pc, mem.peek(0x9005)), (CODE + 3, 0x01));
     }
 }
@@ -0,0 +1,630 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use ay;
+use machine::{Machine, Model};
+
+const V1_HEADER_LENGTH: usize = 30;
+const V2_EXTRA_LENGTH: usize = 23;
+const V3_EXTRA_LENGTH: usize = 54;
+const V3_EXTRA_LENGTH_1FFD: usize = 55;
+
+const PAGE_LENGTH: usize = 16 * 1024;
+// Block length meaning the page is stored without compression
+const UNCOMPRESSED_PAGE: usize = 0xFFFF;
+
+// Flags in byte 12 of the header
+const R_BIT_7: u8 = 0x01;
+const COMPRESSED: u8 = 0x20;
+// Flag in byte 37 for a 48K with an AY interface
+const AY_IN_USE: u8 = 0x04;
+// Flag in byte 37 that turns the 48K hardware into a 16K and the +3 into
+// a +2A
+const MODIFIED_HARDWARE: u8 = 0x80;
+
+// Pages of a 48K snapshot and the RAM bank they hold
+const PAGES_48K: [(u8, usize); 3] = [(8, 5), (4, 2), (5, 0)];
+// On 128K snapshots pages 3 to 10 hold banks 0 to 7
+const FIRST_128K_PAGE: u8 = 3;
+
+// Hardware modes written by this emulator
+const HARDWARE_48K: u8 = 0;
+const HARDWARE_128K: u8 = 4;
+const HARDWARE_PLUS3: u8 = 7;
+const HARDWARE_PENTAGON: u8 = 9;
+const HARDWARE_PLUS2A: u8 = 13;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_word(bytes: &[u8], offset: usize) -> u16 {
+    ((bytes[offset + 1] as u16) << 8) | bytes[offset] as u16
+}
+
+fn write_word(bytes: &mut Vec<u8>, word: u16) {
+    bytes.push((word & 0xFF) as u8);
+    bytes.push((word >> 8) as u8);
+}
+
+// The meaning of the hardware byte changed between versions 2 and 3
+fn model_for(version: u8, hardware: u8) -> Option<Model> {
+    match (version, hardware) {
+        (2, 0) | (2, 1) | (3, 0) | (3, 1) | (3, 3) => Some(Model::Spectrum48K),
+        (2, 3) | (2, 4) | (3, 4) | (3, 5) | (3, 6) | (3, 12) => Some(Model::Spectrum128K),
+        (3, HARDWARE_PLUS3) | (3, 8) => Some(Model::SpectrumPlus3),
+        (3, HARDWARE_PENTAGON) => Some(Model::Pentagon128),
+        (3, HARDWARE_PLUS2A) => Some(Model::SpectrumPlus2A),
+        _ => None,
+    }
+}
+
+fn bank_for(model: Model, page: u8) -> Option<usize> {
+    match model {
+        Model::Spectrum16K | Model::Spectrum48K => PAGES_48K
+            .iter()
+            .find(|&&(n, _)| n == page)
+            .map(|&(_, bank)| bank),
+        _ => {
+            if (FIRST_128K_PAGE..FIRST_128K_PAGE + 8).contains(&page) {
+                Some((page - FIRST_128K_PAGE) as usize)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn page_for(model: Model, bank: usize) -> u8 {
+    match model {
+        Model::Spectrum16K | Model::Spectrum48K => PAGES_48K
+            .iter()
+            .find(|&&(_, b)| b == bank)
+            .map(|&(n, _)| n)
+            .unwrap_or(0),
+        _ => FIRST_128K_PAGE + bank as u8,
+    }
+}
+
+// Expands ED ED nn bb sequences (nn copies of bb) until `out` is full
+fn decompress(data: &[u8], out: &mut [u8]) -> io::Result<()> {
+    let mut src = 0;
+    let mut dst = 0;
+    while dst < out.len() {
+        if src + 3 < data.len() && data[src] == 0xED && data[src + 1] == 0xED {
+            let count = data[src + 2] as usize;
+            let value = data[src + 3];
+            if dst + count > out.len() {
+                return Err(invalid_data(
+                    "bloque comprimido demasiado largo".to_string(),
+                ));
+            }
+            for byte in out[dst..dst + count].iter_mut() {
+                *byte = value;
+            }
+            dst += count;
+            src += 4;
+        } else if src < data.len() {
+            out[dst] = data[src];
+            dst += 1;
+            src += 1;
+        } else {
+            return Err(invalid_data("bloque comprimido incompleto".to_string()));
+        }
+    }
+    Ok(())
+}
+
+// Runs of five or more equal bytes, or of two or more 0xED, become
+// ED ED nn bb. A byte following a lone 0xED is never part of a run.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut lone_ed = false;
+    let mut i = 0;
+    while i < data.len() {
+        let value = data[i];
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == value && run < 255 {
+            run += 1;
+        }
+
+        if !lone_ed && (run >= 5 || (value == 0xED && run >= 2)) {
+            out.extend_from_slice(&[0xED, 0xED, run as u8, value]);
+            lone_ed = false;
+            i += run;
+        } else {
+            out.push(value);
+            lone_ed = value == 0xED && !lone_ed;
+            i += 1;
+        }
+    }
+    out
+}
+
+pub fn load<P: AsRef<Path>>(machine: &mut Machine, path: P) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    load_bytes(machine, &bytes)
+}
+
+pub fn load_bytes(machine: &mut Machine, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() < V1_HEADER_LENGTH {
+        return Err(invalid_data("cabecera Z80 incompleta".to_string()));
+    }
+    let header = &bytes[..V1_HEADER_LENGTH];
+    // Some old programs store 255 in this byte and mean 1
+    let flags = if header[12] == 0xFF { 1 } else { header[12] };
+
+    let mut pc = read_word(header, 6);
+    let mut t_states = None;
+    let mut ay_registers = None;
+    if pc == 0 {
+        // Version 2 or 3, the PC is in the additional header
+        if bytes.len() < V1_HEADER_LENGTH + 2 {
+            return Err(invalid_data("cabecera Z80 incompleta".to_string()));
+        }
+        let extra_length = read_word(bytes, 30) as usize;
+        let pages_start = V1_HEADER_LENGTH + 2 + extra_length;
+        if bytes.len() < pages_start {
+            return Err(invalid_data("cabecera Z80 incompleta".to_string()));
+        }
+        let extra = &bytes[V1_HEADER_LENGTH..pages_start];
+        let version = match extra_length {
+            V2_EXTRA_LENGTH => 2,
+            V3_EXTRA_LENGTH | V3_EXTRA_LENGTH_1FFD => 3,
+            _ => {
+                return Err(invalid_data(format!(
+                    "cabecera adicional de {} bytes no válida",
+                    extra_length
+                )))
+            }
+        };
+
+        pc = read_word(extra, 2);
+        let hardware = extra[4];
+        let model = match model_for(version, hardware) {
+            Some(Model::Spectrum48K) if extra[7] & MODIFIED_HARDWARE != 0 => Model::Spectrum16K,
+            Some(Model::SpectrumPlus3) if extra[7] & MODIFIED_HARDWARE != 0 => {
+                Model::SpectrumPlus2A
+            }
+            Some(model) => model,
+            None => {
+                return Err(invalid_data(format!(
+                    "modelo {} del Z80 v{} no soportado",
+                    hardware, version
+                )))
+            }
+        };
+        machine.set_model(model);
+        machine.mem.restore_7ffd(extra[5]);
+        if extra_length == V3_EXTRA_LENGTH_1FFD {
+            machine.mem.restore_1ffd(extra[2 + V3_EXTRA_LENGTH]);
+        }
+
+        if version == 3 {
+            let quarter = model.timing().frame_t_states / 4;
+            let low = read_word(extra, 25) as u64;
+            let high = extra[27] as u64;
+            t_states = Some(((high + 1) % 4 + 1) * quarter - (low + 1));
+        }
+        if model.has_ay() || extra[7] & AY_IN_USE != 0 {
+            ay_registers = Some((extra[8], &extra[9..9 + ay::REGISTER_COUNT]));
+        }
+
+        load_pages(machine, &bytes[pages_start..])?;
+    } else {
+        machine.set_model(Model::Spectrum48K);
+        let data = &bytes[V1_HEADER_LENGTH..];
+        let mut ram = vec![0; 3 * PAGE_LENGTH];
+        if flags & COMPRESSED != 0 {
+            decompress(data, &mut ram)?;
+        } else if data.len() >= 3 * PAGE_LENGTH {
+            ram.copy_from_slice(&data[..3 * PAGE_LENGTH]);
+        } else {
+            return Err(invalid_data("imagen de memoria incompleta".to_string()));
+        }
+        for (offset, value) in ram.iter().enumerate() {
+            machine.mem.poke((0x4000 + offset) as u16, *value);
+        }
+    }
+
+    let cpu = &mut machine.cpu;
+    cpu.a = header[0];
+    cpu.f = header[1];
+    cpu.c = header[2];
+    cpu.b = header[3];
+    cpu.l = header[4];
+    cpu.h = header[5];
+    cpu.pc = pc;
+    cpu.sp = read_word(header, 8);
+    cpu.i = header[10];
+    cpu.r = (header[11] & 0x7F) | if flags & R_BIT_7 != 0 { 0x80 } else { 0 };
+    cpu.e = header[13];
+    cpu.d = header[14];
+    cpu.c_alt = header[15];
+    cpu.b_alt = header[16];
+    cpu.e_alt = header[17];
+    cpu.d_alt = header[18];
+    cpu.l_alt = header[19];
+    cpu.h_alt = header[20];
+    cpu.a_alt = header[21];
+    cpu.f_alt = header[22];
+    cpu.iy_l = header[23];
+    cpu.iy_h = header[24];
+    cpu.ix_l = header[25];
+    cpu.ix_h = header[26];
+    cpu.iff1 = header[27] != 0;
+    cpu.iff2 = header[28] != 0;
+    cpu.im = header[29] & 0x03;
+    cpu.halted = false;
+    if let Some(t_states) = t_states {
+        cpu.t_states = machine.frame_start + t_states;
+    }
+    machine.io.border = (flags >> 1) & 0x07;
+
+    if let Some((selected, registers)) = ay_registers {
+        if machine.io.ay.is_none() {
+            machine.attach_ay();
+        }
+        if let Some(ref mut ay) = machine.io.ay {
+            for (reg, value) in registers.iter().enumerate() {
+                ay.set_register(reg, *value);
+            }
+            ay.select_register(selected);
+        }
+    } else {
+        machine.io.ay = None;
+    }
+    machine.sync_sound();
+
+    Ok(())
+}
+
+fn load_pages(machine: &mut Machine, mut data: &[u8]) -> io::Result<()> {
+    let mut page = vec![0; PAGE_LENGTH];
+    while data.len() >= 3 {
+        let length = read_word(data, 0) as usize;
+        let number = data[2];
+        data = &data[3..];
+
+        if length == UNCOMPRESSED_PAGE {
+            if data.len() < PAGE_LENGTH {
+                return Err(invalid_data(format!("página {} incompleta", number)));
+            }
+            page.copy_from_slice(&data[..PAGE_LENGTH]);
+            data = &data[PAGE_LENGTH..];
+        } else {
+            if data.len() < length {
+                return Err(invalid_data(format!("página {} incompleta", number)));
+            }
+            decompress(&data[..length], &mut page)?;
+            data = &data[length..];
+        }
+
+        // Pages that are not RAM of the model (ROMs and interface memory) are skipped
+        if let Some(bank) = bank_for(machine.model, number) {
+            machine.mem.ram_bank_mut(bank).copy_from_slice(&page);
+        }
+    }
+    Ok(())
+}
+
+pub fn save<P: AsRef<Path>>(machine: &Machine, path: P) -> io::Result<()> {
+    let bytes = save_bytes(machine);
+    File::create(path)?.write_all(&bytes)
+}
+
+// Writes a version 3 snapshot with compressed pages
+pub fn save_bytes(machine: &Machine) -> Vec<u8> {
+    let cpu = &machine.cpu;
+    let mut bytes = vec![cpu.a, cpu.f, cpu.c, cpu.b, cpu.l, cpu.h];
+    // PC 0 tells the loader to look at the additional header
+    write_word(&mut bytes, 0);
+    write_word(&mut bytes, cpu.sp);
+    bytes.push(cpu.i);
+    bytes.push(cpu.r & 0x7F);
+    bytes.push((cpu.r >> 7) | (machine.io.border << 1));
+    bytes.push(cpu.e);
+    bytes.push(cpu.d);
+    bytes.push(cpu.c_alt);
+    bytes.push(cpu.b_alt);
+    bytes.push(cpu.e_alt);
+    bytes.push(cpu.d_alt);
+    bytes.push(cpu.l_alt);
+    bytes.push(cpu.h_alt);
+    bytes.push(cpu.a_alt);
+    bytes.push(cpu.f_alt);
+    bytes.push(cpu.iy_l);
+    bytes.push(cpu.iy_h);
+    bytes.push(cpu.ix_l);
+    bytes.push(cpu.ix_h);
+    bytes.push(cpu.iff1 as u8);
+    bytes.push(cpu.iff2 as u8);
+    bytes.push(cpu.im);
+
+    // The +2A/+3 need the extra byte for 0x1FFD
+    let extra_length = if machine.mem.has_special_paging() {
+        V3_EXTRA_LENGTH_1FFD
+    } else {
+        V3_EXTRA_LENGTH
+    };
+    let mut extra = vec![0; extra_length];
+    extra[0] = (cpu.pc & 0xFF) as u8;
+    extra[1] = (cpu.pc >> 8) as u8;
+    extra[2] = match machine.model {
+        Model::Spectrum16K | Model::Spectrum48K => HARDWARE_48K,
+        Model::Spectrum128K => HARDWARE_128K,
+        Model::Pentagon128 => HARDWARE_PENTAGON,
+        Model::SpectrumPlus2A => HARDWARE_PLUS2A,
+        Model::SpectrumPlus3 => HARDWARE_PLUS3,
+    };
+    extra[3] = machine.mem.last_7ffd();
+    if machine.model == Model::Spectrum16K {
+        extra[5] = MODIFIED_HARDWARE;
+    }
+    if let Some(ref ay) = machine.io.ay {
+        if !machine.model.has_ay() {
+            extra[5] |= AY_IN_USE;
+        }
+        extra[6] = ay.selected_register();
+        for reg in 0..ay::REGISTER_COUNT {
+            extra[7 + reg] = ay.register(reg);
+        }
+    }
+    let frame_length = machine.model.timing().frame_t_states;
+    let quarter = frame_length / 4;
+    let frame_t_states = cpu.t_states.saturating_sub(machine.frame_start) % frame_length;
+    let low = quarter - (frame_t_states % quarter) - 1;
+    extra[23] = (low & 0xFF) as u8;
+    extra[24] = (low >> 8) as u8;
+    extra[25] = ((frame_t_states / quarter + 3) % 4) as u8;
+    if machine.mem.has_special_paging() {
+        extra[V3_EXTRA_LENGTH] = machine.mem.last_1ffd();
+    }
+    write_word(&mut bytes, extra_length as u16);
+    bytes.extend_from_slice(&extra);
+
+    for &bank in machine.model.ram_banks() {
+        let compressed = compress(machine.mem.ram_bank(bank));
+        write_word(&mut bytes, compressed.len() as u16);
+        bytes.push(page_for(machine.model, bank));
+        bytes.extend_from_slice(&compressed);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        let mut out = vec![0; data.len()];
+        decompress(&compressed, &mut out).unwrap();
+        assert_eq!(out, data);
+        compressed
+    }
+
+    #[test]
+    fn compress_runs() {
+        assert_eq!(round_trip(&[1; 5]), [0xED, 0xED, 5, 1]);
+        assert_eq!(round_trip(&[1; 4]), [1; 4]);
+        // Runs longer than 255 are split
+        assert_eq!(
+            round_trip(&[7; 300]),
+            [0xED, 0xED, 255, 7, 0xED, 0xED, 45, 7]
+        );
+        assert_eq!(round_trip(&[]), []);
+    }
+
+    #[test]
+    fn compress_ed_runs() {
+        // Two 0xED are already a run, one is left alone
+        assert_eq!(round_trip(&[0xED, 0xED]), [0xED, 0xED, 2, 0xED]);
+        assert_eq!(round_trip(&[0xED, 0xED, 0xED]), [0xED, 0xED, 3, 0xED]);
+        assert_eq!(round_trip(&[0xED]), [0xED]);
+        assert_eq!(round_trip(&[0xED, 1, 0xED]), [0xED, 1, 0xED]);
+    }
+
+    #[test]
+    fn compress_after_lone_ed() {
+        // The run cannot start just after a lone 0xED or it would read
+        // as ED ED, so it starts a byte later, if still long enough
+        assert_eq!(round_trip(&[0xED, 0, 0, 0, 0, 0]), [0xED, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            round_trip(&[0xED, 0, 0, 0, 0, 0, 0]),
+            [0xED, 0, 0xED, 0xED, 5, 0]
+        );
+        // A compressed run of 0xED is no lone 0xED
+        assert_eq!(
+            round_trip(&[0xED, 0xED, 0, 0, 0, 0, 0]),
+            [0xED, 0xED, 2, 0xED, 0xED, 0xED, 5, 0]
+        );
+    }
+
+    #[test]
+    fn compress_mixed_data() {
+        let mut seed = 1u32;
+        let data: Vec<u8> = (0..PAGE_LENGTH)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                // Plenty of 0xED and of runs
+                match (seed >> 16) % 4 {
+                    0 => 0xED,
+                    1 => 0,
+                    _ => (seed >> 24) as u8,
+                }
+            })
+            .collect();
+        round_trip(&data);
+    }
+
+    #[test]
+    fn decompress_errors() {
+        let mut out = [0; 4];
+        assert!(decompress(&[0xED, 0xED, 5, 1], &mut out).is_err());
+        assert!(decompress(&[1, 2, 3], &mut out).is_err());
+    }
+
+    fn fill(machine: &mut Machine) {
+        let cpu = &mut machine.cpu;
+        cpu.a = 0x12;
+        cpu.f = 0x34;
+        cpu.b = 0x56;
+        cpu.c = 0x78;
+        cpu.d = 0x9A;
+        cpu.e = 0xBC;
+        cpu.h = 0xDE;
+        cpu.l = 0xF0;
+        cpu.a_alt = 0x21;
+        cpu.f_alt = 0x43;
+        cpu.b_alt = 0x65;
+        cpu.c_alt = 0x87;
+        cpu.d_alt = 0xA9;
+        cpu.e_alt = 0xCB;
+        cpu.h_alt = 0xED;
+        cpu.l_alt = 0x0F;
+        cpu.ix_h = 0x11;
+        cpu.ix_l = 0x22;
+        cpu.iy_h = 0x33;
+        cpu.iy_l = 0x44;
+        cpu.i = 0x3F;
+        cpu.r = 0xD5;
+        cpu.sp = 0xFF00;
+        cpu.pc = 0x8123;
+        cpu.im = 2;
+        cpu.iff1 = true;
+        cpu.iff2 = false;
+        cpu.t_states = machine.frame_start + 12_345;
+        machine.io.border = 3;
+        let banks = machine.model.ram_banks();
+        for &bank in banks {
+            for (i, byte) in machine.mem.ram_bank_mut(bank).iter_mut().enumerate() {
+                // Runs, 0xED and plain bytes
+                *byte = match i % 64 {
+                    0..=9 => 0xED,
+                    10..=39 => bank as u8,
+                    _ => (i * 7) as u8,
+                };
+            }
+        }
+    }
+
+    fn check_round_trip(model: Model) -> Machine {
+        let mut machine = Machine::with_model(model);
+        fill(&mut machine);
+        let bytes = save_bytes(&machine);
+
+        let mut loaded = Machine::with_model(Model::Spectrum48K);
+        load_bytes(&mut loaded, &bytes).unwrap();
+        assert_eq!(loaded.model, model);
+        let (a, b) = (&machine.cpu, &loaded.cpu);
+        assert_eq!((a.a, a.f, a.b, a.c), (b.a, b.f, b.b, b.c));
+        assert_eq!((a.d, a.e, a.h, a.l), (b.d, b.e, b.h, b.l));
+        assert_eq!(
+            (a.a_alt, a.f_alt, a.b_alt, a.c_alt),
+            (b.a_alt, b.f_alt, b.b_alt, b.c_alt)
+        );
+        assert_eq!(
+            (a.d_alt, a.e_alt, a.h_alt, a.l_alt),
+            (b.d_alt, b.e_alt, b.h_alt, b.l_alt)
+        );
+        assert_eq!(
+            (a.ix_h, a.ix_l, a.iy_h, a.iy_l),
+            (b.ix_h, b.ix_l, b.iy_h, b.iy_l)
+        );
+        assert_eq!(
+            (a.i, a.r, a.im, a.iff1, a.iff2),
+            (b.i, b.r, b.im, b.iff1, b.iff2)
+        );
+        assert_eq!((a.sp, a.pc), (b.sp, b.pc));
+        assert_eq!(
+            a.t_states - machine.frame_start,
+            b.t_states - loaded.frame_start
+        );
+        assert_eq!(machine.io.border, loaded.io.border);
+        for &bank in model.ram_banks() {
+            assert_eq!(loaded.mem.ram_bank(bank), machine.mem.ram_bank(bank));
+        }
+        loaded
+    }
+
+    #[test]
+    fn round_trip_48k() {
+        let loaded = check_round_trip(Model::Spectrum48K);
+        assert!(loaded.io.ay.is_none());
+    }
+
+    #[test]
+    fn round_trip_16k() {
+        check_round_trip(Model::Spectrum16K);
+    }
+
+    #[test]
+    fn round_trip_128k() {
+        let mut machine = Machine::with_model(Model::Spectrum128K);
+        fill(&mut machine);
+        machine.mem.restore_7ffd(0x16);
+        if let Some(ref mut ay) = machine.io.ay {
+            ay.set_register(0, 0xAB);
+            ay.set_register(7, 0x38);
+            ay.select_register(7);
+        }
+        let bytes = save_bytes(&machine);
+        let mut loaded = Machine::new();
+        load_bytes(&mut loaded, &bytes).unwrap();
+        assert_eq!(loaded.mem.last_7ffd(), 0x16);
+        let ay = loaded.io.ay.as_ref().unwrap();
+        assert_eq!((ay.register(0), ay.register(7)), (0xAB, 0x38));
+        assert_eq!(ay.selected_register(), 7);
+
+        check_round_trip(Model::Spectrum128K);
+    }
+
+    #[test]
+    fn round_trip_plus3() {
+        let mut machine = Machine::with_model(Model::SpectrumPlus3);
+        machine.mem.restore_1ffd(0x05);
+        let bytes = save_bytes(&machine);
+        let mut loaded = Machine::new();
+        load_bytes(&mut loaded, &bytes).unwrap();
+        assert_eq!(loaded.model, Model::SpectrumPlus3);
+        assert_eq!(loaded.mem.last_1ffd(), 0x05);
+
+        check_round_trip(Model::SpectrumPlus3);
+        check_round_trip(Model::SpectrumPlus2A);
+        check_round_trip(Model::Pentagon128);
+    }
+
+    #[test]
+    fn load_version_1() {
+        let mut bytes = vec![0; V1_HEADER_LENGTH];
+        // PC 0x8000, SP 0xFF00 and the RAM compressed
+        bytes[6] = 0x00;
+        bytes[7] = 0x80;
+        bytes[9] = 0xFF;
+        bytes[12] = COMPRESSED | (2 << 1);
+        bytes.extend_from_slice(&[0xED, 0xED, 0xFF, 0x00]);
+        bytes.extend_from_slice(&[0xAA, 0xED, 0x01]);
+        for _ in 0..(3 * PAGE_LENGTH - 258) / 255 {
+            bytes.extend_from_slice(&[0xED, 0xED, 0xFF, 0x11]);
+        }
+        bytes.extend_from_slice(&[0xED, 0xED, ((3 * PAGE_LENGTH - 258) % 255) as u8, 0x11]);
+        // End marker
+        bytes.extend_from_slice(&[0x00, 0xED, 0xED, 0x00]);
+
+        let mut machine = Machine::with_model(Model::Spectrum128K);
+        load_bytes(&mut machine, &bytes).unwrap();
+        assert_eq!(machine.model, Model::Spectrum48K);
+        assert_eq!((machine.cpu.pc, machine.cpu.sp), (0x8000, 0xFF00));
+        assert_eq!(machine.io.border, 2);
+        assert_eq!(machine.mem.peek(0x4000), 0x00);
+        assert_eq!(machine.mem.peek(0x40FF), 0xAA);
+        assert_eq!(machine.mem.peek(0x4100), 0xED);
+        assert_eq!(machine.mem.peek(0x4101), 0x01);
+        assert_eq!(machine.mem.peek(0xFFFF), 0x11);
+    }
+}
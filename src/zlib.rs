@@ -0,0 +1,477 @@
+use std::io;
+
+use checksum;
+
+// Inflate for the zlib streams found in snapshot files. It favours
+// simplicity over speed: symbols are decoded one bit at a time.
+// The compressor, for the files the emulator writes, only uses the fixed
+// codes, which is enough for screens made of long runs.
+
+const MAX_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Longest match and farthest distance deflate can code
+const MAX_MATCH: usize = 258;
+const MIN_MATCH: usize = 3;
+const WINDOW_SIZE: usize = 32 * 1024;
+const HASH_BITS: usize = 15;
+// Earlier positions with the same hash tried before taking the best match
+const MAX_CHAIN: usize = 64;
+
+// Order in which the code length code lengths are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            bit_buf: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        while self.bit_count < count {
+            if self.pos >= self.data.len() {
+                return Err(invalid_data("flujo deflate incompleto"));
+            }
+            self.bit_buf |= (self.data[self.pos] as u32) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u32 << count) - 1);
+        self.bit_buf >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+// Canonical Huffman code: number of codes of each length and the symbols
+// sorted by code
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> io::Result<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(invalid_data("código Huffman no válido"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &index in CODE_LENGTH_ORDER.iter().take(code_count) {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_lengths.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err(invalid_data("repetición sin longitud previa"));
+                }
+                (lengths[i - 1], 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(invalid_data("demasiadas longitudes de código"));
+        }
+        for length in lengths[i..i + repeat].iter_mut() {
+            *length = value;
+        }
+        i += repeat;
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let index = symbol - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err(invalid_data("longitud no válida"));
+            }
+            let length =
+                LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+            let index = distances.decode(reader)? as usize;
+            if index >= DISTANCE_BASE.len() {
+                return Err(invalid_data("distancia no válida"));
+            }
+            let distance =
+                DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+            if distance > out.len() {
+                return Err(invalid_data("distancia fuera de la ventana"));
+            }
+
+            let start = out.len() - distance;
+            for i in 0..length {
+                let byte = out[start + i];
+                out.push(byte);
+            }
+        }
+    }
+}
+
+// Decompresses a raw deflate stream
+pub fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader = BitReader::new(data);
+    let mut out = Vec::new();
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align_to_byte();
+                let pos = reader.pos;
+                if pos + 4 > data.len() {
+                    return Err(invalid_data("bloque sin comprimir incompleto"));
+                }
+                let len = data[pos] as usize | (data[pos + 1] as usize) << 8;
+                let end = pos + 4 + len;
+                if end > data.len() {
+                    return Err(invalid_data("bloque sin comprimir incompleto"));
+                }
+                out.extend_from_slice(&data[pos + 4..end]);
+                reader.pos = end;
+            }
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(invalid_data("tipo de bloque deflate no válido")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+// Decompresses a zlib stream: two header bytes, deflate data and checksum
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < 2 || data[0] & 0x0F != 8 {
+        return Err(invalid_data("cabecera zlib no válida"));
+    }
+    let check = (data[0] as u16) << 8 | data[1] as u16;
+    if !check.is_multiple_of(31) {
+        return Err(invalid_data("cabecera zlib no válida"));
+    }
+    inflate(&data[2..])
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    // Values go in from the lowest bit
+    fn bits(&mut self, value: u32, count: u32) {
+        self.bit_buf |= value << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    // Huffman codes go in from the highest bit
+    fn code(&mut self, code: u32, length: u32) {
+        let reversed = code.reverse_bits() >> (32 - length);
+        self.bits(reversed, length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.out.push(self.bit_buf as u8);
+        }
+        self.out
+    }
+}
+
+fn write_literal(writer: &mut BitWriter, symbol: usize) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => writer.code(0x30 + symbol, 8),
+        144..=255 => writer.code(0x190 + symbol - 144, 9),
+        256..=279 => writer.code(symbol - 256, 7),
+        _ => writer.code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+    write_literal(writer, 257 + code);
+    writer.bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+    let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+    writer.code(code as u32, 5);
+    writer.bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let value = (data[pos] as usize) << 16 | (data[pos + 1] as usize) << 8 | data[pos + 2] as usize;
+    (value.wrapping_mul(2_654_435_761) >> 8) & ((1 << HASH_BITS) - 1)
+}
+
+// Compresses into a raw deflate stream of a single fixed code block,
+// finding matches through chains of earlier positions with the same hash
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 4),
+        bit_buf: 0,
+        bit_count: 0,
+    };
+    writer.bits(1, 1);
+    writer.bits(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |head: &mut Vec<usize>, prev: &mut Vec<usize>, pos: usize| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(data, pos);
+            prev[pos % WINDOW_SIZE] = head[h];
+            head[h] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_length = 0;
+        let mut best_distance = 0;
+        if pos + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(data, pos)];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(data[pos..pos + max_length].iter())
+                    .take_while(|&(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = pos - candidate;
+                    if length == max_length {
+                        break;
+                    }
+                }
+                candidate = prev[candidate % WINDOW_SIZE];
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_match(&mut writer, best_length, best_distance);
+            for p in pos..pos + best_length {
+                insert(&mut head, &mut prev, p);
+            }
+            pos += best_length;
+        } else {
+            write_literal(&mut writer, data[pos] as usize);
+            insert(&mut head, &mut prev, pos);
+            pos += 1;
+        }
+    }
+
+    write_literal(&mut writer, 256);
+    writer.finish()
+}
+
+// Compresses into a zlib stream, as PNG files want
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window and the check bits that make the header
+    // a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&checksum::adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"The quick brown fox jumps over the lazy dog. Pack my box with five \
+        dozen liquor jugs. How vexingly quick daft zebras jump! ";
+
+    // zlib.compress(b"Sinclair ZX Spectrum", 0)
+    const STORED: [u8; 31] = [
+        0x78, 0x01, 0x01, 0x14, 0x00, 0xEB, 0xFF, 0x53, 0x69, 0x6E, 0x63, 0x6C, 0x61, 0x69, 0x72,
+        0x20, 0x5A, 0x58, 0x20, 0x53, 0x70, 0x65, 0x63, 0x74, 0x72, 0x75, 0x6D, 0x4D, 0x01, 0x07,
+        0x7B,
+    ];
+
+    // zlib.compress(TEXT * 3, 9), a single dynamic Huffman block
+    const DYNAMIC: [u8; 112] = [
+        0x78, 0xDA, 0xE5, 0x8D, 0x4B, 0x16, 0x83, 0x20, 0x10, 0x04, 0xAF, 0xD2, 0xB9, 0x80, 0xE7,
+        0xC8, 0x32, 0x0B, 0x2F, 0x00, 0x3A, 0x20, 0x09, 0x32, 0x91, 0xAF, 0x70, 0x7A, 0xE7, 0xE5,
+        0xE5, 0x16, 0xAE, 0xAB, 0xBA, 0x6B, 0xDE, 0x08, 0x47, 0x71, 0xCB, 0x07, 0x3A, 0x72, 0x0B,
+        0x30, 0x7C, 0xE2, 0x5D, 0xF6, 0x6F, 0x02, 0x57, 0x8A, 0xC8, 0x82, 0xBD, 0x1A, 0x1D, 0x2B,
+        0xDB, 0x09, 0x2F, 0x25, 0xDE, 0xDE, 0xA1, 0x45, 0x6A, 0x2E, 0x6F, 0x30, 0xAE, 0x92, 0xA0,
+        0x41, 0x01, 0xDE, 0x1D, 0x85, 0xA3, 0x6C, 0x6D, 0x9A, 0xF0, 0xE4, 0x86, 0x4A, 0xA7, 0x0B,
+        0xD6, 0xF7, 0xFF, 0xFD, 0xAA, 0x4C, 0xC6, 0x20, 0x1D, 0x55, 0xFA, 0x05, 0x1E, 0x98, 0x6F,
+        0x99, 0xBE, 0x00, 0x53, 0x85, 0x85, 0x27,
+    ];
+
+    #[test]
+    fn round_trip() {
+        // Bytes that hardly repeat, past the 32K window
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..70_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        // A screen: long runs, a pattern and the attributes
+        let mut screen = vec![0; 6144];
+        for (i, byte) in screen.iter_mut().enumerate().skip(2048).take(256) {
+            *byte = (i * 7) as u8;
+        }
+        screen.extend(vec![0x38; 768]);
+        let all_bytes: Vec<u8> = (0..=255).collect();
+
+        let inputs: [&[u8]; 6] = [&[], &[0x42], &all_bytes, &screen, &noise, &TEXT.repeat(400)];
+        for input in inputs.iter() {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed).unwrap(), *input);
+            let adler = &compressed[compressed.len() - 4..];
+            assert_eq!(adler, &checksum::adler32(input).to_be_bytes());
+        }
+        assert!(compress(&screen).len() < 1000);
+    }
+
+    #[test]
+    fn stored_block_from_zlib() {
+        assert_eq!(decompress(&STORED).unwrap(), b"Sinclair ZX Spectrum");
+    }
+
+    #[test]
+    fn dynamic_block_from_zlib() {
+        assert_eq!((DYNAMIC[2] >> 1) & 3, 2);
+        assert_eq!(decompress(&DYNAMIC).unwrap(), TEXT.repeat(3));
+    }
+
+    #[test]
+    fn broken_streams_are_errors() {
+        assert!(decompress(&DYNAMIC[..40]).is_err());
+        assert!(decompress(&STORED[..10]).is_err());
+        assert!(decompress(&[0x78, 0x00]).is_err());
+        // Block type 3 does not exist
+        assert!(inflate(&[0x07]).is_err());
+    }
+}